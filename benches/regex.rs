@@ -0,0 +1,25 @@
+//! Compares the per frame overlay text parsing with the regex compiled for
+//! each frame and with the regex compiled once per process.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use regex::Regex;
+use webrtcperf_vmaf_utils::{parse_timestamp_text, timestamp_regex, TIMESTAMP_PATTERN};
+
+const TEXT: &str = "1-1234567";
+
+fn parse_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_timestamp_text");
+    group.bench_function("compiled per frame", |b| {
+        b.iter(|| {
+            let frame_re = Regex::new(TIMESTAMP_PATTERN).unwrap();
+            parse_timestamp_text(&frame_re, black_box(TEXT), None)
+        })
+    });
+    group.bench_function("compiled once", |b| {
+        b.iter(|| parse_timestamp_text(timestamp_regex(), black_box(TEXT), None))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,616 @@
+extern crate ffmpeg_next as ffmpeg;
+
+use ffmpeg_next::{
+    codec, decoder, encoder, format, frame, software::resampling, ChannelLayout, Packet, Rational,
+};
+use log::debug;
+
+/// The sample rate used for generating and detecting the markers.
+const SAMPLE_RATE: u32 = 48000;
+/// The default Opus bitrate of the markers track (bits/s).
+pub const DEFAULT_AUDIO_BITRATE: usize = 64000;
+/// The interval between two consecutive markers (seconds).
+const MARKER_INTERVAL: f64 = 2.0;
+/// The duration of a single DTMF symbol (seconds).
+const SYMBOL_DURATION: f64 = 0.04;
+/// The silence between two consecutive DTMF symbols (seconds).
+const GAP_DURATION: f64 = 0.04;
+/// The peak amplitude of each DTMF tone.
+const TONE_AMPLITUDE: f32 = 0.25;
+/// The detector analysis window (samples).
+const WINDOW_SIZE: usize = 960;
+/// The detector analysis hop (samples).
+const HOP_SIZE: usize = 240;
+/// The minimum number of consecutive hops needed to confirm a symbol or a gap.
+const MIN_RUN: usize = 3;
+/// The default level (dBFS) below which the audio is considered silent.
+pub const DEFAULT_SILENCE_THRESHOLD_DB: f64 = -60.0;
+/// The minimum duration of a reported silence (seconds).
+const MIN_SILENCE_DURATION: f64 = 0.1;
+
+const DTMF_ROWS: [f32; 4] = [697.0, 770.0, 852.0, 941.0];
+const DTMF_COLS: [f32; 4] = [1209.0, 1336.0, 1477.0, 1633.0];
+const DTMF_SYMBOLS: [[char; 4]; 4] = [
+    ['1', '2', '3', 'A'],
+    ['4', '5', '6', 'B'],
+    ['7', '8', '9', 'C'],
+    ['*', '0', '#', 'D'],
+];
+
+/// Returns the DTMF frequencies for a symbol.
+fn dtmf_frequencies(symbol: char) -> Option<(f32, f32)> {
+    for (row, symbols) in DTMF_SYMBOLS.iter().enumerate() {
+        for (col, s) in symbols.iter().enumerate() {
+            if *s == symbol {
+                return Some((DTMF_ROWS[row], DTMF_COLS[col]));
+            }
+        }
+    }
+    None
+}
+
+/// Returns the marker payload: `*<id>#<time_ms>#`.
+fn marker_payload(id: &str, time_ms: i64) -> String {
+    let id: String = id.chars().filter(|c| c.is_ascii_digit()).collect();
+    format!("*{}#{}#", id, time_ms)
+}
+
+/// Returns the power of `frequency` in `samples` using the Goertzel algorithm.
+fn goertzel(samples: &[f32], frequency: f32) -> f32 {
+    let coeff = 2.0 * (2.0 * std::f32::consts::PI * frequency / SAMPLE_RATE as f32).cos();
+    let (mut s1, mut s2) = (0f32, 0f32);
+    for sample in samples {
+        let s0 = sample + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+    s1 * s1 + s2 * s2 - coeff * s1 * s2
+}
+
+/// Returns the RMS level (dBFS) of `samples`, `-inf` for digital silence.
+fn level_db(samples: &[f32]) -> f64 {
+    let energy: f64 = samples.iter().map(|s| (*s as f64) * (*s as f64)).sum();
+    10.0 * (energy / samples.len().max(1) as f64).log10()
+}
+
+/// Converts a decoded frame to mono F32 samples at `SAMPLE_RATE`, creating the
+/// resampler on the first frame.
+fn resample_mono(
+    resampler: &mut Option<resampling::Context>,
+    frame: &frame::Audio,
+) -> Option<frame::Audio> {
+    let resampler = resampler.get_or_insert_with(|| {
+        resampling::Context::get(
+            frame.format(),
+            frame.channel_layout(),
+            frame.rate(),
+            format::Sample::F32(format::sample::Type::Packed),
+            ChannelLayout::MONO,
+            SAMPLE_RATE,
+        )
+        .expect("Failed to create audio resampler")
+    });
+    let capacity = frame.samples() * SAMPLE_RATE as usize / frame.rate().max(1) as usize;
+    let mut resampled = frame::Audio::new(
+        format::Sample::F32(format::sample::Type::Packed),
+        capacity + 256,
+        ChannelLayout::MONO,
+    );
+    resampler.run(frame, &mut resampled).ok()?;
+    Some(resampled)
+}
+
+/// Returns the DTMF symbol contained in the window, if any.
+fn detect_symbol(samples: &[f32]) -> Option<char> {
+    let energy: f32 = samples.iter().map(|s| s * s).sum();
+    if energy / (samples.len() as f32) < 1e-4 {
+        return None;
+    }
+    // Normalize the tone power to the fraction of the window energy.
+    let scale = 2.0 / (samples.len() as f32 * energy);
+    let rows: Vec<f32> = DTMF_ROWS
+        .iter()
+        .map(|f| goertzel(samples, *f) * scale)
+        .collect();
+    let cols: Vec<f32> = DTMF_COLS
+        .iter()
+        .map(|f| goertzel(samples, *f) * scale)
+        .collect();
+    let (row, row_power) =
+        rows.iter()
+            .enumerate()
+            .fold((0, 0f32), |a, (i, p)| if *p > a.1 { (i, *p) } else { a });
+    let (col, col_power) =
+        cols.iter()
+            .enumerate()
+            .fold((0, 0f32), |a, (i, p)| if *p > a.1 { (i, *p) } else { a });
+    if row_power < 0.2 || col_power < 0.2 || row_power + col_power < 0.6 {
+        return None;
+    }
+    Some(DTMF_SYMBOLS[row][col])
+}
+
+/// Generates an audio track containing DTMF markers encoding the watermark
+/// id and the media time, emitted every `MARKER_INTERVAL` seconds.
+pub struct AudioMarkerEncoder {
+    ost_index: usize,
+    encoder: encoder::Audio,
+    id: String,
+    frame_size: usize,
+    next_sample: Option<i64>,
+    pending: Vec<f32>,
+    pending_pts: i64,
+    marker: Option<(i64, Vec<(f32, f32)>)>,
+    markers: usize,
+}
+
+impl AudioMarkerEncoder {
+    pub fn new(
+        octx: &mut format::context::Output,
+        ost_index: usize,
+        id: &str,
+        bit_rate: usize,
+    ) -> Result<Self, ffmpeg::Error> {
+        let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+        let codec = encoder::find_by_name("libopus").ok_or(ffmpeg::Error::EncoderNotFound)?;
+        let mut ost = octx.add_stream(codec)?;
+        let mut encoder = codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .audio()?;
+        encoder.set_rate(SAMPLE_RATE as i32);
+        encoder.set_channel_layout(ChannelLayout::MONO);
+        encoder.set_format(format::Sample::F32(format::sample::Type::Packed));
+        encoder.set_time_base(Rational(1, SAMPLE_RATE as i32));
+        encoder.set_bit_rate(bit_rate);
+        if global_header {
+            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+        let encoder = encoder.open_as(codec)?;
+        ost.set_parameters(&encoder);
+        let frame_size = match encoder.frame_size() {
+            0 => 960,
+            size => size as usize,
+        };
+
+        Ok(Self {
+            ost_index,
+            encoder,
+            id: id.to_string(),
+            frame_size,
+            next_sample: None,
+            pending: Vec::new(),
+            pending_pts: 0,
+            marker: None,
+            markers: 0,
+        })
+    }
+
+    pub fn ost_index(&self) -> usize {
+        self.ost_index
+    }
+
+    /// The number of markers written.
+    pub fn markers(&self) -> usize {
+        self.markers
+    }
+
+    /// Returns the marker signal value at the absolute sample index.
+    fn sample(&mut self, index: i64) -> f32 {
+        let interval = (MARKER_INTERVAL * SAMPLE_RATE as f64) as i64;
+        let marker_index = index.div_euclid(interval);
+        if self.marker.as_ref().map(|m| m.0) != Some(marker_index) {
+            let time_ms = marker_index * (MARKER_INTERVAL * 1000.0) as i64;
+            let tones = marker_payload(&self.id, time_ms)
+                .chars()
+                .filter_map(dtmf_frequencies)
+                .collect();
+            self.marker = Some((marker_index, tones));
+            self.markers += 1;
+        }
+        let tones = &self.marker.as_ref().unwrap().1;
+        let offset = (index - marker_index * interval) as f64 / SAMPLE_RATE as f64;
+        let symbol = (offset / (SYMBOL_DURATION + GAP_DURATION)) as usize;
+        if symbol >= tones.len()
+            || offset - symbol as f64 * (SYMBOL_DURATION + GAP_DURATION) >= SYMBOL_DURATION
+        {
+            return 0.0;
+        }
+        let (row, col) = tones[symbol];
+        let t = offset as f32;
+        TONE_AMPLITUDE
+            * ((2.0 * std::f32::consts::PI * row * t).sin()
+                + (2.0 * std::f32::consts::PI * col * t).sin())
+    }
+
+    /// Generates and encodes the marker track up to the given media time (seconds).
+    pub fn write_until(
+        &mut self,
+        time: f64,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) {
+        let end_sample = (time * SAMPLE_RATE as f64).round() as i64;
+        let mut next_sample = match self.next_sample {
+            Some(next_sample) => next_sample,
+            None => {
+                self.pending_pts = end_sample;
+                end_sample
+            }
+        };
+        while next_sample < end_sample {
+            let sample = self.sample(next_sample);
+            self.pending.push(sample);
+            next_sample += 1;
+            if self.pending.len() == self.frame_size {
+                self.send_pending(octx, ost_time_base);
+            }
+        }
+        self.next_sample = Some(next_sample);
+    }
+
+    /// Pads and flushes the remaining samples, then drains the encoder.
+    pub fn finish(&mut self, octx: &mut format::context::Output, ost_time_base: Rational) {
+        if !self.pending.is_empty() {
+            self.pending.resize(self.frame_size, 0.0);
+            self.send_pending(octx, ost_time_base);
+        }
+        self.encoder.send_eof().unwrap();
+        self.receive_and_process_encoded_packets(octx, ost_time_base);
+        debug!("Audio markers written: {}", self.markers);
+    }
+
+    fn send_pending(&mut self, octx: &mut format::context::Output, ost_time_base: Rational) {
+        let mut frame = frame::Audio::new(
+            format::Sample::F32(format::sample::Type::Packed),
+            self.pending.len(),
+            ChannelLayout::MONO,
+        );
+        frame.set_rate(SAMPLE_RATE);
+        frame.plane_mut::<f32>(0).copy_from_slice(&self.pending);
+        frame.set_pts(Some(self.pending_pts));
+        self.pending_pts += self.pending.len() as i64;
+        self.pending.clear();
+        self.encoder.send_frame(&frame).unwrap();
+        self.receive_and_process_encoded_packets(octx, ost_time_base);
+    }
+
+    fn receive_and_process_encoded_packets(
+        &mut self,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) {
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(self.ost_index);
+            encoded.rescale_ts(Rational(1, SAMPLE_RATE as i32), ost_time_base);
+            encoded.write_interleaved(octx).unwrap();
+        }
+    }
+}
+
+/// An audio marker detected in the input audio stream.
+#[derive(Debug, Clone)]
+pub struct AudioMarker {
+    /// The marker position in the input (seconds).
+    pub time: f64,
+    /// The id encoded in the marker.
+    pub id: String,
+    /// The media time encoded in the marker (seconds).
+    pub timestamp: f64,
+}
+
+/// Decodes an audio stream and detects the DTMF markers generated by
+/// `AudioMarkerEncoder`.
+pub struct AudioMarkerDetector {
+    decoder: decoder::Audio,
+    time_base: Rational,
+    resampler: Option<resampling::Context>,
+    start_time: Option<f64>,
+    samples: Vec<f32>,
+    samples_offset: i64,
+    run_symbol: Option<char>,
+    run_start: i64,
+    run_length: usize,
+    last_symbol: Option<char>,
+    gap_start: i64,
+    message: String,
+    message_time: f64,
+    markers: Vec<AudioMarker>,
+}
+
+impl AudioMarkerDetector {
+    pub fn new(ist: &format::stream::Stream) -> Result<Self, ffmpeg::Error> {
+        let decoder = codec::context::Context::from_parameters(ist.parameters())?
+            .decoder()
+            .audio()?;
+        Ok(Self {
+            decoder,
+            time_base: ist.time_base(),
+            resampler: None,
+            start_time: None,
+            samples: Vec::new(),
+            samples_offset: 0,
+            run_symbol: None,
+            run_start: 0,
+            run_length: 0,
+            last_symbol: None,
+            gap_start: 0,
+            message: String::new(),
+            message_time: 0.0,
+            markers: Vec::new(),
+        })
+    }
+
+    pub fn send_packet_to_decoder(&mut self, packet: &Packet) {
+        if let Err(e) = self.decoder.send_packet(packet) {
+            debug!("Audio decoding error: {}", e);
+        }
+        self.receive_decoded_frames();
+    }
+
+    pub fn send_eof_to_decoder(&mut self) {
+        self.decoder.send_eof().unwrap();
+        self.receive_decoded_frames();
+    }
+
+    /// The detected markers.
+    pub fn markers(&self) -> &[AudioMarker] {
+        &self.markers
+    }
+
+    fn receive_decoded_frames(&mut self) {
+        let mut frame = frame::Audio::empty();
+        while self.decoder.receive_frame(&mut frame).is_ok() {
+            if self.start_time.is_none() {
+                let pts = frame.pts().unwrap_or(0);
+                self.start_time = Some(pts as f64 * f64::from(self.time_base));
+            }
+            let resampled = match resample_mono(&mut self.resampler, &frame) {
+                Some(resampled) => resampled,
+                None => continue,
+            };
+            self.samples.extend_from_slice(resampled.plane::<f32>(0));
+            self.analyze();
+        }
+    }
+
+    fn analyze(&mut self) {
+        let mut position = 0;
+        while position + WINDOW_SIZE <= self.samples.len() {
+            let symbol = detect_symbol(&self.samples[position..position + WINDOW_SIZE]);
+            self.push_hop(symbol, self.samples_offset + position as i64);
+            position += HOP_SIZE;
+        }
+        self.samples.drain(..position);
+        self.samples_offset += position as i64;
+    }
+
+    fn push_hop(&mut self, symbol: Option<char>, window_start: i64) {
+        if symbol == self.run_symbol {
+            self.run_length += 1;
+            return;
+        }
+        if self.run_length >= MIN_RUN {
+            match self.run_symbol {
+                Some(run_symbol) => {
+                    if self.last_symbol != Some(run_symbol) {
+                        // The run is symmetric around the tone center.
+                        let run_end = self.run_start + ((self.run_length - 1) * HOP_SIZE) as i64;
+                        let center =
+                            (self.run_start + run_end) as f64 / 2.0 + WINDOW_SIZE as f64 / 2.0;
+                        let onset = center / SAMPLE_RATE as f64 - SYMBOL_DURATION / 2.0;
+                        self.push_symbol(run_symbol, onset);
+                    }
+                    self.last_symbol = Some(run_symbol);
+                }
+                None => {
+                    self.last_symbol = None;
+                    self.gap_start = self.run_start;
+                }
+            }
+        }
+        if symbol.is_some()
+            && self.last_symbol.is_none()
+            && (window_start - self.gap_start) as f64 / SAMPLE_RATE as f64
+                > 3.0 * (SYMBOL_DURATION + GAP_DURATION)
+        {
+            self.message.clear();
+        }
+        self.run_symbol = symbol;
+        self.run_start = window_start;
+        self.run_length = 1;
+    }
+
+    fn push_symbol(&mut self, symbol: char, onset: f64) {
+        if symbol == '*' {
+            self.message = String::from("*");
+            self.message_time = onset;
+            return;
+        }
+        if self.message.is_empty() {
+            return;
+        }
+        self.message.push(symbol);
+        if symbol == '#' && self.message.matches('#').count() == 2 {
+            let payload = &self.message[1..self.message.len() - 1];
+            if let Some((id, time_ms)) = payload.split_once('#') {
+                if let Ok(time_ms) = time_ms.parse::<i64>() {
+                    let marker = AudioMarker {
+                        time: self.start_time.unwrap_or(0.0) + self.message_time,
+                        id: id.to_string(),
+                        timestamp: time_ms as f64 / 1000.0,
+                    };
+                    debug!("Audio marker: {:?}", marker);
+                    self.markers.push(marker);
+                }
+            }
+            self.message.clear();
+        } else if self.message.len() > 24 {
+            self.message.clear();
+        }
+    }
+}
+
+/// The runs of consecutive windows below a level threshold, in samples.
+struct SilenceRuns {
+    threshold_db: f64,
+    start: Option<i64>,
+    runs: Vec<(i64, i64)>,
+}
+
+impl SilenceRuns {
+    fn new(threshold_db: f64) -> Self {
+        Self {
+            threshold_db,
+            start: None,
+            runs: Vec::new(),
+        }
+    }
+
+    /// Adds the window starting at `position` with the given level.
+    fn push(&mut self, level_db: f64, position: i64) {
+        if level_db < self.threshold_db {
+            self.start.get_or_insert(position);
+        } else {
+            self.finish(position);
+        }
+    }
+
+    /// Ends the current run at `position`, keeping it if long enough.
+    fn finish(&mut self, position: i64) {
+        if let Some(start) = self.start.take() {
+            if (position - start) as f64 >= MIN_SILENCE_DURATION * SAMPLE_RATE as f64 {
+                self.runs.push((start, position));
+            }
+        }
+    }
+}
+
+/// Decodes an audio stream and detects the intervals whose level stays below
+/// a threshold, e.g. the concealment silence inserted by the receiver on a
+/// packet loss burst.
+pub struct SilenceDetector {
+    decoder: decoder::Audio,
+    time_base: Rational,
+    resampler: Option<resampling::Context>,
+    start_time: Option<f64>,
+    samples: Vec<f32>,
+    samples_offset: i64,
+    runs: SilenceRuns,
+}
+
+impl SilenceDetector {
+    pub fn new(ist: &format::stream::Stream, threshold_db: f64) -> Result<Self, ffmpeg::Error> {
+        let decoder = codec::context::Context::from_parameters(ist.parameters())?
+            .decoder()
+            .audio()?;
+        Ok(Self {
+            decoder,
+            time_base: ist.time_base(),
+            resampler: None,
+            start_time: None,
+            samples: Vec::new(),
+            samples_offset: 0,
+            runs: SilenceRuns::new(threshold_db),
+        })
+    }
+
+    pub fn send_packet_to_decoder(&mut self, packet: &Packet) {
+        if let Err(e) = self.decoder.send_packet(packet) {
+            debug!("Audio decoding error: {}", e);
+        }
+        self.receive_decoded_frames();
+    }
+
+    /// Drains the decoder, closing a silence lasting until the end.
+    pub fn send_eof_to_decoder(&mut self) {
+        self.decoder.send_eof().unwrap();
+        self.receive_decoded_frames();
+        self.runs
+            .finish(self.samples_offset + self.samples.len() as i64);
+    }
+
+    /// The detected silence intervals, as `(start, end)` input times (seconds).
+    pub fn intervals(&self) -> Vec<(f64, f64)> {
+        let start_time = self.start_time.unwrap_or(0.0);
+        self.runs
+            .runs
+            .iter()
+            .map(|(start, end)| {
+                (
+                    start_time + *start as f64 / SAMPLE_RATE as f64,
+                    start_time + *end as f64 / SAMPLE_RATE as f64,
+                )
+            })
+            .collect()
+    }
+
+    fn receive_decoded_frames(&mut self) {
+        let mut frame = frame::Audio::empty();
+        while self.decoder.receive_frame(&mut frame).is_ok() {
+            if self.start_time.is_none() {
+                let pts = frame.pts().unwrap_or(0);
+                self.start_time = Some(pts as f64 * f64::from(self.time_base));
+            }
+            let resampled = match resample_mono(&mut self.resampler, &frame) {
+                Some(resampled) => resampled,
+                None => continue,
+            };
+            self.samples.extend_from_slice(resampled.plane::<f32>(0));
+            let mut position = 0;
+            while position + WINDOW_SIZE <= self.samples.len() {
+                let level = level_db(&self.samples[position..position + WINDOW_SIZE]);
+                self.runs.push(level, self.samples_offset + position as i64);
+                position += WINDOW_SIZE;
+            }
+            self.samples.drain(..position);
+            self.samples_offset += position as i64;
+        }
+    }
+}
+
+/// Returns the audio/video sync offset (ms) for each marker, computed as the
+/// difference between the audio marker delay and the delay of the video frame
+/// nearest to the marker. `sync_points` contains the (input time, recognized
+/// time) pairs of the recognized video frames. Positive values mean that the
+/// audio is late.
+pub fn av_sync_offsets(markers: &[AudioMarker], sync_points: &[(f64, f64)]) -> Vec<f64> {
+    markers
+        .iter()
+        .filter_map(|marker| {
+            let (video_time, video_timestamp) = sync_points.iter().min_by(|a, b| {
+                (a.0 - marker.time)
+                    .abs()
+                    .total_cmp(&(b.0 - marker.time).abs())
+            })?;
+            if (video_time - marker.time).abs() > 1.0 {
+                return None;
+            }
+            let audio_delay = marker.time - marker.timestamp;
+            let video_delay = video_time - video_timestamp;
+            Some((audio_delay - video_delay) * 1000.0)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silence_runs_shorter_than_the_minimum_are_dropped() {
+        let window = WINDOW_SIZE as i64;
+        let mut runs = SilenceRuns::new(DEFAULT_SILENCE_THRESHOLD_DB);
+        // 2 silent windows (40 ms), then 10 (200 ms) lasting until the end.
+        let levels = [-20.0, -70.0, -70.0, -20.0]
+            .into_iter()
+            .chain(std::iter::repeat(f64::NEG_INFINITY).take(10));
+        for (i, level) in levels.enumerate() {
+            runs.push(level, i as i64 * window);
+        }
+        runs.finish(14 * window);
+        assert_eq!(runs.runs, vec![(4 * window, 14 * window)]);
+        assert_eq!(level_db(&[0.0; 4]), f64::NEG_INFINITY);
+        assert!((level_db(&[0.5; 4]) + 6.0206).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,136 @@
+//! Timestamp overlay encoded as a row of intensity bars, an OCR-free
+//! alternative to the text overlay that survives heavy compression.
+//!
+//! The band contains two calibration bars (black and white) followed by the id
+//! digits and the timestamp (ms) digits. Each digit is encoded as a luma level
+//! evenly spaced between the black and white levels.
+
+use ffmpeg::{format, frame};
+use image::{GrayImage, Rgb, RgbImage};
+
+const ID_DIGITS: usize = 3;
+const TIME_DIGITS: usize = 13;
+const CALIBRATION_BARS: usize = 2;
+const BARS: usize = CALIBRATION_BARS + ID_DIGITS + TIME_DIGITS;
+
+const BLACK: u8 = 16;
+const WHITE: u8 = 235;
+
+/// The minimum difference between the decoded calibration bars levels.
+const MIN_CONTRAST: f64 = 64.0;
+
+/// Returns true if the bars can be drawn on frames with the given format
+/// (8-bit luma in the first plane).
+pub fn supports_format(format: format::Pixel) -> bool {
+    matches!(
+        format,
+        format::Pixel::YUV420P
+            | format::Pixel::YUVJ420P
+            | format::Pixel::YUV422P
+            | format::Pixel::YUVJ422P
+            | format::Pixel::YUV444P
+            | format::Pixel::YUVJ444P
+            | format::Pixel::NV12
+            | format::Pixel::NV21
+    )
+}
+
+/// Returns the `count` least significant decimal digits of `value`, most
+/// significant first.
+fn digits(value: u64, count: usize) -> Vec<u8> {
+    let mut digits: Vec<u8> = (0..count)
+        .scan(value, |value, _| {
+            let digit = (*value % 10) as u8;
+            *value /= 10;
+            Some(digit)
+        })
+        .collect();
+    digits.reverse();
+    digits
+}
+
+fn level(digit: u8) -> u8 {
+    BLACK + ((WHITE - BLACK) as f64 * digit as f64 / 9.0).round() as u8
+}
+
+/// Returns the luma levels of all the bars.
+fn levels(id: u32, time_ms: u64) -> Vec<u8> {
+    [BLACK, WHITE]
+        .into_iter()
+        .chain(digits(id as u64, ID_DIGITS).into_iter().map(level))
+        .chain(digits(time_ms, TIME_DIGITS).into_iter().map(level))
+        .collect()
+}
+
+/// Draws the id and timestamp bars in the top `band_height` rows of the frame
+/// luma plane. The band is expected to be already filled with black.
+pub fn draw(frame: &mut frame::Video, band_height: u32, id: u32, time_ms: u64) {
+    let levels = levels(id, time_ms);
+    let bar_width = (frame.width() as usize / BARS).max(1);
+    let height = band_height.min(frame.height()) as usize;
+    let stride = frame.stride(0);
+    let data = frame.data_mut(0);
+    for y in 0..height {
+        for (i, value) in levels.iter().enumerate() {
+            let start = y * stride + i * bar_width;
+            data[start..start + bar_width].fill(*value);
+        }
+    }
+}
+
+/// Draws the id and timestamp bars in the top `band_height` rows of an RGB
+/// image. The limited range luma levels are expanded to the full range, so
+/// that they are restored when the image is converted to YUV.
+pub fn draw_image(image: &mut RgbImage, band_height: u32, id: u32, time_ms: u64) {
+    let bar_width = (image.width() as usize / BARS).max(1) as u32;
+    let height = band_height.min(image.height());
+    for (i, value) in levels(id, time_ms).into_iter().enumerate() {
+        let gray = ((value - BLACK) as f64 * 255.0 / (WHITE - BLACK) as f64).round() as u8;
+        for y in 0..height {
+            for x in (i as u32 * bar_width)..((i as u32 + 1) * bar_width).min(image.width()) {
+                image.put_pixel(x, y, Rgb([gray, gray, gray]));
+            }
+        }
+    }
+}
+
+/// Decodes the id and timestamp (ms) from the overlay band.
+pub fn decode(band: &GrayImage) -> Option<(u32, u64)> {
+    let bar_width = band.width() / BARS as u32;
+    if bar_width < 4 || band.height() < 4 {
+        return None;
+    }
+    // Average the central part of each bar, skipping the edges blurred by the
+    // compression.
+    let average = |i: u32| {
+        let (x0, x1) = (
+            i * bar_width + bar_width / 4,
+            (i + 1) * bar_width - bar_width / 4,
+        );
+        let (y0, y1) = (band.height() / 4, band.height() - band.height() / 4);
+        let sum: f64 = (y0..y1)
+            .flat_map(|y| (x0..x1).map(move |x| (x, y)))
+            .map(|(x, y)| band.get_pixel(x, y)[0] as f64)
+            .sum();
+        sum / ((x1 - x0) * (y1 - y0)) as f64
+    };
+    let black = average(0);
+    let white = average(1);
+    if white - black < MIN_CONTRAST {
+        return None;
+    }
+    let mut values = (CALIBRATION_BARS as u32..BARS as u32).map(|i| {
+        let digit = ((average(i) - black) / (white - black) * 9.0).round();
+        if (0.0..=9.0).contains(&digit) {
+            Some(digit as u64)
+        } else {
+            None
+        }
+    });
+    let mut number = |count: usize| -> Option<u64> {
+        (0..count).try_fold(0u64, |acc, _| Some(acc * 10 + values.next()??))
+    };
+    let id = number(ID_DIGITS)?;
+    let time_ms = number(TIME_DIGITS)?;
+    Some((id as u32, time_ms))
+}
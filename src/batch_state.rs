@@ -0,0 +1,139 @@
+//! Persistent state of a batch run, used for resuming it after an
+//! interruption.
+
+use crate::stats::ProcessResult;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The processing status of a batch input file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchStatus {
+    Completed,
+    Failed,
+}
+
+/// The recorded result of a batch input file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchEntry {
+    pub status: BatchStatus,
+    /// The output file path.
+    pub output_file: Option<String>,
+    /// The output file size (bytes), checked before skipping the entry.
+    pub output_size: Option<u64>,
+    pub recognized_id: Option<String>,
+    pub frames: usize,
+    pub failed_frames: usize,
+    /// The processing error message.
+    pub error: Option<String>,
+}
+
+/// The batch state file content, keyed by input file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BatchState {
+    /// The processing options of the run. A state recorded with different
+    /// options is discarded.
+    pub options: String,
+    pub entries: BTreeMap<String, BatchEntry>,
+}
+
+impl BatchState {
+    /// Loads the state file, returning an empty state if it doesn't exist or
+    /// was recorded with different `options`.
+    pub fn load(path: &str, options: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let empty = Self {
+            options: options.to_string(),
+            entries: BTreeMap::new(),
+        };
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(empty),
+            Err(e) => return Err(e.into()),
+        };
+        let state: Self = serde_json::from_str(&content)?;
+        if state.options != options {
+            debug!("Batch state {} recorded with different options", path);
+            return Ok(empty);
+        }
+        Ok(state)
+    }
+
+    /// Writes the state file atomically, replacing it with a fully written
+    /// temporary file.
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let temp_path = format!("{}.tmp", path);
+        {
+            let file = std::fs::File::create(&temp_path)?;
+            serde_json::to_writer_pretty(&file, self)?;
+            file.sync_all()?;
+        }
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// Returns the completed entry of `input_file` if its output still exists
+    /// with the recorded size.
+    pub fn completed(&self, input_file: &str) -> Option<&BatchEntry> {
+        let entry = self.entries.get(input_file)?;
+        if entry.status != BatchStatus::Completed {
+            return None;
+        }
+        let size = Path::new(entry.output_file.as_ref()?)
+            .metadata()
+            .ok()?
+            .len();
+        if Some(size) != entry.output_size {
+            debug!("Batch output of {} changed", input_file);
+            return None;
+        }
+        Some(entry)
+    }
+
+    pub fn record_completed(&mut self, input_file: &str, result: &ProcessResult) {
+        self.entries.insert(
+            input_file.to_string(),
+            BatchEntry {
+                status: BatchStatus::Completed,
+                output_file: Some(result.output_file.clone()),
+                output_size: Path::new(&result.output_file)
+                    .metadata()
+                    .ok()
+                    .map(|m| m.len()),
+                recognized_id: result.recognized_id.clone(),
+                frames: result.frames,
+                failed_frames: result.failed_frames,
+                error: None,
+            },
+        );
+    }
+
+    pub fn record_failed(&mut self, input_file: &str, error: &str) {
+        self.entries.insert(
+            input_file.to_string(),
+            BatchEntry {
+                status: BatchStatus::Failed,
+                output_file: None,
+                output_size: None,
+                recognized_id: None,
+                frames: 0,
+                failed_frames: 0,
+                error: Some(error.to_string()),
+            },
+        );
+    }
+}
+
+/// Returns the default state file path, in the directory of the first batch
+/// input file.
+pub fn default_batch_state_file(input_files: &[String]) -> String {
+    let dir = input_files
+        .first()
+        .and_then(|input_file| Path::new(input_file).parent())
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    dir.join(".webrtcperf-vmaf-utils-batch.json")
+        .to_string_lossy()
+        .to_string()
+}
@@ -0,0 +1,486 @@
+use crate::encoder_opts::EncoderOptions;
+use crate::frame_hook::{FrameCallback, PtsRemapper};
+
+/// The deinterlacing behavior.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Deinterlace {
+    /// Never deinterlace.
+    Off,
+    /// Always deinterlace.
+    On,
+    /// Deinterlace when the decoded frames are flagged as interlaced.
+    #[default]
+    Auto,
+}
+
+/// The video processing options.
+/// The rounding applied when converting the recognized times to pts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum PtsRounding {
+    Floor,
+    #[default]
+    Nearest,
+    Ceil,
+}
+
+impl PtsRounding {
+    pub fn round(self, value: f64) -> i64 {
+        match self {
+            PtsRounding::Floor => value.floor() as i64,
+            PtsRounding::Nearest => value.round() as i64,
+            PtsRounding::Ceil => value.ceil() as i64,
+        }
+    }
+}
+
+/// The timestamp overlay encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Overlay {
+    /// `<id>-<timestamp>` text, recognized with Tesseract.
+    #[default]
+    Text,
+    /// Intensity bars encoding the id and timestamp digits, decoded from the
+    /// average pixel values.
+    Bars,
+}
+
+/// The Tesseract page segmentation mode used for the text overlay recognition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OcrPageSegMode {
+    /// A single text line, matching the default overlay layout.
+    #[default]
+    SingleLine,
+    /// A single word.
+    SingleWord,
+    /// A single uniform block of text, e.g. multi-line overlays.
+    SingleBlock,
+    /// As much text as possible, in no particular order, e.g. sparse overlays.
+    SparseText,
+    /// A single text line, bypassing the Tesseract specific hacks.
+    RawLine,
+    /// The fully automatic page segmentation.
+    Auto,
+}
+
+/// How the `<id>-<timestamp>` is carried in the watermarked video.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum TimestampCarrier {
+    /// Drawn on the frames with the configured overlay.
+    #[default]
+    Visual,
+    /// Written as per-packet metadata (Matroska block additional), leaving
+    /// the frames pixel-identical. The metadata doesn't survive a WebRTC
+    /// transmission.
+    Metadata,
+    /// Both drawn on the frames and written as metadata.
+    Both,
+}
+
+impl TimestampCarrier {
+    pub fn visual(self) -> bool {
+        self != TimestampCarrier::Metadata
+    }
+
+    pub fn metadata(self) -> bool {
+        self != TimestampCarrier::Visual
+    }
+}
+
+/// The source of the timestamp written in the watermark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum TimestampBase {
+    /// The frame pts (ms), including the input start time.
+    #[default]
+    #[value(alias = "media-time")]
+    Pts,
+    /// The wall clock time (ms since the Unix epoch) when the frame is
+    /// watermarked.
+    #[value(alias = "wall-time")]
+    Wallclock,
+    /// A monotonic clock time (ms) when the frame is watermarked, counted
+    /// from the first monotonic timestamp of the process. Unlike the wall
+    /// clock it never jumps back on clock adjustments.
+    Monotonic,
+    /// The watermarked frame index.
+    #[value(name = "frameindex")]
+    FrameIndex,
+}
+
+/// An additional output file, written from the frames of the main output
+/// after applying a filter graph (e.g. `scale=320:-2` for a preview).
+#[derive(Debug, Clone)]
+pub struct ExtraOutput {
+    pub path: String,
+    pub filter: String,
+}
+
+/// The options used for watermarking single images.
+#[derive(Debug, Clone, Default)]
+pub struct WatermarkConfig {
+    /// The timestamp overlay encoding.
+    pub overlay: Overlay,
+    /// The TrueType font file used for the text overlay. When `None`, the
+    /// font used for watermarking the videos is downloaded and cached.
+    pub font_path: Option<String>,
+    /// The directory of the downloaded font. When `None`,
+    /// `$WEBRTCPERF_CACHE_DIR` or `$HOME/.webrtcperf/cache` is used.
+    pub cache_dir: Option<String>,
+}
+
+/// The handling of the blank frames (e.g. recorded while the remote track
+/// was muted).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum BlankPolicy {
+    /// Handled like the frames whose overlay wasn't recognized: written only
+    /// when the lookahead recovers their timestamp.
+    #[default]
+    Keep,
+    /// Never written.
+    Drop,
+}
+
+/// The frames padding the output up to `Config::target_frames` or
+/// `Config::target_duration`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Padding {
+    /// Repeat the last output frame.
+    #[default]
+    Repeat,
+    /// Black frames.
+    Black,
+}
+
+/// A quality metric computed by `compare_videos`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Metric {
+    Psnr,
+    Ssim,
+}
+
+/// The handling of the reference frames without a matching degraded frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum UnmatchedFrames {
+    /// Reported with the worst metric values (0), included in the means.
+    #[default]
+    Worst,
+    /// Reported without metric values, excluded from the means.
+    Exclude,
+}
+
+/// The video codec of the encoded outputs (image sequences and y4m outputs
+/// excluded).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum VideoCodec {
+    /// VP8, with libvpx.
+    #[default]
+    Vp8,
+    /// AV1, with libsvtav1.
+    Av1,
+}
+
+/// The pixel format of the encoded outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorSpace {
+    /// Planar YUV 4:2:0, supported by VP8, AV1 and y4m.
+    Yuv420p,
+    /// Planar YUV 4:2:2, supported by y4m only.
+    Yuv422p,
+    /// Packed RGB, supported by the PNG image sequences only.
+    Rgb24,
+}
+
+impl ColorSpace {
+    /// Returns the ffmpeg pixel format name.
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorSpace::Yuv420p => "yuv420p",
+            ColorSpace::Yuv422p => "yuv422p",
+            ColorSpace::Rgb24 => "rgb24",
+        }
+    }
+}
+
+/// How the frames of the compared videos are matched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum AlignBy {
+    /// By the pts of the compared files, e.g. two process mode outputs.
+    #[default]
+    Pts,
+    /// By the recognized watermark timestamps, processing both files first.
+    RecognizedTimestamp,
+}
+
+/// The keyframes placement of the VP8 output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Keyframes {
+    /// Keyframes at a fixed interval, `Config::gop` frames (every frame by
+    /// default).
+    #[default]
+    All,
+    /// Keyframes at the scene cuts detected by the `scdet` filter.
+    SceneCut,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// The deinterlacing behavior applied before the watermark or recognition stage.
+    pub deinterlace: Deinterlace,
+    /// The timestamp overlay encoding.
+    pub overlay: Overlay,
+    /// The source of the timestamp written in the watermark.
+    pub timestamp_base: TimestampBase,
+    /// The watermark text format (text overlay), with the `{id}` and
+    /// `{timestamp}` placeholders and `drawtext` `%{...}` expansions. When
+    /// `None`, `{id}-{timestamp}` is used.
+    pub watermark_format: Option<String>,
+    /// The watermark opacity (0-1). The overlay band is composited with the
+    /// `blend` filter, keeping part of the content visible so that it biases
+    /// less the VMAF scores. When `None`, the overlay is opaque.
+    pub watermark_opacity: Option<f32>,
+    /// The probability (0-1) of dropping each watermarked frame before the
+    /// encoder, simulating the frames lost in a transmission for testing the
+    /// timestamps recovery (watermark mode).
+    pub simulate_packet_loss: Option<f64>,
+    /// The seed of the `simulate_packet_loss` random generator. When `None`,
+    /// a random seed is used.
+    pub packet_loss_seed: Option<u64>,
+    /// The strength (0-100) of the temporal Gaussian noise added to the
+    /// encoded frames, simulating the camera sensor noise or the transmission
+    /// artifacts (watermark mode). The watermark band is noisy too.
+    pub noise: Option<u32>,
+    /// The fractional millisecond digits (up to 3) of the text overlay
+    /// timestamp. The bars overlay and the metadata carry integer milliseconds.
+    pub timestamp_decimals: u32,
+    /// The frame rate of the `{timecode}` watermark placeholder and of the
+    /// recognized timecodes, which count the frames from the start of the
+    /// watermarking. When `None`, the input frame rate is used.
+    pub timecode_fps: Option<f64>,
+    /// How the timestamp is carried in watermark mode. In process mode the
+    /// metadata is always read first, falling back to the overlay recognition.
+    pub timestamp_carrier: TimestampCarrier,
+    /// The Tesseract language(s) used for the text overlay recognition (e.g.
+    /// `eng` or `eng+deu`). When `None`, `eng` is used.
+    pub ocr_lang: Option<String>,
+    /// The characters recognized by Tesseract. When `None`, only the digits
+    /// and `-` are recognized.
+    pub ocr_whitelist: Option<String>,
+    /// The regexes matching the overlay text in process mode, tried in order
+    /// on the recognized text, each with an `id` and a `time` (ms) or
+    /// `timecode` named group. When empty, `TIMESTAMP_PATTERN` is used. The
+    /// id locking is disabled with custom patterns.
+    pub timestamp_patterns: Vec<String>,
+    /// The Tesseract page segmentation mode.
+    pub ocr_psm: OcrPageSegMode,
+    /// The contrast adjustment (percent, negative values decrease it) applied
+    /// to the watermark band before the text recognition, e.g. compensating a
+    /// reduced `watermark_opacity`.
+    pub ocr_contrast: Option<f32>,
+    /// The `(x, y, width, height)` part of the watermark band (frame pixels)
+    /// recognized, excluding e.g. the receiver controls drawn over it.
+    pub ocr_region: Option<(u32, u32, u32, u32)>,
+    /// The directory of the downloaded font and Tesseract data. When `None`,
+    /// `$WEBRTCPERF_CACHE_DIR` or `$HOME/.webrtcperf/cache` is used, falling
+    /// back to a temporary directory. Parallel runs can use separate
+    /// directories, but sharing one is safe.
+    pub cache_dir: Option<String>,
+    /// The rounding applied when converting the recognized times to pts.
+    pub pts_rounding: PtsRounding,
+    /// The handling of the frames detected as blank (process mode).
+    pub on_blank: BlankPolicy,
+    /// The maximum mean luma (0-255) of the watermark band of a blank frame.
+    /// The blank frames detection is enabled when both thresholds are set.
+    pub blank_max_luma: Option<f64>,
+    /// The maximum luma variance of the watermark band of a blank frame.
+    pub blank_max_variance: Option<f64>,
+    /// The video codec of the encoded outputs.
+    pub codec: VideoCodec,
+    /// The constant rate factor (0-63, lower is better) of the encoded
+    /// outputs. When `None`, the near-lossless `1` is used.
+    pub crf: Option<u32>,
+    /// The video encoder options, overriding the defaults (e.g. the VP8
+    /// `quality` and `cpu-used`).
+    pub encoder_options: Option<EncoderOptions>,
+    /// The pixel format of the encoded outputs. When `None`, the decoded
+    /// format is kept if the encoder supports it.
+    pub color_space: Option<ColorSpace>,
+    /// The keyframes placement of the encoded output.
+    pub keyframes: Keyframes,
+    /// The keyframe interval (frames) of the encoded output. When `None`,
+    /// every frame is a keyframe with `Keyframes::All`, and the scene cuts
+    /// are at most 300 frames apart with `Keyframes::SceneCut`.
+    pub gop: Option<u32>,
+    /// The `scdet` scene change threshold (0-100) used with
+    /// `Keyframes::SceneCut`.
+    pub scene_threshold: f64,
+    /// When deinterlacing, output one frame for each field (doubling the frame
+    /// rate) instead of one frame for each frame.
+    pub deinterlace_field_rate: bool,
+    /// Write audio sync markers (watermark mode) or measure the audio/video
+    /// sync offset detecting them (process mode).
+    pub audio_sync: bool,
+    /// In process mode with `audio_sync`, recover the timestamp of the frames
+    /// whose overlay wasn't recognized from the nearest audio marker.
+    pub audio_fallback: bool,
+    /// Detect the intervals of the input audio (first segment) whose level
+    /// stays below `silence_threshold_db`, see `ProcessResult::silence_intervals`.
+    pub detect_silence: bool,
+    /// The silence level threshold (dBFS). When `None`, `-60` is used.
+    pub silence_threshold_db: Option<f64>,
+    /// The Opus bitrate (bits/s) of the audio sync markers track. When
+    /// `None`, 64 kbit/s is used.
+    pub audio_bitrate: Option<usize>,
+    /// Scale the input time distances used for recovering the timestamps of
+    /// the failed frames, and the subtitle report delays, by the measured
+    /// capture clock drift (process mode).
+    pub correct_drift: bool,
+    /// The number of decoded frames buffered for recovering the timestamp of
+    /// the frames where the recognition failed from the nearby ones (process mode).
+    pub lookahead_frames: usize,
+    /// The number of consecutive frames with the same recognized id after
+    /// which the id is locked and only the timestamp digits are recognized.
+    /// Zero disables the id locking.
+    pub id_lock_frames: usize,
+    /// The number of consecutive frames an id must be recognized before and
+    /// after a change for reporting it as an id transition (process mode).
+    /// Zero disables the detection.
+    pub id_change_frames: usize,
+    /// The number of frames buffered for emitting the frames sorted by the
+    /// recognized timestamp. Zero disables the reordering.
+    pub reorder_window: usize,
+    /// Drop the frames before the first recognized frame and after the last
+    /// one (e.g. the "connecting" UI of receiver recordings), excluding them
+    /// from the failed frames (process mode).
+    pub trim_to_recognized: bool,
+    /// The maximum ratio (0-1) of frames where the timestamp overlay was not
+    /// recognized. When exceeded, the output is removed and
+    /// `ProcessError::TooManyFailures` is returned (process mode).
+    pub max_failed_ratio: Option<f64>,
+    /// Crop the watermark band from the top of the output frames (process
+    /// mode). The output height is reduced by the band height.
+    pub strip_watermark: bool,
+    /// Rescale the output frames to square pixels (process mode), writing a
+    /// 1:1 sample aspect ratio.
+    pub normalize_sar: bool,
+    /// Recognize the visual overlay only, ignoring the timestamp metadata
+    /// (process mode).
+    pub ignore_timestamp_metadata: bool,
+    /// When set, a CSV file with a row for each decoded frame (input
+    /// timestamp, key frame flag, picture type and recognition result) is
+    /// written (process mode).
+    pub frames_csv: Option<String>,
+    /// The WebVTT (or SRT, with the `.srt` extension) file with a cue for each
+    /// output frame, showing the recognized values in a player (process mode).
+    /// With multiple video streams, a `.s<N>` suffix is added for each stream.
+    pub subtitle_report: Option<String>,
+    /// The CSV file with the original and the recovered pts of each frame
+    /// written to the output, before the `target_fps` resampling (process
+    /// mode). With multiple video streams, a `.s<N>` suffix is added for each
+    /// stream.
+    pub pts_csv: Option<String>,
+    /// The JSON lines file with the input time, the recovered time and the
+    /// drift from the nominal frame rate (ms) of each frame written to the
+    /// output, for plotting the capture timing jitter (process mode). With
+    /// multiple video streams, a `.s<N>` suffix is added for each stream.
+    pub drift_series: Option<String>,
+    /// A hook called for each decoded frame after the recognition (process
+    /// mode), which can skip the frame encoding. See `FrameCallback`.
+    pub on_frame: Option<FrameCallback>,
+    /// When set, the frames timestamps are recovered with this mapping of the
+    /// decoded pts instead of the overlay recognition (process mode).
+    pub pts_remapper: Option<PtsRemapper>,
+    /// The maximum number of decoded frames held by the lookahead and reorder
+    /// buffers. When reached, the oldest frames are encoded early. When
+    /// `None`, the limit is derived from the frame size (about 256 MB).
+    pub max_inflight_frames: Option<usize>,
+    /// Bounds the memory usage for high resolution inputs or many parallel
+    /// runs: the default `max_inflight_frames` budget is reduced to about
+    /// 32 MB, the decoders use slice threading only (the frame threading holds
+    /// a frame for each thread) and the muxer interleaving queue is limited
+    /// to 1 second.
+    pub low_memory: bool,
+    /// The metrics computed by `compare_videos`.
+    pub metrics: Vec<Metric>,
+    /// Compute the metrics on the chroma planes too.
+    pub metrics_chroma: bool,
+    /// The handling of the reference frames without a matching degraded
+    /// frame in `compare_videos`.
+    pub unmatched_frames: UnmatchedFrames,
+    /// How the frames are matched in `compare_videos`.
+    pub align_by: AlignBy,
+    /// The frame intervals histogram bucket width (ms). When `None`, 5 ms
+    /// buckets are used.
+    pub interval_bucket_ms: Option<f64>,
+    /// When set, the recognized frames are resampled to this constant frame
+    /// rate (process mode). The `fps` filter is applied after the pts
+    /// recovery, so frames are duplicated or dropped according to the
+    /// recognized timestamps rather than to the input timestamps.
+    pub target_fps: Option<f64>,
+    /// When set together with `target_fps`, the output is truncated or padded
+    /// repeating the last frame to exactly this number of frames.
+    pub target_frames: Option<usize>,
+    /// When set, the output is truncated or padded to exactly this duration
+    /// (seconds) from the first output frame, after the pts recovery (process
+    /// mode).
+    pub target_duration: Option<f64>,
+    /// When set, only the keyframes at least this interval (seconds) apart
+    /// are decoded and transcoded, producing a sparse output for a fast
+    /// approximate pass. The other frames are skipped by the decoder.
+    pub sample_keyframes: Option<f64>,
+    /// The frames padding the output up to `target_frames` or `target_duration`.
+    pub padding: Padding,
+    /// The `(start, end)` time ranges (seconds) where the watermark is drawn.
+    /// Frames outside the ranges are passed through unchanged. When empty, all
+    /// the frames are watermarked.
+    pub watermark_ranges: Vec<(f64, f64)>,
+    /// The muxer `movflags`. When `None`, `faststart` is used, or
+    /// `frag_keyframe+empty_moov` when the output is a pipe (stdout or a
+    /// FIFO); an empty string disables the movflags.
+    pub movflags: Option<String>,
+    /// The input frame rate. When set, image sequence patterns (e.g.
+    /// `frame_%05d.png`) are accepted as inputs and the frames timestamps are
+    /// derived from this frame rate.
+    pub input_fps: Option<f64>,
+    /// The frame rate of the encoded output (and of the y4m header),
+    /// overriding the input stream metadata, e.g. missing or `0/0`. Unlike
+    /// `input_fps`, the frames timestamps are kept.
+    pub fps: Option<f64>,
+    /// The output file, overriding the name derived from the input. Image
+    /// sequence patterns (e.g. `out_%05d.png`) write one PNG file per frame;
+    /// in process mode the frames are named with the recognized timestamp (ms).
+    pub output: Option<String>,
+    /// The output path template, used when `output` is not set, with the
+    /// `{dir}`, `{stem}` (the input file name without the extension), `{id}`
+    /// (the watermark id, or the recognized id in process mode) and `{ext}`
+    /// placeholders (e.g. `out/{stem}-{id}.{ext}`).
+    pub output_pattern: Option<String>,
+    /// The input format name, for inputs with ambiguous extensions.
+    pub force_format: Option<String>,
+    /// Transcode the best video stream only, dropping the others (e.g. the
+    /// attached cover art or thumbnails).
+    pub best_stream_only: bool,
+    /// Transcode every video stream, each one to its own output file in
+    /// process mode. This is the default, the option overrides
+    /// `best_stream_only`.
+    pub process_all_streams: bool,
+    /// Before opening the inputs, wait until their size hasn't changed for
+    /// this duration (seconds), for files still being written.
+    pub wait_for_stable: Option<f64>,
+    /// The demuxer options, as `key=val,key=val` (e.g. `fflags=+genpts`).
+    pub input_options: Option<String>,
+    /// The video decoder options, as `key=val,key=val` (e.g. `threads=1`).
+    pub decoder_options: Option<String>,
+    /// The output format name, overriding the one guessed from the extension.
+    pub force_output_format: Option<String>,
+    /// Warn when an output size, extrapolated from the first encoded seconds,
+    /// exceeds this size (bytes). The free space of the output filesystem is
+    /// always checked.
+    pub max_output_size: Option<u64>,
+    /// Fail, removing the outputs, instead of warning when an output would
+    /// exceed `max_output_size`.
+    pub abort_on_max_output_size: bool,
+    /// Additional outputs written in the same pass, from the best video stream.
+    pub extra_outputs: Vec<ExtraOutput>,
+    /// In real time mode, delay the output frames whose timestamp is ahead of
+    /// the wall clock (measured from the client connection).
+    pub pace_to_realtime: bool,
+}
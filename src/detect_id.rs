@@ -0,0 +1,251 @@
+//! Fast detection of the watermark id of a file, recognizing a few sparse
+//! frames instead of processing all of them.
+
+use crate::bars;
+use crate::config::{Config, Overlay};
+use crate::ocr::PooledOcr;
+use crate::open_input;
+use crate::transcoder::{
+    band_height, open_video_decoder, parse_timestamp_text_any, timestamp_regexes,
+};
+use ffmpeg::{decoder, format, frame, media, software};
+use image::DynamicImage;
+use log::debug;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// The number of evenly spaced positions recognized.
+const SAMPLES: usize = 10;
+/// The maximum number of packets read after a seek looking for a decodable
+/// frame, before considering the seek unreliable.
+const MAX_SAMPLE_PACKETS: usize = 300;
+/// The input duration (seconds) decoded sequentially when seeking fails.
+const SEQUENTIAL_SECONDS: f64 = 10.0;
+
+/// The id detected by `detect_id`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DetectedId {
+    /// The id recognized in most of the samples.
+    pub id: String,
+    /// The fraction of the decoded samples where `id` was recognized.
+    pub confidence: f64,
+    /// The number of decoded samples.
+    pub samples: usize,
+}
+
+/// Recognizes the watermark band of the sampled frames.
+pub(crate) struct BandRecognizer {
+    overlay: Overlay,
+    ocr: Option<PooledOcr>,
+    timecode_fps: Option<f64>,
+    timestamp_regexes: Vec<regex::Regex>,
+    scaling_ctx: Option<software::scaling::Context>,
+}
+
+impl BandRecognizer {
+    pub(crate) fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let ocr = match config.overlay {
+            Overlay::Text => Some(PooledOcr::acquire(config)?),
+            Overlay::Bars => None,
+        };
+        Ok(Self {
+            overlay: config.overlay,
+            ocr,
+            timecode_fps: config.timecode_fps,
+            timestamp_regexes: timestamp_regexes(config)?,
+            scaling_ctx: None,
+        })
+    }
+
+    /// Returns the id and the timestamp (seconds) of the frame watermark.
+    pub(crate) fn recognize(&mut self, frame: &frame::Video) -> Option<(String, f64)> {
+        let image = self.rgb_image(frame)?;
+        let image = image.crop_imm(0, 0, image.width(), band_height(image.height()));
+        match self.overlay {
+            Overlay::Bars => bars::decode(&image.to_luma8())
+                .map(|(id, timestamp_ms)| (id.to_string(), timestamp_ms as f64 / 1000.0)),
+            Overlay::Text => {
+                let output = self.ocr.as_mut()?.recognize(&image)?;
+                parse_timestamp_text_any(&self.timestamp_regexes, output.trim(), self.timecode_fps)
+                    .map(|(_, text)| (text.id, text.time))
+            }
+        }
+    }
+
+    /// Converts a frame to RGB.
+    pub(crate) fn rgb_image(&mut self, frame: &frame::Video) -> Option<DynamicImage> {
+        rgb_image(&mut self.scaling_ctx, frame)
+    }
+}
+
+/// Converts a frame to RGB, reusing `scaling_ctx` unless the frame format or
+/// resolution changes.
+pub(crate) fn rgb_image(
+    scaling_ctx: &mut Option<software::scaling::Context>,
+    frame: &frame::Video,
+) -> Option<DynamicImage> {
+    if scaling_ctx.as_ref().map_or(true, |ctx| {
+        let input = ctx.input();
+        input.format != frame.format()
+            || input.width != frame.width()
+            || input.height != frame.height()
+    }) {
+        *scaling_ctx = Some(
+            software::scaling::Context::get(
+                frame.format(),
+                frame.width(),
+                frame.height(),
+                format::Pixel::RGB24,
+                frame.width(),
+                frame.height(),
+                software::scaling::Flags::BILINEAR,
+            )
+            .ok()?,
+        );
+    }
+    let mut rgb_frame = frame::Video::empty();
+    scaling_ctx.as_mut()?.run(frame, &mut rgb_frame).ok()?;
+    let row_size = rgb_frame.width() as usize * 3;
+    let data = rgb_frame
+        .data(0)
+        .chunks(rgb_frame.stride(0))
+        .take(rgb_frame.height() as usize)
+        .flat_map(|row| &row[..row_size])
+        .copied()
+        .collect();
+    let image = image::RgbImage::from_raw(rgb_frame.width(), rgb_frame.height(), data)?;
+    Some(DynamicImage::ImageRgb8(image))
+}
+
+/// Decodes the next frame of the stream, reading at most `max_packets`.
+pub(crate) fn next_frame(
+    ictx: &mut format::context::Input,
+    decoder: &mut decoder::Video,
+    stream_index: usize,
+    max_packets: usize,
+) -> Option<frame::Video> {
+    let mut frame = frame::Video::empty();
+    let mut packets = 0;
+    for (stream, packet) in ictx.packets() {
+        if stream.index() != stream_index {
+            continue;
+        }
+        if decoder.send_packet(&packet).is_ok() && decoder.receive_frame(&mut frame).is_ok() {
+            return Some(frame);
+        }
+        packets += 1;
+        if packets >= max_packets {
+            return None;
+        }
+    }
+    let _ = decoder.send_eof();
+    decoder.receive_frame(&mut frame).ok().map(|_| frame)
+}
+
+/// Returns the id recognized in most of the samples.
+fn majority(ids: &[Option<String>]) -> Option<DetectedId> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for id in ids.iter().flatten() {
+        *counts.entry(id.as_str()).or_default() += 1;
+    }
+    let (id, count) = counts
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(a.0)))?;
+    Some(DetectedId {
+        id: id.to_string(),
+        confidence: count as f64 / ids.len() as f64,
+        samples: ids.len(),
+    })
+}
+
+/// Detects the watermark id of a file without processing it, recognizing
+/// one frame at `SAMPLES` evenly spaced positions. When seeking is not
+/// supported or unreliable, the frames of the first `SEQUENTIAL_SECONDS` are
+/// decoded sequentially and sampled instead. Returns `None` if no id was
+/// recognized.
+pub fn detect_id(
+    input_file: &str,
+    config: &Config,
+) -> Result<Option<DetectedId>, Box<dyn std::error::Error>> {
+    ffmpeg::init()?;
+    let mut recognizer = BandRecognizer::new(config)?;
+    let mut ictx = open_input(input_file, config)?;
+    let stream = ictx
+        .streams()
+        .best(media::Type::Video)
+        .ok_or_else(|| format!("no video stream in {}", input_file))?;
+    let stream_index = stream.index();
+    let time_base = stream.time_base();
+    let mut decoder = open_video_decoder(stream.parameters(), config.decoder_options.as_deref())?;
+
+    let mut ids = Vec::new();
+    let duration = ictx.duration();
+    if duration > 0 {
+        for i in 0..SAMPLES {
+            let ts = duration * (2 * i as i64 + 1) / (2 * SAMPLES as i64);
+            if ictx.seek(ts, ..ts).is_err() {
+                break;
+            }
+            decoder.flush();
+            match next_frame(&mut ictx, &mut decoder, stream_index, MAX_SAMPLE_PACKETS) {
+                Some(frame) => ids.push(recognizer.recognize(&frame).map(|(id, _)| id)),
+                None => break,
+            }
+        }
+    }
+    if ids.len() == SAMPLES {
+        return Ok(majority(&ids));
+    }
+
+    debug!(
+        "detect_id: seeking failed after {} samples, decoding the first {}s",
+        ids.len(),
+        SEQUENTIAL_SECONDS
+    );
+    let mut ictx = open_input(input_file, config)?;
+    let mut decoder = open_video_decoder(
+        ictx.stream(stream_index)
+            .ok_or("missing video stream")?
+            .parameters(),
+        config.decoder_options.as_deref(),
+    )?;
+    let interval = SEQUENTIAL_SECONDS / SAMPLES as f64;
+    let mut ids = Vec::new();
+    let mut start_time = None;
+    while ids.len() < SAMPLES {
+        let frame = match next_frame(&mut ictx, &mut decoder, stream_index, usize::MAX) {
+            Some(frame) => frame,
+            None => break,
+        };
+        let time = frame.timestamp().unwrap_or(0) as f64 * f64::from(time_base);
+        let elapsed = time - *start_time.get_or_insert(time);
+        if elapsed > SEQUENTIAL_SECONDS {
+            break;
+        }
+        if elapsed >= ids.len() as f64 * interval {
+            ids.push(recognizer.recognize(&frame).map(|(id, _)| id));
+        }
+    }
+    Ok(majority(&ids))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn majority_id_and_confidence() {
+        let ids = vec![
+            Some("7".to_string()),
+            None,
+            Some("7".to_string()),
+            Some("1".to_string()),
+        ];
+        let detected = majority(&ids).unwrap();
+        assert_eq!(detected.id, "7");
+        assert_eq!(detected.confidence, 0.5);
+        assert_eq!(detected.samples, 4);
+
+        assert!(majority(&[None, None]).is_none());
+    }
+}
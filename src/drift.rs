@@ -0,0 +1,200 @@
+use crate::stats::DriftStats;
+use serde::Serialize;
+use std::io::Write;
+
+/// The minimum input time span (seconds) of the recognized frames for fitting
+/// the drift: over shorter spans the timestamp jitter dominates.
+const MIN_SPAN: f64 = 10.0;
+
+/// The fraction of the frames with the largest residuals discarded at each
+/// refinement of the fit (e.g. freezes and misrecognized timestamps).
+const TRIM_FRACTION: f64 = 0.2;
+const TRIM_ITERATIONS: usize = 2;
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    Some(values[values.len() / 2])
+}
+
+/// Returns the least squares `(slope, intercept)` of the points.
+fn least_squares(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    let sxy: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    if sxx <= 0.0 {
+        return None;
+    }
+    let slope = sxy / sxx;
+    Some((slope, mean_y - slope * mean_x))
+}
+
+/// Fits the recognized times to the input (capture) times of the recognized
+/// frames, `points` being `(input time, recognized time)` pairs. The initial
+/// estimate is the median slope between the frames half the recording apart,
+/// refined with least squares discarding the frames with the largest
+/// residuals. Returns `None` if the frames span less than `MIN_SPAN`.
+pub(crate) fn fit_drift(points: &[(f64, f64)]) -> Option<DriftStats> {
+    let (min_x, max_x) = points
+        .iter()
+        .fold((f64::MAX, f64::MIN), |(min, max), (x, _)| {
+            (min.min(*x), max.max(*x))
+        });
+    if max_x - min_x < MIN_SPAN {
+        return None;
+    }
+    let half = points.len() / 2;
+    let mut slopes: Vec<f64> = points
+        .iter()
+        .zip(points[half..].iter())
+        .filter(|(a, b)| b.0 - a.0 > 0.0)
+        .map(|(a, b)| (b.1 - a.1) / (b.0 - a.0))
+        .collect();
+    let mut slope = median(&mut slopes)?;
+    let mut intercepts: Vec<f64> = points.iter().map(|(x, y)| y - slope * x).collect();
+    let mut intercept = median(&mut intercepts)?;
+
+    let mut inliers = points.to_vec();
+    for _ in 0..TRIM_ITERATIONS {
+        let residual = |(x, y): &(f64, f64)| (y - intercept - slope * x).abs();
+        inliers.sort_by(|a, b| residual(a).total_cmp(&residual(b)));
+        inliers.truncate(((1.0 - TRIM_FRACTION) * inliers.len() as f64).ceil() as usize);
+        (slope, intercept) = least_squares(&inliers)?;
+    }
+    Some(DriftStats {
+        slope,
+        intercept,
+        drift_ppm: (1.0 / slope - 1.0) * 1e6,
+        frames: inliers.len(),
+    })
+}
+
+/// A point of the per frame drift series.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub(crate) struct DriftPoint {
+    /// The input (capture) time of the frame (ms).
+    pub wallclock_time_ms: f64,
+    /// The recovered (recognized or interpolated) time of the frame (ms).
+    pub recovered_pts_ms: f64,
+    /// The recovered time minus the ideal time of the frame, derived from its
+    /// index and the nominal frame rate (ms).
+    pub drift_ms: f64,
+}
+
+/// Writes the drift of each output frame as JSON lines, see `DriftPoint`.
+/// The ideal time of the first frame is its recovered time, so a frame lost
+/// upstream shifts the following frames by a frame interval.
+pub(crate) struct DriftSeriesWriter<W: Write> {
+    writer: W,
+    interval_ms: f64,
+    frames: usize,
+    start_ms: Option<f64>,
+}
+
+impl<W: Write> DriftSeriesWriter<W> {
+    pub(crate) fn new(writer: W, interval_ms: f64) -> Self {
+        Self {
+            writer,
+            interval_ms,
+            frames: 0,
+            start_ms: None,
+        }
+    }
+
+    /// Returns the point of the next frame, `wallclock_time` and
+    /// `recovered_time` being in seconds.
+    fn point(&mut self, wallclock_time: f64, recovered_time: f64) -> DriftPoint {
+        let recovered_pts_ms = recovered_time * 1000.0;
+        let start_ms = *self.start_ms.get_or_insert(recovered_pts_ms);
+        let ideal_ms = start_ms + self.frames as f64 * self.interval_ms;
+        self.frames += 1;
+        DriftPoint {
+            wallclock_time_ms: wallclock_time * 1000.0,
+            recovered_pts_ms,
+            drift_ms: recovered_pts_ms - ideal_ms,
+        }
+    }
+
+    pub(crate) fn push(&mut self, wallclock_time: f64, recovered_time: f64) -> std::io::Result<()> {
+        let point = self.point(wallclock_time, recovered_time);
+        serde_json::to_writer(&mut self.writer, &point)?;
+        writeln!(self.writer)
+    }
+
+    pub(crate) fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn injected_drift_is_measured() {
+        // The capture clock runs 500 ppm fast over 60s at 30 fps, with a
+        // +-2 ms jitter, a 2s freeze and some misrecognized timestamps.
+        let drift = 500e-6;
+        let mut points = Vec::new();
+        let mut frozen = 0.0;
+        for i in 0..1800u64 {
+            let x = i as f64 / 30.0;
+            let jitter = (i * 2_654_435_761 % (1 << 32) % 1001) as f64 / 1000.0 * 0.004 - 0.002;
+            let mut y = 5.0 + x / (1.0 + drift) + jitter;
+            if (600..660).contains(&i) {
+                if i == 600 {
+                    frozen = y;
+                }
+                y = frozen;
+            }
+            if i % 97 == 0 {
+                y += 1000.0;
+            }
+            points.push((x, y));
+        }
+        let fit = fit_drift(&points).unwrap();
+        assert!(
+            (fit.drift_ppm - 500.0).abs() < 20.0,
+            "drift: {}",
+            fit.drift_ppm
+        );
+        assert!(
+            (fit.intercept - 5.0).abs() < 0.005,
+            "intercept: {}",
+            fit.intercept
+        );
+
+        assert!(fit_drift(&points[..150]).is_none());
+    }
+
+    #[test]
+    fn drift_series_lines() {
+        let mut series = DriftSeriesWriter::new(Vec::new(), 40.0);
+        // The third frame is 10 ms late, the fourth one is lost upstream.
+        for (wallclock, recovered) in [(1.0, 5.0), (1.04, 5.04), (1.09, 5.09), (1.16, 5.16)] {
+            series.push(wallclock, recovered).unwrap();
+        }
+        let lines: Vec<serde_json::Value> = String::from_utf8(series.writer)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let drifts: Vec<f64> = lines
+            .iter()
+            .map(|line| (line["drift_ms"].as_f64().unwrap() * 1000.0).round() / 1000.0)
+            .collect();
+        assert_eq!(drifts, vec![0.0, 0.0, 10.0, 40.0]);
+        assert_eq!(lines[0]["wallclock_time_ms"].as_f64(), Some(1000.0));
+        assert_eq!(lines[3]["recovered_pts_ms"].as_f64(), Some(5160.0));
+    }
+}
@@ -1,153 +1,1045 @@
 extern crate ffmpeg_next as ffmpeg;
+mod audio;
+mod bars;
+mod batch_state;
+mod config;
+mod detect_id;
+mod drift;
+mod encoder_opts;
+mod error;
+mod extra_output;
+mod extract;
+mod frame_hook;
+mod id_change;
+mod id_lock;
+mod image_watermark;
+mod input_check;
+mod lock;
+mod metadata;
+mod metrics;
+mod ocr;
+mod ocr_benchmark;
+mod output_size;
+mod pair;
+mod realtime;
+mod session;
+mod stats;
+mod subtitles;
 mod transcoder;
+mod truncation;
+mod validate;
+mod version;
 
-use crate::transcoder::Transcoder;
+pub use crate::batch_state::{default_batch_state_file, BatchEntry, BatchState, BatchStatus};
+pub use crate::config::{
+    AlignBy, BlankPolicy, ColorSpace, Config, Deinterlace, ExtraOutput, Keyframes, Metric,
+    OcrPageSegMode, Overlay, Padding, PtsRounding, TimestampBase, TimestampCarrier,
+    UnmatchedFrames, VideoCodec, WatermarkConfig,
+};
+pub use crate::detect_id::{detect_id, DetectedId};
+pub use crate::encoder_opts::{EncoderOptions, EncoderOptsSource, TomlEncoderOpts};
+pub use crate::error::{OptParseError, ProcessError};
+pub use crate::extract::{extract_frames, extract_output_file, ExtractedFrame};
+pub use crate::frame_hook::{FrameAction, FrameCallback, FrameView, PtsRemapper};
+pub use crate::image_watermark::watermark_image;
+pub use crate::lock::AlreadyProcessingError;
+pub use crate::metadata::metadata_info;
+pub use crate::metrics::{
+    compare_videos, psnr, ssim, FrameMetrics, MetricsResult, Plane, PlaneValues, MAX_PSNR,
+};
+pub use crate::ocr_benchmark::{benchmark_ocr, OcrBenchmark};
+pub use crate::pair::{process_pair, PairFrame, PairResult};
+pub use crate::session::RecordingSession;
+pub use crate::stats::{
+    DriftStats, IdTransition, IntervalStats, Percentiles, ProcessResult, PtsGap, SegmentStats,
+    StreamStats,
+};
+pub use crate::transcoder::{
+    parse_opts, parse_timecode, parse_timestamp_text, parse_timestamp_text_any, time_to_pts,
+    timecode_text, timestamp_pattern_regex, timestamp_regex, TimestampText, TIMESTAMP_PATTERN,
+};
+pub use crate::validate::ValidationCheck;
+pub use crate::version::version_info;
 
-use crossbeam_channel::Receiver;
+use crate::audio::{
+    av_sync_offsets, AudioMarkerDetector, AudioMarkerEncoder, SilenceDetector,
+    DEFAULT_AUDIO_BITRATE, DEFAULT_SILENCE_THRESHOLD_DB,
+};
+use crate::input_check::{check_not_empty, wait_for_stable_size};
+use crate::lock::OutputLock;
+use crate::output_size::{projected_size, OutputSizeMonitor};
+use crate::transcoder::{check_video_codec, parse_opts, Transcoder};
+use crate::validate::validate;
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
 use ffmpeg::Dictionary;
 use ffmpeg::{format, media, Rational};
-use log::debug;
+use log::{debug, warn};
 use regex::Regex;
 use std::collections::HashMap;
+use std::ffi::CString;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use transcoder::Mode;
 
 pub fn watermark_video(
     input_file: &str,
     watermark_id: &str,
+    config: &Config,
+    receiver: Receiver<&str>,
+) -> Result<ProcessResult, Box<dyn std::error::Error>> {
+    ffmpeg_encoder(
+        &[input_file],
+        Mode::Watermark,
+        Some(watermark_id),
+        config,
+        receiver,
+    )
+}
+
+/// Watermarks the raw `yuv420p` frames received on `listen_addr`, streaming
+/// back the VP8 encoded frames. See `realtime` for the framing protocol.
+pub fn watermark_realtime(
+    listen_addr: SocketAddr,
+    width: u32,
+    height: u32,
+    fps: u32,
+    watermark_id: &str,
+    config: &Config,
     receiver: Receiver<&str>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    ffmpeg_encoder(input_file, Mode::Watermark, Some(watermark_id), receiver)
+) -> Result<ProcessResult, Box<dyn std::error::Error>> {
+    realtime::serve(
+        &Mode::WatermarkRealtime { listen_addr },
+        width,
+        height,
+        fps,
+        watermark_id,
+        config,
+        receiver,
+    )
 }
 
+/// Processes the input files as a single continuous timeline. The output file
+/// name is derived from the first input file.
 pub fn process_video(
-    input_file: &str,
+    input_files: &[&str],
+    config: &Config,
     receiver: Receiver<&str>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    ffmpeg_encoder(input_file, Mode::Process, None, receiver)
+) -> Result<ProcessResult, Box<dyn std::error::Error>> {
+    ffmpeg_encoder(input_files, Mode::Process, None, config, receiver)
 }
 
-fn ffmpeg_encoder(
+/// Recognizes the visual overlay of a watermarked video as the process mode
+/// does, ignoring the timestamp metadata. Returns the number of decoded frames
+/// and of frames whose overlay was recognized. The processed output is written
+/// to a temporary file which is removed.
+pub fn self_check_watermarked_video(
+    watermarked_file: &str,
+    config: &Config,
+    receiver: Receiver<&str>,
+) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    if !config.timestamp_carrier.visual() {
+        return Err("the self check requires a visual timestamp carrier".into());
+    }
+    let check_output_file = Regex::new(r"(\.\w+)$")
+        .unwrap()
+        .replace(
+            watermarked_file,
+            format!(".self-check.{}.ivf", std::process::id()),
+        )
+        .to_string();
+    let check_config = Config {
+        output: Some(check_output_file.clone()),
+        ignore_timestamp_metadata: true,
+        frames_csv: None,
+        subtitle_report: None,
+        pts_csv: None,
+        drift_series: None,
+        on_frame: None,
+        pts_remapper: None,
+        extra_outputs: Vec::new(),
+        target_fps: None,
+        strip_watermark: false,
+        ..config.clone()
+    };
+    let result = process_video(&[watermarked_file], &check_config, receiver);
+    if let Err(e) = std::fs::remove_file(&check_output_file) {
+        debug!("Failed to remove {}: {}", check_output_file, e);
+    }
+    let result = result?;
+    Ok((result.frames, result.visual_frames))
+}
+
+/// Validates the watermark configuration without transcoding.
+pub fn validate_watermark_video(
+    input_file: &str,
+    watermark_id: &str,
+    config: &Config,
+) -> Vec<ValidationCheck> {
+    let output_file = output_file_name(input_file, &Mode::Watermark, Some(watermark_id), config);
+    validate(
+        &[input_file],
+        &output_file,
+        &Mode::Watermark,
+        Some(watermark_id),
+        config,
+    )
+}
+
+/// Validates the processing configuration without transcoding.
+pub fn validate_process_video(input_files: &[&str], config: &Config) -> Vec<ValidationCheck> {
+    let output_file = output_file_name(
+        input_files.first().unwrap_or(&""),
+        &Mode::Process,
+        None,
+        config,
+    );
+    validate(input_files, &output_file, &Mode::Process, None, config)
+}
+
+/// Returns the existing process mode outputs of an input file, renamed with
+/// any recognized id (`<input>.<id>.ivf` or `<input>.<id>.s<N>.ivf`, or the
+/// `output_pattern` rendered with any id).
+pub fn existing_process_outputs(input_file: &str, config: &Config) -> Vec<String> {
+    let (prefix, suffix) = match config.output_pattern.as_deref() {
+        Some(pattern) => {
+            // The id placeholder is replaced by a character that can't appear
+            // in a path, for splitting the rendered name around it.
+            let output_file = render_output_pattern(pattern, input_file, "\0", "ivf");
+            match Regex::new(r"(^.+)(\.\w+)$").unwrap().captures(&output_file) {
+                Some(c) => (c[1].to_string(), c[2].to_string()),
+                None => return Vec::new(),
+            }
+        }
+        None => (
+            format!(
+                "{}.\0",
+                Regex::new(r"(\..+)$").unwrap().replace(input_file, "")
+            ),
+            ".ivf".to_string(),
+        ),
+    };
+    let path = std::path::Path::new(&prefix);
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => return Vec::new(),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let name = name
+        .split('\0')
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join("[0-9]+");
+    let re = Regex::new(&format!(
+        r"^{}(\.s[0-9]+)?{}$",
+        name,
+        regex::escape(&suffix)
+    ))
+    .unwrap();
+    let mut outputs: Vec<String> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| re.is_match(&entry.file_name().to_string_lossy()))
+            .map(|entry| entry.path().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    outputs.sort();
+    outputs
+}
+
+/// Returns the message of a caught panic.
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panic".to_string()
+    }
+}
+
+/// The interval of the stop requests polling while flushing.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The `{id}` of the process mode outputs until the id is recognized.
+const PENDING_OUTPUT_ID: &str = "r";
+
+/// The muxer interleaving delay (us) with `Config::low_memory`.
+const LOW_MEMORY_INTERLEAVE_DELTA_US: i64 = 1_000_000;
+
+/// Renders an `output_pattern` for an input file, replacing the `{dir}`,
+/// `{stem}` (the file name without the last extension), `{id}` and `{ext}`
+/// placeholders.
+fn render_output_pattern(pattern: &str, input_file: &str, id: &str, ext: &str) -> String {
+    let path = std::path::Path::new(input_file);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy().to_string(),
+        _ => ".".to_string(),
+    };
+    let stem = path
+        .file_stem()
+        .map_or(String::new(), |stem| stem.to_string_lossy().to_string());
+    pattern
+        .replace("{dir}", &dir)
+        .replace("{stem}", &stem)
+        .replace("{id}", id)
+        .replace("{ext}", ext)
+}
+
+fn output_file_name(
+    input_file: &str,
+    mode: &Mode,
+    watermark_id: Option<&str>,
+    config: &Config,
+) -> String {
+    if let Some(output) = config.output.as_ref() {
+        return output.clone();
+    }
+    // The IVF container can't hold the audio markers track nor the
+    // timestamp metadata.
+    let ext = match (mode, config.audio_sync) {
+        (Mode::Watermark, _) if config.timestamp_carrier.metadata() => "mkv",
+        (Mode::Watermark | Mode::WatermarkRealtime { .. }, true) => "webm",
+        (Mode::Watermark | Mode::WatermarkRealtime { .. }, false) => "ivf",
+        (Mode::Process, _) => "ivf",
+    };
+    let id = match mode {
+        Mode::Process => PENDING_OUTPUT_ID,
+        _ => watermark_id.unwrap_or_default(),
+    };
+    if let Some(pattern) = config.output_pattern.as_deref() {
+        return render_output_pattern(pattern, input_file, id, ext);
+    }
+    let replacement = match mode {
+        Mode::Process => format!("$1.{}.{}", PENDING_OUTPUT_ID, ext),
+        _ => format!("$1.{}", ext),
+    };
+    Regex::new(r"(^.+)\.\w+$")
+        .unwrap()
+        .replace(input_file, replacement)
+        .to_string()
+}
+
+/// Converts an input context duration (`AV_TIME_BASE` units) to seconds, 0
+/// if unknown.
+fn duration_seconds(duration: i64) -> f64 {
+    duration.max(0) as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE)
+}
+
+/// Returns the indexes of the input video streams transcoded: all of them, or
+/// the best one only with `Config::best_stream_only` (e.g. ignoring the
+/// attached cover art) unless `Config::process_all_streams` is set.
+pub(crate) fn processed_video_streams(
+    ictx: &format::context::Input,
+    config: &Config,
+) -> Vec<usize> {
+    let best_video_stream_index = ictx
+        .streams()
+        .best(media::Type::Video)
+        .map(|stream| stream.index());
+    ictx.streams()
+        .filter(|ist| ist.parameters().medium() == media::Type::Video)
+        .map(|ist| ist.index())
+        .filter(|index| {
+            !config.best_stream_only
+                || config.process_all_streams
+                || Some(*index) == best_video_stream_index
+        })
+        .collect()
+}
+
+/// Opens an input file. When `input_fps` is set, image sequence patterns (e.g.
+/// `frame_%05d.png`) are demuxed at that frame rate. The input format is
+/// probed unless `force_format` is set. The `input_options` not consumed by
+/// the demuxer are logged as warnings.
+pub(crate) fn open_input(
     input_file: &str,
+    config: &Config,
+) -> Result<format::context::Input, ffmpeg::Error> {
+    let mut opts = match config.input_options.as_deref() {
+        Some(options) => parse_opts(options).map_err(|e| {
+            warn!("Invalid input options: {}", e);
+            ffmpeg::Error::InvalidData
+        })?,
+        None => Dictionary::new(),
+    };
+    let user_keys: Vec<String> = opts.iter().map(|(key, _)| key.to_string()).collect();
+    if let Some(input_fps) = config.input_fps {
+        opts.set("framerate", &input_fps.to_string());
+    }
+    let input_format = match config.force_format.as_deref() {
+        Some(name) => {
+            let name = CString::new(name).map_err(|_| ffmpeg::Error::InvalidData)?;
+            let input_format = unsafe { ffmpeg::ffi::av_find_input_format(name.as_ptr()) };
+            if input_format.is_null() {
+                return Err(ffmpeg::Error::DemuxerNotFound);
+            }
+            input_format
+        }
+        None => std::ptr::null(),
+    };
+    let path = CString::new(input_file).map_err(|_| ffmpeg::Error::InvalidData)?;
+    unsafe {
+        let mut ps = std::ptr::null_mut();
+        let mut opts = opts.disown();
+        let res = ffmpeg::ffi::avformat_open_input(&mut ps, path.as_ptr(), input_format, &mut opts);
+        // The options left in the dictionary are the ones unknown to the demuxer.
+        for (key, value) in Dictionary::own(opts).iter() {
+            if user_keys.iter().any(|user_key| user_key == key) {
+                warn!(
+                    "Input option {}={} not found for {}",
+                    key, value, input_file
+                );
+            }
+        }
+        if res < 0 {
+            return Err(ffmpeg::Error::from(res));
+        }
+        match ffmpeg::ffi::avformat_find_stream_info(ps, std::ptr::null_mut()) {
+            r if r >= 0 => Ok(format::context::Input::wrap(ps)),
+            e => {
+                ffmpeg::ffi::avformat_close_input(&mut ps);
+                Err(ffmpeg::Error::from(e))
+            }
+        }
+    }
+}
+
+/// Opens an output file. The output format is guessed from the file extension
+/// unless `force_output_format` is set.
+pub(crate) fn open_output(
+    output_file: &str,
+    config: &Config,
+) -> Result<format::context::Output, ffmpeg::Error> {
+    let mut octx = match config.force_output_format.as_deref() {
+        Some(name) => format::output_as(&output_file, name)?,
+        None => format::output(&output_file)?,
+    };
+    if config.low_memory {
+        // The muxer queues the packets for interleaving the streams up to
+        // this delay (10s by default).
+        unsafe {
+            (*octx.as_mut_ptr()).max_interleave_delta = LOW_MEMORY_INTERLEAVE_DELTA_US;
+        }
+    }
+    Ok(octx)
+}
+
+/// Returns true if the output is written to a pipe (stdout or a FIFO), which
+/// can't be seeked.
+fn is_streamed_output(output_file: &str) -> bool {
+    if output_file == "-" || output_file.starts_with("pipe:") {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        std::fs::metadata(output_file).map_or(false, |m| m.file_type().is_fifo())
+    }
+    #[cfg(not(unix))]
+    false
+}
+
+/// Returns the muxer movflags. The default `faststart` needs a second pass
+/// over the output file, so the streaming friendly fragmented layout is used
+/// when writing to a pipe.
+fn movflags<'a>(output_file: &str, config: &'a Config) -> Option<&'a str> {
+    match config.movflags.as_deref() {
+        Some("") => None,
+        Some(movflags) => Some(movflags),
+        None if is_streamed_output(output_file) => Some("frag_keyframe+empty_moov"),
+        None => Some("faststart"),
+    }
+}
+
+fn ffmpeg_encoder(
+    input_files: &[&str],
     mode: Mode,
     watermark_id: Option<&str>,
+    config: &Config,
     receiver: Receiver<&str>,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<ProcessResult, Box<dyn std::error::Error>> {
+    let input_file = *input_files.first().ok_or("no input file specified")?;
     let with_watermark = matches!(mode, Mode::Watermark);
-    let replacement = if with_watermark { "$1.ivf" } else { "$1.r.ivf" };
-    let output_file = Regex::new(r"(^.+)\.\w+$")
-        .unwrap()
-        .replace(input_file, replacement)
-        .to_string();
+    let output_file = output_file_name(input_file, &mode, watermark_id, config);
     debug!(
         "ffmpeg_encoder: {} -> {} mode: {:?}",
         input_file, output_file, mode
     );
+    if config.output_pattern.is_some() && config.output.is_none() {
+        if let Some(dir) = std::path::Path::new(&output_file).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
     /* if std::path::Path::new(&output_file).exists() {
         return Err(format!("output file {} already exists", output_file).into());
     } */
 
     ffmpeg::init()?;
+    check_video_codec(config.codec)?;
     if cfg!(debug_assertions) {
         ffmpeg::log::set_level(ffmpeg::log::Level::Verbose);
     } else {
         ffmpeg::log::set_level(ffmpeg::log::Level::Info);
     }
 
-    let mut ictx = format::input(input_file)?;
-    let mut octx = format::output(&output_file)?;
+    for input_file in input_files.iter() {
+        if let Some(seconds) = config.wait_for_stable {
+            wait_for_stable_size(input_file, Duration::from_secs_f64(seconds), &receiver)?;
+        }
+        check_not_empty(input_file)?;
+    }
+
+    // Validate all the inputs upfront, failing fast instead of in the middle
+    // of a long multiple inputs run.
+    if input_files.len() > 1 {
+        let failed: Vec<String> = validate(input_files, &output_file, &mode, watermark_id, config)
+            .iter()
+            .filter(|check| !check.passed())
+            .map(|check| check.to_string())
+            .collect();
+        if !failed.is_empty() {
+            return Err(format!("validation failed: {}", failed.join(", ")).into());
+        }
+    }
+
+    let mut ictx = open_input(input_file, config)?;
+    // An unfinalized container (e.g. a WebM whose recording was interrupted)
+    // has no duration, but it's still mostly processable.
+    let mut input_unfinalized = ictx.duration() <= 0;
+    // The total input duration (seconds), for projecting the output size.
+    let mut input_duration = duration_seconds(ictx.duration());
 
     let best_video_stream_index = ictx
         .streams()
         .best(media::Type::Video)
         .map(|stream| stream.index());
+    let processed_streams = processed_video_streams(&ictx, config);
+    let video_stream_count = processed_streams.len();
+    // The processed streams are written to separate outputs, since each one
+    // can have a distinct id (and IVF holds a single stream).
+    let per_stream_outputs = !with_watermark && video_stream_count > 1;
+    let output_files: Vec<String> = if per_stream_outputs {
+        (0..video_stream_count)
+            .map(|k| {
+                Regex::new(r"(^.+)(\.\w+)$")
+                    .unwrap()
+                    .replace(&output_file, format!("$1.s{}$2", k))
+                    .to_string()
+            })
+            .collect()
+    } else {
+        vec![output_file.clone()]
+    };
+
+    // Prevent concurrent processes from writing the same output files.
+    let mut _locks = Vec::new();
+    let mut octxs = Vec::new();
+    for output_file in output_files.iter() {
+        _locks.push(OutputLock::acquire(output_file)?);
+        octxs.push(open_output(output_file, config)?);
+    }
+    let mut size_monitors: Vec<OutputSizeMonitor> = output_files
+        .iter()
+        .map(|output_file| OutputSizeMonitor::new(output_file, config))
+        .collect();
+
     let mut stream_mapping: Vec<isize> = vec![0; ictx.nb_streams() as _];
-    let mut ist_time_bases = vec![Rational(0, 0); ictx.nb_streams() as _];
-    let mut ost_time_bases = vec![Rational(0, 0); ictx.nb_streams() as _];
     let mut transcoders = HashMap::new();
+    // The output context index of each transcoder.
+    let mut transcoder_outputs = HashMap::new();
     let mut ost_index = 0;
     for (ist_index, ist) in ictx.streams().enumerate() {
-        let ist_medium = ist.parameters().medium();
-        if ist_medium != media::Type::Video {
+        if !processed_streams.contains(&ist_index) {
             stream_mapping[ist_index] = -1;
             continue;
         }
+        let (output_index, output_ost_index) = if per_stream_outputs {
+            (ost_index as usize, 0)
+        } else {
+            (0, ost_index as usize)
+        };
         stream_mapping[ist_index] = ost_index;
-        ist_time_bases[ist_index] = ist.time_base();
+        transcoder_outputs.insert(ist_index, output_index);
         // Initialize transcoder for video stream.
         transcoders.insert(
             ist_index,
             Transcoder::new(
                 &ist,
-                &mut octx,
-                ost_index as _,
+                &mut octxs[output_index],
+                output_ost_index,
                 Some(ist_index) == best_video_stream_index,
                 &mode,
                 watermark_id,
+                config,
             )?,
         );
+        if matches!(mode, Mode::Process) {
+            // With multiple video streams, a report is written for each stream.
+            let stream_path = |path: &str| {
+                if video_stream_count > 1 {
+                    Regex::new(r"(^.+)(\.\w+)$")
+                        .unwrap()
+                        .replace(path, format!("$1.s{}$2", ost_index))
+                        .to_string()
+                } else {
+                    path.to_string()
+                }
+            };
+            let transcoder = transcoders.get_mut(&ist_index).unwrap();
+            if let Some(frames_csv) = config.frames_csv.as_ref() {
+                transcoder.set_frames_csv(&stream_path(frames_csv))?;
+            }
+            if let Some(pts_csv) = config.pts_csv.as_ref() {
+                transcoder.set_pts_csv(&stream_path(pts_csv))?;
+            }
+            if let Some(drift_series) = config.drift_series.as_ref() {
+                transcoder.set_drift_series(&stream_path(drift_series))?;
+            }
+            if let Some(subtitle_report) = config.subtitle_report.as_ref() {
+                transcoder.set_subtitle_report(&stream_path(subtitle_report))?;
+            }
+        }
         ost_index += 1;
     }
+    if let Some(transcoder) = best_video_stream_index.and_then(|i| transcoders.get_mut(&i)) {
+        for extra_output in config.extra_outputs.iter() {
+            transcoder.add_extra_output(extra_output, config)?;
+        }
+    }
+    // The transcoders are keyed by the first segment video stream indexes.
+    let mut video_streams: Vec<usize> = transcoders.keys().copied().collect();
+    video_streams.sort();
+    let ost_mapping = stream_mapping.clone();
+    let mut segment_transcoders: Vec<usize> = (0..ictx.nb_streams() as usize).collect();
 
-    octx.set_metadata(ictx.metadata().to_owned());
-    let mut movflags_opts = Dictionary::new();
-    movflags_opts.set("movflags", "faststart");
-    octx.write_header_with(movflags_opts)?;
+    let mut audio_marker_encoder = if config.audio_sync && with_watermark {
+        Some(AudioMarkerEncoder::new(
+            &mut octxs[0],
+            ost_index as _,
+            watermark_id.unwrap_or("1"),
+            config.audio_bitrate.unwrap_or(DEFAULT_AUDIO_BITRATE),
+        )?)
+    } else {
+        None
+    };
+    // The audio markers and silences are detected in the first input segment only.
+    let audio_stream_index = ictx
+        .streams()
+        .best(media::Type::Audio)
+        .map(|stream| stream.index());
+    let mut audio_marker_detector = match audio_stream_index {
+        Some(index) if config.audio_sync && !with_watermark => {
+            Some(AudioMarkerDetector::new(&ictx.stream(index).unwrap())?)
+        }
+        _ => None,
+    };
+    let mut silence_detector = match audio_stream_index {
+        Some(index) if config.detect_silence => Some(SilenceDetector::new(
+            &ictx.stream(index).unwrap(),
+            config
+                .silence_threshold_db
+                .unwrap_or(DEFAULT_SILENCE_THRESHOLD_DB),
+        )?),
+        _ => None,
+    };
 
-    for (ost_index, _) in octx.streams().enumerate() {
-        ost_time_bases[ost_index] = octx.stream(ost_index as _).unwrap().time_base();
+    let mut ost_time_bases: Vec<Vec<Rational>> = Vec::new();
+    for (octx, output_file) in octxs.iter_mut().zip(output_files.iter()) {
+        octx.set_metadata(ictx.metadata().to_owned());
+        let mut header_opts = Dictionary::new();
+        if let Some(movflags) = movflags(output_file, config) {
+            header_opts.set("movflags", movflags);
+        }
+        // Name the processed image sequence frames with their recognized timestamp.
+        let image_output = octx.format().name() == "image2";
+        if image_output && !with_watermark {
+            header_opts.set("frame_pts", "1");
+        }
+        octx.write_header_with(header_opts)?;
+        ost_time_bases.push(octx.streams().map(|ost| ost.time_base()).collect());
     }
 
-    for (stream, packet) in ictx.packets() {
-        let ist_index = stream.index();
-        let ost_index = stream_mapping[ist_index];
-        if ost_index < 0 {
-            continue;
-        }
-        let ost_time_base = ost_time_bases[ost_index as usize];
-        let transcoder = transcoders.get_mut(&ist_index).unwrap();
-        transcoder.send_packet_to_decoder(&packet);
-        transcoder.receive_and_process_decoded_frames(&mut octx, ost_time_base);
+    // Run the transcoding catching the errors and panics, so that the packets
+    // written so far can be salvaged.
+    let processed = std::panic::catch_unwind(AssertUnwindSafe(
+        || -> Result<bool, Box<dyn std::error::Error>> {
+            let mut stopped = false;
+            for (segment_index, segment_file) in input_files.iter().enumerate() {
+                if segment_index > 0 {
+                    debug!("ffmpeg_encoder segment: {}", segment_file);
+                    ictx = open_input(segment_file, config)?;
+                    input_unfinalized |= ictx.duration() <= 0;
+                    input_duration += duration_seconds(ictx.duration());
+                    // Map the segment video streams to the transcoders in order.
+                    stream_mapping = vec![-1; ictx.nb_streams() as _];
+                    segment_transcoders = vec![0; ictx.nb_streams() as _];
+                    let mut segment_streams = processed_video_streams(&ictx, config).into_iter();
+                    for transcoder_index in video_streams.iter() {
+                        let ist = segment_streams
+                            .next()
+                            .and_then(|index| ictx.stream(index))
+                            .ok_or(format!("missing video stream in {}", segment_file))?;
+                        let transcoder = transcoders.get_mut(transcoder_index).unwrap();
+                        transcoder.reset_decoder(&ist)?;
+                        stream_mapping[ist.index()] = ost_mapping[*transcoder_index];
+                        segment_transcoders[ist.index()] = *transcoder_index;
+                    }
+                }
+                for transcoder in transcoders.values_mut() {
+                    transcoder.start_segment(segment_file);
+                }
+
+                for (stream, packet) in ictx.packets() {
+                    let ist_index = stream.index();
+                    if segment_index == 0 && Some(ist_index) == audio_stream_index {
+                        if let Some(detector) = silence_detector.as_mut() {
+                            detector.send_packet_to_decoder(&packet);
+                        }
+                        if let Some(detector) = audio_marker_detector.as_mut() {
+                            detector.send_packet_to_decoder(&packet);
+                            if config.audio_fallback {
+                                if let Some(transcoder) =
+                                    best_video_stream_index.and_then(|i| transcoders.get_mut(&i))
+                                {
+                                    transcoder.add_audio_markers(detector.markers());
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    if stream_mapping[ist_index] < 0 {
+                        continue;
+                    }
+                    let transcoder_index = segment_transcoders[ist_index];
+                    let output_index = transcoder_outputs[&transcoder_index];
+                    let octx = &mut octxs[output_index];
+                    let transcoder = transcoders.get_mut(&transcoder_index).unwrap();
+                    let ost_time_base = ost_time_bases[output_index][transcoder.ost_index()];
+                    transcoder.send_packet_to_decoder(&packet);
+                    transcoder.receive_and_process_decoded_frames(octx, ost_time_base);
+                    let projected = projected_size(
+                        transcoder.encoded_bytes(),
+                        transcoder.encoded_span(),
+                        input_duration,
+                    );
+                    size_monitors[output_index].check(transcoder.encoded_bytes(), projected)?;
+                    if let Some(audio_encoder) = audio_marker_encoder.as_mut() {
+                        if Some(ist_index) == best_video_stream_index {
+                            let time = transcoder.last_frame_time();
+                            let audio_time_base = ost_time_bases[0][audio_encoder.ost_index()];
+                            audio_encoder.write_until(time, &mut octxs[0], audio_time_base);
+                        }
+                    }
 
-        match receiver.try_recv() {
-            Ok("stop") => {
-                debug!("ffmpeg_encoder stop received");
-                break;
+                    match receiver.try_recv() {
+                        Ok("stop") => {
+                            debug!("ffmpeg_encoder stop received");
+                            stopped = true;
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+
+                if stopped || segment_index + 1 == input_files.len() {
+                    break;
+                }
+
+                // Flush the segment decoders.
+                for ist_index in video_streams.iter() {
+                    let output_index = transcoder_outputs[ist_index];
+                    let transcoder = transcoders.get_mut(ist_index).unwrap();
+                    let ost_time_base = ost_time_bases[output_index][transcoder.ost_index()];
+                    transcoder.send_eof_to_decoder();
+                    transcoder.receive_and_process_decoded_frames(
+                        &mut octxs[output_index],
+                        ost_time_base,
+                    );
+                }
+            }
+            Ok(stopped)
+        },
+    ));
+    let processed = match processed {
+        Ok(Err(e))
+            if matches!(
+                e.downcast_ref::<ProcessError>(),
+                Some(ProcessError::OutputTooLarge { .. })
+            ) =>
+        {
+            // The oversized outputs aren't kept.
+            drop(octxs);
+            for output_file in output_files.iter() {
+                if let Err(e) = std::fs::remove_file(output_file) {
+                    debug!("Failed to remove {}: {}", output_file, e);
+                }
+            }
+            return Err(e);
+        }
+        processed => processed,
+    };
+    let (stopped, failure) = match processed {
+        Ok(Ok(stopped)) => (stopped, None),
+        Ok(Err(e)) => (false, Some(e.to_string())),
+        Err(panic) => (false, Some(panic_message(panic.as_ref()))),
+    };
+    if let Some(error) = failure {
+        // Write the trailers, leaving playable (if truncated) outputs.
+        for (octx, output_file) in octxs.iter_mut().zip(output_files.iter()) {
+            if let Err(e) = octx.write_trailer() {
+                debug!("Failed to write the trailer of {}: {}", output_file, e);
             }
-            _ => {}
         }
+        let frames = video_streams
+            .first()
+            .and_then(|i| transcoders.get(i))
+            .map_or(0, |transcoder| transcoder.encoded_frames());
+        return Err(Box::new(ProcessError::Truncated {
+            output_file: output_file.clone(),
+            frames,
+            error,
+        }));
     }
 
     debug!("ffmpeg_encoder flushing");
 
-    // Flush encoders and decoders.
-    for (ost_index, transcoder) in transcoders.iter_mut() {
-        let ost_time_base = ost_time_bases[*ost_index];
-        transcoder.send_eof_to_decoder();
-        transcoder.receive_and_process_decoded_frames(&mut octx, ost_time_base);
-        transcoder.send_eof_to_encoder();
-        transcoder.receive_and_process_encoded_packets(&mut octx, ost_time_base);
+    // Flushing a large frame queue can take a while: the stop requests are
+    // still received, and the second one (counting the one that ended the
+    // packet loop) aborts the encoding of the remaining frames.
+    let abort = Arc::new(AtomicBool::new(false));
+    let stop_requests = AtomicUsize::new(usize::from(stopped));
+    let on_stop = || {
+        if stop_requests.fetch_add(1, Ordering::SeqCst) + 1 >= 2 {
+            debug!("ffmpeg_encoder stop received while flushing, aborting");
+            abort.store(true, Ordering::SeqCst);
+        } else {
+            debug!("ffmpeg_encoder stop received while flushing, stop again to abort");
+        }
+    };
+    while let Ok("stop") = receiver.try_recv() {
+        on_stop();
+    }
+    for transcoder in transcoders.values_mut() {
+        transcoder.set_abort(abort.clone());
     }
+    let flushing = AtomicBool::new(true);
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            while flushing.load(Ordering::SeqCst) && !abort.load(Ordering::SeqCst) {
+                match receiver.recv_timeout(STOP_POLL_INTERVAL) {
+                    Ok("stop") => on_stop(),
+                    Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+
+        // Flush encoders and decoders, in stream order. The transcoders are
+        // keyed by the input stream index, which differs from the output
+        // stream index when non video streams are skipped.
+        for ist_index in video_streams.iter() {
+            let output_index = transcoder_outputs[ist_index];
+            let octx = &mut octxs[output_index];
+            let transcoder = transcoders.get_mut(ist_index).unwrap();
+            let ost_time_base = ost_time_bases[output_index][transcoder.ost_index()];
+            transcoder.send_eof_to_decoder();
+            transcoder.receive_and_process_decoded_frames(octx, ost_time_base);
+            transcoder.flush_frames(octx, ost_time_base);
+            if abort.load(Ordering::SeqCst) {
+                continue;
+            }
+            transcoder.send_eof_to_encoder();
+            transcoder.receive_and_process_encoded_packets(octx, ost_time_base);
+        }
+        if let Some(audio_encoder) = audio_marker_encoder.as_mut() {
+            let audio_time_base = ost_time_bases[0][audio_encoder.ost_index()];
+            if let Some(transcoder) = best_video_stream_index.and_then(|i| transcoders.get(&i)) {
+                audio_encoder.write_until(
+                    transcoder.last_frame_time(),
+                    &mut octxs[0],
+                    audio_time_base,
+                );
+            }
+            audio_encoder.finish(&mut octxs[0], audio_time_base);
+        }
+        if let Some(detector) = audio_marker_detector.as_mut() {
+            detector.send_eof_to_decoder();
+        }
+        if let Some(detector) = silence_detector.as_mut() {
+            detector.send_eof_to_decoder();
+        }
+        flushing.store(false, Ordering::SeqCst);
+    });
 
-    octx.write_trailer()?;
+    if abort.load(Ordering::SeqCst) {
+        for (octx, output_file) in octxs.iter_mut().zip(output_files.iter()) {
+            if let Err(e) = octx.write_trailer() {
+                debug!("Failed to write the trailer of {}: {}", output_file, e);
+            }
+        }
+        let frames = video_streams
+            .first()
+            .and_then(|i| transcoders.get(i))
+            .map_or(0, |transcoder| transcoder.encoded_frames());
+        return Err(Box::new(ProcessError::Truncated {
+            output_file: output_file.clone(),
+            frames,
+            error: "aborted while flushing".to_string(),
+        }));
+    }
 
-    if matches!(mode, Mode::Process) {
-        if let Some(transcoder) = transcoders.values().next() {
-            let id = transcoder.recognized_id();
+    for octx in octxs.iter_mut() {
+        octx.write_trailer()?;
+    }
+    let mut extra_output_files = Vec::new();
+    for transcoder in transcoders.values_mut() {
+        extra_output_files.extend(transcoder.finish_extra_outputs()?);
+    }
+
+    let mut result = ProcessResult {
+        output_file: output_file.clone(),
+        extra_output_files,
+        input_unfinalized,
+        ..Default::default()
+    };
+    if input_unfinalized {
+        warn!("{} has no duration, it may be unfinalized", input_file);
+    }
+    if let Some(transcoder) = video_streams.first().and_then(|i| transcoders.get(i)) {
+        result.recognized_id = transcoder.recognized_id().cloned();
+        result.id_agreement = transcoder.id_agreement();
+        result.frames = transcoder.frame_count();
+        result.failed_frames = transcoder.failed_frames();
+        result.trimmed_head_frames = transcoder.trimmed_head_frames();
+        result.trimmed_tail_frames = transcoder.trimmed_tail_frames();
+        result.output_frames = transcoder.output_frames();
+        result.output_size = std::fs::metadata(&output_file)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len());
+        result.output_bitrate = transcoder.encoded_bitrate();
+        result.output_bits_per_pixel = transcoder.encoded_bits_per_pixel();
+        result.padded_frames = transcoder.padded_frames();
+        result.truncated_frames = transcoder.truncated_frames();
+        result.simulated_lost_frames = transcoder.simulated_lost_frames();
+        result.metadata_frames = transcoder.metadata_frames();
+        result.visual_frames = transcoder.visual_frames();
+        result.audio_frames = transcoder.audio_frames();
+        result.interpolated_frames = transcoder.interpolated_frames();
+        result.blank_frames = transcoder.blank_frames();
+        result.hq_rescued_frames = transcoder.hq_rescued_frames();
+        result.id_switches = transcoder.id_switches();
+        result.truncated_reads = transcoder.truncated_reads();
+        result.pattern_matches = transcoder.pattern_matches().to_vec();
+        result.id_transitions = transcoder.id_transitions().to_vec();
+        result.drift = transcoder.drift();
+        result.reordered_frames = transcoder.reordered_frames();
+        result.max_reorder_displacement = transcoder.max_reorder_displacement();
+        result.inflight_frames_high_water = transcoder.inflight_frames_high_water();
+        result.deinterlaced = transcoder.deinterlaced();
+        result.segments = transcoder.segments().to_vec();
+        result.pts_gaps = transcoder.pts_gaps().to_vec();
+        result.frame_intervals = IntervalStats::from_intervals(
+            transcoder.intervals_ms(),
+            transcoder.nominal_interval_ms(),
+            config.interval_bucket_ms.unwrap_or(5.0),
+        );
+        if let Some(detector) = audio_marker_detector.as_ref() {
+            result.audio_markers = detector.markers().len();
+            result.av_sync_ms = av_sync_offsets(detector.markers(), transcoder.sync_points());
+            result.av_sync_ms_percentiles = Percentiles::from_values(&result.av_sync_ms);
+        }
+    }
+    if let Some(audio_encoder) = audio_marker_encoder.as_ref() {
+        result.audio_markers = audio_encoder.markers();
+    }
+    if let Some(detector) = silence_detector.as_ref() {
+        result.silence_intervals = detector.intervals();
+    }
+    for ist_index in video_streams.iter() {
+        let transcoder = &transcoders[ist_index];
+        result.streams.push(StreamStats {
+            input_stream_index: *ist_index,
+            output_file: output_files[transcoder_outputs[ist_index]].clone(),
+            recognized_id: transcoder.recognized_id().cloned(),
+            id_agreement: transcoder.id_agreement(),
+            frames: transcoder.frame_count(),
+            failed_frames: transcoder.failed_frames(),
+            output_bytes: transcoder.encoded_bytes(),
+        });
+    }
+
+    if let Some(max_failed_ratio) = config
+        .max_failed_ratio
+        .filter(|_| matches!(mode, Mode::Process))
+    {
+        let rate = result.failed_frames as f64 / result.frames.max(1) as f64;
+        if rate > max_failed_ratio {
+            for output_file in output_files.iter() {
+                if let Err(e) = std::fs::remove_file(output_file) {
+                    debug!("Failed to remove {}: {}", output_file, e);
+                }
+            }
+            return Err(Box::new(ProcessError::TooManyFailures { rate }));
+        }
+    }
+
+    if matches!(mode, Mode::Process) && config.output.is_none() {
+        let mut used_ids: Vec<String> = Vec::new();
+        for (k, stream) in result.streams.iter_mut().enumerate() {
             debug!(
-                "ffmpeg_encoder done id: {} failed: {}",
-                id.unwrap_or(&"none".to_string()),
-                transcoder.failed_frames()
+                "ffmpeg_encoder done stream: {} id: {} failed: {}",
+                stream.input_stream_index,
+                stream.recognized_id.as_deref().unwrap_or("none"),
+                stream.failed_frames
             );
-            if let Some(id) = id {
-                let new_output_file = Regex::new(r"(\..+)$")
-                    .unwrap()
-                    .replace(&input_file, format!(".{}.ivf", id))
-                    .to_string();
-                std::fs::rename(&output_file, &new_output_file)?;
+            if let Some(id) = stream.recognized_id.as_ref() {
+                // Keep the names unique when several streams have the same id.
+                let new_output_file = match config.output_pattern.as_deref() {
+                    Some(pattern) => {
+                        let output_file = render_output_pattern(pattern, input_file, id, "ivf");
+                        if used_ids.contains(id) {
+                            Regex::new(r"(^.+)(\.\w+)$")
+                                .unwrap()
+                                .replace(&output_file, format!("$1.s{}$2", k))
+                                .to_string()
+                        } else {
+                            output_file
+                        }
+                    }
+                    None => {
+                        let suffix = if used_ids.contains(id) {
+                            format!(".{}.s{}.ivf", id, k)
+                        } else {
+                            format!(".{}.ivf", id)
+                        };
+                        Regex::new(r"(\..+)$")
+                            .unwrap()
+                            .replace(&input_file, suffix)
+                            .to_string()
+                    }
+                };
+                used_ids.push(id.clone());
+                if new_output_file == stream.output_file {
+                    continue;
+                }
+                std::fs::rename(&stream.output_file, &new_output_file)?;
                 debug!("Output file renamed to: {}", new_output_file);
+                stream.output_file = new_output_file;
             }
         }
+        if let Some(stream) = result.streams.first() {
+            result.output_file = stream.output_file.clone();
+        }
     }
 
-    Ok(())
+    Ok(result)
 }
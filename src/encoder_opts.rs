@@ -0,0 +1,143 @@
+//! The user options of the video encoders, overriding the defaults.
+
+use crate::transcoder::parse_opts;
+use ffmpeg::Dictionary;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A source of encoder options.
+pub trait EncoderOptsSource: Send + Sync {
+    /// Returns the options of the encoder of `codec`, the ffmpeg codec name
+    /// (e.g. `vp8`, `av1`, `png`).
+    fn encoder_opts(&self, codec: &str) -> Result<Dictionary<'static>, Box<dyn std::error::Error>>;
+}
+
+/// `KEY=VAL,KEY=VAL` options (see `parse_opts`), applied to every codec.
+impl EncoderOptsSource for String {
+    fn encoder_opts(
+        &self,
+        _codec: &str,
+    ) -> Result<Dictionary<'static>, Box<dyn std::error::Error>> {
+        Ok(parse_opts(self)?)
+    }
+}
+
+/// Options set programmatically, applied to every codec.
+impl EncoderOptsSource for HashMap<String, String> {
+    fn encoder_opts(
+        &self,
+        _codec: &str,
+    ) -> Result<Dictionary<'static>, Box<dyn std::error::Error>> {
+        let mut dict = Dictionary::new();
+        for (key, value) in self.iter() {
+            dict.set(key, value);
+        }
+        Ok(dict)
+    }
+}
+
+/// Options read from a TOML file with a table for each codec:
+/// ```toml
+/// [vp8]
+/// quality = "best"
+/// cpu-used = 0
+/// crf = 1
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TomlEncoderOpts {
+    codecs: HashMap<String, HashMap<String, String>>,
+}
+
+impl TomlEncoderOpts {
+    pub fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let table: toml::Table = s.parse()?;
+        let mut codecs = HashMap::new();
+        for (codec, options) in table.iter() {
+            let options = options
+                .as_table()
+                .ok_or_else(|| format!("[{}] is not a table of options", codec))?;
+            let mut values = HashMap::new();
+            for (key, value) in options.iter() {
+                let value = match value {
+                    toml::Value::String(s) => s.clone(),
+                    toml::Value::Integer(i) => i.to_string(),
+                    toml::Value::Float(f) => f.to_string(),
+                    toml::Value::Boolean(b) => (*b as i32).to_string(),
+                    _ => return Err(format!("invalid value of {}.{}", codec, key).into()),
+                };
+                values.insert(key.clone(), value);
+            }
+            codecs.insert(codec.clone(), values);
+        }
+        Ok(Self { codecs })
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let s = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&s).map_err(|e| format!("{}: {}", path, e).into())
+    }
+}
+
+/// The options of the codec table, none if it is missing.
+impl EncoderOptsSource for TomlEncoderOpts {
+    fn encoder_opts(&self, codec: &str) -> Result<Dictionary<'static>, Box<dyn std::error::Error>> {
+        match self.codecs.get(codec) {
+            Some(options) => options.encoder_opts(codec),
+            None => Ok(Dictionary::new()),
+        }
+    }
+}
+
+/// The encoder options of `Config::encoder_options`.
+#[derive(Clone)]
+pub struct EncoderOptions(pub Arc<dyn EncoderOptsSource>);
+
+impl EncoderOptions {
+    pub fn new<S: EncoderOptsSource + 'static>(source: S) -> Self {
+        Self(Arc::new(source))
+    }
+}
+
+impl std::fmt::Debug for EncoderOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncoderOptions")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_options_by_codec() {
+        let opts = TomlEncoderOpts::parse(
+            r#"
+            [vp8]
+            quality = "best"
+            cpu-used = 0
+            auto-alt-ref = false
+
+            [av1]
+            preset = 8
+            "#,
+        )
+        .unwrap();
+        let dict = opts.encoder_opts("vp8").unwrap();
+        assert_eq!(dict.get("quality"), Some("best"));
+        assert_eq!(dict.get("cpu-used"), Some("0"));
+        assert_eq!(dict.get("auto-alt-ref"), Some("0"));
+        assert_eq!(opts.encoder_opts("av1").unwrap().get("preset"), Some("8"));
+        assert_eq!(opts.encoder_opts("png").unwrap().iter().count(), 0);
+
+        assert!(TomlEncoderOpts::parse("crf = 1").is_err());
+        assert!(TomlEncoderOpts::parse("[vp8]\ncrf = [1]").is_err());
+    }
+
+    #[test]
+    fn string_and_map_options_apply_to_every_codec() {
+        let opts = "crf=4,quality=good".to_string();
+        assert_eq!(opts.encoder_opts("av1").unwrap().get("crf"), Some("4"));
+        let opts = HashMap::from([("crf".to_string(), "4".to_string())]);
+        assert_eq!(opts.encoder_opts("vp8").unwrap().get("crf"), Some("4"));
+    }
+}
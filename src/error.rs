@@ -0,0 +1,69 @@
+/// Returned when the processing result can't be trusted.
+#[derive(Debug)]
+pub enum ProcessError {
+    /// The ratio of frames where the timestamp overlay was not recognized
+    /// exceeded `Config::max_failed_ratio`.
+    TooManyFailures { rate: f64 },
+    /// The processing failed midway. The trailer of the output was written,
+    /// keeping the `frames` frames already encoded.
+    Truncated {
+        output_file: String,
+        frames: usize,
+        error: String,
+    },
+    /// The output exceeded, or was projected to exceed,
+    /// `Config::max_output_size` with `Config::abort_on_max_output_size`. The
+    /// output was removed.
+    OutputTooLarge {
+        output_file: String,
+        size: u64,
+        max_output_size: u64,
+    },
+}
+
+impl std::fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcessError::TooManyFailures { rate } => write!(
+                f,
+                "too many frames without a recognized timestamp ({:.1}%)",
+                rate * 100.0
+            ),
+            ProcessError::Truncated {
+                output_file,
+                frames,
+                error,
+            } => write!(
+                f,
+                "{} (truncated output {} with {} frames)",
+                error, output_file, frames
+            ),
+            ProcessError::OutputTooLarge {
+                output_file,
+                size,
+                max_output_size,
+            } => write!(
+                f,
+                "output {} of {} bytes over the maximum of {} bytes",
+                output_file, size, max_output_size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProcessError {}
+
+/// Returned when a `KEY=VAL,KEY=VAL` options string can't be parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptParseError {
+    /// The option without a `KEY=` prefix.
+    pub token: String,
+}
+
+impl std::fmt::Display for OptParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid option \"{}\", expected KEY=VAL", self.token)
+    }
+}
+
+impl std::error::Error for OptParseError {}
@@ -0,0 +1,138 @@
+use crate::config::{Config, ExtraOutput, Keyframes, VideoCodec};
+use crate::encoder_opts::EncoderOptions;
+use crate::open_output;
+use crate::transcoder::{
+    encoder_input_format, open_encoder, output_codec, ColorProperties, VideoFilter, VideoParams,
+};
+use ffmpeg::{encoder, format, frame, Packet, Rational, Rescale};
+use log::debug;
+
+/// An additional output, encoding the frames sent to the main output after
+/// applying a filter graph. The encoder is opened when the first filtered
+/// frame is available, since the filter can change the frame size; the frames
+/// are converted to a pixel format supported by the encoder at the end of the
+/// filter graph.
+pub struct ExtraOutputEncoder {
+    path: String,
+    octx: format::context::Output,
+    filter: VideoFilter,
+    encoder: Option<encoder::Video>,
+    encoder_time_base: Rational,
+    ost_time_base: Rational,
+    codec: VideoCodec,
+    crf: Option<u32>,
+    encoder_options: Option<EncoderOptions>,
+}
+
+impl ExtraOutputEncoder {
+    pub fn new(
+        output: &ExtraOutput,
+        params: VideoParams,
+        time_base: Rational,
+        config: &Config,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        debug!(
+            "ExtraOutputEncoder {} filter: {}",
+            output.path, output.filter
+        );
+        let octx = open_output(&output.path, config)?;
+        let output_format = encoder_input_format(output_codec(&octx, config.codec), params.format);
+        let filter = VideoFilter::with_output_format(
+            time_base,
+            params,
+            output.filter.clone(),
+            output_format,
+        )?;
+        Ok(Self {
+            path: output.path.clone(),
+            octx,
+            filter,
+            encoder: None,
+            encoder_time_base: time_base,
+            ost_time_base: time_base,
+            codec: config.codec,
+            crf: config.crf,
+            encoder_options: config.encoder_options.clone(),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Sends a frame to the output filter, or flushes it when `frame` is `None`.
+    pub fn send_frame(&mut self, frame: Option<&frame::Video>) {
+        self.filter.send(frame).unwrap();
+        let filter_time_base = self.filter.time_base();
+        while let Some(mut frame) = self.filter.receive() {
+            if self.encoder.is_none() {
+                self.open(&frame).unwrap();
+            }
+            frame.set_pts(
+                frame
+                    .pts()
+                    .map(|pts| pts.rescale(filter_time_base, self.encoder_time_base)),
+            );
+            self.encoder.as_mut().unwrap().send_frame(&frame).unwrap();
+            self.write_packets();
+        }
+    }
+
+    fn open(&mut self, frame: &frame::Video) -> Result<(), ffmpeg::Error> {
+        let global_header = self
+            .octx
+            .format()
+            .flags()
+            .contains(format::Flags::GLOBAL_HEADER);
+        let params = VideoParams {
+            width: frame.width(),
+            height: frame.height(),
+            format: frame.format(),
+            aspect_ratio: Rational(1, 1),
+            color: ColorProperties::from(frame),
+        };
+        self.encoder_time_base = self.filter.time_base();
+        let encoder = open_encoder(
+            params,
+            output_codec(&self.octx, self.codec),
+            self.encoder_time_base,
+            None,
+            global_header,
+            Keyframes::All,
+            None,
+            self.crf,
+            self.encoder_options.as_ref(),
+        )?;
+        let mut ost = self.octx.add_stream(encoder.codec())?;
+        ost.set_parameters(&encoder);
+        self.octx.write_header()?;
+        self.ost_time_base = self.octx.stream(0).unwrap().time_base();
+        self.encoder = Some(encoder);
+        Ok(())
+    }
+
+    fn write_packets(&mut self) {
+        let encoder = match self.encoder.as_mut() {
+            Some(encoder) => encoder,
+            None => return,
+        };
+        let mut packet = Packet::empty();
+        while encoder.receive_packet(&mut packet).is_ok() {
+            packet.set_stream(0);
+            packet.rescale_ts(self.encoder_time_base, self.ost_time_base);
+            packet.write_interleaved(&mut self.octx).unwrap();
+        }
+    }
+
+    /// Flushes the filter and the encoder, writing the output trailer.
+    pub fn finish(&mut self) -> Result<(), ffmpeg::Error> {
+        self.send_frame(None);
+        if let Some(encoder) = self.encoder.as_mut() {
+            encoder.send_eof()?;
+            self.write_packets();
+            self.octx.write_trailer()?;
+        }
+        debug!("ExtraOutputEncoder {} done", self.path);
+        Ok(())
+    }
+}
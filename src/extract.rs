@@ -0,0 +1,292 @@
+//! Extraction of the frames whose watermark shows a given timestamp, for
+//! inspecting the same instant in the reference and the degraded files.
+
+use crate::config::Config;
+use crate::detect_id::{next_frame, BandRecognizer};
+use crate::open_input;
+use crate::transcoder::{
+    font_path, open_video_decoder, valid_aspect_ratio, ColorProperties, VideoFilter, VideoParams,
+};
+use ffmpeg::{decoder, format, frame, media, Rational};
+use log::{debug, warn};
+use serde::Serialize;
+
+/// The time (seconds) decoded before the estimated position of a target.
+const SEEK_MARGIN: f64 = 1.0;
+/// The number of times the seek position is moved back when the first
+/// recognized frame is already past the target.
+const MAX_SEEK_RETRIES: u32 = 3;
+/// The frame interval (seconds) assumed until two frames are recognized.
+const DEFAULT_FRAME_INTERVAL: f64 = 1.0 / 30.0;
+
+/// A frame written by `extract_frames`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExtractedFrame {
+    /// The requested timestamp (ms).
+    pub target_ms: f64,
+    /// The watermark id of the frame.
+    pub id: String,
+    /// The timestamp (ms) recognized in the frame.
+    pub recognized_ms: f64,
+    /// The PNG file written.
+    pub output_file: String,
+    /// When the target falls inside a freeze, the time (ms) between the
+    /// frozen frame, which is returned, and the next one.
+    pub freeze_gap_ms: Option<f64>,
+}
+
+/// A decoded frame with its recognized watermark.
+struct RecognizedFrame {
+    frame: frame::Video,
+    id: String,
+    time: f64,
+}
+
+/// Returns the output file of the frame extracted at `target_ms`: the
+/// `{timestamp}` placeholder of `pattern` is replaced by the target, which is
+/// appended to the file stem when extracting several frames without it.
+pub fn extract_output_file(pattern: &str, target_ms: f64, multiple: bool) -> String {
+    if pattern.contains("{timestamp}") {
+        return pattern.replace("{timestamp}", &target_ms.to_string());
+    }
+    if !multiple {
+        return pattern.to_string();
+    }
+    let path = std::path::Path::new(pattern);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, target_ms, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, target_ms),
+    };
+    path.with_file_name(name).to_string_lossy().to_string()
+}
+
+/// Chooses between the last frame before `target` and the first one at or
+/// after it (times in seconds): returns true if the latter is the closest,
+/// and the gap between them when `target` falls inside a freeze, i.e. the
+/// gap is longer than twice `frame_interval`. In a freeze the frozen frame
+/// (the one before) is the one displayed at `target`.
+fn closest_frame(before: f64, after: f64, target: f64, frame_interval: f64) -> (bool, Option<f64>) {
+    if after - before > 2.0 * frame_interval && target > before && target < after {
+        return (false, Some(after - before));
+    }
+    (after - target < target - before, None)
+}
+
+/// Decodes the frames from the current position until the recognized
+/// timestamp reaches `target` (seconds), returning the frames bracketing it.
+fn bracket_target(
+    ictx: &mut format::context::Input,
+    decoder: &mut decoder::Video,
+    stream_index: usize,
+    recognizer: &mut BandRecognizer,
+    target: f64,
+    frame_interval: &mut f64,
+) -> (Option<RecognizedFrame>, Option<RecognizedFrame>) {
+    let mut before: Option<RecognizedFrame> = None;
+    while let Some(frame) = next_frame(ictx, decoder, stream_index, usize::MAX) {
+        let (id, time) = match recognizer.recognize(&frame) {
+            Some(recognized) => recognized,
+            None => continue,
+        };
+        if let Some(before) = before.as_ref() {
+            let interval = time - before.time;
+            if interval > 0.0 && interval < *frame_interval {
+                *frame_interval = interval;
+            }
+        }
+        let recognized = RecognizedFrame { frame, id, time };
+        if time >= target {
+            return (before, Some(recognized));
+        }
+        before = Some(recognized);
+    }
+    (before, None)
+}
+
+/// Writes `frame` as a PNG file, with a caption showing its recognized
+/// timestamp.
+fn write_frame(
+    frame: &frame::Video,
+    time_base: Rational,
+    caption: &str,
+    output_file: &str,
+    recognizer: &mut BandRecognizer,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let font_path = font_path(config.cache_dir.as_deref())?;
+    let font_size = (frame.height() as f32 / 18.0).round() as i32;
+    let desc = format!(
+        "drawtext=fontfile={}:text='{}':fontcolor=white:fontsize={}\
+:box=1:boxcolor=black@0.6:boxborderw=4:x=(w-text_w)/2:y=h-text_h-{}",
+        font_path, caption, font_size, font_size
+    );
+    let mut filter = VideoFilter::new(
+        time_base,
+        VideoParams {
+            width: frame.width(),
+            height: frame.height(),
+            format: frame.format(),
+            aspect_ratio: valid_aspect_ratio(frame.aspect_ratio()),
+            color: ColorProperties::from(frame),
+        },
+        desc,
+    )?;
+    let captioned = filter.apply(frame)?;
+    let image = recognizer
+        .rgb_image(&captioned)
+        .ok_or("failed to convert the frame to RGB")?;
+    if let Some(dir) = std::path::Path::new(output_file).parent() {
+        if !dir.as_os_str().is_empty() {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+    image.save_with_format(output_file, image::ImageFormat::Png)?;
+    Ok(())
+}
+
+/// Extracts the frames whose recognized watermark timestamp is the closest
+/// to each of `targets_ms`, writing them as PNG files named after
+/// `output_pattern` (see `extract_output_file`). The input is opened once:
+/// for each target it seeks near the position estimated from the container
+/// pts, then decodes forward recognizing the frames until the timestamps
+/// bracket the target. When the target falls inside a freeze, the frozen
+/// frame is returned with the freeze gap.
+pub fn extract_frames(
+    input_file: &str,
+    targets_ms: &[f64],
+    output_pattern: &str,
+    config: &Config,
+) -> Result<Vec<ExtractedFrame>, Box<dyn std::error::Error>> {
+    ffmpeg::init()?;
+    let mut recognizer = BandRecognizer::new(config)?;
+    let mut ictx = open_input(input_file, config)?;
+    let stream = ictx
+        .streams()
+        .best(media::Type::Video)
+        .ok_or_else(|| format!("no video stream in {}", input_file))?;
+    let stream_index = stream.index();
+    let time_base = stream.time_base();
+    // Copied, since the input is reopened when seeking fails.
+    let parameters = stream.parameters().clone();
+    let mut decoder = open_video_decoder(parameters.clone(), config.decoder_options.as_deref())?;
+
+    // The offset between the recognized timestamps and the container pts,
+    // estimated on the first recognized frame.
+    let mut offset = None;
+    while offset.is_none() {
+        let frame = next_frame(&mut ictx, &mut decoder, stream_index, usize::MAX)
+            .ok_or_else(|| format!("no watermark recognized in {}", input_file))?;
+        if let Some((_, time)) = recognizer.recognize(&frame) {
+            offset = Some(time - frame.timestamp().unwrap_or(0) as f64 * f64::from(time_base));
+        }
+    }
+    let offset = offset.unwrap();
+    debug!("extract: timestamps offset {:.3}s", offset);
+
+    let mut targets = targets_ms.to_vec();
+    targets.sort_by(|a, b| a.total_cmp(b));
+    let mut frame_interval = DEFAULT_FRAME_INTERVAL;
+    let mut extracted = Vec::new();
+    for target_ms in targets {
+        let target = target_ms / 1000.0;
+        let mut margin = SEEK_MARGIN;
+        let (before, after) = loop {
+            let position = (target - offset - margin).max(0.0);
+            let ts = (position * f64::from(ffmpeg::ffi::AV_TIME_BASE)) as i64;
+            if ictx.seek(ts, ..ts).is_err() {
+                debug!("extract: seeking failed, decoding from the start");
+                ictx = open_input(input_file, config)?;
+                decoder =
+                    open_video_decoder(parameters.clone(), config.decoder_options.as_deref())?;
+            } else {
+                decoder.flush();
+            }
+            let (before, after) = bracket_target(
+                &mut ictx,
+                &mut decoder,
+                stream_index,
+                &mut recognizer,
+                target,
+                &mut frame_interval,
+            );
+            let retry = before.is_none() && after.is_some() && position > 0.0;
+            if !retry || margin >= SEEK_MARGIN * 2f64.powi(MAX_SEEK_RETRIES as i32) {
+                break (before, after);
+            }
+            margin *= 2.0;
+        };
+
+        let (frame, freeze_gap) = match (before, after) {
+            (Some(before), Some(after)) => {
+                match closest_frame(before.time, after.time, target, frame_interval) {
+                    (true, gap) => (after, gap),
+                    (false, gap) => (before, gap),
+                }
+            }
+            (Some(frame), None) | (None, Some(frame)) => (frame, None),
+            (None, None) => {
+                warn!(
+                    "No frame recognized around {} ms in {}",
+                    target_ms, input_file
+                );
+                continue;
+            }
+        };
+
+        let recognized_ms = (frame.time * 1_000_000.0).round() / 1000.0;
+        let mut caption = format!("{}-{} ms", frame.id, recognized_ms);
+        if let Some(gap) = freeze_gap {
+            caption = format!("{} (freeze {} ms)", caption, (gap * 1000.0).round());
+        }
+        let output_file = extract_output_file(output_pattern, target_ms, targets_ms.len() > 1);
+        write_frame(
+            &frame.frame,
+            time_base,
+            &caption,
+            &output_file,
+            &mut recognizer,
+            config,
+        )?;
+        debug!("extract: {} ms -> {} ({})", target_ms, output_file, caption);
+        extracted.push(ExtractedFrame {
+            target_ms,
+            id: frame.id,
+            recognized_ms,
+            output_file,
+            freeze_gap_ms: freeze_gap.map(|gap| (gap * 1000.0).round()),
+        });
+    }
+    Ok(extracted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closest_frame_and_freeze_gap() {
+        let interval = 1.0 / 30.0;
+        assert_eq!(closest_frame(1.0, 1.033, 1.01, interval), (false, None));
+        assert_eq!(closest_frame(1.0, 1.033, 1.03, interval), (true, None));
+        // Inside a freeze the frozen frame is returned.
+        let (after, gap) = closest_frame(1.0, 1.5, 1.45, interval);
+        assert!(!after);
+        assert!((gap.unwrap() - 0.5).abs() < 1e-9);
+        // A target matching a frame exactly isn't inside a freeze.
+        assert_eq!(closest_frame(1.0, 1.5, 1.5, interval), (true, None));
+    }
+
+    #[test]
+    fn extract_output_file_names() {
+        assert_eq!(extract_output_file("out.png", 1500.0, false), "out.png");
+        assert_eq!(
+            extract_output_file("dir/out.png", 1500.0, true),
+            "dir/out-1500.png"
+        );
+        assert_eq!(
+            extract_output_file("frame_{timestamp}.png", 1500.5, true),
+            "frame_1500.5.png"
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use ffmpeg::{format, frame, Rational};
+use std::sync::Arc;
+
+/// A read-only view of a decoded frame passed to the `Config::on_frame` hook.
+pub struct FrameView<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub format: format::Pixel,
+    /// The plane data, each row padded to the plane stride.
+    pub planes: Vec<&'a [u8]>,
+    /// The plane strides (bytes).
+    pub strides: Vec<usize>,
+    /// The decoded frame pts, in the input stream time base (with twice the
+    /// resolution with `Config::deinterlace_field_rate`).
+    pub pts: Option<i64>,
+    /// The id recognized from the frame overlay or metadata.
+    pub recognized_id: Option<&'a str>,
+    /// The time (seconds) recognized from the frame overlay or metadata.
+    pub recognized_time: Option<f64>,
+}
+
+impl<'a> FrameView<'a> {
+    pub(crate) fn new(
+        frame: &'a frame::Video,
+        recognized_id: Option<&'a str>,
+        recognized_time: Option<f64>,
+    ) -> Self {
+        let planes = (0..frame.planes()).map(|i| frame.data(i)).collect();
+        let strides = (0..frame.planes()).map(|i| frame.stride(i)).collect();
+        Self {
+            width: frame.width(),
+            height: frame.height(),
+            format: frame.format(),
+            planes,
+            strides,
+            pts: frame.pts(),
+            recognized_id,
+            recognized_time,
+        }
+    }
+}
+
+/// The action taken on a frame after the `Config::on_frame` hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FrameAction {
+    /// The frame is written to the output as usual.
+    #[default]
+    Encode,
+    /// The frame is not written to the output.
+    Skip,
+}
+
+/// A per frame hook, called in process mode after the recognition and before
+/// the frame is buffered for encoding. The hook runs on the processing thread
+/// and blocks the pipeline: it must return promptly, moving any slow analysis
+/// to another thread.
+#[derive(Clone)]
+pub struct FrameCallback(pub Arc<dyn Fn(&FrameView) -> FrameAction + Send + Sync>);
+
+impl FrameCallback {
+    pub fn new<F: Fn(&FrameView) -> FrameAction + Send + Sync + 'static>(f: F) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for FrameCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FrameCallback")
+    }
+}
+
+/// A custom timestamp recovery, used in process mode instead of the overlay
+/// recognition (e.g. a clock correction based on NTP reports). `map` receives
+/// the decoded frame pts and its time base and returns the output pts, in
+/// the same time base.
+#[derive(Clone)]
+pub struct PtsRemapper {
+    pub map: Arc<dyn Fn(i64, Rational) -> i64 + Send + Sync>,
+}
+
+impl PtsRemapper {
+    pub fn new<F: Fn(i64, Rational) -> i64 + Send + Sync + 'static>(f: F) -> Self {
+        Self { map: Arc::new(f) }
+    }
+}
+
+impl std::fmt::Debug for PtsRemapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PtsRemapper")
+    }
+}
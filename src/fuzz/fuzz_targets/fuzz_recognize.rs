@@ -0,0 +1,20 @@
+//! Feeds arbitrary recognized texts through the overlay text parsing and the
+//! pts computation, checking that no input panics.
+#![no_main]
+
+use ffmpeg_next::Rational;
+use libfuzzer_sys::fuzz_target;
+use webrtcperf_vmaf_utils::{parse_timestamp_text, time_to_pts, timestamp_regex, PtsRounding};
+
+fuzz_target!(|data: &[u8]| {
+    let text = String::from_utf8_lossy(data);
+    if let Some(text) = parse_timestamp_text(timestamp_regex(), text.trim(), Some(29.97)) {
+        assert!(!text.id.is_empty() && text.id.len() <= 3);
+        assert!(text.time >= 0.0 && text.time_digits <= 17);
+        for time_base in [Rational(1, 1000), Rational(1, 90000), Rational(1001, 30000)] {
+            for rounding in [PtsRounding::Floor, PtsRounding::Nearest, PtsRounding::Ceil] {
+                assert!(time_to_pts(text.time, time_base, rounding) >= 0);
+            }
+        }
+    }
+});
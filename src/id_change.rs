@@ -0,0 +1,81 @@
+use crate::stats::IdTransition;
+
+/// Detects the changes of the recognized overlay id within a recording (e.g.
+/// a participant switch in a spliced clip). A change is reported only when the
+/// previous id was stable for `stable_frames` frames and the new one is stable
+/// for as many frames, so the single frame OCR flickers are ignored.
+#[derive(Debug)]
+pub(crate) struct IdChangeDetector {
+    stable_frames: usize,
+    stable_id: Option<String>,
+    /// The candidate new id, its consecutive frames and the time of its first frame.
+    candidate: Option<(String, usize, f64)>,
+}
+
+impl IdChangeDetector {
+    pub(crate) fn new(stable_frames: usize) -> Self {
+        Self {
+            stable_frames: stable_frames.max(1),
+            stable_id: None,
+            candidate: None,
+        }
+    }
+
+    /// Updates the detector with the id recognized from the frame at `time`
+    /// (seconds), returning the transition confirmed by this frame.
+    pub(crate) fn update(&mut self, id: &str, time: f64) -> Option<IdTransition> {
+        if self.stable_id.as_deref() == Some(id) {
+            self.candidate = None;
+            return None;
+        }
+        let frames = match self.candidate.as_mut() {
+            Some((candidate, frames, _)) if candidate == id => {
+                *frames += 1;
+                *frames
+            }
+            _ => {
+                self.candidate = Some((id.to_string(), 1, time));
+                1
+            }
+        };
+        if frames < self.stable_frames {
+            return None;
+        }
+        let (id, _, time) = self.candidate.take().unwrap();
+        let transition = self.stable_id.take().map(|from_id| IdTransition {
+            from_id,
+            to_id: id.clone(),
+            time,
+        });
+        self.stable_id = Some(id);
+        transition
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(ids: &[&str]) -> Vec<IdTransition> {
+        let mut detector = IdChangeDetector::new(3);
+        ids.iter()
+            .enumerate()
+            .filter_map(|(i, id)| detector.update(id, i as f64))
+            .collect()
+    }
+
+    #[test]
+    fn spliced_id_change_is_reported() {
+        let transitions = run(&["1", "1", "1", "1", "2", "2", "2", "2"]);
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].from_id, "1");
+        assert_eq!(transitions[0].to_id, "2");
+        assert_eq!(transitions[0].time, 4.0);
+    }
+
+    #[test]
+    fn flickers_are_ignored() {
+        let transitions = run(&["1", "1", "1", "7", "1", "2", "1", "7", "7", "1", "1"]);
+        assert!(transitions.is_empty());
+    }
+}
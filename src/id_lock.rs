@@ -0,0 +1,94 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage};
+
+/// The luma threshold separating the overlay text from the black band.
+const TEXT_LUMA_THRESHOLD: u8 = 128;
+
+/// The id region signature size.
+const SIGNATURE_WIDTH: u32 = 32;
+const SIGNATURE_HEIGHT: u32 = 8;
+
+/// The maximum mean absolute difference between the id region signatures for
+/// considering the id unchanged.
+const SIGNATURE_MAX_DIFF: f64 = 32.0;
+
+/// Returns the horizontal extent `(start, end)` of the overlay text in the band.
+fn text_extent(band: &GrayImage) -> Option<(u32, u32)> {
+    let is_text_column =
+        |x: u32| (0..band.height()).any(|y| band.get_pixel(x, y)[0] > TEXT_LUMA_THRESHOLD);
+    let start = (0..band.width()).find(|&x| is_text_column(x))?;
+    let end = (start..band.width()).rev().find(|&x| is_text_column(x))?;
+    Some((start, end + 1))
+}
+
+/// Returns a downscaled copy of a band region, used for cheaply comparing it
+/// across frames.
+fn region_signature(band: &GrayImage, x: u32, width: u32) -> Vec<u8> {
+    let width = width.min(band.width().saturating_sub(x)).max(1);
+    let region = imageops::crop_imm(band, x, 0, width, band.height()).to_image();
+    imageops::resize(
+        &region,
+        SIGNATURE_WIDTH,
+        SIGNATURE_HEIGHT,
+        FilterType::Triangle,
+    )
+    .into_raw()
+}
+
+/// The overlay id, locked after being recognized identically for several
+/// consecutive frames. The overlay is rendered as centered monospace
+/// `<id>-<timestamp>` text, so the id region is located from the text start and
+/// the character width, and the timestamp digits follow it.
+pub struct IdLock {
+    id: String,
+    band_width: u32,
+    char_width: f64,
+    signature: Vec<u8>,
+}
+
+impl IdLock {
+    /// Creates the lock from the overlay band of a frame where `id` and a
+    /// timestamp with `time_digits` digits were recognized.
+    pub fn new(band: &DynamicImage, id: &str, time_digits: usize) -> Option<Self> {
+        let band = band.to_luma8();
+        let (start, end) = text_extent(&band)?;
+        let chars = id.len() + 1 + time_digits;
+        let char_width = (end - start) as f64 / chars as f64;
+        let id_width = (char_width * id.len() as f64).round() as u32;
+        Some(Self {
+            id: id.to_string(),
+            band_width: band.width(),
+            char_width,
+            signature: region_signature(&band, start, id_width),
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the band region containing the timestamp digits, or `None` if
+    /// the id region doesn't match the locked one anymore.
+    pub fn timestamp_region(&self, band: &DynamicImage) -> Option<DynamicImage> {
+        let gray = band.to_luma8();
+        if gray.width() != self.band_width {
+            return None;
+        }
+        let (start, end) = text_extent(&gray)?;
+        let id_width = (self.char_width * self.id.len() as f64).round() as u32;
+        let signature = region_signature(&gray, start, id_width);
+        let diff = signature
+            .iter()
+            .zip(self.signature.iter())
+            .map(|(a, b)| (*a as f64 - *b as f64).abs())
+            .sum::<f64>()
+            / signature.len() as f64;
+        if diff > SIGNATURE_MAX_DIFF {
+            return None;
+        }
+        // Skip the id and the separator, keeping half a character of margin.
+        let x = start as f64 + self.char_width * (self.id.len() as f64 + 0.5);
+        let x = (x.round() as u32).min(end.saturating_sub(1));
+        Some(band.crop_imm(x, 0, band.width() - x, band.height()))
+    }
+}
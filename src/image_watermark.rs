@@ -0,0 +1,52 @@
+//! Timestamp overlay drawn directly on images, without going through FFmpeg.
+
+use crate::bars;
+use crate::config::{Overlay, WatermarkConfig};
+use crate::transcoder::{band_height, bars_id, font_path};
+use ab_glyph::{FontVec, PxScale};
+use image::{DynamicImage, Rgb};
+use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut, text_size};
+use imageproc::rect::Rect;
+
+/// Draws the `<id>-<timestamp>` overlay on an image, with the same layout used
+/// by the video watermark.
+pub fn watermark_image(
+    image: &DynamicImage,
+    timestamp_ms: u64,
+    id: &str,
+    config: &WatermarkConfig,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let mut image = image.to_rgb8();
+    let text_height = band_height(image.height());
+    draw_filled_rect_mut(
+        &mut image,
+        Rect::at(0, 0).of_size(image.width(), text_height.max(1)),
+        Rgb([0, 0, 0]),
+    );
+
+    match config.overlay {
+        Overlay::Bars => {
+            bars::draw_image(&mut image, text_height, bars_id(Some(id))?, timestamp_ms);
+        }
+        Overlay::Text => {
+            let font_path = match config.font_path.as_ref() {
+                Some(font_path) => font_path.clone(),
+                None => font_path(config.cache_dir.as_deref())?,
+            };
+            let font = FontVec::try_from_vec(std::fs::read(&font_path)?)?;
+            let scale = PxScale::from((image.height() as f32 / 18.0).round());
+            let text = format!("{}-{}", id, timestamp_ms);
+            let (text_w, text_h) = text_size(scale, &font, &text);
+            draw_text_mut(
+                &mut image,
+                Rgb([255, 255, 255]),
+                (image.width() as i32 - text_w as i32) / 2,
+                (text_height as i32 - text_h as i32) / 2,
+                scale,
+                &font,
+                &text,
+            );
+        }
+    }
+    Ok(DynamicImage::ImageRgb8(image))
+}
@@ -0,0 +1,95 @@
+//! Checks of the input files possibly still being written, e.g. by a
+//! MediaRecorder, which would otherwise fail with confusing probe errors or
+//! give a partial result.
+
+use crossbeam_channel::Receiver;
+use log::debug;
+use std::time::{Duration, Instant};
+
+/// The maximum interval between the size checks of `wait_for_stable_size`.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Fails if `input_file` is an empty regular file. The other inputs (URLs,
+/// image sequence patterns, devices) aren't checked.
+pub(crate) fn check_not_empty(input_file: &str) -> Result<(), String> {
+    match std::fs::metadata(input_file) {
+        Ok(metadata) if metadata.is_file() && metadata.len() == 0 => Err(format!(
+            "input file {} is empty (0 bytes), it may still be being written",
+            input_file
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Waits until the size of `input_file` hasn't changed for `stable_for`,
+/// returning immediately if it isn't a regular file. Fails if a stop message
+/// is received while waiting.
+pub(crate) fn wait_for_stable_size(
+    input_file: &str,
+    stable_for: Duration,
+    receiver: &Receiver<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file_size = || {
+        std::fs::metadata(input_file)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+    };
+    let mut size = match file_size() {
+        Some(size) => size,
+        None => return Ok(()),
+    };
+    let mut stable_since = Instant::now();
+    let interval = POLL_INTERVAL.min(stable_for);
+    while stable_since.elapsed() < stable_for {
+        std::thread::sleep(interval);
+        if let Ok("stop") = receiver.try_recv() {
+            return Err(format!("stopped waiting for {} to be stable", input_file).into());
+        }
+        let new_size = file_size().unwrap_or(0);
+        if new_size != size {
+            debug!("{} is growing: {} -> {} bytes", input_file, size, new_size);
+            size = new_size;
+            stable_since = Instant::now();
+        }
+    }
+    debug!("{} is stable at {} bytes", input_file, size);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_and_growing_inputs() {
+        let path = std::env::temp_dir().join(format!("input-check-{}.webm", std::process::id()));
+        let input_file = path.to_str().unwrap();
+        std::fs::write(&path, b"").unwrap();
+        assert!(check_not_empty(input_file).is_err());
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                for _ in 0..3 {
+                    std::thread::sleep(Duration::from_millis(20));
+                    let mut data = std::fs::read(&path).unwrap();
+                    data.extend_from_slice(b"data");
+                    std::fs::write(&path, data).unwrap();
+                }
+            })
+        };
+        wait_for_stable_size(input_file, Duration::from_millis(300), &receiver).unwrap();
+        // The writes ended before the size was stable.
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 12);
+        writer.join().unwrap();
+        assert!(check_not_empty(input_file).is_ok());
+
+        sender.send("stop").unwrap();
+        assert!(wait_for_stable_size(input_file, Duration::from_secs(10), &receiver).is_err());
+        std::fs::remove_file(&path).unwrap();
+        // Not a regular file.
+        assert!(wait_for_stable_size(input_file, Duration::from_secs(10), &receiver).is_ok());
+    }
+}
@@ -0,0 +1,232 @@
+use log::{debug, warn};
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Returned when the output file is locked by another running process.
+#[derive(Debug)]
+pub struct AlreadyProcessingError {
+    pub lock_file: String,
+    pub pid: u32,
+}
+
+impl std::fmt::Display for AlreadyProcessingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "already being processed by pid {} (lock file: {})",
+            self.pid, self.lock_file
+        )
+    }
+}
+
+impl std::error::Error for AlreadyProcessingError {}
+
+/// An advisory lock on an output file, implemented as a `<output>.lock` file
+/// containing the owner pid and start time. The content is written to a
+/// temporary file first, then hard linked to the lock file, which fails
+/// atomically if the lock exists: a lock is never visible without its pid.
+/// A lock whose owner is no longer running is reclaimed; on non-unix systems
+/// the owner liveness can't be checked, so a stale lock must be removed by
+/// hand. The lock file is removed when the lock is dropped.
+pub struct OutputLock {
+    lock_file: String,
+}
+
+impl OutputLock {
+    pub fn acquire(output_file: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+        let lock_file = format!("{}.lock", output_file);
+        let temp_file = format!(
+            "{}.{}-{}.tmp",
+            lock_file,
+            std::process::id(),
+            TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        );
+        fs::write(
+            &temp_file,
+            format!(
+                "{} {}\n",
+                std::process::id(),
+                chrono::Local::now().to_rfc3339()
+            ),
+        )?;
+        let result = Self::link(&lock_file, &temp_file);
+        if let Err(e) = fs::remove_file(&temp_file) {
+            debug!("Failed to remove {}: {}", temp_file, e);
+        }
+        result
+    }
+
+    fn link(lock_file: &str, temp_file: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        loop {
+            match fs::hard_link(temp_file, lock_file) {
+                Ok(()) => {
+                    debug!("Lock acquired: {}", lock_file);
+                    return Ok(Self {
+                        lock_file: lock_file.to_string(),
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let content = match fs::read_to_string(lock_file) {
+                        Ok(content) => content,
+                        // Released in the meantime.
+                        Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                        Err(e) => return Err(e.into()),
+                    };
+                    let pid = lock_owner(&content);
+                    match pid {
+                        Some(pid) if is_process_alive(pid) => {
+                            return Err(Box::new(AlreadyProcessingError {
+                                lock_file: lock_file.to_string(),
+                                pid,
+                            }));
+                        }
+                        _ => Self::reclaim(lock_file, temp_file, &content)?,
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Removes the stale lock with `content`. The lock is moved aside first
+    /// and put back if another process took it over in the meantime, so a
+    /// live lock is never removed.
+    fn reclaim(
+        lock_file: &str,
+        temp_file: &str,
+        content: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let stale_file = format!("{}.stale", temp_file);
+        match fs::rename(lock_file, &stale_file) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let moved = fs::read_to_string(&stale_file).unwrap_or_default();
+        if moved == content {
+            warn!(
+                "Removing stale lock file {} ({})",
+                lock_file,
+                content.trim()
+            );
+        } else if let Err(e) = fs::hard_link(&stale_file, lock_file) {
+            warn!("Failed to restore lock file {}: {}", lock_file, e);
+        }
+        fs::remove_file(&stale_file)?;
+        Ok(())
+    }
+}
+
+/// Returns the pid written in a lock file, `None` if it is unparsable.
+fn lock_owner(content: &str) -> Option<u32> {
+    content
+        .split_whitespace()
+        .next()
+        .and_then(|pid| pid.parse::<u32>().ok())
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.lock_file) {
+            warn!("Failed to remove lock file {}: {}", self.lock_file, e);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_process_alive(pid: u32) -> bool {
+    // The pids that don't fit a positive pid_t would signal process groups.
+    if pid == 0 || pid > libc::pid_t::MAX as u32 {
+        return false;
+    }
+    // Signal 0 only checks for the process existence.
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_process_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("webrtcperf-lock-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn a_held_lock_is_not_acquired_again() {
+        let dir = test_dir("held");
+        let output = dir.join("out.ivf").to_string_lossy().to_string();
+        let lock = OutputLock::acquire(&output).unwrap();
+        let e = OutputLock::acquire(&output).err().unwrap();
+        let e = e.downcast_ref::<AlreadyProcessingError>().unwrap();
+        assert_eq!(e.pid, std::process::id());
+        drop(lock);
+        assert!(!std::path::Path::new(&format!("{}.lock", output)).exists());
+        // Only the lock file was ever written.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_stale_lock_is_reclaimed() {
+        let dir = test_dir("stale");
+        let output = dir.join("out.ivf").to_string_lossy().to_string();
+        let lock_file = format!("{}.lock", output);
+        fs::write(
+            &lock_file,
+            format!("{} 2020-01-01T00:00:00+00:00\n", u32::MAX),
+        )
+        .unwrap();
+        let lock = OutputLock::acquire(&output).unwrap();
+        let content = fs::read_to_string(&lock_file).unwrap();
+        assert_eq!(lock_owner(&content), Some(std::process::id()));
+        drop(lock);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_acquires_have_a_single_owner() {
+        let dir = test_dir("concurrent");
+        let output = dir.join("out.ivf").to_string_lossy().to_string();
+        let barrier = std::sync::Barrier::new(8);
+        let locks: Vec<OutputLock> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        // The error isn't Send: only the owner pid is kept.
+                        OutputLock::acquire(&output)
+                            .map_err(|e| e.downcast_ref::<AlreadyProcessingError>().map(|e| e.pid))
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .filter_map(|handle| match handle.join().unwrap() {
+                    Ok(lock) => Some(lock),
+                    Err(pid) => {
+                        assert_eq!(pid, Some(std::process::id()));
+                        None
+                    }
+                })
+                .collect()
+        });
+        assert_eq!(locks.len(), 1);
+        drop(locks);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
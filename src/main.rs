@@ -1,6 +1,33 @@
 use clap::Parser;
 use env_logger;
-use webrtcperf_vmaf_utils::{process_video, watermark_video};
+use std::sync::atomic::{AtomicBool, Ordering};
+use webrtcperf_vmaf_utils::{
+    benchmark_ocr, compare_videos, default_batch_state_file, detect_id, existing_process_outputs,
+    extract_frames, metadata_info, parse_opts, process_pair, process_video,
+    self_check_watermarked_video, timestamp_pattern_regex, validate_process_video,
+    validate_watermark_video, version_info, watermark_realtime, watermark_video, AlignBy,
+    AlreadyProcessingError, BatchState, BlankPolicy, ColorSpace, Config, Deinterlace,
+    EncoderOptions, ExtraOutput, Keyframes, Metric, OcrPageSegMode, Overlay, Padding, ProcessError,
+    ProcessResult, PtsRounding, TimestampBase, TimestampCarrier, TomlEncoderOpts, UnmatchedFrames,
+    VideoCodec,
+};
+
+/// Set when the processing is stopped with Ctrl-C.
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Set when the output is written to stdout (e.g. `--output -`).
+static OUTPUT_TO_STDOUT: AtomicBool = AtomicBool::new(false);
+
+/// Prints to stdout, or to stderr when the output is written to stdout.
+macro_rules! report {
+    ($($arg:tt)*) => {
+        if OUTPUT_TO_STDOUT.load(Ordering::SeqCst) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
 
 /// Utility for processing real time videos for VMAF evaluation
 #[derive(Parser, Debug)]
@@ -10,34 +37,1309 @@ struct Args {
     #[arg(short, long, default_value_t = String::new())]
     watermark: String,
 
+    /// Draw the watermark only in the START:END time range (seconds). Can be repeated
+    #[arg(long = "watermark-range", value_parser = parse_range)]
+    watermark_ranges: Vec<(f64, f64)>,
+
     /// The id to write on the watermark
     #[arg(long, default_value_t = String::new())]
     watermark_id: String,
 
-    /// When set, the video will be processed recognizing the timestamp overlay and setting the frames pts accordingly
-    #[arg(short, long, default_value_t = String::new())]
-    process: String,
+    /// When set, the raw frames received on this TCP address are watermarked in real time
+    #[arg(long)]
+    listen: Option<std::net::SocketAddr>,
+
+    /// The real time mode frames size
+    #[arg(long, default_value = "1280x720", value_parser = parse_size)]
+    realtime_size: (u32, u32),
+
+    /// The real time mode frame rate
+    #[arg(long, default_value_t = 30)]
+    realtime_fps: u32,
+
+    /// In real time mode, pace the output frames to the wall clock
+    #[arg(long, default_value_t = false)]
+    pace_realtime: bool,
+
+    /// When set, the video will be processed recognizing the timestamp overlay and setting the frames pts accordingly.
+    /// Multiple files are processed in sequence as a single continuous timeline
+    #[arg(short, long, num_args = 1..)]
+    process: Vec<String>,
+
+    /// Process each `--process` file separately instead of as a single timeline
+    #[arg(long, default_value_t = false)]
+    batch: bool,
+
+    /// In batch mode, skip the files already processed (with an existing `<input>.<id>.ivf` output)
+    #[arg(long, default_value_t = false, requires = "batch")]
+    skip_existing: bool,
+
+    /// The batch state file, recording each completed or failed file for resuming an interrupted
+    /// batch. Defaults to `.webrtcperf-vmaf-utils-batch.json` in the first file directory
+    #[arg(long, requires = "batch")]
+    state_file: Option<String>,
+
+    /// Ignore the batch state file, processing every file again
+    #[arg(long, default_value_t = false, requires = "batch")]
+    no_resume: bool,
+
+    /// The timestamp overlay encoding
+    #[arg(long, value_enum, default_value_t = Overlay::Text)]
+    overlay: Overlay,
+
+    /// The source of the timestamp written in the watermark. The process mode expects the pts base
+    #[arg(long, value_enum, default_value_t = TimestampBase::Pts, alias = "clock-source")]
+    timestamp_base: TimestampBase,
+
+    /// The fractional millisecond digits of the text overlay timestamp, e.g. 2 for 16.66 ms frame
+    /// times at 60 fps
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=3))]
+    timestamp_decimals: u32,
+
+    /// The watermark text format, with the `{id}` and `{timestamp}` placeholders and the ffmpeg
+    /// drawtext `%{...}` expansions (e.g. `%{n}` for the frame number)
+    #[arg(long)]
+    watermark_format: Option<String>,
+
+    /// The frame rate of the `{timecode}` watermark placeholder and of the recognized timecodes
+    /// [default: the input frame rate]
+    #[arg(long)]
+    timecode_fps: Option<f64>,
+
+    /// The watermark opacity (0-1). Below 1 the overlay band is blended with the content, which
+    /// biases less the VMAF scores (text overlay only, see `--ocr-contrast`)
+    #[arg(long, default_value_t = 1.0)]
+    watermark_opacity: f32,
+
+    /// Drop each watermarked frame before the encoder with this probability (0-1), simulating
+    /// the frames lost in a transmission for testing the timestamps recovery
+    #[arg(long, value_parser = parse_ratio, requires = "watermark")]
+    simulate_packet_loss: Option<f64>,
+
+    /// The seed of the --simulate-packet-loss random generator, for reproducible runs
+    #[arg(long, requires = "simulate_packet_loss")]
+    packet_loss_seed: Option<u64>,
+
+    /// Adds temporal Gaussian noise of this strength (0-100) to the watermarked video before
+    /// encoding, simulating the camera sensor noise or the transmission artifacts
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=100), requires = "watermark")]
+    noise: Option<u32>,
+
+    /// How the timestamp is carried in watermark mode. The metadata keeps the frames pixel-identical
+    /// but doesn't survive a WebRTC transmission
+    #[arg(long, value_enum, default_value_t = TimestampCarrier::Visual)]
+    timestamp_carrier: TimestampCarrier,
+
+    /// The Tesseract language(s) used for recognizing the text overlay (e.g. eng+deu), downloaded on
+    /// demand [default: eng]
+    #[arg(long)]
+    ocr_lang: Option<String>,
+
+    /// The characters recognized by Tesseract, e.g. adding letters for overlays with a hostname
+    /// prefix [default: 0123456789-.:]
+    #[arg(long)]
+    ocr_whitelist: Option<String>,
+
+    /// A regex matching the overlay text, with an `id` and a `time` (ms) or `timecode` named group
+    /// (default: the `<id>-<timestamp>` pattern). Can be repeated: the patterns are tried in order
+    #[arg(long = "timestamp-pattern", value_parser = parse_timestamp_pattern)]
+    timestamp_patterns: Vec<String>,
+
+    /// The Tesseract page segmentation mode, e.g. sparse-text for overlays differing from the
+    /// default single line layout
+    #[arg(long, value_enum, default_value_t = OcrPageSegMode::SingleLine)]
+    ocr_psm: OcrPageSegMode,
+
+    /// The contrast adjustment (percent) applied to the watermark band before the text recognition,
+    /// e.g. 50 for recognizing a semi-transparent overlay
+    #[arg(long)]
+    ocr_contrast: Option<f32>,
+
+    /// The part of the watermark band recognized, as X:Y:WIDTH:HEIGHT (frame pixels), excluding
+    /// e.g. the receiver controls drawn over the end of the timestamp
+    #[arg(long, value_parser = parse_region)]
+    ocr_region: Option<(u32, u32, u32, u32)>,
+
+    /// The directory of the downloaded font and Tesseract data [default: $WEBRTCPERF_CACHE_DIR, then
+    /// $HOME/.webrtcperf/cache, then a temporary directory]
+    #[arg(long)]
+    cache_dir: Option<String>,
+
+    /// The rounding applied when converting the recognized timestamps to pts
+    #[arg(long, value_enum, default_value_t = PtsRounding::Nearest)]
+    pts_rounding: PtsRounding,
+
+    /// The handling of the blank frames (e.g. a muted track), detected before the recognition and
+    /// not counted as failed
+    #[arg(long, value_enum, default_value_t = BlankPolicy::Keep)]
+    on_blank: BlankPolicy,
+
+    /// The maximum mean luma (0-255) of the watermark band of a blank frame
+    #[arg(long, default_value_t = 24.0)]
+    blank_max_luma: f64,
+
+    /// The maximum luma variance of the watermark band of a blank frame
+    #[arg(long, default_value_t = 16.0)]
+    blank_max_variance: f64,
+
+    /// The keyframes placement. `scene-cut` reduces the output size, keeping the per frame
+    /// seekability only at the scene cuts
+    #[arg(long, value_enum, default_value_t = Keyframes::All)]
+    keyframes: Keyframes,
+
+    /// The keyframe interval (frames) of the encoded outputs [default: 1, every frame is a
+    /// keyframe]. With `--keyframes scene-cut`, the maximum interval between the scene cuts
+    /// [default: 300]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    gop: Option<u32>,
+
+    /// The video codec of the encoded outputs. AV1 requires ffmpeg built with libsvtav1
+    #[arg(long, value_enum, default_value_t = VideoCodec::Vp8)]
+    codec: VideoCodec,
+
+    /// The constant rate factor of the encoded outputs (0-63, lower is better), mapped to the VP8 crf
+    /// or to the SVT-AV1 crf [default: 1]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=63))]
+    crf: Option<u32>,
+
+    /// The video encoder options overriding the defaults, as KEY=VAL,KEY=VAL (e.g.
+    /// cpu-used=4,deadline=realtime for a faster VP8 encoding)
+    #[arg(long, value_parser = parse_options)]
+    encoder_options: Option<String>,
+
+    /// A TOML file with the video encoder options overriding the defaults, in a table for each
+    /// codec (e.g. [vp8] quality = "best" cpu-used = 0)
+    #[arg(long, conflicts_with = "encoder_options")]
+    encoder_config: Option<String>,
+
+    /// The pixel format of the encoded outputs (default: the decoded format when supported by the
+    /// encoder). VP8 and AV1 support yuv420p, y4m yuv420p and yuv422p, PNG rgb24
+    #[arg(long, value_enum)]
+    color_space: Option<ColorSpace>,
+
+    /// The scene change threshold (0-100) used with `--keyframes scene-cut`
+    #[arg(long, default_value_t = 10.0)]
+    scene_threshold: f64,
+
+    /// Deinterlace the frames before the watermark or recognition stage
+    #[arg(long, value_enum, default_value_t = Deinterlace::Auto)]
+    deinterlace: Deinterlace,
+
+    /// When deinterlacing, output one frame for each field doubling the frame rate
+    #[arg(long, default_value_t = false)]
+    deinterlace_field_rate: bool,
+
+    /// Write audio sync markers (watermark mode) or measure the audio/video sync offset (process mode)
+    #[arg(long, default_value_t = false)]
+    audio_sync: bool,
+
+    /// Timestamp the frames whose overlay isn't recognized from the nearest audio marker (process
+    /// mode), e.g. for captures with an unreadable overlay
+    #[arg(long, default_value_t = false, requires = "audio_sync")]
+    audio_fallback: bool,
+
+    /// The Opus bitrate of the audio sync markers track, e.g. 64k or 128000
+    #[arg(long, default_value = "64k", value_parser = parse_bitrate, requires = "audio_sync")]
+    audio_bitrate: usize,
+
+    /// Report the intervals where the input audio level stays below `--silence-threshold`, e.g.
+    /// the concealment silences of packet loss bursts
+    #[arg(long, default_value_t = false)]
+    detect_silence: bool,
+
+    /// The silence level threshold (dBFS) of `--detect-silence`
+    #[arg(long, default_value_t = -60.0, allow_negative_numbers = true, requires = "detect_silence")]
+    silence_threshold: f64,
+
+    /// The number of frames buffered for recovering the timestamp of the failed frames from the nearby ones
+    #[arg(long, default_value_t = 0)]
+    lookahead_frames: usize,
+
+    /// The number of consecutive frames with the same id after which only the timestamp is recognized (0 disables)
+    #[arg(long, default_value_t = 30)]
+    id_lock_frames: usize,
+
+    /// Report an id change when the new id is recognized for N consecutive frames after the previous
+    /// one was recognized for as many frames (0 disables)
+    #[arg(long, default_value_t = 10)]
+    id_change_frames: usize,
+
+    /// Buffer up to N frames for emitting them sorted by the recognized timestamp (0 disables)
+    #[arg(long, default_value_t = 0)]
+    reorder_window: usize,
+
+    /// Write a CSV file with a row for each decoded frame (timestamp, key frame flag, picture type and
+    /// recognition result). With multiple video streams, one file is written per stream
+    #[arg(long)]
+    frames_csv: Option<String>,
+
+    /// Write a WebVTT (or SRT, with the `.srt` extension) subtitle file with a cue for each output
+    /// frame showing the recognized id, timestamp and delay, for inspecting the output in a player.
+    /// With multiple video streams, one file is written per stream
+    #[arg(long)]
+    subtitle_report: Option<String>,
+
+    /// Write a CSV file with the original and the recovered pts of each frame written to the output,
+    /// for comparing them without running the recognition again. With multiple video streams, one
+    /// file is written per stream
+    #[arg(long)]
+    pts_csv: Option<String>,
+
+    /// Write a JSON lines file with the input time, the recovered time and the drift from the
+    /// nominal frame rate (ms) of each frame written to the output, for plotting the capture timing
+    /// jitter. With multiple video streams, one file is written per stream
+    #[arg(long, requires = "process")]
+    drift_series: Option<String>,
+
+    /// Drop the frames before the first recognized frame and after the last one, excluding them from
+    /// the failed frames
+    #[arg(long, default_value_t = false)]
+    trim_to_recognized: bool,
+
+    /// Correct the input time distances used for recovering the failed frames timestamps (and the
+    /// subtitle report delays) for the measured capture clock drift
+    #[arg(long, default_value_t = false)]
+    correct_drift: bool,
+
+    /// Abort with exit code 3, removing the output, when the ratio (0-1) of frames whose timestamp
+    /// was not recognized is greater than this value
+    #[arg(long, value_parser = parse_ratio, requires = "process")]
+    max_failed_ratio: Option<f64>,
+
+    /// After watermarking, decode the output and recognize its overlay, printing the recognition
+    /// rate
+    #[arg(long, default_value_t = false, requires = "watermark")]
+    self_check: bool,
+
+    /// The self check recognition rate (0-1) below which a warning is printed
+    #[arg(long, default_value_t = 0.95)]
+    self_check_min_rate: f64,
+
+    /// Remove the watermark band from the top of the processed frames, reducing the output height
+    #[arg(long, default_value_t = false, requires = "process")]
+    strip_watermark: bool,
+
+    /// Rescale the processed frames with a non-square sample aspect ratio to square pixels,
+    /// adjusting the width (rounded to even), and write a 1:1 aspect ratio
+    #[arg(long, default_value_t = false)]
+    normalize_sar: bool,
+
+    /// The maximum number of decoded frames buffered for the lookahead and reordering
+    /// (default: derived from the frame size, about 256 MB)
+    #[arg(long)]
+    max_inflight_frames: Option<usize>,
+
+    /// Bound the memory usage for high resolution inputs or many parallel runs: a smaller default
+    /// --max-inflight-frames (about 32 MB), slice threaded decoding and a short muxer queue
+    #[arg(long, default_value_t = false)]
+    low_memory: bool,
+
+    /// The frame intervals histogram bucket width (ms)
+    #[arg(long, default_value_t = 5.0)]
+    interval_bucket_ms: f64,
+
+    /// Resample the processed output to this constant frame rate, after the timestamps recovery
+    #[arg(long)]
+    target_fps: Option<f64>,
+
+    /// Truncate or pad the resampled output to exactly this number of frames
+    #[arg(long, requires = "target_fps")]
+    target_frames: Option<usize>,
+
+    /// Truncate or pad the output to exactly this duration (seconds) from the first output frame,
+    /// after the timestamps recovery
+    #[arg(long, requires = "process")]
+    target_duration: Option<f64>,
+
+    /// Watermark or process only the keyframes at least this interval (seconds) apart, producing a
+    /// sparse output for a fast approximate pass
+    #[arg(long, conflicts_with_all = ["target_fps", "target_frames", "target_duration"])]
+    sample_keyframes: Option<f64>,
+
+    /// The frames padding the output up to --target-frames or --target-duration
+    #[arg(long, value_enum, default_value_t = Padding::Repeat)]
+    padding: Padding,
+
+    /// The input frame rate, required for image sequence inputs (e.g. frame_%05d.png)
+    #[arg(long)]
+    input_fps: Option<f64>,
+
+    /// The frame rate of the encoded outputs, overriding the input metadata when missing or invalid
+    /// (e.g. 0/0 in IVF files exported from WebRTC)
+    #[arg(long, value_parser = parse_fps)]
+    fps: Option<f64>,
+
+    /// The output file (default: derived from the input). Image sequence patterns (e.g. frames/out_%05d.png) are supported
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// The output path template, with the {dir}, {stem} (the input name without the extension),
+    /// {id} (the watermark id, or the recognized id in process mode) and {ext} placeholders (e.g.
+    /// out/{stem}-{id}.{ext}). Ignored when --output is set
+    #[arg(long)]
+    output_pattern: Option<String>,
+
+    /// Write an additional output applying a filter graph to the output frames, as PATH=FILTER (e.g. preview.ivf=scale=320:-2). Can be repeated
+    #[arg(long = "extra-output", value_parser = parse_extra_output)]
+    extra_outputs: Vec<ExtraOutput>,
+
+    /// Force the input format (e.g. for .raw or .bin files)
+    #[arg(long)]
+    force_format: Option<String>,
+
+    /// Transcode only the best video stream, dropping the others (e.g. an attached cover art)
+    #[arg(long, default_value_t = false)]
+    best_stream_only: bool,
+
+    /// Transcode every video stream (the default), each one to its own `.s<N>` output file in
+    /// process mode
+    #[arg(
+        long,
+        alias = "process-all-streams",
+        default_value_t = false,
+        conflicts_with = "best_stream_only"
+    )]
+    ignore_stream_mapping: bool,
+
+    /// Wait until the size of the input files hasn't changed for SEC seconds before opening them,
+    /// for files still being written (e.g. by a MediaRecorder)
+    #[arg(long, value_name = "SEC", value_parser = parse_seconds)]
+    wait_for_stable: Option<f64>,
+
+    /// The demuxer options, as KEY=VAL,KEY=VAL (a comma in a value is escaped as \,)
+    ///
+    /// Useful combinations:
+    ///   fflags=+genpts                          regenerate the missing pts of MediaRecorder files
+    ///   analyzeduration=10000000,probesize=50M  streams whose parameters appear late
+    ///   rtsp_transport=tcp                      RTSP sources over lossy networks
+    #[arg(long, value_parser = parse_options, verbatim_doc_comment)]
+    input_options: Option<String>,
+
+    /// The video decoder options, as KEY=VAL,KEY=VAL (a comma in a value is escaped as \,)
+    ///
+    /// Useful combinations:
+    ///   threads=1                               deterministic single threaded decoding
+    ///   skip_loop_filter=all                    faster H.264 decoding of long recordings
+    #[arg(long, value_parser = parse_options, verbatim_doc_comment)]
+    decoder_options: Option<String>,
+
+    /// Force the output format
+    #[arg(long)]
+    force_output_format: Option<String>,
+
+    /// Warn when an output is projected (from its first 10 encoded seconds) to exceed this size,
+    /// in bytes with an optional k, M or G suffix. The output filesystem free space is always
+    /// checked
+    #[arg(long, value_parser = parse_bytes)]
+    max_output_size: Option<u64>,
+
+    /// Fail, removing the outputs, when an output would exceed `--max-output-size`
+    #[arg(long, default_value_t = false, requires = "max_output_size")]
+    abort_on_max_output_size: bool,
+
+    /// The muxer movflags (default: faststart, or frag_keyframe+empty_moov for stdout and FIFO
+    /// outputs). An empty value disables them
+    #[arg(long)]
+    movflags: Option<String>,
+
+    /// Print the container and streams metadata of a file as JSON and exit
+    #[arg(long)]
+    metadata_only: Option<String>,
+
+    /// Print the watermark id of a file as JSON (with the fraction of the sampled frames agreeing)
+    /// and exit, recognizing a few evenly spaced frames instead of processing the whole file
+    #[arg(long)]
+    detect_id: Option<String>,
+
+    /// Run the OCR N times on the first watermarked frame of the `--process` files, with the
+    /// process mode OCR options, and print the Tesseract initialization and mean recognition
+    /// times as JSON, then exit
+    #[arg(long, value_name = "N", requires = "process", value_parser = clap::value_parser!(u32).range(1..))]
+    benchmark_ocr: Option<u32>,
+
+    /// Extract the frames of the `--process` files whose recognized watermark timestamp is the
+    /// closest to these times (ms), writing them as PNG files with a caption, and exit. Inside a
+    /// freeze the frozen frame is extracted, reporting the gap
+    #[arg(long, num_args = 1.., requires_all = ["process", "extract_out"])]
+    extract_at: Vec<f64>,
+
+    /// The PNG file written by `--extract-at`. The `{timestamp}` and `{stem}` (the input file name
+    /// without extension) placeholders are replaced, `{stem}` is required with multiple files
+    #[arg(long, requires = "extract_at")]
+    extract_out: Option<String>,
+
+    /// Compute the `--metrics` of the DEGRADED frames against the REFERENCE frames with the same
+    /// timestamp (e.g. two process mode outputs), writing a JSON report
+    #[arg(long, num_args = 2, value_names = ["REFERENCE", "DEGRADED"])]
+    compare: Vec<String>,
+
+    /// The metrics computed with `--compare`
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Metric::Psnr, Metric::Ssim])]
+    metrics: Vec<Metric>,
+
+    /// Compute the `--compare` metrics on the chroma planes too
+    #[arg(long, default_value_t = false, requires = "compare")]
+    metrics_chroma: bool,
+
+    /// The handling of the reference frames without a matching degraded frame
+    #[arg(long, value_enum, default_value_t = UnmatchedFrames::Worst)]
+    unmatched_frames: UnmatchedFrames,
+
+    /// The `--compare` JSON report file (default: `<DEGRADED>.metrics.json`)
+    #[arg(long, requires = "compare")]
+    metrics_report: Option<String>,
+
+    /// Write the `--compare` per frame metrics to a CSV file too
+    #[arg(long, requires = "compare")]
+    metrics_csv: Option<String>,
+
+    /// How the `--compare` frames are matched: by the pts of two process mode outputs, or by the
+    /// recognized timestamps, processing both files first
+    #[arg(long, value_enum, default_value_t = AlignBy::Pts, requires = "compare")]
+    align_by: AlignBy,
+
+    /// Measure the latency of the DEGRADED receiver capture against the REFERENCE watermark mode
+    /// output of the same id, matching the frames with the same timestamp
+    #[arg(long, num_args = 2, value_names = ["REFERENCE", "DEGRADED"], conflicts_with = "compare")]
+    pair: Vec<String>,
+
+    /// The `--pair` JSON report file (default: `<DEGRADED>.pair.json`)
+    #[arg(long, requires = "pair")]
+    pair_report: Option<String>,
+
+    /// Write the `--pair` per frame records to a CSV file too
+    #[arg(long, requires = "pair")]
+    pair_csv: Option<String>,
+
+    /// Print the linked ffmpeg and tesseract versions and exit
+    #[arg(long, default_value_t = false)]
+    version_info: bool,
+
+    /// Validate the inputs, filters, Tesseract, encoder and output without transcoding
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Write the decode, recognition and encode spans (with their duration) and the log events to
+    /// stderr as JSON lines, filtered by RUST_LOG
+    #[cfg(feature = "tracing-json")]
+    #[arg(long, default_value_t = false)]
+    tracing: bool,
+}
+
+/// Parses a `START:END` time range (seconds).
+fn parse_range(s: &str) -> Result<(f64, f64), String> {
+    let (start, end) = s
+        .split_once(':')
+        .ok_or(format!("invalid range {}, expected START:END", s))?;
+    let start: f64 = start.parse().map_err(|e| format!("invalid start: {}", e))?;
+    let end: f64 = end.parse().map_err(|e| format!("invalid end: {}", e))?;
+    if end <= start {
+        return Err(format!(
+            "invalid range {}, END must be greater than START",
+            s
+        ));
+    }
+    Ok((start, end))
+}
+
+/// Parses a ratio in the `0-1` range.
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    let ratio: f64 = s
+        .parse()
+        .map_err(|e| format!("invalid ratio {}: {}", s, e))?;
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(format!("invalid ratio {}, expected a value in 0-1", s));
+    }
+    Ok(ratio)
+}
+
+/// Parses a `X:Y:WIDTH:HEIGHT` region.
+fn parse_region(s: &str) -> Result<(u32, u32, u32, u32), String> {
+    let values = s
+        .split(':')
+        .map(|v| v.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid region {}: {}", s, e))?;
+    match values[..] {
+        [x, y, width, height] if width > 0 && height > 0 => Ok((x, y, width, height)),
+        _ => Err(format!("invalid region {}, expected X:Y:WIDTH:HEIGHT", s)),
+    }
+}
+
+/// Parses a positive frame rate.
+fn parse_fps(s: &str) -> Result<f64, String> {
+    let fps: f64 = s.parse().map_err(|e| format!("invalid fps {}: {}", s, e))?;
+    if !fps.is_finite() || fps <= 0.0 {
+        return Err(format!("invalid fps {}, expected a positive value", s));
+    }
+    Ok(fps)
+}
+
+/// Parses a positive duration (seconds).
+fn parse_seconds(s: &str) -> Result<f64, String> {
+    let seconds: f64 = s
+        .parse()
+        .map_err(|e| format!("invalid duration {}: {}", s, e))?;
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return Err(format!("invalid duration {}, expected a positive value", s));
+    }
+    Ok(seconds)
+}
+
+/// Parses a size (bytes), with an optional `k`, `M` or `G` (powers of 1024)
+/// suffix.
+fn parse_bytes(s: &str) -> Result<u64, String> {
+    let (value, multiplier) = match s.strip_suffix(['k', 'K']) {
+        Some(value) => (value, 1u64 << 10),
+        None => match s.strip_suffix('M') {
+            Some(value) => (value, 1 << 20),
+            None => match s.strip_suffix('G') {
+                Some(value) => (value, 1 << 30),
+                None => (s, 1),
+            },
+        },
+    };
+    let value: f64 = value
+        .parse()
+        .map_err(|e| format!("invalid size {}: {}", s, e))?;
+    if !value.is_finite() || value <= 0.0 {
+        return Err(format!("invalid size {}, expected a positive value", s));
+    }
+    Ok((value * multiplier as f64).round() as u64)
+}
+
+/// Parses a bitrate (bits/s), with an optional `k` or `M` suffix.
+fn parse_bitrate(s: &str) -> Result<usize, String> {
+    let (value, multiplier) = match s.strip_suffix(['k', 'K']) {
+        Some(value) => (value, 1000.0),
+        None => match s.strip_suffix('M') {
+            Some(value) => (value, 1_000_000.0),
+            None => (s, 1.0),
+        },
+    };
+    let value: f64 = value
+        .parse()
+        .map_err(|e| format!("invalid bitrate {}: {}", s, e))?;
+    if value <= 0.0 {
+        return Err(format!("invalid bitrate {}, expected a positive value", s));
+    }
+    Ok((value * multiplier).round() as usize)
+}
+
+/// Parses a `WIDTHxHEIGHT` frame size.
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    let (width, height) = s
+        .split_once('x')
+        .ok_or(format!("invalid size {}, expected WIDTHxHEIGHT", s))?;
+    let width: u32 = width.parse().map_err(|e| format!("invalid width: {}", e))?;
+    let height: u32 = height
+        .parse()
+        .map_err(|e| format!("invalid height: {}", e))?;
+    Ok((width, height))
+}
+
+/// Validates `KEY=VAL,KEY=VAL` ffmpeg options.
+fn parse_options(s: &str) -> Result<String, String> {
+    parse_opts(s)
+        .map(|_| s.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Validates a `--timestamp-pattern` regex.
+fn parse_timestamp_pattern(s: &str) -> Result<String, String> {
+    timestamp_pattern_regex(s).map(|_| s.to_string())
+}
+
+/// Parses a `PATH=FILTER` extra output.
+fn parse_extra_output(s: &str) -> Result<ExtraOutput, String> {
+    let (path, filter) = s
+        .split_once('=')
+        .ok_or(format!("invalid extra output {}, expected PATH=FILTER", s))?;
+    Ok(ExtraOutput {
+        path: path.to_string(),
+        filter: filter.to_string(),
+    })
+}
+
+/// Returns the process exit code for an error.
+fn exit_code(e: &(dyn std::error::Error + 'static)) -> i32 {
+    if e.downcast_ref::<AlreadyProcessingError>().is_some() {
+        2
+    } else if let Some(ProcessError::TooManyFailures { .. }) = e.downcast_ref::<ProcessError>() {
+        3
+    } else {
+        1
+    }
+}
+
+/// Recognizes the overlay of the watermarked output, warning when the
+/// recognition rate is below `min_rate`.
+fn self_check(
+    output_file: &str,
+    config: &Config,
+    receiver: crossbeam_channel::Receiver<&str>,
+    min_rate: f64,
+) {
+    match self_check_watermarked_video(output_file, config, receiver) {
+        Ok((frames, recognized)) => {
+            let rate = recognized as f64 / frames.max(1) as f64;
+            report!(
+                "self check: recognized {}/{} frames ({:.1}%)",
+                recognized,
+                frames,
+                rate * 100.0
+            );
+            if rate < min_rate {
+                eprintln!(
+                    "Warning: the watermark recognition rate is below {:.1}%, the overlay may be too small or low-contrast for the encoding bitrate",
+                    min_rate * 100.0
+                );
+            }
+        }
+        Err(e) => {
+            eprintln!("Error checking the watermarked video: {}", e);
+            std::process::exit(exit_code(e.as_ref()));
+        }
+    }
+}
+
+/// Prints the output file size and the average bitrate of the encoded video.
+fn print_output_size(result: &ProcessResult) {
+    if let Some(size) = result.output_size {
+        report!(
+            "output size: {} bytes bitrate: {} bits per pixel: {}",
+            size,
+            result
+                .output_bitrate
+                .map_or("n/a".to_string(), |bitrate| format!("{:.0} bps", bitrate)),
+            result
+                .output_bits_per_pixel
+                .map_or("n/a".to_string(), |bpp| format!("{:.4}", bpp))
+        );
+    }
+}
+
+/// Prints the process mode statistics.
+fn print_process_result(result: &ProcessResult) {
+    for segment in result.segments.iter() {
+        report!(
+            "segment: {} frames: {} failed: {} pts offset: {}",
+            segment.input_file,
+            segment.frames,
+            segment.failed_frames,
+            segment.pts_offset
+        );
+    }
+    report!(
+        "output: {} frames: {} output frames: {} failed: {} blank: {} interpolated: {} id switches: {} deinterlaced: {}",
+        result.output_file,
+        result.frames,
+        result.output_frames,
+        result.failed_frames,
+        result.blank_frames,
+        result.interpolated_frames,
+        result.id_switches,
+        result.deinterlaced
+    );
+    print_output_size(result);
+    if result.truncated_reads > 0 {
+        report!(
+            "truncated reads: {} (occluded watermark band? see --ocr-region)",
+            result.truncated_reads
+        );
+    }
+    if let (Some(id), Some(agreement)) = (result.recognized_id.as_ref(), result.id_agreement) {
+        report!("id: {} agreement: {:.3}", id, agreement);
+    }
+    if result.pattern_matches.len() > 1 {
+        for (index, matches) in result.pattern_matches.iter().enumerate() {
+            report!("timestamp pattern: {} matches: {}", index, matches);
+        }
+    }
+    for transition in result.id_transitions.iter() {
+        report!(
+            "id transition: {} -> {} at {:.3}s",
+            transition.from_id,
+            transition.to_id,
+            transition.time
+        );
+        eprintln!(
+            "Warning: the recognized id changed from {} to {} at {:.3}s, the output mixes the frames of different recordings",
+            transition.from_id, transition.to_id, transition.time
+        );
+    }
+    report!(
+        "timestamp carrier frames: visual: {} metadata: {} audio: {} rescued by the Lanczos scaler: {}",
+        result.visual_frames,
+        result.metadata_frames,
+        result.audio_frames,
+        result.hq_rescued_frames
+    );
+    report!(
+        "in-flight frames high water: {}",
+        result.inflight_frames_high_water
+    );
+    if result.padded_frames > 0 || result.truncated_frames > 0 {
+        report!(
+            "target length: padded frames: {} truncated frames: {}",
+            result.padded_frames,
+            result.truncated_frames
+        );
+    }
+    if result.trimmed_head_frames > 0 || result.trimmed_tail_frames > 0 {
+        report!(
+            "trimmed frames: head: {} tail: {}",
+            result.trimmed_head_frames,
+            result.trimmed_tail_frames
+        );
+    }
+    if let Some(intervals) = result.frame_intervals.as_ref() {
+        report!(
+            "frame intervals (ms): mean: {:.1} stddev: {:.1} p10: {:.1} p50: {:.1} p90: {:.1} over 2x: {} over 4x: {}",
+            intervals.mean_ms,
+            intervals.stddev_ms,
+            intervals.percentiles.p10,
+            intervals.percentiles.p50,
+            intervals.percentiles.p90,
+            intervals.over_2x,
+            intervals.over_4x
+        );
+        let buckets: Vec<String> = intervals
+            .histogram
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(i, count)| {
+                format!(
+                    "{}-{}: {}",
+                    i as f64 * intervals.bucket_ms,
+                    (i + 1) as f64 * intervals.bucket_ms,
+                    count
+                )
+            })
+            .collect();
+        report!("frame intervals histogram (ms): {}", buckets.join(", "));
+    }
+    if !result.pts_gaps.is_empty() {
+        let max_gap_ms = result
+            .pts_gaps
+            .iter()
+            .map(|gap| gap.gap_ms)
+            .fold(0.0, f64::max);
+        report!(
+            "pts gaps: {} max: {:.1} ms",
+            result.pts_gaps.len(),
+            max_gap_ms
+        );
+    }
+    if result.input_unfinalized {
+        report!("input unfinalized: true");
+    }
+    if result.reordered_frames > 0 {
+        report!(
+            "reordered frames: {} max displacement: {}",
+            result.reordered_frames,
+            result.max_reorder_displacement
+        );
+    }
+    if result.streams.len() > 1 {
+        for stream in result.streams.iter() {
+            report!(
+                "stream: {} output: {} id: {} agreement: {:.3} frames: {} failed: {} bytes: {}",
+                stream.input_stream_index,
+                stream.output_file,
+                stream.recognized_id.as_deref().unwrap_or("none"),
+                stream.id_agreement.unwrap_or(0.0),
+                stream.frames,
+                stream.failed_frames,
+                stream.output_bytes
+            );
+        }
+    }
+    if let Some(drift) = result.drift.as_ref() {
+        report!(
+            "capture clock drift: {:.1} ppm slope: {:.9} intercept: {:.3}s frames: {}",
+            drift.drift_ppm,
+            drift.slope,
+            drift.intercept,
+            drift.frames
+        );
+    }
+    if let Some(av_sync) = result.av_sync_ms_percentiles {
+        report!(
+            "audio markers: {} av sync (ms): p10: {:.1} p50: {:.1} p90: {:.1}",
+            result.audio_markers,
+            av_sync.p10,
+            av_sync.p50,
+            av_sync.p90
+        );
+    }
+    print_silence_intervals(result);
+}
+
+/// Prints the silent audio intervals.
+fn print_silence_intervals(result: &ProcessResult) {
+    for (start, end) in result.silence_intervals.iter() {
+        report!(
+            "silence: {:.3}s - {:.3}s duration: {:.3}s",
+            start,
+            end,
+            end - start
+        );
+    }
 }
+
 fn main() {
-    env_logger::init();
     let args = Args::parse();
+    #[cfg(feature = "tracing-json")]
+    if args.tracing {
+        // The `log` records of the other modules are forwarded as events.
+        tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .with_writer(std::io::stderr)
+            .init();
+    } else {
+        env_logger::init();
+    }
+    #[cfg(not(feature = "tracing-json"))]
+    env_logger::init();
+
+    if args.version_info {
+        println!("{}", version_info());
+        return;
+    }
+
+    let encoder_options = match (args.encoder_options, args.encoder_config.as_deref()) {
+        (Some(options), _) => Some(EncoderOptions::new(options)),
+        (None, Some(path)) => match TomlEncoderOpts::from_file(path) {
+            Ok(options) => Some(EncoderOptions::new(options)),
+            Err(e) => {
+                eprintln!("Error reading the encoder config: {}", e);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => None,
+    };
+
+    let config = Config {
+        deinterlace: args.deinterlace,
+        overlay: args.overlay,
+        timestamp_base: args.timestamp_base,
+        timestamp_decimals: args.timestamp_decimals,
+        timecode_fps: args.timecode_fps,
+        watermark_format: args.watermark_format,
+        watermark_opacity: Some(args.watermark_opacity),
+        simulate_packet_loss: args.simulate_packet_loss,
+        packet_loss_seed: args.packet_loss_seed,
+        noise: args.noise,
+        timestamp_carrier: args.timestamp_carrier,
+        ocr_lang: args.ocr_lang,
+        ocr_contrast: args.ocr_contrast,
+        ocr_region: args.ocr_region,
+        cache_dir: args.cache_dir,
+        ocr_whitelist: args.ocr_whitelist,
+        timestamp_patterns: args.timestamp_patterns,
+        ocr_psm: args.ocr_psm,
+        pts_rounding: args.pts_rounding,
+        on_blank: args.on_blank,
+        blank_max_luma: Some(args.blank_max_luma),
+        blank_max_variance: Some(args.blank_max_variance),
+        codec: args.codec,
+        crf: args.crf,
+        encoder_options,
+        color_space: args.color_space,
+        keyframes: args.keyframes,
+        gop: args.gop,
+        scene_threshold: args.scene_threshold,
+        deinterlace_field_rate: args.deinterlace_field_rate,
+        audio_sync: args.audio_sync,
+        audio_fallback: args.audio_fallback,
+        detect_silence: args.detect_silence,
+        silence_threshold_db: Some(args.silence_threshold),
+        audio_bitrate: Some(args.audio_bitrate),
+        correct_drift: args.correct_drift,
+        lookahead_frames: args.lookahead_frames,
+        id_lock_frames: args.id_lock_frames,
+        id_change_frames: args.id_change_frames,
+        reorder_window: args.reorder_window,
+        trim_to_recognized: args.trim_to_recognized,
+        max_failed_ratio: args.max_failed_ratio,
+        strip_watermark: args.strip_watermark,
+        normalize_sar: args.normalize_sar,
+        ignore_timestamp_metadata: false,
+        frames_csv: args.frames_csv,
+        subtitle_report: args.subtitle_report,
+        pts_csv: args.pts_csv,
+        drift_series: args.drift_series,
+        on_frame: None,
+        pts_remapper: None,
+        max_inflight_frames: args.max_inflight_frames,
+        low_memory: args.low_memory,
+        metrics: args.metrics,
+        metrics_chroma: args.metrics_chroma,
+        unmatched_frames: args.unmatched_frames,
+        align_by: args.align_by,
+        interval_bucket_ms: Some(args.interval_bucket_ms),
+        target_fps: args.target_fps,
+        target_frames: args.target_frames,
+        target_duration: args.target_duration,
+        sample_keyframes: args.sample_keyframes,
+        padding: args.padding,
+        watermark_ranges: args.watermark_ranges,
+        movflags: args.movflags,
+        input_fps: args.input_fps,
+        fps: args.fps,
+        output: args.output,
+        output_pattern: args.output_pattern,
+        force_format: args.force_format,
+        best_stream_only: args.best_stream_only,
+        process_all_streams: args.ignore_stream_mapping,
+        wait_for_stable: args.wait_for_stable,
+        input_options: args.input_options,
+        decoder_options: args.decoder_options,
+        force_output_format: args.force_output_format,
+        max_output_size: args.max_output_size,
+        abort_on_max_output_size: args.abort_on_max_output_size,
+        extra_outputs: args.extra_outputs,
+        pace_to_realtime: args.pace_realtime,
+    };
+    OUTPUT_TO_STDOUT.store(
+        config
+            .output
+            .as_deref()
+            .is_some_and(|output| output == "-" || output.starts_with("pipe:")),
+        Ordering::SeqCst,
+    );
+
+    if let Some(input_file) = args.metadata_only.as_ref() {
+        match metadata_info(input_file, &config) {
+            Ok(info) => println!("{}", info),
+            Err(e) => {
+                eprintln!("Error reading metadata: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(input_file) = args.detect_id.as_ref() {
+        match detect_id(input_file, &config) {
+            Ok(detected) => println!("{}", serde_json::to_string(&detected).unwrap()),
+            Err(e) => {
+                eprintln!("Error detecting the id: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(iterations) = args.benchmark_ocr {
+        for input_file in args.process.iter() {
+            match benchmark_ocr(input_file, &config, iterations as usize) {
+                Ok(benchmark) => println!("{}", serde_json::to_string(&benchmark).unwrap()),
+                Err(e) => {
+                    eprintln!("Error benchmarking the OCR of {}: {}", input_file, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        return;
+    }
+
+    if let (false, Some(output_pattern)) = (args.extract_at.is_empty(), args.extract_out.as_ref()) {
+        if args.process.len() > 1 && !output_pattern.contains("{stem}") {
+            eprintln!("--extract-out requires the {{stem}} placeholder with multiple files");
+            std::process::exit(1);
+        }
+        for input_file in args.process.iter() {
+            let stem = std::path::Path::new(input_file)
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy();
+            let output_pattern = output_pattern.replace("{stem}", &stem);
+            match extract_frames(input_file, &args.extract_at, &output_pattern, &config) {
+                Ok(extracted) => {
+                    for frame in extracted.iter() {
+                        println!("{}", serde_json::to_string(frame).unwrap());
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error extracting the frames of {}: {}", input_file, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        return;
+    }
+
+    if args.dry_run {
+        let input_files: Vec<&str> = args.process.iter().map(|s| s.as_str()).collect();
+        let checks = if !args.watermark.is_empty() {
+            validate_watermark_video(&args.watermark, &args.watermark_id, &config)
+        } else if !input_files.is_empty() {
+            validate_process_video(&input_files, &config)
+        } else {
+            eprintln!("No action specified");
+            std::process::exit(1);
+        };
+        for check in checks.iter() {
+            println!("{}", check);
+        }
+        if !checks.iter().all(|check| check.passed()) {
+            std::process::exit(1);
+        }
+        return;
+    }
 
     let (sender, receiver) = crossbeam_channel::unbounded();
 
     ctrlc::set_handler(move || {
-        sender.send("stop").expect("Error sending signal");
+        STOPPED.store(true, Ordering::SeqCst);
+        // The receivers are gone once the processing is done: the repeated
+        // signals are ignored.
+        let _ = sender.send("stop");
     })
     .expect("Error setting Ctrl-C handler");
 
-    if !args.watermark.is_empty() {
-        println!("watermark video: {}", args.watermark);
-        if let Err(e) = watermark_video(&args.watermark, &args.watermark_id, receiver) {
-            eprintln!("Error watermarking video: {}", e);
+    if let [reference_file, degraded_file] = &args.compare[..] {
+        report!(
+            "compare video: {} reference: {}",
+            degraded_file,
+            reference_file
+        );
+        let result = match compare_videos(reference_file, degraded_file, &config, receiver) {
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("Error comparing videos: {}", e);
+                std::process::exit(exit_code(e.as_ref()));
+            }
+        };
+        report!(
+            "reference frames: {} matched: {} unmatched: {} extra: {}",
+            result.reference_frames,
+            result.matched_frames,
+            result.unmatched_frames,
+            result.extra_frames
+        );
+        for (name, mean) in [("psnr", result.psnr_mean), ("ssim", result.ssim_mean)] {
+            if let Some(mean) = mean {
+                report!("{} mean: {:?}", name, mean);
+            }
+        }
+        let report_file = args
+            .metrics_report
+            .clone()
+            .unwrap_or_else(|| format!("{}.metrics.json", degraded_file));
+        let written = std::fs::File::create(&report_file)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                serde_json::to_writer_pretty(file, &result).map_err(|e| e.to_string())
+            });
+        if let Err(e) = written {
+            eprintln!("Error writing the metrics report {}: {}", report_file, e);
+            std::process::exit(1);
+        }
+        report!("metrics report: {}", report_file);
+        if let Some(metrics_csv) = args.metrics_csv.as_ref() {
+            if let Err(e) = result.write_csv(metrics_csv) {
+                eprintln!("Error writing the metrics CSV {}: {}", metrics_csv, e);
+                std::process::exit(1);
+            }
+            report!("metrics csv: {}", metrics_csv);
+        }
+        return;
+    }
+
+    if let [reference_file, degraded_file] = &args.pair[..] {
+        report!(
+            "pair video: {} reference: {}",
+            degraded_file,
+            reference_file
+        );
+        let result = match process_pair(reference_file, degraded_file, &config, receiver) {
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("Error pairing videos: {}", e);
+                std::process::exit(exit_code(e.as_ref()));
+            }
+        };
+        report!(
+            "reference frames: {} matched: {} unmatched: {} missing: {}",
+            result.reference_frames,
+            result.matched_frames,
+            result.unmatched_frames,
+            result.missing_frames
+        );
+        if let (Some(mean), Some(delta)) = (result.delta_ms_mean, result.delta_ms.as_ref()) {
+            report!(
+                "latency (ms): mean: {:.1} p10: {:.1} p50: {:.1} p90: {:.1}",
+                mean,
+                delta.p10,
+                delta.p50,
+                delta.p90
+            );
+        }
+        let report_file = args
+            .pair_report
+            .clone()
+            .unwrap_or_else(|| format!("{}.pair.json", degraded_file));
+        let written = std::fs::File::create(&report_file)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                serde_json::to_writer_pretty(file, &result).map_err(|e| e.to_string())
+            });
+        if let Err(e) = written {
+            eprintln!("Error writing the pair report {}: {}", report_file, e);
+            std::process::exit(1);
+        }
+        report!("pair report: {}", report_file);
+        if let Some(pair_csv) = args.pair_csv.as_ref() {
+            if let Err(e) = result.write_csv(pair_csv) {
+                eprintln!("Error writing the pair CSV {}: {}", pair_csv, e);
+                std::process::exit(1);
+            }
+            report!("pair csv: {}", pair_csv);
+        }
+        return;
+    }
+
+    if let Some(listen_addr) = args.listen {
+        report!("watermark real time: {}", listen_addr);
+        let (width, height) = args.realtime_size;
+        match watermark_realtime(
+            listen_addr,
+            width,
+            height,
+            args.realtime_fps,
+            &args.watermark_id,
+            &config,
+            receiver,
+        ) {
+            Ok(result) => report!("frames: {}", result.frames),
+            Err(e) => {
+                eprintln!("Error watermarking real time video: {}", e);
+                std::process::exit(exit_code(e.as_ref()));
+            }
+        }
+    } else if !args.watermark.is_empty() {
+        report!("watermark video: {}", args.watermark);
+        match watermark_video(
+            &args.watermark,
+            &args.watermark_id,
+            &config,
+            receiver.clone(),
+        ) {
+            Ok(result) => {
+                report!(
+                    "output: {} frames: {} deinterlaced: {} audio markers: {}",
+                    result.output_file,
+                    result.frames,
+                    result.deinterlaced,
+                    result.audio_markers
+                );
+                report!(
+                    "timestamp carrier frames: visual: {} metadata: {}",
+                    result.visual_frames,
+                    result.metadata_frames
+                );
+                print_output_size(&result);
+                print_silence_intervals(&result);
+                if result.simulated_lost_frames > 0 {
+                    report!("simulated lost frames: {}", result.simulated_lost_frames);
+                }
+                for extra_output_file in result.extra_output_files.iter() {
+                    report!("extra output: {}", extra_output_file);
+                }
+                if args.self_check {
+                    self_check(
+                        &result.output_file,
+                        &config,
+                        receiver,
+                        args.self_check_min_rate,
+                    );
+                }
+            }
+            Err(e) => {
+                eprintln!("Error watermarking video: {}", e);
+                std::process::exit(exit_code(e.as_ref()));
+            }
+        }
+    } else if !args.process.is_empty() && args.batch {
+        let mut exit = 0;
+        let state_file = args
+            .state_file
+            .clone()
+            .unwrap_or_else(|| default_batch_state_file(&args.process));
+        let options = format!("{:?}", config);
+        let mut state = if args.no_resume {
+            BatchState {
+                options,
+                ..Default::default()
+            }
+        } else {
+            match BatchState::load(&state_file, &options) {
+                Ok(state) => state,
+                Err(e) => {
+                    eprintln!("Error loading the batch state {}: {}", state_file, e);
+                    std::process::exit(1);
+                }
+            }
+        };
+        for input_file in args.process.iter() {
+            if STOPPED.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Some(entry) = state.completed(input_file) {
+                report!(
+                    "skip video: {} completed: {}",
+                    input_file,
+                    entry.output_file.as_deref().unwrap_or_default()
+                );
+                continue;
+            }
+            if args.skip_existing {
+                let outputs = existing_process_outputs(input_file, &config);
+                if !outputs.is_empty() {
+                    report!(
+                        "skip video: {} existing: {}",
+                        input_file,
+                        outputs.join(", ")
+                    );
+                    continue;
+                }
+            }
+            report!("process video: {}", input_file);
+            match process_video(&[input_file.as_str()], &config, receiver.clone()) {
+                Ok(result) => {
+                    print_process_result(&result);
+                    state.record_completed(input_file, &result);
+                }
+                Err(e) => {
+                    eprintln!("Error processing video {}: {}", input_file, e);
+                    exit = exit_code(e.as_ref());
+                    state.record_failed(input_file, &e.to_string());
+                }
+            }
+            if let Err(e) = state.save(&state_file) {
+                eprintln!("Error saving the batch state {}: {}", state_file, e);
+            }
+        }
+        if exit != 0 {
+            std::process::exit(exit);
         }
     } else if !args.process.is_empty() {
-        println!("process video: {}", args.process);
-        if let Err(e) = process_video(&args.process, receiver) {
-            eprintln!("Error processing video: {}", e);
+        report!("process video: {}", args.process.join(", "));
+        let input_files: Vec<&str> = args.process.iter().map(|s| s.as_str()).collect();
+        match process_video(&input_files, &config, receiver) {
+            Ok(result) => {
+                print_process_result(&result);
+            }
+            Err(e) => {
+                eprintln!("Error processing video: {}", e);
+                std::process::exit(exit_code(e.as_ref()));
+            }
         }
     } else {
         eprintln!("No action specified");
@@ -0,0 +1,84 @@
+//! Lightweight introspection of the container and stream metadata.
+
+use crate::config::Config;
+use crate::open_input;
+use ffmpeg::{media, DictionaryRef};
+
+/// Escapes a string as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Formats the metadata key-value pairs as a JSON object.
+fn json_dictionary(dictionary: &DictionaryRef) -> String {
+    let pairs: Vec<String> = dictionary
+        .iter()
+        .map(|(key, value)| format!("{}: {}", json_string(key), json_string(value)))
+        .collect();
+    format!("{{{}}}", pairs.join(", "))
+}
+
+/// Returns the container metadata and the per stream info (codec,
+/// resolution, frame count, time base) of a file as JSON, without decoding it.
+pub fn metadata_info(
+    input_file: &str,
+    config: &Config,
+) -> Result<String, Box<dyn std::error::Error>> {
+    ffmpeg::init()?;
+    let ictx = open_input(input_file, config)?;
+
+    let streams: Vec<String> = ictx
+        .streams()
+        .map(|stream| {
+            let parameters = stream.parameters();
+            let medium = parameters.medium();
+            let mut fields = vec![
+                format!("\"index\": {}", stream.index()),
+                format!(
+                    "\"type\": {}",
+                    json_string(&format!("{:?}", medium).to_lowercase())
+                ),
+                format!("\"codec\": {}", json_string(parameters.id().name())),
+            ];
+            if medium == media::Type::Video {
+                let (width, height) =
+                    unsafe { ((*parameters.as_ptr()).width, (*parameters.as_ptr()).height) };
+                fields.push(format!("\"width\": {}", width));
+                fields.push(format!("\"height\": {}", height));
+            }
+            fields.push(format!("\"frames\": {}", stream.frames()));
+            fields.push(format!(
+                "\"time_base\": \"{}/{}\"",
+                stream.time_base().numerator(),
+                stream.time_base().denominator()
+            ));
+            fields.push(format!(
+                "\"metadata\": {}",
+                json_dictionary(&stream.metadata())
+            ));
+            format!("{{{}}}", fields.join(", "))
+        })
+        .collect();
+
+    Ok(format!(
+        "{{\"file\": {}, \"format\": {}, \"metadata\": {}, \"streams\": [{}]}}",
+        json_string(input_file),
+        json_string(ictx.format().name()),
+        json_dictionary(&ictx.metadata()),
+        streams.join(", ")
+    ))
+}
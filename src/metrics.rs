@@ -0,0 +1,641 @@
+//! PSNR and SSIM between the frames of a reference and a degraded video,
+//! matched by timestamp. Both videos are expected to be process mode outputs,
+//! whose pts are the recognized watermark timestamps, unless they are aligned
+//! with `AlignBy::RecognizedTimestamp`.
+
+use crate::config::{AlignBy, Config, Metric, UnmatchedFrames};
+use crate::transcoder::open_video_decoder;
+use crate::{open_input, process_video};
+use crossbeam_channel::Receiver;
+use ffmpeg::{decoder, format, frame, media, software, Rational};
+use log::debug;
+use serde::Serialize;
+use std::io::Write;
+
+/// The PSNR (dB) reported for identical planes.
+pub const MAX_PSNR: f64 = 100.0;
+
+/// The maximum time difference (seconds) between matched frames. The
+/// recognized timestamps have a millisecond resolution.
+const MATCH_TOLERANCE: f64 = 0.002;
+
+/// The SSIM stabilization constants, `(0.01 * 255)^2` and `(0.03 * 255)^2`.
+const SSIM_C1: f64 = 6.5025;
+const SSIM_C2: f64 = 58.5225;
+
+/// The number of pixels summed at once in the squared error computation.
+/// 64 squared 8-bit differences fit in a `u32`.
+const CHUNK_SIZE: usize = 64;
+
+/// A metric value for the luma plane and, optionally, the chroma planes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct PlaneValues {
+    pub y: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub u: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<f64>,
+}
+
+/// The metrics of a reference frame.
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameMetrics {
+    /// The reference frame time (seconds).
+    pub time: f64,
+    /// False if no degraded frame has the reference frame timestamp.
+    pub matched: bool,
+    /// The PSNR (dB). Unset for the excluded unmatched frames.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub psnr: Option<PlaneValues>,
+    /// The SSIM (0-1). Unset for the excluded unmatched frames.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssim: Option<PlaneValues>,
+}
+
+/// The per frame and pooled metrics of a degraded video.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MetricsResult {
+    pub reference_file: String,
+    pub degraded_file: String,
+    /// The number of decoded reference frames.
+    pub reference_frames: usize,
+    /// The number of reference frames matched by a degraded frame.
+    pub matched_frames: usize,
+    /// The number of reference frames without a degraded frame (e.g. frames
+    /// dropped by the receiver).
+    pub unmatched_frames: usize,
+    /// The number of degraded frames without a reference frame.
+    pub extra_frames: usize,
+    /// The mean PSNR (dB) of the matched frames and, when reported as worst
+    /// case, of the unmatched ones.
+    pub psnr_mean: Option<PlaneValues>,
+    /// The mean SSIM of the matched frames and, when reported as worst case,
+    /// of the unmatched ones.
+    pub ssim_mean: Option<PlaneValues>,
+    pub frames: Vec<FrameMetrics>,
+}
+
+/// A plane of a decoded frame.
+pub struct Plane<'a> {
+    data: &'a [u8],
+    stride: usize,
+    width: usize,
+    height: usize,
+}
+
+impl<'a> Plane<'a> {
+    pub fn new(data: &'a [u8], stride: usize, width: usize, height: usize) -> Self {
+        assert!(width <= stride && data.len() >= stride * height.saturating_sub(1) + width);
+        Self {
+            data,
+            stride,
+            width,
+            height,
+        }
+    }
+
+    fn of(frame: &'a frame::Video, index: usize) -> Self {
+        Self::new(
+            frame.data(index),
+            frame.stride(index),
+            frame.plane_width(index) as usize,
+            frame.plane_height(index) as usize,
+        )
+    }
+
+    fn row(&self, y: usize) -> &'a [u8] {
+        &self.data[y * self.stride..y * self.stride + self.width]
+    }
+}
+
+/// Returns the PSNR (dB) between two planes of the same size.
+pub fn psnr(a: &Plane, b: &Plane) -> f64 {
+    let mut sse = 0u64;
+    for y in 0..a.height {
+        // Sum fixed size chunks, so that the inner loop is vectorized.
+        for (ca, cb) in a.row(y).chunks(CHUNK_SIZE).zip(b.row(y).chunks(CHUNK_SIZE)) {
+            let chunk: u32 = ca
+                .iter()
+                .zip(cb)
+                .map(|(x, y)| {
+                    let d = *x as i32 - *y as i32;
+                    (d * d) as u32
+                })
+                .sum();
+            sse += chunk as u64;
+        }
+    }
+    if sse == 0 {
+        return MAX_PSNR;
+    }
+    let mse = sse as f64 / (a.width * a.height) as f64;
+    (10.0 * (255.0 * 255.0 / mse).log10()).min(MAX_PSNR)
+}
+
+/// Returns the mean SSIM between two planes of the same size, over 8x8
+/// windows with a 4 pixels step. The windows are combined from the sums of
+/// the 4x4 blocks, each one computed once.
+pub fn ssim(a: &Plane, b: &Plane) -> f64 {
+    let blocks_width = a.width / 4;
+    let blocks_height = a.height / 4;
+    if blocks_width < 2 || blocks_height < 2 {
+        // A single window over the whole plane.
+        let mut sums = [0u64; 5];
+        for y in 0..a.height {
+            add_sums(&mut sums, a.row(y), b.row(y));
+        }
+        return window_ssim(&sums, (a.width * a.height).max(1) as f64);
+    }
+    let mut previous = block_row_sums(a, b, 0, blocks_width);
+    let mut total = 0.0;
+    for by in 1..blocks_height {
+        let current = block_row_sums(a, b, by, blocks_width);
+        for bx in 0..blocks_width - 1 {
+            let mut sums = [0u64; 5];
+            for block in [previous[bx], previous[bx + 1], current[bx], current[bx + 1]] {
+                for (sum, value) in sums.iter_mut().zip(block) {
+                    *sum += value;
+                }
+            }
+            total += window_ssim(&sums, 64.0);
+        }
+        previous = current;
+    }
+    total / ((blocks_width - 1) * (blocks_height - 1)) as f64
+}
+
+/// Adds the sums of `a`, `b`, `a^2`, `b^2` and `a * b` over two rows.
+fn add_sums(sums: &mut [u64; 5], a: &[u8], b: &[u8]) {
+    for (x, y) in a.iter().zip(b) {
+        let (x, y) = (*x as u64, *y as u64);
+        sums[0] += x;
+        sums[1] += y;
+        sums[2] += x * x;
+        sums[3] += y * y;
+        sums[4] += x * y;
+    }
+}
+
+/// Returns the sums (see `add_sums`) of each 4x4 block of the block row `by`.
+fn block_row_sums(a: &Plane, b: &Plane, by: usize, blocks_width: usize) -> Vec<[u64; 5]> {
+    let mut sums = vec![[0u64; 5]; blocks_width];
+    for y in by * 4..by * 4 + 4 {
+        let a_row = &a.row(y)[..blocks_width * 4];
+        let b_row = &b.row(y)[..blocks_width * 4];
+        for (block, (ca, cb)) in sums
+            .iter_mut()
+            .zip(a_row.chunks_exact(4).zip(b_row.chunks_exact(4)))
+        {
+            add_sums(block, ca, cb);
+        }
+    }
+    sums
+}
+
+/// Returns the SSIM of a window of `n` pixels from its sums.
+fn window_ssim(sums: &[u64; 5], n: f64) -> f64 {
+    let (mean_a, mean_b) = (sums[0] as f64 / n, sums[1] as f64 / n);
+    let variance_a = sums[2] as f64 / n - mean_a * mean_a;
+    let variance_b = sums[3] as f64 / n - mean_b * mean_b;
+    let covariance = sums[4] as f64 / n - mean_a * mean_b;
+    ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+        / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1) * (variance_a + variance_b + SSIM_C2))
+}
+
+/// Computes a metric between two `yuv420p` frames of the same size.
+fn plane_values(metric: Metric, a: &frame::Video, b: &frame::Video, chroma: bool) -> PlaneValues {
+    let value = |index: usize| {
+        let (a, b) = (Plane::of(a, index), Plane::of(b, index));
+        match metric {
+            Metric::Psnr => psnr(&a, &b),
+            Metric::Ssim => ssim(&a, &b),
+        }
+    };
+    PlaneValues {
+        y: value(0),
+        u: chroma.then(|| value(1)),
+        v: chroma.then(|| value(2)),
+    }
+}
+
+/// The sums of the pooled metric values.
+#[derive(Default)]
+struct PooledValues {
+    sum: PlaneValues,
+    count: usize,
+}
+
+impl PooledValues {
+    fn add(&mut self, values: Option<PlaneValues>) {
+        if let Some(values) = values {
+            self.sum.y += values.y;
+            self.sum.u = values.u.map(|u| self.sum.u.unwrap_or(0.0) + u);
+            self.sum.v = values.v.map(|v| self.sum.v.unwrap_or(0.0) + v);
+            self.count += 1;
+        }
+    }
+
+    fn mean(&self) -> Option<PlaneValues> {
+        if self.count == 0 {
+            return None;
+        }
+        let n = self.count as f64;
+        Some(PlaneValues {
+            y: self.sum.y / n,
+            u: self.sum.u.map(|u| u / n),
+            v: self.sum.v.map(|v| v / n),
+        })
+    }
+}
+
+/// Decodes the best video stream of a file, converting the frames to
+/// `yuv420p`.
+struct FrameReader {
+    ictx: format::context::Input,
+    decoder: decoder::Video,
+    stream_index: usize,
+    time_base: Rational,
+    scaling_ctx: Option<software::scaling::Context>,
+    eof: bool,
+}
+
+impl FrameReader {
+    fn open(input_file: &str, config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let ictx = open_input(input_file, config)?;
+        let stream = ictx
+            .streams()
+            .best(media::Type::Video)
+            .ok_or_else(|| format!("no video stream in {}", input_file))?;
+        let stream_index = stream.index();
+        let time_base = stream.time_base();
+        let decoder = open_video_decoder(stream.parameters(), config.decoder_options.as_deref())?;
+        Ok(Self {
+            ictx,
+            decoder,
+            stream_index,
+            time_base,
+            scaling_ctx: None,
+            eof: false,
+        })
+    }
+
+    /// Returns the next frame time (seconds) and the frame, scaled to `size`
+    /// when set.
+    fn next(
+        &mut self,
+        size: Option<(u32, u32)>,
+    ) -> Result<Option<(f64, frame::Video)>, ffmpeg::Error> {
+        let mut decoded = frame::Video::empty();
+        loop {
+            if self.decoder.receive_frame(&mut decoded).is_ok() {
+                let time = decoded.timestamp().or(decoded.pts()).unwrap_or(0) as f64
+                    * f64::from(self.time_base);
+                let size = size.unwrap_or((decoded.width(), decoded.height()));
+                return Ok(Some((time, self.convert(decoded, size)?)));
+            }
+            if self.eof {
+                return Ok(None);
+            }
+            let packet = self
+                .ictx
+                .packets()
+                .next()
+                .map(|(stream, packet)| (stream.index(), packet));
+            match packet {
+                Some((index, packet)) if index == self.stream_index => {
+                    self.decoder.send_packet(&packet)?;
+                }
+                Some(_) => {}
+                None => {
+                    self.decoder.send_eof()?;
+                    self.eof = true;
+                }
+            }
+        }
+    }
+
+    fn convert(
+        &mut self,
+        frame: frame::Video,
+        (width, height): (u32, u32),
+    ) -> Result<frame::Video, ffmpeg::Error> {
+        if frame.format() == format::Pixel::YUV420P
+            && frame.width() == width
+            && frame.height() == height
+        {
+            return Ok(frame);
+        }
+        // Reuse the scaling context unless the frame format or resolution changes.
+        if self.scaling_ctx.as_ref().map_or(true, |ctx| {
+            let input = ctx.input();
+            input.format != frame.format()
+                || input.width != frame.width()
+                || input.height != frame.height()
+        }) {
+            debug!(
+                "Converting the frames from {:?} {}x{} to yuv420p {}x{}",
+                frame.format(),
+                frame.width(),
+                frame.height(),
+                width,
+                height
+            );
+            self.scaling_ctx = Some(software::scaling::Context::get(
+                frame.format(),
+                frame.width(),
+                frame.height(),
+                format::Pixel::YUV420P,
+                width,
+                height,
+                software::scaling::Flags::BICUBIC,
+            )?);
+        }
+        let mut converted = frame::Video::empty();
+        self.scaling_ctx
+            .as_mut()
+            .unwrap()
+            .run(&frame, &mut converted)?;
+        Ok(converted)
+    }
+}
+
+impl MetricsResult {
+    /// Writes a CSV row with the metrics of each reference frame to `path`.
+    pub fn write_csv(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut csv = std::io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(
+            csv,
+            "time,matched,psnr_y,psnr_u,psnr_v,ssim_y,ssim_u,ssim_v"
+        )?;
+        let value = |value: Option<f64>| value.map_or(String::new(), |v| format!("{:.6}", v));
+        let plane_values = |values: Option<PlaneValues>| {
+            format!(
+                "{},{},{}",
+                value(values.map(|v| v.y)),
+                value(values.and_then(|v| v.u)),
+                value(values.and_then(|v| v.v))
+            )
+        };
+        for frame in self.frames.iter() {
+            writeln!(
+                csv,
+                "{:.6},{},{},{}",
+                frame.time,
+                frame.matched,
+                plane_values(frame.psnr),
+                plane_values(frame.ssim)
+            )?;
+        }
+        csv.flush()?;
+        Ok(())
+    }
+}
+
+/// Processes `input_file` into a temporary file whose pts are the recognized
+/// timestamps, returning its path.
+fn process_aligned(
+    input_file: &str,
+    name: &str,
+    config: &Config,
+    receiver: Receiver<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let output = std::env::temp_dir().join(format!(
+        "webrtcperf-compare-{}-{}.ivf",
+        std::process::id(),
+        name
+    ));
+    let process_config = Config {
+        output: Some(output.to_string_lossy().to_string()),
+        frames_csv: None,
+        subtitle_report: None,
+        pts_csv: None,
+        drift_series: None,
+        on_frame: None,
+        pts_remapper: None,
+        extra_outputs: Vec::new(),
+        target_fps: None,
+        ..config.clone()
+    };
+    let result = process_video(&[input_file], &process_config, receiver)?;
+    Ok(result.output_file)
+}
+
+/// Computes the `config.metrics` of each reference frame against the degraded
+/// frame with the same timestamp. The degraded frames are scaled to the
+/// reference size when needed. With `AlignBy::RecognizedTimestamp` both
+/// videos are processed first, matching the frames by the recognized
+/// timestamps.
+pub fn compare_videos(
+    reference_file: &str,
+    degraded_file: &str,
+    config: &Config,
+    receiver: Receiver<&str>,
+) -> Result<MetricsResult, Box<dyn std::error::Error>> {
+    if config.metrics.is_empty() {
+        return Err("no metrics specified".into());
+    }
+    ffmpeg::init()?;
+    let mut result = match config.align_by {
+        AlignBy::Pts => compare_frames(reference_file, degraded_file, config, receiver)?,
+        AlignBy::RecognizedTimestamp => {
+            let mut aligned_files = Vec::new();
+            let mut result = Ok(());
+            for (file, name) in [(reference_file, "reference"), (degraded_file, "degraded")] {
+                match process_aligned(file, name, config, receiver.clone()) {
+                    Ok(aligned_file) => aligned_files.push(aligned_file),
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            let result = result.and_then(|_| {
+                compare_frames(&aligned_files[0], &aligned_files[1], config, receiver)
+            });
+            for aligned_file in aligned_files.iter() {
+                if let Err(e) = std::fs::remove_file(aligned_file) {
+                    debug!("Failed to remove {}: {}", aligned_file, e);
+                }
+            }
+            result?
+        }
+    };
+    result.reference_file = reference_file.to_string();
+    result.degraded_file = degraded_file.to_string();
+    Ok(result)
+}
+
+fn compare_frames(
+    reference_file: &str,
+    degraded_file: &str,
+    config: &Config,
+    receiver: Receiver<&str>,
+) -> Result<MetricsResult, Box<dyn std::error::Error>> {
+    let mut reference = FrameReader::open(reference_file, config)?;
+    let mut degraded = FrameReader::open(degraded_file, config)?;
+    let mut result = MetricsResult {
+        reference_file: reference_file.to_string(),
+        degraded_file: degraded_file.to_string(),
+        ..Default::default()
+    };
+    let with_metric = |metric: Metric| config.metrics.contains(&metric);
+    let worst = |value: f64| PlaneValues {
+        y: value,
+        u: config.metrics_chroma.then_some(value),
+        v: config.metrics_chroma.then_some(value),
+    };
+    let mut psnr_pooled = PooledValues::default();
+    let mut ssim_pooled = PooledValues::default();
+
+    let mut stopped = false;
+    let mut reference_frame = reference.next(None)?;
+    let size = reference_frame
+        .as_ref()
+        .map(|(_, frame)| (frame.width(), frame.height()));
+    let mut degraded_frame = degraded.next(size)?;
+    while let Some((time, frame)) = reference_frame.as_ref() {
+        let time = *time;
+        let frame_metrics = match degraded_frame.as_ref() {
+            Some((degraded_time, _)) if *degraded_time < time - MATCH_TOLERANCE => {
+                result.extra_frames += 1;
+                degraded_frame = degraded.next(size)?;
+                continue;
+            }
+            Some((degraded_time, degraded_video))
+                if (*degraded_time - time).abs() <= MATCH_TOLERANCE =>
+            {
+                let metric_values = |metric: Metric| {
+                    with_metric(metric)
+                        .then(|| plane_values(metric, frame, degraded_video, config.metrics_chroma))
+                };
+                let frame_metrics = FrameMetrics {
+                    time,
+                    matched: true,
+                    psnr: metric_values(Metric::Psnr),
+                    ssim: metric_values(Metric::Ssim),
+                };
+                result.matched_frames += 1;
+                degraded_frame = degraded.next(size)?;
+                frame_metrics
+            }
+            _ => {
+                debug!("Reference frame at {:.3}s unmatched", time);
+                result.unmatched_frames += 1;
+                let worst_values = |metric: Metric, value: f64| {
+                    (with_metric(metric) && config.unmatched_frames == UnmatchedFrames::Worst)
+                        .then(|| worst(value))
+                };
+                FrameMetrics {
+                    time,
+                    matched: false,
+                    psnr: worst_values(Metric::Psnr, 0.0),
+                    ssim: worst_values(Metric::Ssim, 0.0),
+                }
+            }
+        };
+        psnr_pooled.add(frame_metrics.psnr);
+        ssim_pooled.add(frame_metrics.ssim);
+        result.frames.push(frame_metrics);
+        result.reference_frames += 1;
+
+        if let Ok("stop") = receiver.try_recv() {
+            debug!("compare_videos stop received");
+            stopped = true;
+            break;
+        }
+        reference_frame = reference.next(size)?;
+    }
+    while !stopped && degraded_frame.is_some() {
+        result.extra_frames += 1;
+        degraded_frame = degraded.next(size)?;
+    }
+
+    result.psnr_mean = psnr_pooled.mean();
+    result.ssim_mean = ssim_pooled.mean();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 67;
+    const HEIGHT: usize = 35;
+    const STRIDE: usize = 80;
+
+    fn test_plane(value: impl Fn(usize, usize) -> u8) -> Vec<u8> {
+        let mut data = vec![0u8; STRIDE * HEIGHT];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                data[y * STRIDE + x] = value(x, y);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn psnr_of_known_error() {
+        let a = test_plane(|x, y| ((x * 7 + y * 3) % 200) as u8);
+        let plane = Plane::new(&a, STRIDE, WIDTH, HEIGHT);
+        assert_eq!(psnr(&plane, &plane), MAX_PSNR);
+
+        // A constant error of 5 gives an MSE of 25, ignoring the row padding.
+        let mut b = test_plane(|x, y| ((x * 7 + y * 3) % 200) as u8 + 5);
+        b[STRIDE - 1] = 255;
+        let expected = 10.0 * (255.0f64 * 255.0 / 25.0).log10();
+        let value = psnr(&plane, &Plane::new(&b, STRIDE, WIDTH, HEIGHT));
+        assert!((value - expected).abs() < 1e-9, "psnr: {}", value);
+    }
+
+    #[test]
+    fn ssim_decreases_with_the_distortion() {
+        let a = test_plane(|x, y| ((x * 7 + y * 3) % 200) as u8);
+        let plane = Plane::new(&a, STRIDE, WIDTH, HEIGHT);
+        assert!((ssim(&plane, &plane) - 1.0).abs() < 1e-9);
+
+        let noisy = |amplitude: usize| {
+            test_plane(|x, y| ((x * 7 + y * 3) % 200 + (x * 31 + y * 17) % amplitude) as u8)
+        };
+        let (b, c) = (noisy(8), noisy(40));
+        let low = ssim(&plane, &Plane::new(&b, STRIDE, WIDTH, HEIGHT));
+        let high = ssim(&plane, &Plane::new(&c, STRIDE, WIDTH, HEIGHT));
+        assert!(low < 1.0 && high < low, "ssim: {} {}", low, high);
+
+        // Planes smaller than a window are compared as a whole.
+        let small = Plane::new(&a, STRIDE, 6, 6);
+        assert!((ssim(&small, &small) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn csv_rows_leave_the_missing_values_empty() {
+        let result = MetricsResult {
+            frames: vec![
+                FrameMetrics {
+                    time: 0.5,
+                    matched: true,
+                    psnr: Some(PlaneValues {
+                        y: 40.0,
+                        u: None,
+                        v: None,
+                    }),
+                    ssim: None,
+                },
+                FrameMetrics {
+                    time: 0.6,
+                    matched: false,
+                    psnr: None,
+                    ssim: None,
+                },
+            ],
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join(format!("metrics-{}.csv", std::process::id()));
+        result.write_csv(path.to_str().unwrap()).unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            csv.lines().skip(1).collect::<Vec<_>>(),
+            ["0.500000,true,40.000000,,,,,", "0.600000,false,,,,,,"]
+        );
+    }
+}
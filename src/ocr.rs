@@ -0,0 +1,225 @@
+//! Text overlay recognition with Tesseract. Without the `ocr` feature
+//! Tesseract isn't linked at all and the text overlay can't be recognized,
+//! while the watermark mode and the bars overlay keep working.
+
+use crate::config::{Config, OcrPageSegMode};
+#[cfg(feature = "ocr")]
+use crate::transcoder::{cache_dir, write_cache_file};
+use image::DynamicImage;
+use log::debug;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+#[cfg(feature = "ocr")]
+use tesseract_rs::{TessPageSegMode, TesseractAPI};
+
+/// The characters recognized by default.
+pub const DEFAULT_WHITELIST: &str = "0123456789-.:";
+
+/// An initialized Tesseract instance.
+pub struct Ocr {
+    #[cfg(feature = "ocr")]
+    api: TesseractAPI,
+    #[cfg(feature = "ocr")]
+    contrast: Option<f32>,
+    #[cfg(not(feature = "ocr"))]
+    _never: std::convert::Infallible,
+}
+
+/// The options an `Ocr` instance is initialized with.
+#[derive(Debug, Clone, PartialEq)]
+struct OcrOptions {
+    lang: Option<String>,
+    whitelist: String,
+    psm: OcrPageSegMode,
+    contrast: Option<f32>,
+    cache_dir: Option<String>,
+}
+
+impl OcrOptions {
+    fn new(config: &Config) -> Self {
+        Self {
+            lang: config.ocr_lang.clone(),
+            whitelist: ocr_whitelist(config).to_string(),
+            psm: config.ocr_psm,
+            contrast: config.ocr_contrast,
+            cache_dir: config.cache_dir.clone(),
+        }
+    }
+}
+
+/// The idle Tesseract instances, returned by the dropped `PooledOcr`s. The
+/// files processed one after the other (e.g. in batch mode) reuse the same
+/// instance instead of loading the trained data again.
+static POOL: Mutex<Vec<(OcrOptions, Ocr)>> = Mutex::new(Vec::new());
+
+/// An `Ocr` instance taken from the pool, returned to it when dropped.
+pub struct PooledOcr {
+    options: OcrOptions,
+    ocr: Option<Ocr>,
+}
+
+impl PooledOcr {
+    /// Takes an idle instance initialized with the same options from the
+    /// pool, or initializes a new one.
+    pub fn acquire(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let options = OcrOptions::new(config);
+        let pooled = {
+            let mut pool = POOL.lock().unwrap();
+            pool.iter()
+                .position(|(o, _)| *o == options)
+                .map(|index| pool.swap_remove(index).1)
+        };
+        let ocr = match pooled {
+            Some(ocr) => {
+                debug!("Reusing the pooled Tesseract instance");
+                // The id locking may have restricted the whitelist.
+                ocr.set_whitelist(&options.whitelist);
+                ocr
+            }
+            None => Ocr::new(config)?,
+        };
+        Ok(Self {
+            options,
+            ocr: Some(ocr),
+        })
+    }
+}
+
+impl Deref for PooledOcr {
+    type Target = Ocr;
+
+    fn deref(&self) -> &Ocr {
+        self.ocr.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledOcr {
+    fn deref_mut(&mut self) -> &mut Ocr {
+        self.ocr.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledOcr {
+    fn drop(&mut self) {
+        if let Some(ocr) = self.ocr.take() {
+            POOL.lock().unwrap().push((self.options.clone(), ocr));
+        }
+    }
+}
+
+/// Returns the characters recognized by Tesseract.
+pub fn ocr_whitelist(config: &Config) -> &str {
+    config.ocr_whitelist.as_deref().unwrap_or(DEFAULT_WHITELIST)
+}
+
+#[cfg(feature = "ocr")]
+fn page_seg_mode(mode: OcrPageSegMode) -> TessPageSegMode {
+    match mode {
+        OcrPageSegMode::SingleLine => TessPageSegMode::PSM_SINGLE_LINE,
+        OcrPageSegMode::SingleWord => TessPageSegMode::PSM_SINGLE_WORD,
+        OcrPageSegMode::SingleBlock => TessPageSegMode::PSM_SINGLE_BLOCK,
+        OcrPageSegMode::SparseText => TessPageSegMode::PSM_SPARSE_TEXT,
+        OcrPageSegMode::RawLine => TessPageSegMode::PSM_RAW_LINE,
+        OcrPageSegMode::Auto => TessPageSegMode::PSM_AUTO,
+    }
+}
+
+#[cfg(feature = "ocr")]
+impl Ocr {
+    /// Initializes Tesseract for recognizing the timestamp overlay, downloading
+    /// the trained data if missing. This is the only place touching the
+    /// Tesseract cache directory.
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        use flate2::read::GzDecoder;
+
+        let lang = config.ocr_lang.as_deref().unwrap_or("eng");
+        debug!("Initializing Tesseract (lang: {})", lang);
+        let tesseract_dir = cache_dir(config.cache_dir.as_deref())?;
+        for lang in lang.split('+') {
+            if lang.is_empty() || !lang.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("invalid Tesseract language: {}", lang).into());
+            }
+            let tesseract_path = format!("{}/{}.traineddata", tesseract_dir, lang);
+            if !std::path::Path::new(&tesseract_path).exists() {
+                // Download the file from the URL
+                debug!("Downloading Tesseract {} data file", lang);
+                let response = reqwest::blocking::get(format!(
+                    "https://cdn.jsdelivr.net/npm/@tesseract.js-data/{lang}/4.0.0/{lang}.traineddata.gz",
+                    lang = lang
+                ))?
+                .error_for_status()?;
+                let mut decoder = GzDecoder::new(response);
+                // Concurrent runs sharing the cache never see a truncated file.
+                write_cache_file(&tesseract_path, |file| {
+                    std::io::copy(&mut decoder, file)?;
+                    Ok(())
+                })?;
+            }
+        }
+        let api = TesseractAPI::new();
+        api.init(tesseract_dir, lang)
+            .map_err(|e| format!("failed to initialize Tesseract language {}: {}", lang, e))?;
+        api.set_variable("tessedit_char_whitelist", ocr_whitelist(config))?;
+        api.set_page_seg_mode(page_seg_mode(config.ocr_psm))?;
+        Ok(Self {
+            api,
+            contrast: config.ocr_contrast,
+        })
+    }
+
+    /// Sets the characters recognized.
+    pub fn set_whitelist(&self, chars: &str) {
+        self.api
+            .set_variable("tessedit_char_whitelist", chars)
+            .unwrap();
+    }
+
+    /// Runs the OCR on an image, returning the recognized text.
+    pub fn recognize(&mut self, image: &DynamicImage) -> Option<String> {
+        let adjusted;
+        let image = match self.contrast {
+            Some(contrast) => {
+                adjusted = image.adjust_contrast(contrast);
+                &adjusted
+            }
+            None => image,
+        };
+        self.api
+            .set_image(
+                &image.to_rgb8(),
+                image.width() as i32,
+                image.height() as i32,
+                3i32,
+                3i32 * image.width() as i32,
+            )
+            .unwrap();
+        Some(self.api.get_utf8_text().unwrap())
+    }
+}
+
+#[cfg(not(feature = "ocr"))]
+impl Ocr {
+    pub fn new(_config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        Err("built without the ocr feature, the text overlay can't be recognized".into())
+    }
+
+    pub fn set_whitelist(&self, _chars: &str) {
+        match self._never {}
+    }
+
+    pub fn recognize(&mut self, _image: &DynamicImage) -> Option<String> {
+        match self._never {}
+    }
+}
+
+/// Returns the linked Tesseract version.
+pub fn version() -> String {
+    #[cfg(feature = "ocr")]
+    {
+        TesseractAPI::version()
+    }
+    #[cfg(not(feature = "ocr"))]
+    {
+        "not available (built without the ocr feature)".to_string()
+    }
+}
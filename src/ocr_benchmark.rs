@@ -0,0 +1,133 @@
+//! Timing of the Tesseract initialization and recognition on the watermark
+//! band of a single frame, recognized repeatedly with the process mode
+//! parameters.
+
+use crate::config::{Config, Overlay};
+use crate::detect_id::{next_frame, rgb_image};
+use crate::ocr::Ocr;
+use crate::open_input;
+use crate::transcoder::{
+    band_height, clip_region, open_video_decoder, parse_timestamp_text_any, timestamp_regexes,
+};
+use ffmpeg::{media, software};
+use log::debug;
+use serde::Serialize;
+use std::time::Instant;
+
+/// The maximum number of frames decoded looking for a watermarked frame.
+const MAX_SEARCH_FRAMES: usize = 300;
+
+/// The result of `benchmark_ocr`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OcrBenchmark {
+    /// The index of the benchmarked frame, the first one with a recognized
+    /// watermark.
+    pub frame_index: usize,
+    /// The text recognized in the benchmarked frame.
+    pub text: String,
+    /// The Tesseract initialization time (ms).
+    pub init_ms: f64,
+    /// The number of recognitions.
+    pub iterations: usize,
+    /// The mean recognition time (ms).
+    pub mean_ms: f64,
+    /// The recognition time standard deviation (ms).
+    pub stddev_ms: f64,
+}
+
+/// Returns the mean and the standard deviation of `values`.
+fn mean_stddev(values: &[f64]) -> (f64, f64) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+    (mean, variance.sqrt())
+}
+
+/// Decodes the frames of `input_file` until the watermark of one is
+/// recognized, then runs the OCR on its band `iterations` times, with the
+/// same Tesseract options and `ocr_region` crop of the process mode.
+pub fn benchmark_ocr(
+    input_file: &str,
+    config: &Config,
+    iterations: usize,
+) -> Result<OcrBenchmark, Box<dyn std::error::Error>> {
+    if config.overlay != Overlay::Text {
+        return Err("the OCR benchmark requires the text overlay".into());
+    }
+    ffmpeg::init()?;
+    let start = Instant::now();
+    // Not taken from the pool, initializing a new instance.
+    let mut ocr = Ocr::new(config)?;
+    let init_ms = start.elapsed().as_secs_f64() * 1000.0;
+    debug!("benchmark_ocr: Tesseract initialized in {:.1} ms", init_ms);
+
+    let regexes = timestamp_regexes(config)?;
+    let mut ictx = open_input(input_file, config)?;
+    let stream = ictx
+        .streams()
+        .best(media::Type::Video)
+        .ok_or_else(|| format!("no video stream in {}", input_file))?;
+    let stream_index = stream.index();
+    let mut decoder = open_video_decoder(stream.parameters(), config.decoder_options.as_deref())?;
+    let mut scaling_ctx: Option<software::scaling::Context> = None;
+
+    for frame_index in 0..MAX_SEARCH_FRAMES {
+        let frame = match next_frame(&mut ictx, &mut decoder, stream_index, usize::MAX) {
+            Some(frame) => frame,
+            None => break,
+        };
+        let image = match rgb_image(&mut scaling_ctx, &frame) {
+            Some(image) => image,
+            None => continue,
+        };
+        let band = (0, 0, image.width(), band_height(image.height()));
+        let (x, y, width, height) = config
+            .ocr_region
+            .and_then(|region| clip_region(region, band.2, band.3))
+            .unwrap_or(band);
+        let image = image.crop_imm(x, y, width, height);
+        let text = match ocr.recognize(&image) {
+            Some(text) => text.trim().to_string(),
+            None => continue,
+        };
+        if parse_timestamp_text_any(&regexes, &text, config.timecode_fps).is_none() {
+            continue;
+        }
+        debug!("benchmark_ocr: frame {} recognized: {}", frame_index, text);
+        let mut times_ms = Vec::with_capacity(iterations);
+        for _ in 0..iterations {
+            let start = Instant::now();
+            ocr.recognize(&image);
+            times_ms.push(start.elapsed().as_secs_f64() * 1000.0);
+        }
+        let (mean_ms, stddev_ms) = mean_stddev(&times_ms);
+        return Ok(OcrBenchmark {
+            frame_index,
+            text,
+            init_ms,
+            iterations,
+            mean_ms,
+            stddev_ms,
+        });
+    }
+    Err(format!(
+        "no watermark recognized in the first {} frames of {}",
+        MAX_SEARCH_FRAMES, input_file
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognition_times_mean_and_stddev() {
+        assert_eq!(mean_stddev(&[]), (0.0, 0.0));
+        let (mean, stddev) = mean_stddev(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]);
+        assert_eq!(mean, 5.0);
+        assert_eq!(stddev, 2.0);
+    }
+}
@@ -0,0 +1,140 @@
+//! Accounting of the encoded output size, warning (or failing) early when the
+//! output would exceed `Config::max_output_size` or fill the filesystem.
+
+use crate::config::Config;
+use crate::error::ProcessError;
+use log::{debug, warn};
+
+/// The encoded duration (seconds) the final output size is extrapolated from.
+pub(crate) const PROJECTION_SECONDS: f64 = 10.0;
+
+/// Returns the final output size (bytes), extrapolating the `bytes` encoded
+/// over `encoded_span` seconds to `input_duration` seconds. `None` before
+/// `PROJECTION_SECONDS` are encoded or when the input duration is unknown.
+pub(crate) fn projected_size(bytes: u64, encoded_span: f64, input_duration: f64) -> Option<u64> {
+    if encoded_span < PROJECTION_SECONDS || input_duration <= 0.0 {
+        return None;
+    }
+    Some((bytes as f64 * input_duration.max(encoded_span) / encoded_span) as u64)
+}
+
+/// Returns the space (bytes) available to unprivileged users on the
+/// filesystem containing `output_file`.
+#[cfg(unix)]
+fn free_space(output_file: &str) -> Option<u64> {
+    let dir = match std::path::Path::new(output_file).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let path = std::ffi::CString::new(dir.to_str()?).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_output_file: &str) -> Option<u64> {
+    None
+}
+
+/// Checks the size of an output file while it's written.
+pub(crate) struct OutputSizeMonitor {
+    output_file: String,
+    max_output_size: Option<u64>,
+    abort: bool,
+    free_space: Option<u64>,
+    warned: bool,
+}
+
+impl OutputSizeMonitor {
+    pub(crate) fn new(output_file: &str, config: &Config) -> Self {
+        let free_space = free_space(output_file);
+        debug!(
+            "OutputSizeMonitor {} free space: {:?}",
+            output_file, free_space
+        );
+        Self {
+            output_file: output_file.to_string(),
+            max_output_size: config.max_output_size,
+            abort: config.abort_on_max_output_size,
+            free_space,
+            warned: false,
+        }
+    }
+
+    /// Checks the `written` bytes and the `projected` final size, warning
+    /// once when the projection exceeds the maximum size or the free space.
+    /// With `Config::abort_on_max_output_size` it fails instead when the
+    /// maximum size would be exceeded.
+    pub(crate) fn check(
+        &mut self,
+        written: u64,
+        projected: Option<u64>,
+    ) -> Result<(), ProcessError> {
+        let size = projected.unwrap_or(written).max(written);
+        if let Some(max_output_size) = self.max_output_size {
+            if size > max_output_size {
+                if self.abort {
+                    return Err(ProcessError::OutputTooLarge {
+                        output_file: self.output_file.clone(),
+                        size,
+                        max_output_size,
+                    });
+                }
+                if !self.warned {
+                    warn!(
+                        "{} is projected to {} bytes, over the maximum of {} bytes",
+                        self.output_file, size, max_output_size
+                    );
+                    self.warned = true;
+                }
+            }
+        }
+        if let Some(free_space) = self.free_space {
+            if size > free_space && !self.warned {
+                warn!(
+                    "{} is projected to {} bytes, over the {} bytes free on its filesystem",
+                    self.output_file, size, free_space
+                );
+                self.warned = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_projection_and_cap() {
+        assert_eq!(projected_size(1_000_000, 5.0, 100.0), None);
+        assert_eq!(projected_size(1_000_000, 10.0, 0.0), None);
+        assert_eq!(projected_size(1_000_000, 10.0, 100.0), Some(10_000_000));
+        // Inputs shorter than the encoded span (e.g. a wrong duration).
+        assert_eq!(projected_size(1_000_000, 20.0, 10.0), Some(1_000_000));
+
+        let mut monitor = OutputSizeMonitor {
+            output_file: "out.ivf".to_string(),
+            max_output_size: Some(5_000_000),
+            abort: false,
+            free_space: None,
+            warned: false,
+        };
+        assert!(monitor.check(1_000_000, None).is_ok());
+        assert!(monitor.check(1_000_000, Some(10_000_000)).is_ok());
+        assert!(monitor.warned);
+        monitor.abort = true;
+        assert!(matches!(
+            monitor.check(1_000_000, Some(10_000_000)),
+            Err(ProcessError::OutputTooLarge {
+                size: 10_000_000,
+                ..
+            })
+        ));
+        assert!(monitor.check(6_000_000, None).is_err());
+    }
+}
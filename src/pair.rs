@@ -0,0 +1,318 @@
+//! Latency measurement between the sender reference and the receiver capture
+//! of the same id, matching the receiver frames to the reference frames with
+//! the same watermark timestamp.
+
+use crate::config::{Config, TimestampBase};
+use crate::frame_hook::{FrameAction, FrameCallback};
+use crate::stats::Percentiles;
+use crate::transcoder::{encoder_time_base, packet_timestamp_metadata, watermark_timestamp_us};
+use crate::{open_input, process_video};
+use crossbeam_channel::Receiver;
+use ffmpeg::{media, Rational};
+use log::debug;
+use serde::Serialize;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The maximum difference (seconds) between the recognized timestamp of a
+/// receiver frame and the timestamp of the matched reference frame.
+const MATCH_TOLERANCE: f64 = 0.002;
+
+/// A receiver frame of `process_pair`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PairFrame {
+    /// The receiver frame time (seconds), from its pts.
+    pub receiver_time: f64,
+    /// The watermark timestamp (seconds) recognized in the receiver frame.
+    pub timestamp: Option<f64>,
+    /// The time (seconds) of the reference frame with the same timestamp.
+    pub sender_time: Option<f64>,
+    /// The receiver time minus the sender time (ms).
+    pub delta_ms: Option<f64>,
+    /// False if the recognition failed or no reference frame has the
+    /// recognized timestamp.
+    pub matched: bool,
+}
+
+/// The per frame records and the pooled latency of `process_pair`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PairResult {
+    pub reference_file: String,
+    pub degraded_file: String,
+    /// The number of reference frames.
+    pub reference_frames: usize,
+    /// The number of receiver frames matched to a reference frame.
+    pub matched_frames: usize,
+    /// The number of receiver frames without a reference frame.
+    pub unmatched_frames: usize,
+    /// The number of reference frames never seen at the receiver (drops).
+    pub missing_frames: usize,
+    /// The mean of the matched frames deltas (ms).
+    pub delta_ms_mean: Option<f64>,
+    /// The percentiles of the matched frames deltas (ms).
+    pub delta_ms: Option<Percentiles>,
+    pub frames: Vec<PairFrame>,
+}
+
+impl PairResult {
+    /// Writes a CSV row for each receiver frame to `path`.
+    pub fn write_csv(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut csv = std::io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(csv, "receiver_time,timestamp,sender_time,delta_ms,matched")?;
+        let value = |value: Option<f64>| value.map_or(String::new(), |v| format!("{:.6}", v));
+        for frame in self.frames.iter() {
+            writeln!(
+                csv,
+                "{:.6},{},{},{},{}",
+                frame.receiver_time,
+                value(frame.timestamp),
+                value(frame.sender_time),
+                value(frame.delta_ms),
+                frame.matched
+            )?;
+        }
+        csv.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the `(time, timestamp)` (seconds) of the reference frames from the
+/// packets, without decoding: the timestamp is the one of the packet
+/// metadata, or derived from the pts, as written by the watermark mode with
+/// `TimestampBase::Pts`.
+fn reference_timestamps(
+    reference_file: &str,
+    config: &Config,
+) -> Result<Vec<(f64, f64)>, Box<dyn std::error::Error>> {
+    let mut ictx = open_input(reference_file, config)?;
+    let stream = ictx
+        .streams()
+        .best(media::Type::Video)
+        .ok_or_else(|| format!("no video stream in {}", reference_file))?;
+    let stream_index = stream.index();
+    let time_base = stream.time_base();
+    let mut timestamps = Vec::new();
+    let mut metadata_frames = 0;
+    for (stream, packet) in ictx.packets() {
+        if stream.index() != stream_index {
+            continue;
+        }
+        let pts = match packet.pts() {
+            Some(pts) => pts,
+            None => continue,
+        };
+        let time = pts as f64 * f64::from(time_base);
+        let timestamp = match packet_timestamp_metadata(&packet) {
+            Some((_, timestamp)) => {
+                metadata_frames += 1;
+                timestamp
+            }
+            None => {
+                let us = watermark_timestamp_us(TimestampBase::Pts, pts, time_base, 0);
+                // The overlay shows milliseconds.
+                (us / 1000) as f64 / 1000.0
+            }
+        };
+        timestamps.push((time, timestamp));
+    }
+    if metadata_frames == 0 && config.timestamp_base != TimestampBase::Pts {
+        return Err(format!(
+            "{} has no timestamp metadata, its timestamps can be derived from the pts only with \
+             --timestamp-base pts",
+            reference_file
+        )
+        .into());
+    }
+    debug!(
+        "pair: {} reference frames, {} with metadata",
+        timestamps.len(),
+        metadata_frames
+    );
+    Ok(timestamps)
+}
+
+/// Returns the time (seconds) of a receiver frame `pts` in `time_base`.
+fn receiver_time(pts: Option<i64>, time_base: Rational) -> f64 {
+    pts.unwrap_or(0) as f64 * f64::from(time_base)
+}
+
+/// Matches the receiver frames, as `(time, recognized timestamp)` pairs, to
+/// the reference frames, as `(time, timestamp)` pairs (seconds).
+fn match_frames(reference: &[(f64, f64)], receiver: &[(f64, Option<f64>)]) -> PairResult {
+    let mut by_timestamp: Vec<(f64, f64)> = reference.to_vec();
+    by_timestamp.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let mut seen = vec![false; by_timestamp.len()];
+    let mut result = PairResult {
+        reference_frames: reference.len(),
+        ..Default::default()
+    };
+    let mut deltas = Vec::new();
+    for (receiver_time, timestamp) in receiver.iter() {
+        let matched = timestamp.and_then(|timestamp| {
+            let index = by_timestamp.partition_point(|(_, t)| *t < timestamp - MATCH_TOLERANCE);
+            // The nearer of the first two reference frames above the lower bound.
+            (index..by_timestamp.len().min(index + 2))
+                .min_by(|a, b| {
+                    let distance = |i: &usize| (by_timestamp[*i].1 - timestamp).abs();
+                    distance(a).total_cmp(&distance(b))
+                })
+                .filter(|i| (by_timestamp[*i].1 - timestamp).abs() <= MATCH_TOLERANCE)
+                .map(|i| (i, by_timestamp[i].0))
+        });
+        let frame = match matched {
+            Some((index, sender_time)) => {
+                seen[index] = true;
+                let delta_ms = (receiver_time - sender_time) * 1000.0;
+                deltas.push(delta_ms);
+                result.matched_frames += 1;
+                PairFrame {
+                    receiver_time: *receiver_time,
+                    timestamp: *timestamp,
+                    sender_time: Some(sender_time),
+                    delta_ms: Some(delta_ms),
+                    matched: true,
+                }
+            }
+            None => {
+                result.unmatched_frames += 1;
+                PairFrame {
+                    receiver_time: *receiver_time,
+                    timestamp: *timestamp,
+                    sender_time: None,
+                    delta_ms: None,
+                    matched: false,
+                }
+            }
+        };
+        result.frames.push(frame);
+    }
+    result.missing_frames = seen.iter().filter(|seen| !**seen).count();
+    if !deltas.is_empty() {
+        result.delta_ms_mean = Some(deltas.iter().sum::<f64>() / deltas.len() as f64);
+    }
+    result.delta_ms = Percentiles::from_values(&deltas);
+    result
+}
+
+/// Measures the latency between `reference`, the pristine watermark mode
+/// output of the sender, and `degraded`, the receiver capture of the same id.
+/// The reference timestamps are read from the packets (metadata or pts)
+/// without decoding, the receiver ones are recognized as in process mode; the
+/// receiver frames are then matched to the reference frames with the same
+/// timestamp.
+pub fn process_pair(
+    reference_file: &str,
+    degraded_file: &str,
+    config: &Config,
+    receiver: Receiver<&str>,
+) -> Result<PairResult, Box<dyn std::error::Error>> {
+    ffmpeg::init()?;
+    let reference = reference_timestamps(reference_file, config)?;
+
+    // The pts of the frames seen by the hook.
+    let time_base = {
+        let ictx = open_input(degraded_file, config)?;
+        let stream = ictx
+            .streams()
+            .best(media::Type::Video)
+            .ok_or_else(|| format!("no video stream in {}", degraded_file))?;
+        encoder_time_base(stream.time_base(), config)
+    };
+    let frames: Arc<Mutex<Vec<(f64, Option<f64>)>>> = Arc::new(Mutex::new(Vec::new()));
+    let on_frame = {
+        let frames = frames.clone();
+        FrameCallback::new(move |view| {
+            let time = receiver_time(view.pts, time_base);
+            frames.lock().unwrap().push((time, view.recognized_time));
+            FrameAction::Skip
+        })
+    };
+    // The receiver frames are skipped by the hook: the output, unique to the
+    // call for the output lock, is discarded by the null muxer.
+    static PAIR_OUTPUTS: AtomicUsize = AtomicUsize::new(0);
+    let output = std::env::temp_dir().join(format!(
+        "webrtcperf-pair-{}-{}.ivf",
+        std::process::id(),
+        PAIR_OUTPUTS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let process_config = Config {
+        output: Some(output.to_string_lossy().to_string()),
+        force_output_format: Some("null".to_string()),
+        frames_csv: None,
+        subtitle_report: None,
+        pts_csv: None,
+        drift_series: None,
+        on_frame: Some(on_frame),
+        pts_remapper: None,
+        extra_outputs: Vec::new(),
+        target_fps: None,
+        ..config.clone()
+    };
+    let processed = process_video(&[degraded_file], &process_config, receiver);
+    if let Err(e) = std::fs::remove_file(&output) {
+        debug!("Failed to remove {}: {}", output.display(), e);
+    }
+    processed?;
+
+    let frames = frames.lock().unwrap();
+    let mut result = match_frames(&reference, &frames);
+    result.reference_file = reference_file.to_string();
+    result.degraded_file = degraded_file.to_string();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Deinterlace;
+
+    #[test]
+    fn drops_and_unmatched_frames_are_counted() {
+        let reference: Vec<(f64, f64)> = (0..5)
+            .map(|i| (i as f64 / 10.0, 10.0 + i as f64 / 10.0))
+            .collect();
+        // The second reference frame is dropped, the third one repeated, the
+        // last receiver frame isn't recognized and one has a foreign timestamp.
+        let receiver = [
+            (1.05, Some(10.0)),
+            (1.25, Some(10.2)),
+            (1.3, Some(10.2005)),
+            (1.35, Some(10.3)),
+            (1.4, Some(99.0)),
+            (1.45, None),
+        ];
+        let result = match_frames(&reference, &receiver);
+        assert_eq!(result.reference_frames, 5);
+        assert_eq!(result.matched_frames, 4);
+        assert_eq!(result.unmatched_frames, 2);
+        // The frames with the timestamps 10.1 and 10.4.
+        assert_eq!(result.missing_frames, 2);
+        assert_eq!(result.frames[1].sender_time, Some(0.2));
+        let delta = result.frames[0].delta_ms.unwrap();
+        assert!((delta - 1050.0).abs() < 1e-6);
+        assert!(!result.frames[4].matched);
+    }
+
+    #[test]
+    fn receiver_time_with_field_rate_deinterlacing() {
+        let config = Config {
+            deinterlace: Deinterlace::On,
+            deinterlace_field_rate: true,
+            ..Default::default()
+        };
+        let time_base = encoder_time_base(Rational(1, 90000), &config);
+        assert_eq!(time_base, Rational(1, 180000));
+        assert_eq!(receiver_time(Some(180000), time_base), 1.0);
+        let time_base = encoder_time_base(Rational(1, 90000), &Config::default());
+        assert_eq!(receiver_time(Some(180000), time_base), 2.0);
+    }
+
+    #[test]
+    fn the_nearest_reference_frame_is_matched() {
+        let reference = [(0.0, 10.0), (0.1, 10.0015)];
+        let result = match_frames(&reference, &[(1.0, Some(10.0012))]);
+        assert_eq!(result.frames[0].sender_time, Some(0.1));
+        assert_eq!(result.missing_frames, 1);
+    }
+}
@@ -0,0 +1,274 @@
+//! Real time watermarking over TCP.
+//!
+//! The client sends raw `yuv420p` frames, each one prefixed by its length as a
+//! 4-byte big endian unsigned integer. The frames must have the configured
+//! size (the length must be `width * height * 3 / 2` rounding up the chroma
+//! planes size) and are timestamped at the configured frame rate. For each
+//! received frame the server replies with the VP8 encoded frame, using the same
+//! framing (4-byte big endian length + data). Closing the client write side
+//! flushes the encoder; the server then closes the connection and waits for
+//! the next client.
+
+use crate::bars;
+use crate::config::{Config, Keyframes, Overlay, TimestampBase};
+use crate::stats::ProcessResult;
+use crate::transcoder::{
+    band_height, bars_id, check_watermark_format, check_watermark_opacity, font_path,
+    make_writable, open_encoder, set_frame_metadata, timecode_text, timestamp_text,
+    watermark_filter_desc, watermark_timestamp_us, ColorProperties, Mode, VideoFilter, VideoParams,
+    TIMECODE_METADATA_KEY, TIMESTAMP_METADATA_KEY,
+};
+use chrono::Utc;
+use crossbeam_channel::Receiver;
+use ffmpeg::{codec, encoder, format, frame, Packet, Rational};
+use log::{debug, warn};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// The settings shared by all the client connections.
+struct Session {
+    params: VideoParams,
+    time_base: Rational,
+    filter_desc: String,
+    bars_id: Option<u32>,
+    pace_to_realtime: bool,
+    timestamp_base: TimestampBase,
+    timestamp_decimals: u32,
+    timecode_fps: f64,
+}
+
+/// Returns the `yuv420p` frame size in bytes.
+fn frame_size(frame: &frame::Video) -> usize {
+    (0..frame.planes())
+        .map(|plane| frame.plane_width(plane) as usize * frame.plane_height(plane) as usize)
+        .sum()
+}
+
+/// Copies the packed planes data into the frame, honoring the planes stride.
+fn fill_frame(frame: &mut frame::Video, data: &[u8]) {
+    let mut offset = 0;
+    for plane in 0..frame.planes() {
+        let width = frame.plane_width(plane) as usize;
+        let height = frame.plane_height(plane) as usize;
+        let stride = frame.stride(plane);
+        let plane_data = frame.data_mut(plane);
+        for y in 0..height {
+            plane_data[y * stride..y * stride + width]
+                .copy_from_slice(&data[offset..offset + width]);
+            offset += width;
+        }
+    }
+}
+
+fn write_packets(
+    encoder: &mut encoder::Video,
+    stream: &mut TcpStream,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut packet = Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        let data = packet.data().unwrap_or(&[]);
+        stream.write_all(&(data.len() as u32).to_be_bytes())?;
+        stream.write_all(data)?;
+    }
+    Ok(())
+}
+
+/// Watermarks the frames received from a single client, returning the number
+/// of processed frames.
+fn handle_client(
+    mut stream: TcpStream,
+    session: &Session,
+    receiver: &Receiver<&str>,
+    stopped: &mut bool,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let Session {
+        params,
+        time_base,
+        ref filter_desc,
+        bars_id,
+        pace_to_realtime,
+        timestamp_base,
+        timestamp_decimals,
+        timecode_fps,
+    } = *session;
+    let mut filter = VideoFilter::new(time_base, params, filter_desc.to_string())?;
+    let mut encoder = open_encoder(
+        params,
+        codec::Id::VP8,
+        time_base,
+        Some(time_base.invert()),
+        false,
+        Keyframes::All,
+        None,
+        None,
+        None,
+    )?;
+    let expected_size = frame_size(&frame::Video::new(
+        params.format,
+        params.width,
+        params.height,
+    ));
+    let mut data = vec![0u8; expected_size];
+    let mut frames = 0;
+    let start_time = Utc::now().timestamp_millis();
+
+    loop {
+        let mut length = [0u8; 4];
+        match stream.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let length = u32::from_be_bytes(length) as usize;
+        if length != expected_size {
+            return Err(format!(
+                "invalid frame length {}, expected {}",
+                length, expected_size
+            )
+            .into());
+        }
+        stream.read_exact(&mut data)?;
+        // The filter source takes the frame data ownership, so a new frame is
+        // needed each time.
+        let mut frame = frame::Video::new(params.format, params.width, params.height);
+        fill_frame(&mut frame, &data);
+        frame.set_pts(Some(frames as i64));
+
+        let time_ms =
+            frames as i64 * 1000 * time_base.numerator() as i64 / time_base.denominator() as i64;
+        let timestamp_us =
+            watermark_timestamp_us(timestamp_base, frames as i64, time_base, frames as u64);
+        let timestamp_ms = timestamp_us / 1000;
+        set_frame_metadata(
+            &mut frame,
+            TIMESTAMP_METADATA_KEY,
+            &timestamp_text(timestamp_us, timestamp_decimals),
+        );
+        set_frame_metadata(
+            &mut frame,
+            TIMECODE_METADATA_KEY,
+            &timecode_text(frames as u64, timecode_fps),
+        );
+        let mut watermarked = filter.apply(&frame)?;
+        if let Some(id) = bars_id {
+            make_writable(&mut watermarked);
+            bars::draw(
+                &mut watermarked,
+                band_height(params.height),
+                id,
+                timestamp_ms,
+            );
+        }
+        if pace_to_realtime {
+            // Sleep when the frame timestamp is ahead of the wall clock.
+            let ahead = start_time + time_ms - Utc::now().timestamp_millis();
+            if ahead > 0 {
+                std::thread::sleep(Duration::from_millis(ahead as u64));
+            }
+        }
+        encoder.send_frame(&watermarked)?;
+        write_packets(&mut encoder, &mut stream)?;
+        frames += 1;
+
+        if let Ok("stop") = receiver.try_recv() {
+            debug!("realtime stop received");
+            *stopped = true;
+            break;
+        }
+    }
+
+    encoder.send_eof()?;
+    write_packets(&mut encoder, &mut stream)?;
+    Ok(frames)
+}
+
+/// Runs the real time watermarking server until a stop message is received.
+/// Clients are served one at a time.
+pub fn serve(
+    mode: &Mode,
+    width: u32,
+    height: u32,
+    fps: u32,
+    watermark_id: &str,
+    config: &Config,
+    receiver: Receiver<&str>,
+) -> Result<ProcessResult, Box<dyn std::error::Error>> {
+    let listen_addr = match mode {
+        Mode::WatermarkRealtime { listen_addr } => *listen_addr,
+        _ => return Err(format!("unsupported mode {:?}", mode).into()),
+    };
+    ffmpeg::init()?;
+
+    let params = VideoParams {
+        width,
+        height,
+        format: format::Pixel::YUV420P,
+        aspect_ratio: Rational(1, 1),
+        color: ColorProperties::default(),
+    };
+    let time_base = Rational(1, fps as i32);
+    let (font_path, bars_id) = match config.overlay {
+        Overlay::Text => (Some(font_path(config.cache_dir.as_deref())?), None),
+        Overlay::Bars => (None, Some(bars_id(Some(watermark_id))?)),
+    };
+    if let Some(format) = config.watermark_format.as_deref() {
+        check_watermark_format(format)?;
+    }
+    check_watermark_opacity(config.watermark_opacity, config.overlay)?;
+    let session = Session {
+        params,
+        time_base,
+        filter_desc: watermark_filter_desc(
+            height,
+            Some(watermark_id),
+            font_path.as_deref(),
+            config.watermark_format.as_deref(),
+            config.watermark_opacity,
+        ),
+        bars_id,
+        pace_to_realtime: config.pace_to_realtime,
+        timestamp_base: config.timestamp_base,
+        timestamp_decimals: config.timestamp_decimals,
+        timecode_fps: config.timecode_fps.unwrap_or(fps as f64),
+    };
+    if !config.watermark_ranges.is_empty() {
+        warn!("Watermark ranges are not supported in real time mode");
+    }
+
+    let listener = TcpListener::bind(listen_addr)?;
+    // Poll the listener for checking the stop messages.
+    listener.set_nonblocking(true)?;
+    debug!("realtime listening on {}", listen_addr);
+
+    let mut result = ProcessResult {
+        output_file: listen_addr.to_string(),
+        ..Default::default()
+    };
+    let mut stopped = false;
+    while !stopped {
+        if let Ok("stop") = receiver.try_recv() {
+            debug!("realtime stop received");
+            break;
+        }
+        let (stream, addr) = match listener.accept() {
+            Ok(client) => client,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        debug!("realtime client connected: {}", addr);
+        stream.set_nonblocking(false)?;
+        match handle_client(stream, &session, &receiver, &mut stopped) {
+            Ok(frames) => {
+                debug!("realtime client {} done, frames: {}", addr, frames);
+                result.frames += frames;
+            }
+            Err(e) => warn!("realtime client {} error: {}", addr, e),
+        }
+    }
+
+    Ok(result)
+}
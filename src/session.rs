@@ -0,0 +1,61 @@
+use crate::config::Config;
+use crate::stats::ProcessResult;
+use crossbeam_channel::Sender;
+use log::debug;
+use std::thread::JoinHandle;
+
+/// Watermarks a live capture (e.g. a WebRTC call recording) on a background
+/// thread, from when a test harness starts it until it's stopped.
+pub struct RecordingSession {
+    sender: Sender<&'static str>,
+    handle: Option<JoinHandle<Result<ProcessResult, String>>>,
+    result: Option<Result<ProcessResult, String>>,
+}
+
+impl RecordingSession {
+    /// Starts watermarking `input_file` with `watermark_id`, see `watermark_video`.
+    pub fn start(input_file: &str, watermark_id: &str, config: &Config) -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let input_file = input_file.to_string();
+        let watermark_id = watermark_id.to_string();
+        let config = config.clone();
+        let handle = std::thread::spawn(move || {
+            crate::watermark_video(&input_file, &watermark_id, &config, receiver)
+                .map_err(|e| e.to_string())
+        });
+        Self {
+            sender,
+            handle: Some(handle),
+            result: None,
+        }
+    }
+
+    /// Sends the stop signal and waits for the watermarking thread, which
+    /// writes the output trailer before exiting.
+    pub fn stop(&mut self) {
+        let handle = match self.handle.take() {
+            Some(handle) => handle,
+            None => return,
+        };
+        // The thread may have already exited at the end of the input.
+        if self.sender.send("stop").is_err() {
+            debug!("RecordingSession already finished");
+        }
+        self.result = Some(match handle.join() {
+            Ok(result) => result,
+            Err(_) => Err("the watermarking thread panicked".to_string()),
+        });
+    }
+
+    /// Stops the session, if still running, and returns the watermarking result.
+    pub fn result(mut self) -> Result<ProcessResult, Box<dyn std::error::Error>> {
+        self.stop();
+        Ok(self.result.take().unwrap()?)
+    }
+}
+
+impl Drop for RecordingSession {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,271 @@
+/// Statistics collected for a single input segment.
+#[derive(Debug, Clone, Default)]
+pub struct SegmentStats {
+    /// The segment input file.
+    pub input_file: String,
+    /// The number of decoded frames.
+    pub frames: usize,
+    /// The number of frames where the timestamp overlay was not recognized.
+    pub failed_frames: usize,
+    /// The offset (in output time base units) added to the recognized pts.
+    pub pts_offset: i64,
+}
+
+/// Statistics collected for a single video stream.
+#[derive(Debug, Clone, Default)]
+pub struct StreamStats {
+    /// The stream index in the (first) input file.
+    pub input_stream_index: usize,
+    /// The output file containing the stream.
+    pub output_file: String,
+    /// The id recognized from the stream timestamp overlay.
+    pub recognized_id: Option<String>,
+    /// The fraction of the recognized frames agreeing on `recognized_id`.
+    pub id_agreement: Option<f64>,
+    /// The number of decoded frames.
+    pub frames: usize,
+    /// The number of frames where the timestamp overlay was not recognized.
+    pub failed_frames: usize,
+    /// The size (bytes) of the stream encoded packets.
+    pub output_bytes: u64,
+}
+
+/// A gap between consecutive output frames, longer than twice the nominal
+/// frame interval (e.g. the frames lost by the network).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PtsGap {
+    /// The index (from 0) of the output frame after the gap, before the
+    /// `target_fps` resampling.
+    pub frame_index: usize,
+    /// The time between the frame and the previous one (ms).
+    pub gap_ms: f64,
+}
+
+/// A change of the recognized overlay id within a recording.
+#[derive(Debug, Clone, Default)]
+pub struct IdTransition {
+    /// The id recognized before the change.
+    pub from_id: String,
+    /// The id recognized after the change.
+    pub to_id: String,
+    /// The input time (seconds) of the first frame with the new id.
+    pub time: f64,
+}
+
+/// The linear fit of the recognized times to the input (capture) times of the
+/// recognized frames, measuring the capture clock drift.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DriftStats {
+    /// The recognized seconds per input second.
+    pub slope: f64,
+    /// The recognized time (seconds) at the input time zero.
+    pub intercept: f64,
+    /// The input clock drift (ppm) relative to the recognized timestamps,
+    /// positive when the capture clock runs fast.
+    pub drift_ppm: f64,
+    /// The number of recognized frames used for the fit, after discarding the
+    /// outliers (e.g. freezes).
+    pub frames: usize,
+}
+
+/// Percentiles of a set of values.
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct Percentiles {
+    pub p10: f64,
+    pub p50: f64,
+    pub p90: f64,
+}
+
+impl Percentiles {
+    /// Computes the percentiles using the nearest rank method. Returns `None`
+    /// if `values` is empty.
+    pub fn from_values(values: &[f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let rank =
+            |p: f64| sorted[((p * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len()) - 1];
+        Some(Self {
+            p10: rank(0.1),
+            p50: rank(0.5),
+            p90: rank(0.9),
+        })
+    }
+}
+
+/// Statistics of the intervals between consecutive recognized frames.
+#[derive(Debug, Clone, Default)]
+pub struct IntervalStats {
+    /// The number of intervals.
+    pub count: usize,
+    /// The mean interval (ms).
+    pub mean_ms: f64,
+    /// The interval standard deviation (ms).
+    pub stddev_ms: f64,
+    /// The interval percentiles (ms).
+    pub percentiles: Percentiles,
+    /// The nominal interval (ms), from the input frame rate or, when unknown,
+    /// the median interval.
+    pub nominal_ms: f64,
+    /// The number of intervals longer than twice the nominal interval.
+    pub over_2x: usize,
+    /// The number of intervals longer than four times the nominal interval.
+    pub over_4x: usize,
+    /// The histogram bucket width (ms).
+    pub bucket_ms: f64,
+    /// The number of intervals in each bucket: the i-th bucket counts the
+    /// intervals in `[i * bucket_ms, (i + 1) * bucket_ms)`.
+    pub histogram: Vec<usize>,
+}
+
+impl IntervalStats {
+    /// Computes the statistics of the intervals (ms). Returns `None` if
+    /// `intervals_ms` is empty.
+    pub fn from_intervals(
+        intervals_ms: &[f64],
+        nominal_ms: Option<f64>,
+        bucket_ms: f64,
+    ) -> Option<Self> {
+        let percentiles = Percentiles::from_values(intervals_ms)?;
+        let count = intervals_ms.len();
+        let mean_ms = intervals_ms.iter().sum::<f64>() / count as f64;
+        let variance = intervals_ms
+            .iter()
+            .map(|i| (i - mean_ms).powi(2))
+            .sum::<f64>()
+            / count as f64;
+        let nominal_ms = nominal_ms.unwrap_or(percentiles.p50);
+        let bucket_ms = if bucket_ms > 0.0 { bucket_ms } else { 1.0 };
+        let mut histogram = Vec::new();
+        for interval in intervals_ms.iter() {
+            let bucket = (interval.max(0.0) / bucket_ms) as usize;
+            if bucket >= histogram.len() {
+                histogram.resize(bucket + 1, 0);
+            }
+            histogram[bucket] += 1;
+        }
+        Some(Self {
+            count,
+            mean_ms,
+            stddev_ms: variance.sqrt(),
+            percentiles,
+            nominal_ms,
+            over_2x: intervals_ms
+                .iter()
+                .filter(|i| **i > 2.0 * nominal_ms)
+                .count(),
+            over_4x: intervals_ms
+                .iter()
+                .filter(|i| **i > 4.0 * nominal_ms)
+                .count(),
+            bucket_ms,
+            histogram,
+        })
+    }
+}
+
+/// Statistics collected while processing a video.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessResult {
+    /// The output file path.
+    pub output_file: String,
+    /// The id recognized in most of the frames from the timestamp overlay.
+    pub recognized_id: Option<String>,
+    /// The fraction of the recognized frames agreeing on `recognized_id`.
+    pub id_agreement: Option<f64>,
+    /// The extra output file paths.
+    pub extra_output_files: Vec<String>,
+    /// The total number of decoded frames.
+    pub frames: usize,
+    /// The total number of frames where the timestamp overlay was not recognized.
+    pub failed_frames: usize,
+    /// The number of frames whose timestamp was carried by the packet
+    /// metadata (written in watermark mode, read in process mode).
+    pub metadata_frames: usize,
+    /// The number of frames whose timestamp was carried by the visual overlay
+    /// (drawn in watermark mode, recognized in process mode).
+    pub visual_frames: usize,
+    /// The number of frames trimmed before the first recognized frame.
+    pub trimmed_head_frames: usize,
+    /// The number of frames trimmed after the last recognized frame.
+    pub trimmed_tail_frames: usize,
+    /// The number of frames whose overlay wasn't recognized, timestamped from
+    /// the nearest audio marker (see `Config::audio_fallback`).
+    pub audio_frames: usize,
+    /// The number of frames recognized only after retrying the RGB conversion
+    /// with the sharper Lanczos scaler.
+    pub hq_rescued_frames: usize,
+    /// The number of blank frames (e.g. muted track), detected before the
+    /// recognition and not counted as failed.
+    pub blank_frames: usize,
+    /// The number of frames written to the output.
+    pub output_frames: usize,
+    /// The size (bytes) of the output file, `None` when written to a pipe.
+    pub output_size: Option<u64>,
+    /// The average bitrate (bits/s) of the encoded video.
+    pub output_bitrate: Option<f64>,
+    /// The average number of encoded bits per pixel.
+    pub output_bits_per_pixel: Option<f64>,
+    /// The number of frames added at the end of the output to reach the
+    /// target frame count or duration.
+    pub padded_frames: usize,
+    /// The number of frames dropped past the target frame count or duration.
+    pub truncated_frames: usize,
+    /// The watermarked frames dropped by `Config::simulate_packet_loss`.
+    pub simulated_lost_frames: usize,
+    /// The number of failed frames whose timestamp was recovered from the nearby
+    /// recognized frames.
+    pub interpolated_frames: usize,
+    /// The number of frames recognized with a timestamp lower than the one of
+    /// a previously decoded frame.
+    pub reordered_frames: usize,
+    /// The maximum number of previously decoded frames with a greater
+    /// timestamp than a reordered frame.
+    pub max_reorder_displacement: usize,
+    /// The maximum number of decoded frames held at once by the lookahead and
+    /// reorder buffers.
+    pub inflight_frames_high_water: usize,
+    /// The number of times the locked overlay id region stopped matching,
+    /// signaling a possible content switch.
+    pub id_switches: usize,
+    /// The number of timestamp reads with fewer time digits than the recent
+    /// ones, discarded as truncated by an occlusion of the watermark band.
+    pub truncated_reads: usize,
+    /// The number of frames matched by each of `Config::timestamp_patterns`
+    /// (or by the default pattern), in order.
+    pub pattern_matches: Vec<usize>,
+    /// The confirmed changes of the recognized id, see `Config::id_change_frames`.
+    pub id_transitions: Vec<IdTransition>,
+    /// True if the frames were deinterlaced before the watermark or recognition stage.
+    pub deinterlaced: bool,
+    /// The number of audio sync markers written (watermark mode) or detected
+    /// (process mode).
+    pub audio_markers: usize,
+    /// The audio/video sync offset (ms) measured at each audio marker.
+    /// Positive values mean that the audio is late.
+    pub av_sync_ms: Vec<f64>,
+    /// The audio/video sync offset percentiles.
+    pub av_sync_ms_percentiles: Option<Percentiles>,
+    /// The `(start, end)` input times (seconds) of the silent audio intervals,
+    /// see `Config::detect_silence`. They may match the video freezes of a
+    /// packet loss burst.
+    pub silence_intervals: Vec<(f64, f64)>,
+    /// The capture clock drift, when the recognized frames span enough time.
+    pub drift: Option<DriftStats>,
+    /// The statistics of the intervals between consecutive recognized frames
+    /// (failed and interpolated frames excluded).
+    pub frame_intervals: Option<IntervalStats>,
+    /// The gaps between consecutive output frames (process mode), see `PtsGap`.
+    pub pts_gaps: Vec<PtsGap>,
+    /// True if an input container has no duration, typical of an unfinalized
+    /// WebM (e.g. a MediaRecorder capture still being written): the frames
+    /// decoded until the EOF are processed anyway.
+    pub input_unfinalized: bool,
+    /// The per input segment statistics.
+    pub segments: Vec<SegmentStats>,
+    /// The per video stream statistics. When processing inputs with multiple
+    /// video streams, each stream is written to a separate output file.
+    pub streams: Vec<StreamStats>,
+}
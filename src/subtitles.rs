@@ -0,0 +1,136 @@
+use std::io::Write;
+
+/// The subtitle file format, guessed from the file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SubtitleFormat {
+    Vtt,
+    Srt,
+}
+
+impl SubtitleFormat {
+    pub(crate) fn from_path(path: &str) -> Self {
+        if path.to_lowercase().ends_with(".srt") {
+            SubtitleFormat::Srt
+        } else {
+            SubtitleFormat::Vtt
+        }
+    }
+}
+
+/// Formats a cue time (seconds) as `HH:MM:SS.mmm` (VTT) or `HH:MM:SS,mmm` (SRT).
+pub(crate) fn cue_time(seconds: f64, format: SubtitleFormat) -> String {
+    let ms = (seconds.max(0.0) * 1000.0).round() as u64;
+    let separator = match format {
+        SubtitleFormat::Vtt => '.',
+        SubtitleFormat::Srt => ',',
+    };
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        separator,
+        ms % 1000
+    )
+}
+
+/// Writes a subtitle cue for each output frame, merging the adjacent frames
+/// with the same text (e.g. the frames duplicated by the output resampling).
+pub(crate) struct SubtitleWriter<W: Write> {
+    writer: W,
+    format: SubtitleFormat,
+    cues: usize,
+    /// The output time of the first frame, the cues start from zero.
+    start_time: Option<f64>,
+    /// The pending cue start time and text.
+    pending: Option<(f64, String)>,
+    last_time: f64,
+    last_duration: f64,
+}
+
+impl<W: Write> SubtitleWriter<W> {
+    pub(crate) fn new(mut writer: W, format: SubtitleFormat) -> std::io::Result<Self> {
+        if format == SubtitleFormat::Vtt {
+            writeln!(writer, "WEBVTT")?;
+            writeln!(writer)?;
+        }
+        Ok(Self {
+            writer,
+            format,
+            cues: 0,
+            start_time: None,
+            pending: None,
+            last_time: 0.0,
+            last_duration: 0.0,
+        })
+    }
+
+    /// Adds the frame at output `time` (seconds).
+    pub(crate) fn push(&mut self, time: f64, text: &str) -> std::io::Result<()> {
+        let time = time - *self.start_time.get_or_insert(time);
+        if time > self.last_time {
+            self.last_duration = time - self.last_time;
+        }
+        self.last_time = time;
+        match self.pending.as_ref() {
+            Some((_, pending_text)) if pending_text == text => return Ok(()),
+            Some(_) => {
+                let (start, pending_text) = self.pending.take().unwrap();
+                self.write_cue(start, time, &pending_text)?;
+            }
+            None => {}
+        }
+        self.pending = Some((time, text.to_string()));
+        Ok(())
+    }
+
+    /// Writes the last cue, lasting one frame past its last frame.
+    pub(crate) fn finish(&mut self) -> std::io::Result<()> {
+        if let Some((start, text)) = self.pending.take() {
+            let end = self.last_time + self.last_duration;
+            self.write_cue(start, end, &text)?;
+        }
+        self.writer.flush()
+    }
+
+    fn write_cue(&mut self, start: f64, end: f64, text: &str) -> std::io::Result<()> {
+        self.cues += 1;
+        if self.format == SubtitleFormat::Srt {
+            writeln!(self.writer, "{}", self.cues)?;
+        }
+        writeln!(
+            self.writer,
+            "{} --> {}",
+            cue_time(start, self.format),
+            cue_time(end.max(start), self.format)
+        )?;
+        writeln!(self.writer, "{}", text)?;
+        writeln!(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cue_times() {
+        assert_eq!(cue_time(0.0, SubtitleFormat::Vtt), "00:00:00.000");
+        assert_eq!(cue_time(3723.0456, SubtitleFormat::Vtt), "01:02:03.046");
+        assert_eq!(cue_time(61.5, SubtitleFormat::Srt), "00:01:01,500");
+    }
+
+    #[test]
+    fn identical_adjacent_cues_are_merged() {
+        let mut output = Vec::new();
+        let mut writer = SubtitleWriter::new(&mut output, SubtitleFormat::Srt).unwrap();
+        writer.push(10.0, "a").unwrap();
+        writer.push(10.1, "a").unwrap();
+        writer.push(10.2, "b").unwrap();
+        writer.finish().unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "1\n00:00:00,000 --> 00:00:00,200\na\n\n2\n00:00:00,200 --> 00:00:00,300\nb\n\n"
+        );
+    }
+}
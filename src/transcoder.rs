@@ -1,14 +1,159 @@
 extern crate ffmpeg_next as ffmpeg;
 
+use crate::audio::AudioMarker;
+use crate::bars;
+use crate::config::{
+    BlankPolicy, ColorSpace, Config, Deinterlace, ExtraOutput, Keyframes, Overlay, Padding,
+    PtsRounding, TimestampBase, VideoCodec,
+};
+use crate::drift::{fit_drift, DriftSeriesWriter};
+use crate::encoder_opts::EncoderOptions;
+use crate::error::OptParseError;
+use crate::extra_output::ExtraOutputEncoder;
+use crate::frame_hook::{FrameAction, FrameCallback, FrameView, PtsRemapper};
+use crate::id_change::IdChangeDetector;
+use crate::id_lock::IdLock;
+use crate::ocr::{ocr_whitelist, PooledOcr};
+use crate::stats::{DriftStats, IdTransition, PtsGap, SegmentStats};
+use crate::subtitles::{SubtitleFormat, SubtitleWriter};
+use crate::truncation::TruncationDetector;
+use ffmpeg_next::packet::side_data;
+use ffmpeg_next::packet::Mut;
 use ffmpeg_next::{
-    codec, decoder, encoder, filter, format, frame, threading, Dictionary, Packet, Rational,
+    codec, color, decoder, encoder, filter, format, frame, picture, software, threading,
+    Dictionary, Packet, Rational, Rescale,
 };
-use flate2::read::GzDecoder;
 use image::DynamicImage;
-use log::debug;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use regex::Regex;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::time::Instant;
-use tesseract_rs::{TessPageSegMode, TesseractAPI};
+use tracing::{debug_span, event, Level};
+
+/// The parameters of the raw video frames.
+#[derive(Debug, Clone, Copy)]
+pub struct VideoParams {
+    pub width: u32,
+    pub height: u32,
+    pub format: format::Pixel,
+    pub aspect_ratio: Rational,
+    pub color: ColorProperties,
+}
+
+impl From<&decoder::Video> for VideoParams {
+    fn from(decoder: &decoder::Video) -> Self {
+        Self {
+            width: decoder.width(),
+            height: decoder.height(),
+            format: decoder.format(),
+            aspect_ratio: valid_aspect_ratio(decoder.aspect_ratio()),
+            color: ColorProperties::from(decoder),
+        }
+    }
+}
+
+/// The color properties of the decoded stream, propagated to the encoders:
+/// VMAF is sensitive to a shift of the range (limited or full) or of the
+/// primaries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorProperties {
+    pub range: color::Range,
+    pub space: color::Space,
+    pub primaries: color::Primaries,
+    pub transfer: color::TransferCharacteristic,
+}
+
+impl Default for ColorProperties {
+    fn default() -> Self {
+        Self {
+            range: color::Range::Unspecified,
+            space: color::Space::Unspecified,
+            primaries: color::Primaries::Unspecified,
+            transfer: color::TransferCharacteristic::Unspecified,
+        }
+    }
+}
+
+impl From<&frame::Video> for ColorProperties {
+    fn from(frame: &frame::Video) -> Self {
+        Self {
+            range: frame.color_range(),
+            space: frame.color_space(),
+            primaries: frame.color_primaries(),
+            transfer: frame.color_transfer_characteristic(),
+        }
+    }
+}
+
+impl From<&decoder::Video> for ColorProperties {
+    fn from(decoder: &decoder::Video) -> Self {
+        Self {
+            range: decoder.color_range(),
+            space: decoder.color_space(),
+            primaries: decoder.color_primaries(),
+            transfer: decoder.color_transfer_characteristic(),
+        }
+    }
+}
+
+/// Sets the color properties of an encoder before opening it, written in the
+/// bitstream (AV1) or in the container (y4m, WebM, MP4).
+fn set_color_properties(encoder: &mut encoder::video::Video, color: ColorProperties) {
+    encoder.set_color_range(color.range);
+    encoder.set_colorspace(color.space);
+    unsafe {
+        let context = encoder.as_mut_ptr();
+        (*context).color_primaries = color.primaries.into();
+        (*context).color_trc = color.transfer.into();
+    }
+}
+
+/// Returns the sample aspect ratio, or 1:1 if it is unknown or invalid (e.g.
+/// 0/0 in some WebM files).
+pub fn valid_aspect_ratio(aspect_ratio: Rational) -> Rational {
+    if aspect_ratio.numerator() > 0 && aspect_ratio.denominator() > 0 {
+        aspect_ratio
+    } else {
+        Rational(1, 1)
+    }
+}
+
+/// Returns the width of the frames rescaled to square pixels, rounded to even.
+pub fn square_pixels_width(width: u32, aspect_ratio: Rational) -> u32 {
+    ((width as f64 * f64::from(aspect_ratio) / 2.0).round() as u32 * 2).max(2)
+}
+
+/// Returns the filter applied to the output frames before encoding: the
+/// watermark band crop (`strip_height` > 0) and the rescaling to square
+/// pixels of width `square_width`.
+pub fn output_filter_desc(strip_height: u32, square_width: Option<u32>) -> Option<String> {
+    let mut filters = Vec::new();
+    if strip_height > 0 {
+        filters.push(format!("crop=iw:ih-{0}:0:{0}", strip_height));
+    }
+    if let Some(width) = square_width {
+        filters.push(format!("scale={}:ih,setsar=1", width));
+    }
+    if filters.is_empty() {
+        None
+    } else {
+        Some(filters.join(","))
+    }
+}
+
+/// Returns the filter adding temporal Gaussian noise of strength `sigma`
+/// (0-100) to all the planes.
+pub fn noise_filter_desc(sigma: u32) -> String {
+    format!("noise=alls={}:allf=t+u", sigma)
+}
 
 pub struct VideoFilter {
     _filter_graph: ffmpeg::filter::Graph,
@@ -17,25 +162,42 @@ pub struct VideoFilter {
 }
 
 impl VideoFilter {
-    pub fn new(
-        input: &format::stream::Stream,
-        decoder: &decoder::Video,
+    pub fn new<P: Into<VideoParams>>(
+        time_base: Rational,
+        params: P,
+        desc: String,
+    ) -> Result<Self, ffmpeg::Error> {
+        Self::with_output_format(time_base, params, desc, None)
+    }
+
+    /// Creates a filter whose output frames have the `output_format` pixel
+    /// format (e.g. the one supported by the encoder), converting them after
+    /// `desc` if needed. With `None` the output format is the one of the
+    /// last filter.
+    pub fn with_output_format<P: Into<VideoParams>>(
+        time_base: Rational,
+        params: P,
         desc: String,
+        output_format: Option<format::Pixel>,
     ) -> Result<Self, ffmpeg::Error> {
+        let params = params.into();
         let mut filter_graph = ffmpeg::filter::Graph::new();
         let args = format!(
             "video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect={}/{}",
-            decoder.width(),
-            decoder.height(),
-            decoder.format().descriptor().unwrap().name(),
-            input.time_base().numerator(),
-            input.time_base().denominator(),
-            decoder.aspect_ratio().numerator(),
-            decoder.aspect_ratio().denominator()
+            params.width,
+            params.height,
+            params.format.descriptor().unwrap().name(),
+            time_base.numerator(),
+            time_base.denominator(),
+            params.aspect_ratio.numerator(),
+            params.aspect_ratio.denominator()
         );
         let filter_in = filter_graph.add(&ffmpeg::filter::find("buffer").unwrap(), "in", &args)?;
-        let filter_out =
+        let mut filter_out =
             filter_graph.add(&ffmpeg::filter::find("buffersink").unwrap(), "out", "")?;
+        if let Some(output_format) = output_format {
+            filter_out.set_pixel_format(output_format);
+        }
 
         filter_graph
             .output("in", 0)?
@@ -61,29 +223,286 @@ impl VideoFilter {
         self.filter_out.sink().frame(&mut filtered_frame)?;
         Ok(filtered_frame)
     }
+
+    /// Sends a frame to the filter graph, or flushes it when `frame` is `None`.
+    pub fn send(&mut self, frame: Option<&frame::Video>) -> Result<(), ffmpeg::Error> {
+        match frame {
+            Some(frame) => self.filter_in.source().add(frame),
+            None => self.filter_in.source().flush(),
+        }
+    }
+
+    /// Receives a filtered frame, returning `None` when more input is needed.
+    pub fn receive(&mut self) -> Option<frame::Video> {
+        let mut filtered_frame = frame::Video::empty();
+        match self.filter_out.sink().frame(&mut filtered_frame) {
+            Ok(()) => Some(filtered_frame),
+            Err(_) => None,
+        }
+    }
+
+    /// The time base of the filtered frames.
+    pub fn time_base(&mut self) -> Rational {
+        self.filter_out.sink().time_base()
+    }
+}
+
+/// The maximum distance (seconds) from a recognized frame for recovering the
+/// timestamp of a failed frame.
+const LOOKAHEAD_MAX_DISTANCE: f64 = 0.1;
+
+/// The maximum number of frames between keyframes with `Keyframes::SceneCut`,
+/// bounding the seek distance in long scenes.
+const SCENE_CUT_MAX_KEYFRAME_INTERVAL: u32 = 300;
+
+/// The AV1 encoder.
+const SVT_AV1_ENCODER: &str = "libsvtav1";
+
+/// The SVT-AV1 preset (0-13, higher is faster), favoring the speed: the
+/// quality is set by the crf.
+const SVT_AV1_PRESET: u32 = 8;
+
+/// The default constant rate factor, near-lossless.
+const DEFAULT_CRF: u32 = 1;
+
+/// The frame metadata key set by the `scdet` filter on the scene cuts.
+const SCENE_CUT_METADATA_KEY: &str = "lavfi.scd.time";
+
+/// The number of recognized frames between the drift fits with
+/// `Config::correct_drift`.
+const DRIFT_REFIT_FRAMES: usize = 300;
+
+/// The maximum distance (seconds) of the audio marker used for timestamping a
+/// frame whose overlay wasn't recognized: a bit more than the markers interval.
+const MAX_AUDIO_MARKER_DISTANCE: f64 = 3.0;
+
+/// The frame metadata key holding the subtitle cue text of a processed frame,
+/// carried by the frame through the lookahead, reorder and resampling stages.
+const CUE_METADATA_KEY: &str = "webrtcperf_cue";
+
+/// The number of recent recognized timestamps used for detecting the frames
+/// recognized out of order.
+const REORDER_HISTORY: usize = 64;
+
+/// The memory budget (bytes) used for deriving the default maximum number of
+/// in-flight decoded frames.
+const MAX_INFLIGHT_BYTES: usize = 256 * 1024 * 1024;
+/// The in-flight frames memory budget (bytes) with `Config::low_memory`.
+const LOW_MEMORY_INFLIGHT_BYTES: usize = 32 * 1024 * 1024;
+
+/// A decoded frame with its recognition result.
+struct CachedFrame {
+    frame: frame::Video,
+    /// The frame input time (seconds).
+    time: f64,
+    /// The recognized time (seconds).
+    recognized_time: Option<f64>,
+}
+
+/// A ring buffer of decoded frames, used for recovering the timestamp of the
+/// frames where the recognition failed from the nearby recognized ones.
+struct FrameCache {
+    capacity: usize,
+    frames: VecDeque<CachedFrame>,
+    last_recognized: Option<(f64, f64)>,
+}
+
+impl FrameCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            frames: VecDeque::with_capacity(capacity + 1),
+            last_recognized: None,
+        }
+    }
+
+    fn push(&mut self, frame: CachedFrame) {
+        self.frames.push_back(frame);
+    }
+
+    fn is_full(&self) -> bool {
+        self.frames.len() > self.capacity
+    }
+
+    fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Removes the oldest frame, returning it with its recognized time or the
+    /// time recovered from the nearest recognized frame. `drift_scale`
+    /// converts the input time distances to recognized time distances.
+    fn pop(&mut self, drift_scale: f64) -> Option<(CachedFrame, Option<f64>)> {
+        let cached = self.frames.pop_front()?;
+        let time = match cached.recognized_time {
+            Some(time) => {
+                self.last_recognized = Some((cached.time, time));
+                Some(time)
+            }
+            None => self
+                .frames
+                .iter()
+                .filter_map(|f| f.recognized_time.map(|t| (f.time, t)))
+                .chain(self.last_recognized)
+                .filter(|(t, _)| (t - cached.time).abs() <= LOOKAHEAD_MAX_DISTANCE)
+                .min_by(|a, b| {
+                    (a.0 - cached.time)
+                        .abs()
+                        .total_cmp(&(b.0 - cached.time).abs())
+                })
+                .map(|(frame_time, recognized_time)| {
+                    recognized_time + (cached.time - frame_time) * drift_scale
+                }),
+        };
+        Some((cached, time))
+    }
 }
 
 #[derive(Debug)]
 pub enum Mode {
     Watermark,
+    /// Watermarks the raw frames received on a TCP socket, see `crate::realtime`.
+    WatermarkRealtime {
+        listen_addr: SocketAddr,
+    },
     Process,
 }
 
 pub struct Transcoder {
     ost_index: usize,
     decoder: decoder::Video,
-    input_time_base: Rational,
+    decoder_eof: bool,
+    encoder_time_base: Rational,
+    segment_time_base: Rational,
     encoder: encoder::Video,
     logging_enabled: bool,
     frame_count: usize,
     total_frames: i64,
     last_log_frame_count: usize,
     last_log_time: Instant,
-    frame_re: Regex,
     failed_frames: usize,
+    trim_to_recognized: bool,
+    frames_csv: Option<std::io::BufWriter<std::fs::File>>,
+    pts_csv: Option<std::io::BufWriter<std::fs::File>>,
+    drift_series: Option<DriftSeriesWriter<std::io::BufWriter<std::fs::File>>>,
+    /// The frames sent to the output in timestamp order, the last one pts
+    /// and the gaps between them.
+    ordered_frames: usize,
+    last_ordered_pts: Option<i64>,
+    pts_gaps: Vec<PtsGap>,
+    subtitles: Option<SubtitleWriter<std::io::BufWriter<std::fs::File>>>,
+    /// The offset between the input and the recognized time of the first
+    /// recognized frame, the cues report the delay relative to it.
+    cue_delay_base: Option<f64>,
+    on_frame: Option<FrameCallback>,
+    /// Replaces the overlay recognition, see `Config::pts_remapper`.
+    pts_remapper: Option<PtsRemapper>,
+    recognized_frames: usize,
+    trimmed_head_frames: usize,
+    trailing_failed_frames: usize,
+    intervals_ms: Vec<f64>,
+    last_interval_time: Option<f64>,
+    nominal_interval_ms: Option<f64>,
+    deinterlace: Deinterlace,
+    deinterlace_field_rate: bool,
+    deinterlace_filter: Option<VideoFilter>,
+    deinterlaced: bool,
     watermark_filter: Option<VideoFilter>,
-    tesseract: Option<TesseractAPI>,
+    output_filter: Option<VideoFilter>,
+    scene_filter: Option<VideoFilter>,
+    noise_filter: Option<VideoFilter>,
+    metadata_id: Option<String>,
+    timestamp_base: TimestampBase,
+    timestamp_decimals: u32,
+    timecode_fps: Option<f64>,
+    watermark_frame_index: u64,
+    watermark_timestamps: HashMap<i64, u64>,
+    metadata_times: HashMap<i64, (String, f64)>,
+    metadata_frames: usize,
+    visual_frames: usize,
+    watermark_ranges: Vec<(f64, f64)>,
+    scaling_ctx: Option<software::scaling::Context>,
+    hq_scaling_ctx: Option<software::scaling::Context>,
+    hq_rescued_frames: usize,
+    encoder_scaling_ctx: Option<software::scaling::Context>,
+    input_fps: Option<f64>,
+    /// The decoder options, reused when the decoder is reset.
+    decoder_options: Option<String>,
+    sample_keyframes: Option<f64>,
+    last_sample_time: Option<f64>,
+    frame_cache: Option<FrameCache>,
+    interpolated_frames: usize,
+    blank_frames: usize,
+    on_blank: BlankPolicy,
+    blank_thresholds: Option<(f64, f64)>,
+    /// The `(x, y, width, height)` part of the watermark band recognized, see
+    /// `Config::ocr_region`.
+    ocr_region: Option<(u32, u32, u32, u32)>,
+    truncation: TruncationDetector,
+    truncated_reads: usize,
+    pts_rounding: PtsRounding,
+    recent_times: VecDeque<f64>,
+    reordered_frames: usize,
+    max_reorder_displacement: usize,
+    reorder_window: usize,
+    reorder_buffer: VecDeque<(f64, frame::Video)>,
+    max_inflight_frames: usize,
+    inflight_frames_high_water: usize,
+    extra_outputs: Vec<ExtraOutputEncoder>,
+    fps_filter: Option<VideoFilter>,
+    target_frames: Option<usize>,
+    /// The target output duration, in encoder time base units.
+    target_duration: Option<i64>,
+    padding: Padding,
+    /// The duration of the padding frames: the last output frame duration.
+    target_frame_duration: i64,
+    first_output_pts: Option<i64>,
+    last_output_frame: Option<frame::Video>,
+    output_frames: usize,
+    padded_frames: usize,
+    truncated_frames: usize,
+    /// The frame drop probability and generator, see `Config::simulate_packet_loss`.
+    packet_loss: Option<(f64, StdRng)>,
+    simulated_lost_frames: usize,
+    /// The `(input time, marker time)` of the detected audio markers, used
+    /// for the frames whose overlay wasn't recognized.
+    audio_sync_points: Vec<(f64, f64)>,
+    audio_frames: usize,
+    /// Set by a stop request while flushing: the remaining frames are dropped.
+    abort: Option<Arc<AtomicBool>>,
+    encoded_frames: usize,
+    /// The size of the encoded packets and their pts range (encoder time
+    /// base), for the average bitrate.
+    encoded_bytes: u64,
+    encoded_pts_range: Option<(i64, i64)>,
+    recognize: bool,
+    ignore_timestamp_metadata: bool,
+    overlay: Overlay,
+    bars_id: Option<u32>,
+    tesseract: Option<PooledOcr>,
+    ocr_whitelist: String,
+    /// The id recognized in the last frame.
     recognized_id: Option<String>,
+    /// The number of frames where each id was recognized.
+    id_counts: HashMap<String, usize>,
+    /// The overlay text patterns, tried in order.
+    timestamp_regexes: Vec<Regex>,
+    /// The number of frames matched by each of `timestamp_regexes`.
+    pattern_matches: Vec<usize>,
+    id_lock_frames: usize,
+    id_stable_frames: usize,
+    id_lock: Option<IdLock>,
+    id_switches: usize,
+    id_change: Option<IdChangeDetector>,
+    id_transitions: Vec<IdTransition>,
+    segments: Vec<SegmentStats>,
+    segment_start_pending: bool,
+    pts_offset: i64,
+    last_pts: Option<i64>,
+    last_pts_delta: i64,
+    last_frame_time: f64,
+    sync_points: Vec<(f64, f64)>,
+    correct_drift: bool,
+    drift_scale: f64,
 }
 
 impl Transcoder {
@@ -94,7 +513,8 @@ impl Transcoder {
         enable_logging: bool,
         mode: &Mode,
         watermark_id: Option<&str>,
-    ) -> Result<Self, ffmpeg::Error> {
+        config: &Config,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         debug!(
             "Transcoder mode: {:?} watermark_id: {}",
             mode,
@@ -102,137 +522,512 @@ impl Transcoder {
         );
 
         let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
-        let decoder = codec::context::Context::from_parameters(ist.parameters())?
-            .decoder()
-            .video()?;
-
-        let codec = encoder::find(codec::Id::VP8);
-        let mut ost = octx.add_stream(codec)?;
-
-        let mut encoder =
-            codec::context::Context::new_with_codec(codec.ok_or(ffmpeg::Error::InvalidData)?)
-                .encoder()
-                .video()?;
-        ost.set_parameters(&encoder);
-        encoder.set_height(decoder.height());
-        encoder.set_width(decoder.width());
-        encoder.set_aspect_ratio(decoder.aspect_ratio());
-        encoder.set_format(decoder.format());
-        encoder.set_frame_rate(decoder.frame_rate());
-        encoder.set_time_base(ist.time_base());
-        encoder.set_bit_rate(20000);
-        encoder.set_threading(threading::Config::count(0));
-        encoder.set_gop(1);
-
-        if global_header {
-            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
-        }
-
-        let encoder_opts = parse_opts(
-            "quality=best,cpu-used=0,crf=1,qmin=1,qmax=10,kf-min-dist=1,kf-max-dist=1".to_owned(),
-        )
-        .unwrap();
-        let opened_encoder = encoder
-            .open_with(encoder_opts)
-            .expect("error opening encoder with supplied settings");
+        let mut extra_decoder_options = Vec::new();
+        // When sampling the keyframes, the other frames are not even decoded.
+        if config.sample_keyframes.is_some() {
+            extra_decoder_options.push("skip_frame=nokey");
+        }
+        // The frame threading holds a decoded frame for each thread.
+        if config.low_memory {
+            extra_decoder_options.push("thread_type=slice");
+        }
+        let decoder_options = config
+            .decoder_options
+            .iter()
+            .map(String::as_str)
+            .chain(extra_decoder_options)
+            .collect::<Vec<_>>()
+            .join(",");
+        let decoder_options = Some(decoder_options).filter(|options| !options.is_empty());
+        let decoder = open_video_decoder(ist.parameters(), decoder_options.as_deref())?;
+
+        let field_rate = config.deinterlace != Deinterlace::Off && config.deinterlace_field_rate;
+        let encoder_time_base = encoder_time_base(ist.time_base(), config);
+        let target_fps = config.target_fps.filter(|_| matches!(mode, Mode::Process));
+        let input_frame_rate = input_frame_rate(decoder.frame_rate(), config.fps);
+        let frame_rate = if let Some(target_fps) = target_fps {
+            Some(Rational::from(target_fps))
+        } else if field_rate {
+            input_frame_rate.map(|r| r * Rational(2, 1))
+        } else {
+            input_frame_rate
+        };
+        let timecode_fps = config
+            .timecode_fps
+            .or_else(|| input_frame_rate.map(f64::from));
+        if config
+            .watermark_format
+            .as_deref()
+            .is_some_and(|format| format.contains("{timecode}"))
+            && timecode_fps.is_none()
+        {
+            return Err(
+                "the input frame rate is unknown, the timecode requires --timecode-fps".into(),
+            );
+        }
+        let nominal_interval_ms =
+            input_frame_rate.map(|r| 1000.0 / f64::from(r) / if field_rate { 2.0 } else { 1.0 });
+        let codec_id = output_codec(octx, config.codec);
+        // The watermark band is cropped from the top of the recognized frames.
+        let strip_height = if matches!(mode, Mode::Process) && config.strip_watermark {
+            band_height(decoder.height())
+        } else {
+            0
+        };
+        let params = VideoParams::from(&decoder);
+        let square_width = if matches!(mode, Mode::Process) && config.normalize_sar {
+            Some(square_pixels_width(params.width, params.aspect_ratio))
+        } else {
+            None
+        };
+        if let Some(color_space) = config.color_space {
+            check_color_space(codec_id, color_space)?;
+        }
+        let output_params = VideoParams {
+            width: square_width.unwrap_or(params.width),
+            height: params.height - strip_height,
+            aspect_ratio: if square_width.is_some() {
+                Rational(1, 1)
+            } else {
+                params.aspect_ratio
+            },
+            format: config.color_space.map_or(params.format, color_space_format),
+            ..params
+        };
+        let mut opened_encoder = open_encoder(
+            output_params,
+            codec_id,
+            encoder_time_base,
+            frame_rate,
+            global_header,
+            config.keyframes,
+            config.gop,
+            config.crf,
+            config.encoder_options.as_ref(),
+        )?;
+        if codec_id == codec::Id::WRAPPED_AVFRAME {
+            // Written in the y4m header, with the color range.
+            unsafe {
+                (*opened_encoder.as_mut_ptr()).chroma_sample_location =
+                    decoder.chroma_location().into();
+            }
+        }
+        let mut ost = octx.add_stream(opened_encoder.codec())?;
         ost.set_parameters(&opened_encoder);
+        if codec_id == codec::Id::PNG && matches!(mode, Mode::Process) {
+            // The image sequence file names are the recognized timestamps (ms).
+            ost.set_time_base(Rational(1, 1000));
+        }
+        if let (codec::Id::WRAPPED_AVFRAME, Some(frame_rate)) = (codec_id, frame_rate) {
+            // The y4m frames have no timestamps, the header frame rate is
+            // derived from the stream time base.
+            ost.set_time_base(frame_rate.invert());
+        }
 
-        let watermark_filter = if matches!(mode, Mode::Watermark) {
-            let home_dir = std::env::var("HOME").expect("Failed to get home directory");
-            let font_path = format!("{}/.webrtcperf/cache/NotoMono-Regular.ttf", home_dir);
-            if !std::path::Path::new(&font_path).exists() {
-                // Download the file from the URL
-                debug!("Downloading font file");
-                let mut response = reqwest::blocking::get(
-                    "https://cdn.jsdelivr.net/npm/@typopro/web-noto@3.7.5/TypoPRO-NotoMono-Regular.ttf",
-                )
-                .expect("Failed to download font file");
-                let mut file =
-                    std::fs::File::create(&font_path).expect("Failed to create font file");
-                std::io::copy(&mut response, &mut file).expect("Failed to write font file");
-            }
-            let text_height = (decoder.height() as f32 / 15.0).round() as i32;
-            let font_size = (decoder.height() as f32 / 18.0).round() as i32;
-            let id = watermark_id.unwrap_or("1");
-            let id = if id.is_empty() { "1" } else { id };
-            let watermark_filter = VideoFilter::new(
-                ist,
-                &decoder,
-                format!(
-                    "\
-drawbox=x=0:y=0:w=iw:h={text_height}:color=black:t=fill,\
-drawtext=fontfile={font_path}:text='{id}-%{{eif\\:t*1000\\:u}}'\
-:fontcolor=white:fontsize={font_size}:x=(w-text_w)/2:y=({text_height}-text_h)/2",
-                    text_height = text_height,
-                    id = id,
-                    font_path = font_path,
-                    font_size = font_size
-                ),
+        // With the metadata carrier only, the frames are left untouched.
+        let draw_overlay = matches!(mode, Mode::Watermark) && config.timestamp_carrier.visual();
+        // The bars are drawn on the watermark filter output, in the encoder format.
+        let bars_format = if draw_overlay {
+            opened_encoder.format()
+        } else {
+            decoder.format()
+        };
+        if config.overlay == Overlay::Bars && !bars::supports_format(bars_format) {
+            return Err(format!(
+                "unsupported pixel format {:?} for the bars overlay",
+                bars_format
             )
-            .expect("Failed to create watermark filter");
+            .into());
+        }
+        let bars_id = match (draw_overlay, config.overlay) {
+            (true, Overlay::Bars) => Some(bars_id(watermark_id)?),
+            _ => None,
+        };
+
+        let watermark_filter = if draw_overlay {
+            let font_path = match config.overlay {
+                Overlay::Text => Some(font_path(config.cache_dir.as_deref())?),
+                Overlay::Bars => None,
+            };
+            if let Some(format) = config.watermark_format.as_deref() {
+                check_watermark_format(format)?;
+            }
+            check_watermark_opacity(config.watermark_opacity, config.overlay)?;
+            let mut desc = watermark_filter_desc(
+                decoder.height(),
+                watermark_id,
+                font_path.as_deref(),
+                config.watermark_format.as_deref(),
+                config.watermark_opacity,
+            );
+            // Draw the overlay in the native format, converting it only once
+            // (YUV to YUV) when the encoder doesn't support it.
+            if opened_encoder.format() != decoder.format() {
+                desc = format!(
+                    "{},format={}",
+                    desc,
+                    opened_encoder.format().descriptor().unwrap().name()
+                );
+            }
+            debug!("Watermark filter: {}", desc);
+            let watermark_filter = VideoFilter::new(encoder_time_base, &decoder, desc)?;
             Some(watermark_filter)
         } else {
             None
         };
 
-        let tesseract = if matches!(mode, Mode::Process) {
-            debug!("Initializing Tesseract");
-            // Initialize Tesseract
-            let home_dir = std::env::var("HOME").expect("Failed to get home directory");
-            let tesseract_dir = format!("{}/.webrtcperf/cache", home_dir);
-            std::fs::create_dir_all(&tesseract_dir)
-                .expect("Failed to create Tesseract data directory");
-            let tesseract_path = format!("{}/eng.traineddata", tesseract_dir);
-            if !std::path::Path::new(&tesseract_path).exists() {
-                // Download the file from the URL
-                debug!("Downloading Tesseract data file");
-                let response = reqwest::blocking::get(
-                    "https://cdn.jsdelivr.net/npm/@tesseract.js-data/eng/4.0.0/eng.traineddata.gz",
-                )
-                .expect("Failed to download Tesseract data file");
-                let mut decoder = GzDecoder::new(response);
-                let mut file = std::fs::File::create(&tesseract_path)
-                    .expect("Failed to create Tesseract data file");
-                std::io::copy(&mut decoder, &mut file)
-                    .expect("Failed to write Tesseract data file");
-            }
-            let tesseract = TesseractAPI::new();
-            tesseract.init(tesseract_dir, "eng").unwrap();
-            tesseract
-                .set_variable("tessedit_char_whitelist", "0123456789-")
-                .unwrap();
-            tesseract
-                .set_page_seg_mode(TessPageSegMode::PSM_SINGLE_LINE)
-                .unwrap();
-            Some(tesseract)
+        let output_filter = match output_filter_desc(strip_height, square_width) {
+            Some(desc) => {
+                debug!("Output filter: {}", desc);
+                Some(VideoFilter::new(encoder_time_base, &decoder, desc)?)
+            }
+            None => None,
+        };
+
+        let scene_filter = if matches!(codec_id, codec::Id::VP8 | codec::Id::AV1)
+            && config.keyframes == Keyframes::SceneCut
+        {
+            Some(VideoFilter::new(
+                encoder_time_base,
+                VideoParams {
+                    format: opened_encoder.format(),
+                    ..output_params
+                },
+                format!("scdet=threshold={}", config.scene_threshold),
+            )?)
+        } else {
+            None
+        };
+
+        // The noise is added to the whole encoded frames, watermark band included.
+        let noise_filter = match config.noise.filter(|_| matches!(mode, Mode::Watermark)) {
+            Some(sigma) => {
+                let desc = noise_filter_desc(sigma);
+                debug!("Noise filter: {}", desc);
+                Some(VideoFilter::new(
+                    encoder_time_base,
+                    VideoParams {
+                        format: opened_encoder.format(),
+                        ..output_params
+                    },
+                    desc,
+                )?)
+            }
+            None => None,
+        };
+
+        // Resample the recognized frames to a constant frame rate.
+        let fps_filter_enabled = target_fps.is_some();
+        let fps_filter = match target_fps {
+            Some(target_fps) => Some(VideoFilter::new(
+                encoder_time_base,
+                &decoder,
+                format!("fps=fps={}", target_fps),
+            )?),
+            None => None,
+        };
+        let target_frame_duration = target_fps.map_or(1, |target_fps| {
+            ((1.0 / target_fps) / f64::from(encoder_time_base)).round() as i64
+        });
+
+        let tesseract = if matches!(mode, Mode::Process) && config.overlay == Overlay::Text {
+            Some(PooledOcr::acquire(config)?)
         } else {
             None
         };
+        let timestamp_regexes = timestamp_regexes(config)?;
 
         Ok(Self {
             ost_index,
             decoder,
-            input_time_base: ist.time_base(),
+            decoder_eof: false,
+            encoder_time_base,
+            segment_time_base: ist.time_base(),
             encoder: opened_encoder,
             logging_enabled: enable_logging,
             frame_count: 0,
             total_frames: ist.frames(),
             last_log_frame_count: 0,
             last_log_time: Instant::now(),
-            frame_re: Regex::new(r"(?<id>[0-9]{1,3})-(?<time>[0-9]{1,13})").unwrap(),
             failed_frames: 0,
+            trim_to_recognized: config.trim_to_recognized,
+            frames_csv: None,
+            pts_csv: None,
+            drift_series: None,
+            ordered_frames: 0,
+            last_ordered_pts: None,
+            pts_gaps: Vec::new(),
+            subtitles: None,
+            cue_delay_base: None,
+            on_frame: config.on_frame.clone(),
+            pts_remapper: config.pts_remapper.clone(),
+            recognized_frames: 0,
+            trimmed_head_frames: 0,
+            trailing_failed_frames: 0,
+            intervals_ms: Vec::new(),
+            last_interval_time: None,
+            nominal_interval_ms,
+            deinterlace: config.deinterlace,
+            deinterlace_field_rate: config.deinterlace_field_rate,
+            deinterlace_filter: None,
+            deinterlaced: false,
             watermark_filter,
+            output_filter,
+            scene_filter,
+            noise_filter,
+            metadata_id: match mode {
+                Mode::Watermark if config.timestamp_carrier.metadata() => {
+                    Some(watermark_id_or_default(watermark_id).to_string())
+                }
+                _ => None,
+            },
+            metadata_times: HashMap::new(),
+            timestamp_base: config.timestamp_base,
+            timestamp_decimals: config.timestamp_decimals,
+            timecode_fps,
+            watermark_frame_index: 0,
+            watermark_timestamps: HashMap::new(),
+            metadata_frames: 0,
+            visual_frames: 0,
+            watermark_ranges: config.watermark_ranges.clone(),
+            scaling_ctx: None,
+            hq_scaling_ctx: None,
+            hq_rescued_frames: 0,
+            encoder_scaling_ctx: None,
+            input_fps: config.input_fps,
+            decoder_options,
+            sample_keyframes: config.sample_keyframes,
+            last_sample_time: None,
+            frame_cache: if matches!(mode, Mode::Process) && config.lookahead_frames > 0 {
+                Some(FrameCache::new(config.lookahead_frames))
+            } else {
+                None
+            },
+            interpolated_frames: 0,
+            blank_frames: 0,
+            on_blank: config.on_blank,
+            blank_thresholds: config.blank_max_luma.zip(config.blank_max_variance),
+            ocr_region: config.ocr_region,
+            truncation: TruncationDetector::default(),
+            truncated_reads: 0,
+            pts_rounding: config.pts_rounding,
+            recent_times: VecDeque::with_capacity(REORDER_HISTORY),
+            reordered_frames: 0,
+            max_reorder_displacement: 0,
+            reorder_window: config.reorder_window,
+            reorder_buffer: VecDeque::with_capacity(config.reorder_window + 1),
+            max_inflight_frames: config.max_inflight_frames.unwrap_or_else(|| {
+                let budget = if config.low_memory {
+                    LOW_MEMORY_INFLIGHT_BYTES
+                } else {
+                    MAX_INFLIGHT_BYTES
+                };
+                default_max_inflight_frames(decoder.width(), decoder.height(), budget)
+            }),
+            inflight_frames_high_water: 0,
+            extra_outputs: Vec::new(),
+            fps_filter,
+            target_frames: config.target_frames.filter(|_| fps_filter_enabled),
+            target_duration: config
+                .target_duration
+                .filter(|_| matches!(mode, Mode::Process))
+                .map(|duration| (duration / f64::from(encoder_time_base)).round() as i64),
+            padding: config.padding,
+            target_frame_duration,
+            first_output_pts: None,
+            last_output_frame: None,
+            output_frames: 0,
+            padded_frames: 0,
+            truncated_frames: 0,
+            packet_loss: config
+                .simulate_packet_loss
+                .filter(|probability| !matches!(mode, Mode::Process) && *probability > 0.0)
+                .map(|probability| {
+                    let rng = match config.packet_loss_seed {
+                        Some(seed) => StdRng::seed_from_u64(seed),
+                        None => StdRng::from_entropy(),
+                    };
+                    (probability, rng)
+                }),
+            simulated_lost_frames: 0,
+            audio_sync_points: Vec::new(),
+            audio_frames: 0,
+            abort: None,
+            encoded_frames: 0,
+            encoded_bytes: 0,
+            encoded_pts_range: None,
+            recognize: matches!(mode, Mode::Process),
+            ignore_timestamp_metadata: config.ignore_timestamp_metadata,
+            overlay: config.overlay,
+            bars_id,
             tesseract,
+            ocr_whitelist: ocr_whitelist(config).to_string(),
             recognized_id: None::<String>,
+            id_counts: HashMap::new(),
+            pattern_matches: vec![0; timestamp_regexes.len()],
+            timestamp_regexes,
+            // The locked region layout assumes the default `<id>-<timestamp>` text.
+            id_lock_frames: if config.timestamp_patterns.is_empty() {
+                config.id_lock_frames
+            } else {
+                0
+            },
+            id_stable_frames: 0,
+            id_lock: None,
+            id_switches: 0,
+            id_change: (config.id_change_frames > 0)
+                .then(|| IdChangeDetector::new(config.id_change_frames)),
+            id_transitions: Vec::new(),
+            segments: Vec::new(),
+            segment_start_pending: false,
+            pts_offset: 0,
+            last_pts: None,
+            last_pts_delta: 1,
+            last_frame_time: 0.0,
+            sync_points: Vec::new(),
+            correct_drift: config.correct_drift,
+            drift_scale: 1.0,
         })
     }
 
+    /// Adds an output receiving a filtered copy of the encoded frames.
+    pub fn add_extra_output(
+        &mut self,
+        output: &ExtraOutput,
+        config: &Config,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.extra_outputs.push(ExtraOutputEncoder::new(
+            output,
+            VideoParams::from(&self.decoder),
+            self.encoder_time_base,
+            config,
+        )?);
+        Ok(())
+    }
+
+    /// Flushes the extra outputs, returning their paths.
+    pub fn finish_extra_outputs(&mut self) -> Result<Vec<String>, ffmpeg::Error> {
+        let mut paths = Vec::new();
+        for extra_output in self.extra_outputs.iter_mut() {
+            extra_output.finish()?;
+            paths.push(extra_output.path().to_string());
+        }
+        Ok(paths)
+    }
+
+    /// Writes a CSV row for each decoded frame to `path`.
+    pub fn set_frames_csv(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut csv = std::io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(
+            csv,
+            "frame,pts,time,key_frame,picture_type,recognized_time,carrier"
+        )?;
+        self.frames_csv = Some(csv);
+        Ok(())
+    }
+
+    /// Writes a CSV row with the original and the recovered pts of each
+    /// recognized or interpolated frame to `path`.
+    pub fn set_pts_csv(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut csv = std::io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(csv, "pts,time,recovered_time,output_pts")?;
+        self.pts_csv = Some(csv);
+        Ok(())
+    }
+
+    /// Writes a JSON line with the input time, the recovered time and the
+    /// drift from the nominal frame rate of each recognized or interpolated
+    /// frame to `path`.
+    pub fn set_drift_series(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let interval_ms = self
+            .nominal_interval_ms
+            .ok_or("the input frame rate is unknown, the drift series requires --fps")?;
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.drift_series = Some(DriftSeriesWriter::new(file, interval_ms));
+        Ok(())
+    }
+
+    /// Writes a WebVTT (or SRT, with the `.srt` extension) subtitle cue for
+    /// each output frame to `path`, with the recognized values.
+    pub fn set_subtitle_report(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.subtitles = Some(SubtitleWriter::new(file, SubtitleFormat::from_path(path))?);
+        Ok(())
+    }
+
+    /// Starts collecting statistics for a new input segment.
+    pub fn start_segment(&mut self, input_file: &str) {
+        self.segments.push(SegmentStats {
+            input_file: input_file.to_string(),
+            ..Default::default()
+        });
+        self.segment_start_pending = true;
+        self.last_interval_time = None;
+    }
+
+    /// Replaces the decoder with a new one for the next input segment stream.
+    pub fn reset_decoder(&mut self, ist: &format::stream::Stream) -> Result<(), ffmpeg::Error> {
+        self.decoder = open_video_decoder(ist.parameters(), self.decoder_options.as_deref())?;
+        self.decoder_eof = false;
+        self.deinterlace_filter = None;
+        self.metadata_times.clear();
+        self.segment_time_base = ist.time_base();
+        self.total_frames += ist.frames();
+        Ok(())
+    }
+
     pub fn send_packet_to_decoder(&mut self, packet: &Packet) {
+        if self.recognize && !self.ignore_timestamp_metadata {
+            if let (Some(pts), Some(metadata)) = (packet.pts(), packet_timestamp_metadata(packet)) {
+                // Keyed by the pts of the decoded frames reaching `process_frame`.
+                self.metadata_times.insert(
+                    pts.rescale(self.segment_time_base, self.encoder_time_base),
+                    metadata,
+                );
+            }
+        }
         self.decoder.send_packet(packet).unwrap();
     }
 
+    /// Adds the audio markers detected since the last call, see
+    /// `Config::audio_fallback`.
+    pub fn add_audio_markers(&mut self, markers: &[AudioMarker]) {
+        for marker in markers.iter().skip(self.audio_sync_points.len()) {
+            if self.recognized_id.is_none() {
+                self.recognized_id = Some(marker.id.clone());
+            }
+            self.audio_sync_points.push((marker.time, marker.timestamp));
+        }
+    }
+
+    /// Returns the time of the frame at input time `frame_time` (seconds),
+    /// extrapolated from the nearest audio marker.
+    fn audio_marker_time(&self, frame_time: f64) -> Option<f64> {
+        let (time, timestamp) = self.audio_sync_points.iter().min_by(|a, b| {
+            (a.0 - frame_time)
+                .abs()
+                .total_cmp(&(b.0 - frame_time).abs())
+        })?;
+        if (time - frame_time).abs() > MAX_AUDIO_MARKER_DISTANCE {
+            return None;
+        }
+        Some(timestamp + (frame_time - time) * self.drift_scale)
+    }
+
+    /// Sets the flag aborting the decoding and encoding of the remaining frames.
+    pub fn set_abort(&mut self, abort: Arc<AtomicBool>) {
+        self.abort = Some(abort);
+    }
+
+    fn aborted(&self) -> bool {
+        self.abort
+            .as_ref()
+            .is_some_and(|abort| abort.load(Ordering::SeqCst))
+    }
+
     pub fn send_eof_to_decoder(&mut self) {
         self.decoder.send_eof().unwrap();
+        self.decoder_eof = true;
     }
 
     pub fn receive_and_process_decoded_frames(
@@ -240,148 +1035,2506 @@ drawtext=fontfile={font_path}:text='{id}-%{{eif\\:t*1000\\:u}}'\
         octx: &mut format::context::Output,
         ost_time_base: Rational,
     ) {
-        let mut frame = frame::Video::empty();
-
-        while self.decoder.receive_frame(&mut frame).is_ok() {
+        loop {
+            if self.aborted() {
+                break;
+            }
+            let mut frame = frame::Video::empty();
+            if self.decoder.receive_frame(&mut frame).is_err() {
+                break;
+            }
             self.frame_count += 1;
+            let _span = debug_span!("decode", frame = self.frame_count).entered();
+            if let Some(segment) = self.segments.last_mut() {
+                segment.frames += 1;
+            }
+            // Image sequences have no timestamps, derive them from the input
+            // frame rate.
+            if let Some(input_fps) = self.input_fps {
+                let index = self.segments.last().map_or(self.frame_count, |s| s.frames) - 1;
+                let duration = (1.0 / input_fps) / f64::from(self.segment_time_base);
+                frame.set_pts(Some((index as f64 * duration).round() as i64));
+            }
             let timestamp = frame.timestamp().unwrap_or(0);
             self.log_progress(f64::from(
-                Rational(timestamp as i32, 1) * self.input_time_base,
+                Rational(timestamp as i32, 1) * self.segment_time_base,
             ));
 
-            match self.tesseract {
-                Some(ref mut tesseract) => {
-                    let mut rgb_frame = frame::Video::empty();
-                    ffmpeg::software::scaling::context::Context::get(
-                        self.decoder.format(),
-                        self.decoder.width(),
-                        self.decoder.height(),
-                        ffmpeg::format::Pixel::RGB24,
-                        self.decoder.width(),
-                        self.decoder.height(),
-                        ffmpeg::software::scaling::Flags::BILINEAR,
-                    )
-                    .unwrap()
-                    .run(&frame, &mut rgb_frame)
-                    .unwrap();
+            if let Some(interval) = self.sample_keyframes {
+                // Some decoders ignore `skip_frame`, the keyframes are checked again.
+                let time = timestamp as f64 * f64::from(self.segment_time_base);
+                if !frame.is_key()
+                    || self
+                        .last_sample_time
+                        .map_or(false, |last| time >= last && time - last < interval)
+                {
+                    continue;
+                }
+                self.last_sample_time = Some(time);
+            }
 
-                    let image_data = rgb_frame.data(0);
-                    let image = DynamicImage::ImageRgb8(
-                        image::RgbImage::from_raw(
-                            self.decoder.width(),
-                            self.decoder.height(),
-                            image_data.to_vec(),
-                        )
-                        .expect("Failed to create RgbImage from raw data"),
-                    );
-                    let image =
-                        image.crop_imm(0, 0, image.width(), (image.height() as f32 / 15f32) as u32);
-
-                    tesseract
-                        .set_image(
-                            &image.to_rgb8(),
-                            image.width() as i32,
-                            image.height() as i32,
-                            3i32,
-                            3i32 * frame.width() as i32,
-                        )
-                        .unwrap();
-                    let output = tesseract.get_utf8_text().unwrap();
-
-                    if !self.frame_re.captures(output.trim()).map_or_else(
-                        || {
-                            eprintln!("failed to recognize text: \"{:?}\"", output.trim());
-                            false
-                        },
-                        |c| {
-                            let id: i32 = c["id"].parse().unwrap();
-                            let time: f64 = c["time"].parse().unwrap_or(0f64) / 1000f64;
-                            let pts_new = (time / f64::from(self.input_time_base)) as i64;
-                            if cfg!(debug_assertions) {
-                                println!(
-                                    "  pts={:?} id={:?} time={:?} pts_new={:?}",
-                                    frame.pts(),
-                                    id,
-                                    time,
-                                    pts_new
-                                );
-                            }
-                            self.recognized_id = Some(c["id"].to_string());
-                            frame.set_pts(Some(pts_new));
-                            self.send_frame_to_encoder(&frame);
-                            self.receive_and_process_encoded_packets(octx, ost_time_base);
-                            true
+            if self.deinterlace_filter.is_none()
+                && (self.deinterlace == Deinterlace::On
+                    || (self.deinterlace == Deinterlace::Auto && frame.is_interlaced()))
+            {
+                match VideoFilter::new(
+                    self.segment_time_base,
+                    &self.decoder,
+                    format!(
+                        "yadif=mode={}:parity=auto:deint={}",
+                        if self.deinterlace_field_rate {
+                            "send_field"
+                        } else {
+                            "send_frame"
                         },
-                    ) {
-                        self.failed_frames += 1;
+                        if self.deinterlace == Deinterlace::On {
+                            "all"
+                        } else {
+                            "interlaced"
+                        }
+                    ),
+                ) {
+                    Ok(filter) => {
+                        self.deinterlace_filter = Some(filter);
+                        if !self.deinterlaced {
+                            debug!("Deinterlacing activated (mode: {:?})", self.deinterlace);
+                            self.deinterlaced = true;
+                        }
                     }
+                    Err(e) => self.disable_deinterlacing("create", e),
                 }
-                None => {
-                    frame = match &mut self.watermark_filter {
-                        Some(filter) => filter.apply(&frame).unwrap(),
-                        None => frame,
-                    };
+            }
 
-                    self.send_frame_to_encoder(&frame);
-                    self.receive_and_process_encoded_packets(octx, ost_time_base);
-                }
+            let unfiltered = match self.deinterlace_filter.as_mut() {
+                Some(filter) => match filter.send(Some(&frame)) {
+                    Ok(()) => {
+                        self.receive_and_process_deinterlaced_frames(octx, ost_time_base);
+                        None
+                    }
+                    Err(e) => {
+                        self.disable_deinterlacing("feed", e);
+                        Some(frame)
+                    }
+                },
+                None => Some(frame),
+            };
+            if let Some(mut frame) = unfiltered {
+                frame.set_pts(
+                    frame
+                        .pts()
+                        .map(|pts| pts.rescale(self.segment_time_base, self.encoder_time_base)),
+                );
+                self.process_frame(frame, octx, ost_time_base);
             }
         }
-    }
 
-    fn send_frame_to_encoder(&mut self, frame: &frame::Video) {
-        self.encoder.send_frame(frame).unwrap();
+        if self.decoder_eof {
+            if let Some(filter) = self.deinterlace_filter.as_mut() {
+                match filter.send(None) {
+                    Ok(()) => self.receive_and_process_deinterlaced_frames(octx, ost_time_base),
+                    Err(e) => warn!("Failed to flush the deinterlace filter: {}", e),
+                }
+                self.deinterlace_filter = None;
+            }
+            self.flush_frame_cache(octx, ost_time_base);
+            self.flush_reorder_buffer(octx, ost_time_base);
+        }
     }
 
-    pub fn send_eof_to_encoder(&mut self) {
-        self.encoder.send_eof().unwrap();
+    /// Falls back to no deinterlacing when the yadif filter fails to `action`
+    /// (create or feed), so the frames are transcoded as they are decoded.
+    fn disable_deinterlacing(&mut self, action: &str, e: ffmpeg::Error) {
+        warn!(
+            "Failed to {} the deinterlace filter, disabling deinterlacing: {}",
+            action, e
+        );
+        self.deinterlace_filter = None;
+        self.deinterlace = Deinterlace::Off;
     }
 
-    pub fn receive_and_process_encoded_packets(
+    fn receive_and_process_deinterlaced_frames(
         &mut self,
         octx: &mut format::context::Output,
         ost_time_base: Rational,
     ) {
-        let mut encoded = Packet::empty();
-        while self.encoder.receive_packet(&mut encoded).is_ok() {
-            encoded.set_stream(self.ost_index);
-            encoded.rescale_ts(self.input_time_base, ost_time_base);
-            encoded.write_interleaved(octx).unwrap();
+        while let Some(filter) = self.deinterlace_filter.as_mut() {
+            let time_base = filter.time_base();
+            let mut frame = match filter.receive() {
+                Some(frame) => frame,
+                None => break,
+            };
+            frame.set_pts(
+                frame
+                    .pts()
+                    .map(|pts| pts.rescale(time_base, self.encoder_time_base)),
+            );
+            self.process_frame(frame, octx, ost_time_base);
         }
     }
 
-    fn log_progress(&mut self, timestamp: f64) {
-        if !self.logging_enabled
-            || (self.frame_count - self.last_log_frame_count < 100
-                && self.last_log_time.elapsed().as_secs_f64() < 1.0)
-        {
+    /// Returns true if the watermark should be drawn on a frame at `time`
+    /// (seconds).
+    fn in_watermark_ranges(&self, time: f64) -> bool {
+        self.watermark_ranges.is_empty()
+            || self
+                .watermark_ranges
+                .iter()
+                .any(|(start, end)| time >= *start && time < *end)
+    }
+
+    fn process_frame(
+        &mut self,
+        mut frame: frame::Video,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) {
+        let _span = debug_span!("process_frame", pts = ?frame.pts()).entered();
+        if !self.recognize {
+            let frame_time = frame.pts().unwrap_or(0) as f64 * f64::from(self.encoder_time_base);
+            let timestamp_us = watermark_timestamp_us(
+                self.timestamp_base,
+                frame.pts().unwrap_or(0),
+                self.encoder_time_base,
+                self.watermark_frame_index,
+            );
+            let timestamp_ms = timestamp_us / 1000;
+            let frame_index = self.watermark_frame_index;
+            self.watermark_frame_index += 1;
+            if self.metadata_id.is_some() {
+                // The VP8 packets keep the frames pts.
+                self.watermark_timestamps
+                    .insert(frame.pts().unwrap_or(0), timestamp_ms);
+            }
+            let frame = match &mut self.watermark_filter {
+                Some(filter) if self.in_watermark_ranges(frame_time) => {
+                    let mut frame = frame;
+                    set_frame_metadata(
+                        &mut frame,
+                        TIMESTAMP_METADATA_KEY,
+                        &timestamp_text(timestamp_us, self.timestamp_decimals),
+                    );
+                    if let Some(fps) = self.timecode_fps {
+                        set_frame_metadata(
+                            &mut frame,
+                            TIMECODE_METADATA_KEY,
+                            &timecode_text(frame_index, fps),
+                        );
+                    }
+                    let mut frame = filter.apply(&frame).unwrap();
+                    if let Some(id) = self.bars_id {
+                        make_writable(&mut frame);
+                        let band_height = band_height(frame.height());
+                        bars::draw(&mut frame, band_height, id, timestamp_ms);
+                    }
+                    frame
+                }
+                _ => frame,
+            };
+            if self.watermark_filter.is_some() && self.in_watermark_ranges(frame_time) {
+                self.visual_frames += 1;
+            }
+            self.last_frame_time = frame_time;
+
+            if let Some((probability, rng)) = self.packet_loss.as_mut() {
+                if rng.gen_bool(*probability) {
+                    debug!("Simulated loss of the frame at {:.3}s", frame_time);
+                    self.simulated_lost_frames += 1;
+                    return;
+                }
+            }
+            self.send_frame_to_encoder(&frame);
+            self.receive_and_process_encoded_packets(octx, ost_time_base);
             return;
         }
-        debug!(
-            "[{}/{}] {:.2}s (failed: {})",
-            self.frame_count, self.total_frames, timestamp, self.failed_frames,
+
+        let frame_time = frame.pts().unwrap_or(0) as f64 * f64::from(self.encoder_time_base);
+        let metadata = frame.pts().and_then(|pts| self.metadata_times.remove(&pts));
+        let remapped_time = self.pts_remapper.as_ref().map(|remapper| {
+            frame.pts().map(|pts| {
+                (remapper.map)(pts, self.encoder_time_base) as f64
+                    * f64::from(self.encoder_time_base)
+            })
+        });
+        let (recognized_time, carrier) = match (remapped_time, metadata) {
+            (Some(time), _) => (time, if time.is_some() { "remapped" } else { "" }),
+            (None, Some((id, time))) => {
+                *self.id_counts.entry(id.clone()).or_default() += 1;
+                self.recognized_id = Some(id);
+                self.metadata_frames += 1;
+                (Some(time), "metadata")
+            }
+            (None, None) => {
+                let image = self.overlay_image(&frame, false);
+                if self.is_blank(&image) {
+                    (None, "blank")
+                } else {
+                    let mut time = self.recognize_image(&image, frame.pts());
+                    if time.is_none() {
+                        // Retry with the sharper scaler, preserving the text edges.
+                        let image = self.overlay_image(&frame, true);
+                        time = self.recognize_image(&image, frame.pts());
+                        if time.is_some() {
+                            self.hq_rescued_frames += 1;
+                        }
+                    }
+                    if time.is_some() {
+                        self.visual_frames += 1;
+                    }
+                    (time, if time.is_some() { "visual" } else { "" })
+                }
+            }
+        };
+        let (recognized_time, carrier) = match recognized_time {
+            None if carrier != "blank" => match self.audio_marker_time(frame_time) {
+                Some(time) => (Some(time), "audio"),
+                None => (None, carrier),
+            },
+            _ => (recognized_time, carrier),
+        };
+        event!(
+            Level::DEBUG,
+            frame_time,
+            recognized_time = ?recognized_time,
+            carrier,
+            "Frame recognized"
         );
-        self.last_log_frame_count = self.frame_count;
-        self.last_log_time = Instant::now();
-    }
+        if let Some(csv) = self.frames_csv.as_mut() {
+            let written = writeln!(
+                csv,
+                "{},{},{:.6},{},{:?},{},{}",
+                self.frame_count,
+                frame.pts().map_or(String::new(), |pts| pts.to_string()),
+                frame_time,
+                frame.is_key(),
+                frame.kind(),
+                recognized_time.map_or(String::new(), |time| format!("{:.6}", time)),
+                carrier
+            );
+            disable_on_error(&mut self.frames_csv, "frames CSV", written);
+        }
+        match recognized_time {
+            // Estimated times: not used for the intervals and the drift.
+            Some(_) if carrier == "audio" => {
+                self.audio_frames += 1;
+                self.last_interval_time = None;
+                self.trailing_failed_frames = 0;
+            }
+            Some(time) => {
+                self.recognized_frames += 1;
+                self.trailing_failed_frames = 0;
+                // Reordered frames are accounted separately.
+                if let Some(last_time) = self.last_interval_time {
+                    if time >= last_time {
+                        self.intervals_ms.push((time - last_time) * 1000.0);
+                    }
+                }
+                self.last_interval_time = Some(time);
+                self.sync_points.push((frame_time, time));
+                if self.correct_drift && self.sync_points.len() % DRIFT_REFIT_FRAMES == 0 {
+                    if let Some(drift) = fit_drift(&self.sync_points) {
+                        debug!("Drift: {:.1} ppm", drift.drift_ppm);
+                        self.drift_scale = drift.slope;
+                    }
+                }
+                if let (Some(id_change), Some(id)) =
+                    (self.id_change.as_mut(), self.recognized_id.as_deref())
+                {
+                    if let Some(transition) = id_change.update(id, frame_time) {
+                        warn!(
+                            "The recognized id changed from {} to {} at {:.3}s",
+                            transition.from_id, transition.to_id, transition.time
+                        );
+                        self.id_transitions.push(transition);
+                    }
+                }
+            }
+            None if self.trim_to_recognized && self.recognized_frames == 0 => {
+                self.trimmed_head_frames += 1;
+                return;
+            }
+            None if carrier == "blank" => {
+                self.last_interval_time = None;
+                self.blank_frames += 1;
+                if self.on_blank == BlankPolicy::Drop {
+                    return;
+                }
+            }
+            None => {
+                // The intervals spanning a failed frame are not measured.
+                self.last_interval_time = None;
+                self.trailing_failed_frames += 1;
+                self.failed_frames += 1;
+                if let Some(segment) = self.segments.last_mut() {
+                    segment.failed_frames += 1;
+                }
+            }
+        }
 
-    pub fn failed_frames(&self) -> usize {
-        self.failed_frames
-    }
+        if let Some(time) = recognized_time.filter(|_| self.subtitles.is_some()) {
+            let frame_time = frame_time * self.drift_scale;
+            let delay_ms =
+                (frame_time - time - *self.cue_delay_base.get_or_insert(frame_time - time))
+                    * 1000.0;
+            let cue = format!(
+                "id: {} time: {:.3} delay: {:+.1} ms ({})",
+                self.recognized_id.as_deref().unwrap_or("-"),
+                time,
+                delay_ms,
+                carrier
+            );
+            set_frame_metadata(&mut frame, CUE_METADATA_KEY, &cue);
+        }
 
-    pub fn recognized_id(&self) -> Option<&String> {
-        self.recognized_id.as_ref()
+        if let Some(on_frame) = self.on_frame.as_ref() {
+            let recognized_id = recognized_time.and(self.recognized_id.as_deref());
+            let view = FrameView::new(&frame, recognized_id, recognized_time);
+            if (on_frame.0)(&view) == FrameAction::Skip {
+                return;
+            }
+        }
+
+        match self.frame_cache.as_mut() {
+            Some(frame_cache) => {
+                frame_cache.push(CachedFrame {
+                    frame,
+                    time: frame_time,
+                    recognized_time,
+                });
+                while self.frame_cache.as_ref().map_or(false, FrameCache::is_full) {
+                    self.encode_cached_frame(octx, ost_time_base);
+                }
+            }
+            None => {
+                if let Some(time) = recognized_time {
+                    self.encode_recognized_frame(frame, time, octx, ost_time_base);
+                }
+            }
+        }
+        self.limit_inflight_frames(octx, ost_time_base);
     }
-}
 
-fn parse_opts<'a>(s: String) -> Option<Dictionary<'a>> {
-    let mut dict = Dictionary::new();
-    for keyval in s.split_terminator(',') {
-        let tokens: Vec<&str> = keyval.split('=').collect();
-        match tokens[..] {
-            [key, val] => dict.set(key, val),
-            _ => return None,
+    /// Converts a frame to RGB, returning its watermark band. With
+    /// `high_quality` the Lanczos scaler is used instead of the bilinear one.
+    fn overlay_image(&mut self, frame: &frame::Video, high_quality: bool) -> DynamicImage {
+        let (scaling_ctx, flags) = if high_quality {
+            (&mut self.hq_scaling_ctx, software::scaling::Flags::LANCZOS)
+        } else {
+            (&mut self.scaling_ctx, software::scaling::Flags::BILINEAR)
+        };
+        // Reuse the scaling context unless the frame format or resolution changes.
+        if scaling_ctx.as_ref().map_or(true, |ctx| {
+            let input = ctx.input();
+            input.format != frame.format()
+                || input.width != frame.width()
+                || input.height != frame.height()
+        }) {
+            *scaling_ctx = Some(
+                software::scaling::Context::get(
+                    frame.format(),
+                    frame.width(),
+                    frame.height(),
+                    ffmpeg::format::Pixel::RGB24,
+                    frame.width(),
+                    frame.height(),
+                    flags,
+                )
+                .unwrap(),
+            );
         }
+        let mut rgb_frame = frame::Video::empty();
+        scaling_ctx
+            .as_mut()
+            .unwrap()
+            .run(frame, &mut rgb_frame)
+            .unwrap();
+
+        let image_data = rgb_frame.data(0);
+        let image = DynamicImage::ImageRgb8(
+            image::RgbImage::from_raw(rgb_frame.width(), rgb_frame.height(), image_data.to_vec())
+                .expect("Failed to create RgbImage from raw data"),
+        );
+        let band = (0, 0, image.width(), band_height(image.height()));
+        let (x, y, width, height) = self
+            .ocr_region
+            .and_then(|region| clip_region(region, band.2, band.3))
+            .unwrap_or(band);
+        image.crop_imm(x, y, width, height)
+    }
+
+    /// Returns true (counting it) if the read of `id` with `digits` time
+    /// digits is truncated by an occlusion, see `TruncationDetector`.
+    fn is_truncated_read(&mut self, id: &str, digits: usize, output: &str) -> bool {
+        if !self.truncation.is_truncated(id, digits) {
+            return false;
+        }
+        self.truncated_reads += 1;
+        eprintln!("truncated timestamp read: \"{:?}\"", output);
+        true
+    }
+
+    /// Returns true if the watermark band mean luma and variance are below
+    /// the blank frame thresholds.
+    fn is_blank(&self, image: &DynamicImage) -> bool {
+        let (max_luma, max_variance) = match self.blank_thresholds {
+            Some(thresholds) => thresholds,
+            None => return false,
+        };
+        let luma = image.to_luma8();
+        let count = luma.len().max(1) as f64;
+        let mean = luma.iter().map(|v| *v as f64).sum::<f64>() / count;
+        if mean > max_luma {
+            return false;
+        }
+        let variance = luma.iter().map(|v| (*v as f64 - mean).powi(2)).sum::<f64>() / count;
+        variance <= max_variance
+    }
+
+    /// Recognizes the watermark band of the frame with pts `pts`, returning
+    /// the recognized time (seconds).
+    fn recognize_image(&mut self, image: &DynamicImage, pts: Option<i64>) -> Option<f64> {
+        let _span = debug_span!("recognize", pts = ?pts).entered();
+        if self.overlay == Overlay::Bars {
+            let (id, time_ms) = match bars::decode(&image.to_luma8()) {
+                Some(decoded) => decoded,
+                None => {
+                    eprintln!("failed to decode the bars overlay");
+                    return None;
+                }
+            };
+            let id = id.to_string();
+            *self.id_counts.entry(id.clone()).or_default() += 1;
+            self.recognized_id = Some(id);
+            return Some(time_ms as f64 / 1000f64);
+        }
+
+        if let Some(id_lock) = self.id_lock.as_ref() {
+            match id_lock.timestamp_region(image) {
+                Some(region) => {
+                    let id = id_lock.id().to_string();
+                    let output = self.ocr(&region)?;
+                    let time = TIME_RE
+                        .captures(output.trim())
+                        .and_then(|c| captured_time(&c, self.timecode_fps));
+                    return match time {
+                        Some((_, digits)) if self.is_truncated_read(&id, digits, output.trim()) => {
+                            None
+                        }
+                        Some((time, _)) => {
+                            *self.id_counts.entry(id).or_default() += 1;
+                            Some(time)
+                        }
+                        None => {
+                            eprintln!("failed to recognize timestamp: \"{:?}\"", output.trim());
+                            None
+                        }
+                    };
+                }
+                None => {
+                    event!(
+                        Level::DEBUG,
+                        id = id_lock.id(),
+                        "Id region changed, possible content switch"
+                    );
+                    self.id_lock = None;
+                    self.id_switches += 1;
+                    self.id_stable_frames = 0;
+                    if let Some(tesseract) = self.tesseract.as_ref() {
+                        tesseract.set_whitelist(&self.ocr_whitelist);
+                    }
+                }
+            }
+        }
+
+        let output = self.ocr(image)?;
+        match parse_timestamp_text_any(&self.timestamp_regexes, output.trim(), self.timecode_fps) {
+            Some((_, text))
+                if self.is_truncated_read(&text.id, text.time_digits, output.trim()) =>
+            {
+                None
+            }
+            Some((pattern_index, text)) => {
+                self.pattern_matches[pattern_index] += 1;
+                let time = text.time;
+                if cfg!(debug_assertions) {
+                    eprintln!("  pts={:?} id={:?} time={:?}", pts, text.id, time);
+                }
+                if self.recognized_id.as_deref() == Some(text.id.as_str()) {
+                    self.id_stable_frames += 1;
+                } else {
+                    self.id_stable_frames = 1;
+                }
+                *self.id_counts.entry(text.id.clone()).or_default() += 1;
+                self.recognized_id = Some(text.id.clone());
+                if self.id_lock_frames > 0 && self.id_stable_frames >= self.id_lock_frames {
+                    self.id_lock = IdLock::new(image, &text.id, text.time_digits);
+                    if self.id_lock.is_some() {
+                        event!(Level::DEBUG, id = text.id.as_str(), "Id locked");
+                        if let Some(tesseract) = self.tesseract.as_ref() {
+                            tesseract.set_whitelist("0123456789.:");
+                        }
+                    }
+                }
+                Some(time)
+            }
+            None => {
+                eprintln!("failed to recognize text: \"{:?}\"", output.trim());
+                None
+            }
+        }
+    }
+
+    /// Runs the OCR on an image, returning the recognized text.
+    fn ocr(&mut self, image: &DynamicImage) -> Option<String> {
+        self.tesseract.as_mut()?.recognize(image)
+    }
+
+    /// Encodes the oldest cached frame, recovering its timestamp from the
+    /// nearby recognized frames when the recognition failed.
+    fn encode_cached_frame(&mut self, octx: &mut format::context::Output, ost_time_base: Rational) {
+        let drift_scale = self.drift_scale;
+        let (cached, time) = match self.frame_cache.as_mut().and_then(|c| c.pop(drift_scale)) {
+            Some(popped) => popped,
+            None => return,
+        };
+        if let Some(time) = time {
+            let mut frame = cached.frame;
+            if cached.recognized_time.is_none() {
+                self.interpolated_frames += 1;
+                if self.subtitles.is_some() {
+                    let cue = format!(
+                        "id: {} time: {:.3} (interpolated)",
+                        self.recognized_id.as_deref().unwrap_or("-"),
+                        time
+                    );
+                    set_frame_metadata(&mut frame, CUE_METADATA_KEY, &cue);
+                }
+            }
+            self.encode_recognized_frame(frame, time, octx, ost_time_base);
+        }
+    }
+
+    fn flush_frame_cache(&mut self, octx: &mut format::context::Output, ost_time_base: Rational) {
+        if self.trim_to_recognized {
+            // Don't recover the frames after the last recognized one.
+            if let Some(frame_cache) = self.frame_cache.as_mut() {
+                while frame_cache
+                    .frames
+                    .back()
+                    .map_or(false, |f| f.recognized_time.is_none())
+                {
+                    frame_cache.frames.pop_back();
+                }
+            }
+        }
+        while self.frame_cache.as_ref().map_or(false, |c| !c.is_empty()) {
+            self.encode_cached_frame(octx, ost_time_base);
+        }
+    }
+
+    /// Tracks the frames recognized out of order and, when the reorder window
+    /// is enabled, buffers the frames for encoding them sorted by time.
+    fn encode_recognized_frame(
+        &mut self,
+        frame: frame::Video,
+        time: f64,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) {
+        let displacement = self.recent_times.iter().filter(|t| **t > time).count();
+        if displacement > 0 {
+            debug!("Frame at {} out of order by {} frames", time, displacement);
+            self.reordered_frames += 1;
+            self.max_reorder_displacement = self.max_reorder_displacement.max(displacement);
+        }
+        if self.recent_times.len() == REORDER_HISTORY {
+            self.recent_times.pop_front();
+        }
+        self.recent_times.push_back(time);
+
+        if self.reorder_window == 0 {
+            self.encode_ordered_frame(frame, time, octx, ost_time_base);
+            return;
+        }
+        let index = self.reorder_buffer.partition_point(|(t, _)| *t <= time);
+        self.reorder_buffer.insert(index, (time, frame));
+        if self.reorder_buffer.len() > self.reorder_window {
+            let (time, frame) = self.reorder_buffer.pop_front().unwrap();
+            self.encode_ordered_frame(frame, time, octx, ost_time_base);
+        }
+    }
+
+    /// Returns the number of decoded frames held by the lookahead and reorder
+    /// buffers.
+    fn inflight_frames(&self) -> usize {
+        self.frame_cache.as_ref().map_or(0, |c| c.frames.len()) + self.reorder_buffer.len()
+    }
+
+    /// Applies backpressure encoding the oldest buffered frames until the
+    /// in-flight frames are within the budget.
+    fn limit_inflight_frames(
+        &mut self,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) {
+        while self.inflight_frames() > self.max_inflight_frames {
+            debug!(
+                "In-flight frames {} over the budget {}, encoding early",
+                self.inflight_frames(),
+                self.max_inflight_frames
+            );
+            if let Some((time, frame)) = self.reorder_buffer.pop_front() {
+                self.encode_ordered_frame(frame, time, octx, ost_time_base);
+            } else {
+                self.encode_cached_frame(octx, ost_time_base);
+            }
+        }
+        self.inflight_frames_high_water =
+            self.inflight_frames_high_water.max(self.inflight_frames());
+    }
+
+    fn flush_reorder_buffer(
+        &mut self,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) {
+        while let Some((time, frame)) = self.reorder_buffer.pop_front() {
+            self.encode_ordered_frame(frame, time, octx, ost_time_base);
+        }
+    }
+
+    /// Records a gap longer than twice the nominal frame interval between
+    /// the output frame with pts `pts` and the previous one.
+    fn check_pts_gap(&mut self, pts: i64) {
+        let frame_index = self.ordered_frames;
+        self.ordered_frames += 1;
+        let last_pts = self.last_ordered_pts.replace(pts);
+        let (last_pts, interval_ms) = match (last_pts, self.nominal_interval_ms) {
+            (Some(last_pts), Some(interval_ms)) => (last_pts, interval_ms),
+            _ => return,
+        };
+        let gap_ms = (pts - last_pts) as f64 * f64::from(self.encoder_time_base) * 1000.0;
+        if gap_ms > 2.0 * interval_ms {
+            warn!(
+                "PTS gap of {:.1} ms between the output frames {} and {}",
+                gap_ms,
+                frame_index - 1,
+                frame_index
+            );
+            self.pts_gaps.push(PtsGap {
+                frame_index,
+                gap_ms,
+            });
+        }
+    }
+
+    fn encode_ordered_frame(
+        &mut self,
+        mut frame: frame::Video,
+        time: f64,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) {
+        let pts = time_to_pts(time, self.encoder_time_base, self.pts_rounding);
+        let pts_new = self.apply_pts_offset(pts);
+        if let Some(csv) = self.pts_csv.as_mut() {
+            let written = writeln!(
+                csv,
+                "{},{:.6},{:.6},{}",
+                frame.pts().map_or(String::new(), |pts| pts.to_string()),
+                frame.pts().unwrap_or(0) as f64 * f64::from(self.encoder_time_base),
+                time,
+                pts_new
+            );
+            disable_on_error(&mut self.pts_csv, "pts CSV", written);
+        }
+        if let Some(series) = self.drift_series.as_mut() {
+            let wallclock_time =
+                frame.pts().unwrap_or(0) as f64 * f64::from(self.encoder_time_base);
+            let written = series.push(wallclock_time, time);
+            disable_on_error(&mut self.drift_series, "drift series", written);
+        }
+        if cfg!(debug_assertions) {
+            eprintln!("  pts={:?} pts_new={:?}", frame.pts(), pts_new);
+        }
+        self.check_pts_gap(pts_new);
+        frame.set_pts(Some(pts_new));
+        match self.fps_filter.as_mut() {
+            Some(filter) => match filter.send(Some(&frame)) {
+                Ok(()) => self.receive_and_process_resampled_frames(octx, ost_time_base),
+                Err(e) => {
+                    self.disable_resampling("feed", e);
+                    self.encode_output_frame(frame, octx, ost_time_base);
+                }
+            },
+            None => self.encode_output_frame(frame, octx, ost_time_base),
+        }
+    }
+
+    /// Falls back to the recognized frame rate when the fps filter fails to
+    /// `action` (feed or flush), so that the transcoding goes on.
+    fn disable_resampling(&mut self, action: &str, e: ffmpeg::Error) {
+        warn!(
+            "Failed to {} the fps filter, disabling the resampling: {}",
+            action, e
+        );
+        self.fps_filter = None;
+    }
+
+    fn receive_and_process_resampled_frames(
+        &mut self,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) {
+        while let Some(filter) = self.fps_filter.as_mut() {
+            let time_base = filter.time_base();
+            let mut frame = match filter.receive() {
+                Some(frame) => frame,
+                None => break,
+            };
+            frame.set_pts(
+                frame
+                    .pts()
+                    .map(|pts| pts.rescale(time_base, self.encoder_time_base)),
+            );
+            self.encode_output_frame(frame, octx, ost_time_base);
+        }
+    }
+
+    /// Returns true if the output reached the target frame count or the frame
+    /// at `pts` is past the target duration.
+    fn past_target(&self, pts: i64) -> bool {
+        self.target_frames
+            .is_some_and(|target_frames| self.output_frames >= target_frames)
+            || self.target_duration.is_some_and(|target_duration| {
+                pts - self.first_output_pts.unwrap_or(pts) >= target_duration
+            })
+    }
+
+    /// Encodes a frame, stopping once the target frame count or duration is
+    /// reached.
+    fn encode_output_frame(
+        &mut self,
+        frame: frame::Video,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) {
+        let pts = frame.pts().unwrap_or(0);
+        if self.past_target(pts) {
+            self.truncated_frames += 1;
+            return;
+        }
+        self.first_output_pts.get_or_insert(pts);
+        if self.target_frames.is_some() || self.target_duration.is_some() {
+            if let Some(last_pts) = self.last_output_frame.as_ref().and_then(|f| f.pts()) {
+                if pts > last_pts {
+                    self.target_frame_duration = pts - last_pts;
+                }
+            }
+            self.last_output_frame = Some(frame.clone());
+        }
+        if let Some(subtitles) = self.subtitles.as_mut() {
+            if let Some(cue) = frame.metadata().get(CUE_METADATA_KEY) {
+                let time = frame.pts().unwrap_or(0) as f64 * f64::from(self.encoder_time_base);
+                let written = subtitles.push(time, cue);
+                disable_on_error(&mut self.subtitles, "subtitle report", written);
+            }
+        }
+        self.send_frame_to_encoder(&frame);
+        self.receive_and_process_encoded_packets(octx, ost_time_base);
+    }
+
+    /// Flushes the frames buffered for the output resampling, padding the
+    /// output up to the target frame count or duration.
+    pub fn flush_frames(&mut self, octx: &mut format::context::Output, ost_time_base: Rational) {
+        if let Some(csv) = self.frames_csv.as_mut() {
+            let flushed = csv.flush();
+            disable_on_error(&mut self.frames_csv, "frames CSV", flushed);
+        }
+        if let Some(csv) = self.pts_csv.as_mut() {
+            let flushed = csv.flush();
+            disable_on_error(&mut self.pts_csv, "pts CSV", flushed);
+        }
+        if let Some(series) = self.drift_series.as_mut() {
+            let flushed = series.flush();
+            disable_on_error(&mut self.drift_series, "drift series", flushed);
+        }
+        if let Some(filter) = self.fps_filter.as_mut() {
+            match filter.send(None) {
+                Ok(()) => self.receive_and_process_resampled_frames(octx, ost_time_base),
+                Err(e) => self.disable_resampling("flush", e),
+            }
+            self.fps_filter = None;
+        }
+        if let Some(last_frame) = self.last_output_frame.take() {
+            let padding_frame = match self.padding {
+                Padding::Repeat => last_frame.clone(),
+                Padding::Black => black_frame(&last_frame),
+            };
+            let mut pts = last_frame.pts().unwrap_or(0) + self.target_frame_duration;
+            while !self.past_target(pts) {
+                let mut frame = padding_frame.clone();
+                frame.set_pts(Some(pts));
+                self.encode_output_frame(frame, octx, ost_time_base);
+                self.padded_frames += 1;
+                pts += self.target_frame_duration;
+            }
+            if self.padded_frames > 0 || self.truncated_frames > 0 {
+                debug!(
+                    "Output padded with {} frames, truncated by {} frames",
+                    self.padded_frames, self.truncated_frames
+                );
+            }
+        }
+        if let Some(subtitles) = self.subtitles.as_mut() {
+            let finished = subtitles.finish();
+            disable_on_error(&mut self.subtitles, "subtitle report", finished);
+        }
+    }
+
+    /// Adds the current pts offset to a recognized pts, updating the offset at
+    /// segment boundaries so that the output pts stay monotonic.
+    fn apply_pts_offset(&mut self, pts: i64) -> i64 {
+        if self.segment_start_pending {
+            self.segment_start_pending = false;
+            if let Some(last_pts) = self.last_pts {
+                if pts + self.pts_offset <= last_pts {
+                    self.pts_offset = last_pts + self.last_pts_delta - pts;
+                    debug!("Segment pts offset: {}", self.pts_offset);
+                }
+            }
+            if let Some(segment) = self.segments.last_mut() {
+                segment.pts_offset = self.pts_offset;
+            }
+        }
+        let pts = pts + self.pts_offset;
+        if let Some(last_pts) = self.last_pts {
+            if pts > last_pts {
+                self.last_pts_delta = pts - last_pts;
+            }
+        }
+        self.last_pts = Some(pts);
+        pts
+    }
+
+    fn send_frame_to_encoder(&mut self, output_frame: &frame::Video) {
+        if self.aborted() {
+            return;
+        }
+        let _span = debug_span!("encode", pts = ?output_frame.pts()).entered();
+        // The extra outputs keep the full frames.
+        let filtered_frame;
+        let frame = match self.output_filter.as_mut() {
+            Some(filter) => {
+                filtered_frame = filter.apply(output_frame).unwrap();
+                &filtered_frame
+            }
+            None => output_frame,
+        };
+        let mut converted_frame = frame::Video::empty();
+        let frame = if frame.format() == self.encoder.format() {
+            frame
+        } else {
+            // Reuse the scaling context unless the frame format or resolution changes.
+            if self.encoder_scaling_ctx.as_ref().map_or(true, |ctx| {
+                let input = ctx.input();
+                input.format != frame.format()
+                    || input.width != frame.width()
+                    || input.height != frame.height()
+            }) {
+                debug!(
+                    "Converting the frames from {:?} to {:?} for the encoder",
+                    frame.format(),
+                    self.encoder.format()
+                );
+                self.encoder_scaling_ctx = Some(
+                    software::scaling::Context::get(
+                        frame.format(),
+                        frame.width(),
+                        frame.height(),
+                        self.encoder.format(),
+                        self.encoder.width(),
+                        self.encoder.height(),
+                        software::scaling::Flags::BILINEAR,
+                    )
+                    .unwrap(),
+                );
+            }
+            self.encoder_scaling_ctx
+                .as_mut()
+                .unwrap()
+                .run(frame, &mut converted_frame)
+                .unwrap();
+            converted_frame.set_pts(frame.pts());
+            &converted_frame
+        };
+        // The noise is a best-effort test aid: the filter is dropped on errors.
+        let noisy_frame = match self.noise_filter.as_mut().map(|filter| filter.apply(frame)) {
+            Some(Ok(noisy_frame)) => Some(noisy_frame),
+            Some(Err(e)) => {
+                warn!("Failed to apply the noise filter, disabling it: {}", e);
+                self.noise_filter = None;
+                None
+            }
+            None => None,
+        };
+        let frame = noisy_frame.as_ref().unwrap_or(frame);
+        match self.scene_filter.as_mut() {
+            Some(filter) => {
+                let mut frame = filter.apply(frame).unwrap();
+                if frame.metadata().get(SCENE_CUT_METADATA_KEY).is_some() {
+                    debug!("Scene cut keyframe at pts {:?}", frame.pts());
+                    frame.set_kind(picture::Type::I);
+                }
+                self.encoder.send_frame(&frame).unwrap();
+            }
+            None => self.encoder.send_frame(frame).unwrap(),
+        }
+        for extra_output in self.extra_outputs.iter_mut() {
+            // The filter source takes the frame data ownership.
+            extra_output.send_frame(Some(&output_frame.clone()));
+        }
+        self.output_frames += 1;
+    }
+
+    pub fn send_eof_to_encoder(&mut self) {
+        self.encoder.send_eof().unwrap();
+    }
+
+    pub fn receive_and_process_encoded_packets(
+        &mut self,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) {
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(self.ost_index);
+            if let Some(id) = self.metadata_id.as_deref() {
+                let pts = encoded.pts().unwrap_or(0);
+                let timestamp_ms = self.watermark_timestamps.remove(&pts).unwrap_or_else(|| {
+                    watermark_timestamp_ms(TimestampBase::Pts, pts, self.encoder_time_base, 0)
+                });
+                set_timestamp_metadata(&mut encoded, id, timestamp_ms);
+                self.metadata_frames += 1;
+            }
+            if let Some(pts) = encoded.pts() {
+                self.encoded_pts_range = Some(match self.encoded_pts_range {
+                    Some((first, last)) => (first.min(pts), last.max(pts)),
+                    None => (pts, pts),
+                });
+            }
+            self.encoded_bytes += encoded.size() as u64;
+            encoded.rescale_ts(self.encoder_time_base, ost_time_base);
+            encoded.write_interleaved(octx).unwrap();
+            self.encoded_frames += 1;
+        }
+    }
+
+    fn log_progress(&mut self, timestamp: f64) {
+        if !self.logging_enabled
+            || (self.frame_count - self.last_log_frame_count < 100
+                && self.last_log_time.elapsed().as_secs_f64() < 1.0)
+        {
+            return;
+        }
+        event!(
+            Level::DEBUG,
+            frames = self.frame_count,
+            total_frames = self.total_frames,
+            timestamp,
+            failed_frames = self.failed_frames,
+            "Progress"
+        );
+        self.last_log_frame_count = self.frame_count;
+        self.last_log_time = Instant::now();
+    }
+
+    /// The number of frames whose timestamp was carried by the packet
+    /// metadata.
+    pub fn metadata_frames(&self) -> usize {
+        self.metadata_frames
+    }
+
+    pub fn visual_frames(&self) -> usize {
+        self.visual_frames
+    }
+
+    /// The intervals (ms) between consecutive recognized frames.
+    pub fn intervals_ms(&self) -> &[f64] {
+        &self.intervals_ms
+    }
+
+    /// The nominal frame interval (ms), from the input frame rate.
+    pub fn nominal_interval_ms(&self) -> Option<f64> {
+        self.nominal_interval_ms
+    }
+
+    pub fn inflight_frames_high_water(&self) -> usize {
+        self.inflight_frames_high_water
+    }
+
+    pub fn output_frames(&self) -> usize {
+        self.output_frames
+    }
+
+    /// The number of frames added to reach the target frame count or duration.
+    pub fn padded_frames(&self) -> usize {
+        self.padded_frames
+    }
+
+    /// The number of frames timestamped from the audio markers.
+    pub fn audio_frames(&self) -> usize {
+        self.audio_frames
+    }
+
+    /// The number of frames dropped past the target frame count or duration.
+    pub fn truncated_frames(&self) -> usize {
+        self.truncated_frames
+    }
+
+    /// The number of watermarked frames dropped by the simulated packet loss.
+    pub fn simulated_lost_frames(&self) -> usize {
+        self.simulated_lost_frames
+    }
+
+    /// The number of encoded frames written to the output.
+    pub fn encoded_frames(&self) -> usize {
+        self.encoded_frames
+    }
+
+    /// The size (bytes) of the encoded packets.
+    pub fn encoded_bytes(&self) -> u64 {
+        self.encoded_bytes
+    }
+
+    /// The time span (seconds) between the first and the last encoded frame.
+    pub fn encoded_span(&self) -> f64 {
+        self.encoded_pts_range.map_or(0.0, |(first, last)| {
+            (last - first) as f64 * f64::from(self.encoder_time_base)
+        })
+    }
+
+    /// The average bitrate (bits/s) of the encoded video.
+    pub fn encoded_bitrate(&self) -> Option<f64> {
+        average_bitrate(self.encoded_bytes, self.encoded_frames, self.encoded_span())
+    }
+
+    /// The average number of encoded bits per pixel.
+    pub fn encoded_bits_per_pixel(&self) -> Option<f64> {
+        let pixels =
+            self.encoded_frames as f64 * self.encoder.width() as f64 * self.encoder.height() as f64;
+        if pixels > 0.0 {
+            Some(self.encoded_bytes as f64 * 8.0 / pixels)
+        } else {
+            None
+        }
+    }
+
+    pub fn reordered_frames(&self) -> usize {
+        self.reordered_frames
+    }
+
+    pub fn max_reorder_displacement(&self) -> usize {
+        self.max_reorder_displacement
+    }
+
+    /// The number of timestamp reads discarded as truncated by an occlusion.
+    pub fn truncated_reads(&self) -> usize {
+        self.truncated_reads
+    }
+
+    pub fn id_switches(&self) -> usize {
+        self.id_switches
+    }
+
+    pub fn id_transitions(&self) -> &[IdTransition] {
+        &self.id_transitions
+    }
+
+    pub fn interpolated_frames(&self) -> usize {
+        self.interpolated_frames
+    }
+
+    /// The number of frames recognized only after retrying with the Lanczos
+    /// scaler.
+    pub fn hq_rescued_frames(&self) -> usize {
+        self.hq_rescued_frames
+    }
+
+    pub fn blank_frames(&self) -> usize {
+        self.blank_frames
+    }
+
+    pub fn ost_index(&self) -> usize {
+        self.ost_index
+    }
+
+    /// The number of failed frames, excluding the trimmed ones.
+    pub fn failed_frames(&self) -> usize {
+        self.failed_frames - self.trimmed_tail_frames()
+    }
+
+    /// The number of frames dropped before the first recognized frame.
+    pub fn trimmed_head_frames(&self) -> usize {
+        self.trimmed_head_frames
+    }
+
+    /// The number of frames dropped after the last recognized frame.
+    pub fn trimmed_tail_frames(&self) -> usize {
+        if self.trim_to_recognized {
+            self.trailing_failed_frames
+        } else {
+            0
+        }
+    }
+
+    /// Returns the id recognized in most of the frames, so that a few
+    /// misread frames don't change it, or the id of the audio markers when no
+    /// frame was recognized.
+    pub fn recognized_id(&self) -> Option<&String> {
+        majority_id(&self.id_counts)
+            .map(|(id, _)| id)
+            .or(self.recognized_id.as_ref())
+    }
+
+    /// The number of frames matched by each `--timestamp-pattern`, in order.
+    pub fn pattern_matches(&self) -> &[usize] {
+        &self.pattern_matches
+    }
+
+    /// Returns the fraction of the recognized frames agreeing on
+    /// `recognized_id`.
+    pub fn id_agreement(&self) -> Option<f64> {
+        majority_id(&self.id_counts).map(|(_, agreement)| agreement)
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    pub fn deinterlaced(&self) -> bool {
+        self.deinterlaced
+    }
+
+    /// The media time (seconds) of the last frame sent to the encoder in watermark mode.
+    pub fn last_frame_time(&self) -> f64 {
+        self.last_frame_time
+    }
+
+    /// The (input time, recognized time) pairs of the recognized frames.
+    pub fn sync_points(&self) -> &[(f64, f64)] {
+        &self.sync_points
+    }
+
+    /// Fits the capture clock drift over all the recognized frames.
+    pub fn drift(&self) -> Option<DriftStats> {
+        fit_drift(&self.sync_points)
+    }
+
+    /// The gaps between consecutive output frames.
+    pub fn pts_gaps(&self) -> &[PtsGap] {
+        &self.pts_gaps
+    }
+
+    pub fn segments(&self) -> &[SegmentStats] {
+        &self.segments
+    }
+}
+
+/// Clips the `(x, y, width, height)` region to a `width` x `height` image,
+/// `None` if they don't overlap.
+pub fn clip_region(
+    region: (u32, u32, u32, u32),
+    width: u32,
+    height: u32,
+) -> Option<(u32, u32, u32, u32)> {
+    let (x, y, region_width, region_height) = region;
+    let right = x.saturating_add(region_width).min(width);
+    let bottom = y.saturating_add(region_height).min(height);
+    if x >= right || y >= bottom {
+        return None;
+    }
+    Some((x, y, right - x, bottom - y))
+}
+
+/// Returns the input frame rate: `fps` when set, otherwise the decoder frame
+/// rate if valid (e.g. the IVF files exported by WebRTC can declare `0/0`).
+pub fn input_frame_rate(
+    decoder_frame_rate: Option<Rational>,
+    fps: Option<f64>,
+) -> Option<Rational> {
+    match fps {
+        Some(fps) => Some(Rational::from(fps)),
+        None => decoder_frame_rate.filter(|r| r.numerator() > 0 && r.denominator() > 0),
+    }
+}
+
+/// Returns the time base of the frames after decoding, the one of the
+/// `FrameView` pts: with field rate deinterlacing the frame rate is doubled,
+/// so the input time base needs twice the resolution.
+pub(crate) fn encoder_time_base(input_time_base: Rational, config: &Config) -> Rational {
+    if config.deinterlace != Deinterlace::Off && config.deinterlace_field_rate {
+        input_time_base * Rational(1, 2)
+    } else {
+        input_time_base
+    }
+}
+
+/// Creates and opens the encoder for the decoded frames. `gop` sets the
+/// keyframe interval (see `Config::gop`). `crf` sets the VP8 and AV1
+/// constant rate factor, `DEFAULT_CRF` when `None`. The `options` of the
+/// codec override the default encoder options.
+#[allow(clippy::too_many_arguments)]
+pub fn open_encoder<P: Into<VideoParams>>(
+    params: P,
+    codec_id: codec::Id,
+    time_base: Rational,
+    frame_rate: Option<Rational>,
+    global_header: bool,
+    keyframes: Keyframes,
+    gop: Option<u32>,
+    crf: Option<u32>,
+    options: Option<&EncoderOptions>,
+) -> Result<encoder::Video, ffmpeg::Error> {
+    let params = params.into();
+    let gop = gop.filter(|gop| *gop > 0);
+    let user_opts = match options {
+        Some(options) => options.0.encoder_opts(codec_id.name()).map_err(|e| {
+            warn!("Invalid {} encoder options: {}", codec_id.name(), e);
+            ffmpeg::Error::InvalidData
+        })?,
+        None => Dictionary::new(),
+    };
+    let with_user_opts = |mut opts: Dictionary<'static>| {
+        for (key, value) in user_opts.iter() {
+            opts.set(key, value);
+        }
+        opts
+    };
+    let codec = if codec_id == codec::Id::AV1 {
+        encoder::find_by_name(SVT_AV1_ENCODER)
+    } else {
+        encoder::find(codec_id)
+    }
+    .ok_or(ffmpeg::Error::EncoderNotFound)?;
+    let crf = crf.unwrap_or(DEFAULT_CRF).min(63);
+    let mut encoder = codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    encoder.set_height(params.height);
+    encoder.set_width(params.width);
+    encoder.set_aspect_ratio(params.aspect_ratio);
+    encoder.set_frame_rate(frame_rate);
+    encoder.set_time_base(time_base);
+
+    if global_header {
+        encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+
+    if codec_id == codec::Id::WRAPPED_AVFRAME {
+        // The y4m frames are written uncompressed.
+        encoder.set_format(y4m_format(params.format));
+        set_color_properties(&mut encoder, params.color);
+        return encoder.open();
+    }
+
+    if codec_id == codec::Id::AV1 {
+        encoder.set_format(encoder_format(codec, params.format));
+        // The scene cut keyframes are forced on the frames, see `SCENE_CUT_METADATA_KEY`.
+        encoder.set_gop(match keyframes {
+            Keyframes::All => gop.unwrap_or(1),
+            Keyframes::SceneCut => gop.unwrap_or(SCENE_CUT_MAX_KEYFRAME_INTERVAL),
+        });
+        set_color_properties(&mut encoder, params.color);
+        // The SVT-AV1 crf range starts from 1.
+        let opts = format!("preset={},crf={}", SVT_AV1_PRESET, crf.max(1));
+        return encoder.open_with(with_user_opts(parse_opts(&opts).unwrap()));
+    }
+
+    if codec_id != codec::Id::VP8 {
+        // The image sequences are written as RGB.
+        encoder.set_format(format::Pixel::RGB24);
+        return encoder.open_with(with_user_opts(Dictionary::new()));
+    }
+
+    encoder.set_format(encoder_format(codec, params.format));
+    // With the crf, libvpx runs in constrained quality mode with this target
+    // bitrate; the `b` encoder option overrides it.
+    encoder.set_bit_rate(20000);
+    encoder.set_threading(threading::Config::count(0));
+    // libvpx requires the crf within the quantizer range.
+    let mut opts = format!(
+        "quality=best,cpu-used=0,crf={},qmin=1,qmax={}",
+        crf,
+        crf.max(10)
+    );
+    match keyframes {
+        Keyframes::All => {
+            // A fixed interval: the equal kf-min-dist keeps libvpx from
+            // placing its own keyframes before kf-max-dist.
+            let gop = gop.unwrap_or(1);
+            encoder.set_gop(gop);
+            opts.push_str(&format!(",kf-min-dist={},kf-max-dist={}", gop, gop));
+        }
+        Keyframes::SceneCut => {
+            // The scene cut keyframes are forced on the frames, see `SCENE_CUT_METADATA_KEY`.
+            encoder.set_gop(gop.unwrap_or(SCENE_CUT_MAX_KEYFRAME_INTERVAL));
+        }
+    };
+    set_color_properties(&mut encoder, params.color);
+    let encoder_opts = with_user_opts(parse_opts(&opts).unwrap());
+    encoder.open_with(encoder_opts)
+}
+
+/// Fails if the encoder of `codec` isn't available in the linked ffmpeg.
+pub fn check_video_codec(codec: VideoCodec) -> Result<(), String> {
+    match codec {
+        VideoCodec::Av1 if encoder::find_by_name(SVT_AV1_ENCODER).is_none() => Err(format!(
+            "the AV1 output requires ffmpeg built with {}",
+            SVT_AV1_ENCODER
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Returns `format` if the codec supports it, otherwise the first format
+/// supported by the codec (e.g. `yuv420p` for NV12 or UYVY inputs with VP8).
+pub fn encoder_format(codec: codec::Codec, format: format::Pixel) -> format::Pixel {
+    let formats: Vec<format::Pixel> = match codec.video().ok().and_then(|v| v.formats()) {
+        Some(formats) => formats.collect(),
+        None => return format,
+    };
+    if formats.contains(&format) {
+        format
+    } else {
+        formats.first().copied().unwrap_or(format)
+    }
+}
+
+/// Returns the pixel format of the frames sent to the `codec_id` encoder
+/// opened by `open_encoder` for `format` frames, `None` if the encoder isn't
+/// available.
+pub fn encoder_input_format(codec_id: codec::Id, format: format::Pixel) -> Option<format::Pixel> {
+    match codec_id {
+        codec::Id::WRAPPED_AVFRAME => Some(y4m_format(format)),
+        codec::Id::AV1 => {
+            encoder::find_by_name(SVT_AV1_ENCODER).map(|codec| encoder_format(codec, format))
+        }
+        codec::Id::VP8 => encoder::find(codec_id).map(|codec| encoder_format(codec, format)),
+        _ => Some(format::Pixel::RGB24),
+    }
+}
+
+/// Returns the pixel format of `color_space`.
+pub fn color_space_format(color_space: ColorSpace) -> format::Pixel {
+    match color_space {
+        ColorSpace::Yuv420p => format::Pixel::YUV420P,
+        ColorSpace::Yuv422p => format::Pixel::YUV422P,
+        ColorSpace::Rgb24 => format::Pixel::RGB24,
+    }
+}
+
+/// Fails if the encoder of `codec_id` (see `output_codec`) doesn't support
+/// the `color_space` pixel format.
+pub fn check_color_space(codec_id: codec::Id, color_space: ColorSpace) -> Result<(), String> {
+    let format = color_space_format(color_space);
+    let supported: Vec<format::Pixel> = if codec_id == codec::Id::WRAPPED_AVFRAME {
+        if y4m_format(format) == format {
+            return Ok(());
+        }
+        vec![format::Pixel::YUV420P, format::Pixel::YUV422P]
+    } else {
+        let codec = if codec_id == codec::Id::AV1 {
+            encoder::find_by_name(SVT_AV1_ENCODER)
+        } else {
+            encoder::find(codec_id)
+        };
+        match codec
+            .and_then(|codec| codec.video().ok())
+            .and_then(|video| video.formats())
+        {
+            Some(formats) => formats.collect(),
+            None => return Ok(()),
+        }
+    };
+    if supported.contains(&format) {
+        return Ok(());
+    }
+    let names: Vec<&str> = supported
+        .iter()
+        .filter_map(|format| format.descriptor())
+        .map(|descriptor| descriptor.name())
+        .collect();
+    Err(format!(
+        "the {} pixel format is not supported by the {} encoder (supported: {})",
+        color_space.name(),
+        codec_id.name(),
+        names.join(", ")
+    ))
+}
+
+/// Returns the output video codec: PNG for image sequences, uncompressed
+/// frames for y4m, `codec` otherwise.
+pub fn output_codec(octx: &format::context::Output, codec: VideoCodec) -> codec::Id {
+    match (octx.format().name(), codec) {
+        ("image2", _) => codec::Id::PNG,
+        ("yuv4mpegpipe", _) => codec::Id::WRAPPED_AVFRAME,
+        (_, VideoCodec::Vp8) => codec::Id::VP8,
+        (_, VideoCodec::Av1) => codec::Id::AV1,
+    }
+}
+
+/// Returns `format` if the y4m muxer supports it, otherwise `yuv420p`.
+fn y4m_format(format: format::Pixel) -> format::Pixel {
+    match format {
+        format::Pixel::GRAY8
+        | format::Pixel::YUV411P
+        | format::Pixel::YUV420P
+        | format::Pixel::YUV422P
+        | format::Pixel::YUV444P
+        | format::Pixel::YUVJ420P
+        | format::Pixel::YUVJ422P
+        | format::Pixel::YUVJ444P
+        | format::Pixel::YUV420P10LE
+        | format::Pixel::YUV422P10LE
+        | format::Pixel::YUV444P10LE => format,
+        _ => format::Pixel::YUV420P,
+    }
+}
+
+/// The environment variable overriding the cache directory.
+pub const CACHE_DIR_ENV: &str = "WEBRTCPERF_CACHE_DIR";
+/// The former name of `CACHE_DIR_ENV`, still accepted.
+pub const LEGACY_CACHE_DIR_ENV: &str = "WEBRTCPERF_CACHE";
+
+/// Returns the directory of the downloaded font and Tesseract data, creating
+/// it if missing: `cache_dir` when set, then `$WEBRTCPERF_CACHE_DIR` (or
+/// `$WEBRTCPERF_CACHE`), then `$HOME/.webrtcperf/cache`, falling back to a
+/// temporary directory (e.g. in services running without a home directory).
+pub fn cache_dir(cache_dir: Option<&str>) -> Result<String, Box<dyn std::error::Error>> {
+    let env_var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    let dir = match cache_dir
+        .map(str::to_string)
+        .or_else(|| env_var(CACHE_DIR_ENV))
+        .or_else(|| env_var(LEGACY_CACHE_DIR_ENV))
+    {
+        Some(dir) => dir,
+        None => match env_var("HOME") {
+            Some(home_dir) => format!("{}/.webrtcperf/cache", home_dir),
+            None => std::env::temp_dir()
+                .join("webrtcperf-cache")
+                .to_string_lossy()
+                .to_string(),
+        },
+    };
+    std::fs::create_dir_all(&dir)?;
+    debug!("Cache directory: {}", dir);
+    Ok(dir)
+}
+
+/// Writes a cache file atomically: `write` fills a temporary file unique to
+/// the calling process and thread, which is then renamed to `path`. The
+/// processes sharing the cache directory never read a partially written
+/// file; when several of them download the same file, the last rename wins
+/// with the same content.
+pub(crate) fn write_cache_file<F>(path: &str, write: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(&mut std::fs::File) -> Result<(), Box<dyn std::error::Error>>,
+{
+    static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+    let temp_path = format!(
+        "{}.{}-{}.tmp",
+        path,
+        std::process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    );
+    let result = std::fs::File::create(&temp_path)
+        .map_err(|e| e.into())
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()?;
+            Ok(())
+        })
+        .and_then(|_| match std::fs::rename(&temp_path, path) {
+            Ok(()) => Ok(()),
+            // Completed concurrently by another process, on the platforms
+            // where the rename doesn't replace an existing file.
+            Err(e) if std::path::Path::new(path).exists() => {
+                debug!("{} written concurrently: {}", path, e);
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        });
+    if std::path::Path::new(&temp_path).exists() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Returns the path of the watermark font, downloading it into the cache
+/// directory if missing.
+pub fn font_path(cache_dir_override: Option<&str>) -> Result<String, Box<dyn std::error::Error>> {
+    let font_dir = cache_dir(cache_dir_override)?;
+    let font_path = format!("{}/NotoMono-Regular.ttf", font_dir);
+    if !std::path::Path::new(&font_path).exists() {
+        // Download the file from the URL
+        debug!("Downloading font file");
+        let mut response = reqwest::blocking::get(
+            "https://cdn.jsdelivr.net/npm/@typopro/web-noto@3.7.5/TypoPRO-NotoMono-Regular.ttf",
+        )?
+        .error_for_status()?;
+        write_cache_file(&font_path, |file| {
+            std::io::copy(&mut response, file)?;
+            Ok(())
+        })?;
+    }
+    Ok(font_path)
+}
+
+/// The Matroska BlockAddID of the timestamp metadata. The muxer writes only
+/// the codec specific (opaque) block additions.
+const TIMESTAMP_BLOCK_ADD_ID: u64 = 1;
+
+/// Parses the `<id>-<timestamp>` metadata attached to a packet in watermark
+/// mode (see `set_timestamp_metadata`), returning the id and the time
+/// (seconds).
+pub(crate) fn packet_timestamp_metadata(packet: &Packet) -> Option<(String, f64)> {
+    let data = packet
+        .side_data()
+        .find(|s| s.kind() == side_data::Type::MatroskaBlockAdditional)?
+        .data()
+        .to_vec();
+    if data.len() < 8 || data[..8] != TIMESTAMP_BLOCK_ADD_ID.to_be_bytes() {
+        return None;
+    }
+    let text = parse_timestamp_text(
+        timestamp_regex(),
+        &String::from_utf8_lossy(&data[8..]),
+        None,
+    )?;
+    Some((text.id, text.time))
+}
+
+/// Attaches the `<id>-<timestamp>` metadata to a packet as a Matroska block
+/// additional (8 bytes big endian BlockAddID + data).
+fn set_timestamp_metadata(packet: &mut Packet, id: &str, time_ms: u64) {
+    let text = format!("{}-{}", id, time_ms);
+    let size = 8 + text.len();
+    unsafe {
+        let data = ffmpeg::ffi::av_packet_new_side_data(
+            packet.as_mut_ptr(),
+            side_data::Type::MatroskaBlockAdditional.into(),
+            size as _,
+        );
+        if data.is_null() {
+            eprintln!("failed to add the timestamp metadata");
+            return;
+        }
+        let data = std::slice::from_raw_parts_mut(data, size);
+        data[..8].copy_from_slice(&TIMESTAMP_BLOCK_ADD_ID.to_be_bytes());
+        data[8..].copy_from_slice(text.as_bytes());
+    }
+}
+
+/// Returns the average bitrate (bits/s) of `frames` frames of `bytes` bytes
+/// whose pts span `span` seconds, counting the duration of the last frame as
+/// the average frame interval. `None` with less than two frames.
+pub fn average_bitrate(bytes: u64, frames: usize, span: f64) -> Option<f64> {
+    if frames < 2 || span <= 0.0 {
+        return None;
+    }
+    let duration = span * frames as f64 / (frames - 1) as f64;
+    Some(bytes as f64 * 8.0 / duration)
+}
+
+/// Returns the number of `width`x`height` frames fitting in the in-flight
+/// frames memory `budget` (bytes), assuming 4:2:0 frames.
+fn default_max_inflight_frames(width: u32, height: u32, budget: usize) -> usize {
+    let frame_size = (width as usize * height as usize * 3 / 2).max(1);
+    (budget / frame_size).max(1)
+}
+
+/// An `<id>-<timestamp>` overlay text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimestampText {
+    pub id: String,
+    /// The timestamp (seconds).
+    pub time: f64,
+    /// The number of timestamp digits.
+    pub time_digits: usize,
+}
+
+/// The pattern of the `<id>-<timestamp>` overlay text, where the timestamp is
+/// in milliseconds or a `HH:MM:SS:FF` timecode.
+pub const TIMESTAMP_PATTERN: &str = r"(?<id>[0-9]{1,3})-(?:(?<timecode>[0-9]{2}:[0-9]{2}:[0-9]{2}:[0-9]{2})|(?<time>[0-9]{1,13}(?:\.[0-9]{1,3})?))";
+
+static TIMESTAMP_RE: Lazy<Regex> = Lazy::new(|| Regex::new(TIMESTAMP_PATTERN).unwrap());
+
+/// Matches the timestamp region text of a locked id.
+static TIME_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?:(?<timecode>[0-9]{2}:[0-9]{2}:[0-9]{2}:[0-9]{2})|(?<time>[0-9]{1,13}(?:\.[0-9]{1,3})?))$")
+        .unwrap()
+});
+
+/// Returns the regex matching the `<id>-<timestamp>` overlay text, compiled
+/// once per process.
+pub fn timestamp_regex() -> &'static Regex {
+    &TIMESTAMP_RE
+}
+
+/// Compiles a custom overlay text pattern, which needs an `id` and a `time`
+/// (ms) or `timecode` named group.
+pub fn timestamp_pattern_regex(pattern: &str) -> Result<Regex, String> {
+    let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+    let names: Vec<&str> = regex.capture_names().flatten().collect();
+    if !names.contains(&"id") {
+        return Err(format!("the pattern {} has no id group", pattern));
+    }
+    if !names.contains(&"time") && !names.contains(&"timecode") {
+        return Err(format!(
+            "the pattern {} has no time or timecode group",
+            pattern
+        ));
+    }
+    Ok(regex)
+}
+
+/// Returns the compiled `Config::timestamp_patterns`, or the default
+/// `TIMESTAMP_PATTERN` when empty.
+pub fn timestamp_regexes(config: &Config) -> Result<Vec<Regex>, String> {
+    if config.timestamp_patterns.is_empty() {
+        return Ok(vec![timestamp_regex().clone()]);
+    }
+    config
+        .timestamp_patterns
+        .iter()
+        .map(|pattern| timestamp_pattern_regex(pattern))
+        .collect()
+}
+
+/// Parses a recognized text with the first of `regexes` matching it,
+/// returning the index of the matching regex.
+pub fn parse_timestamp_text_any(
+    regexes: &[Regex],
+    text: &str,
+    timecode_fps: Option<f64>,
+) -> Option<(usize, TimestampText)> {
+    regexes.iter().enumerate().find_map(|(index, regex)| {
+        parse_timestamp_text(regex, text, timecode_fps).map(|text| (index, text))
+    })
+}
+
+/// Returns the id with the highest count (the lowest one on ties) and the
+/// fraction of the total count it represents.
+pub fn majority_id(counts: &HashMap<String, usize>) -> Option<(&String, f64)> {
+    let total: usize = counts.values().sum();
+    counts
+        .iter()
+        .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+        .map(|(id, count)| (id, *count as f64 / total as f64))
+}
+
+/// Parses the first `<id>-<timestamp>` in a recognized text. A timecode is
+/// converted to a time with `timecode_fps`, and isn't accepted without it.
+pub fn parse_timestamp_text(
+    frame_re: &Regex,
+    text: &str,
+    timecode_fps: Option<f64>,
+) -> Option<TimestampText> {
+    let c = frame_re.captures(text)?;
+    let (time, time_digits) = captured_time(&c, timecode_fps)?;
+    Some(TimestampText {
+        id: c["id"].to_string(),
+        time,
+        time_digits,
+    })
+}
+
+/// Returns the time (seconds) and the number of characters of a captured
+/// `time` (ms) or `timecode`.
+fn captured_time(c: &regex::Captures, timecode_fps: Option<f64>) -> Option<(f64, usize)> {
+    if let Some(timecode) = c.name("timecode") {
+        let fps = timecode_fps?;
+        let frame_index = parse_timecode(timecode.as_str(), fps)?;
+        return Some((frame_index as f64 / fps, timecode.len()));
+    }
+    let time = c.name("time")?;
+    let time_ms: f64 = time.as_str().parse().ok()?;
+    Some((time_ms / 1000f64, time.len()))
+}
+
+/// The frame metadata key holding the watermark timecode, drawn by `drawtext`.
+pub const TIMECODE_METADATA_KEY: &str = "webrtcperf_timecode";
+
+/// Returns the frames counted in each second of a timecode at `fps`.
+fn timecode_base(fps: f64) -> u64 {
+    (fps.round() as u64).max(1)
+}
+
+/// Formats a frame index as a `HH:MM:SS:FF` SMPTE timecode (non drop frame),
+/// counting `fps` frames per second rounded to an integer. The hours wrap
+/// at 24.
+pub fn timecode_text(frame_index: u64, fps: f64) -> String {
+    let base = timecode_base(fps);
+    let seconds = frame_index / base;
+    format!(
+        "{:02}:{:02}:{:02}:{:02}",
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60,
+        frame_index % base
+    )
+}
+
+/// Parses a `HH:MM:SS:FF` timecode written with `timecode_text`, returning
+/// the frame index.
+pub fn parse_timecode(text: &str, fps: f64) -> Option<u64> {
+    let base = timecode_base(fps);
+    let fields: Vec<u64> = text
+        .split(':')
+        .map(|field| field.parse().ok())
+        .collect::<Option<_>>()?;
+    let [hours, minutes, seconds, frames] = fields[..] else {
+        return None;
+    };
+    if hours >= 24 || minutes >= 60 || seconds >= 60 || frames >= base {
+        return None;
+    }
+    Some(((hours * 60 + minutes) * 60 + seconds) * base + frames)
+}
+
+/// The frame metadata key holding the watermark timestamp, drawn by `drawtext`.
+pub const TIMESTAMP_METADATA_KEY: &str = "webrtcperf_timestamp";
+
+/// The maximum number of fractional millisecond digits of the watermark text.
+pub const MAX_TIMESTAMP_DECIMALS: u32 = 3;
+
+/// Returns the timestamp (us) written in the watermark of the `index`-th
+/// watermarked frame. The pts are truncated to microseconds.
+pub fn watermark_timestamp_us(
+    base: TimestampBase,
+    pts: i64,
+    time_base: Rational,
+    index: u64,
+) -> u64 {
+    match base {
+        TimestampBase::Pts => (pts as i128 * 1_000_000 * time_base.numerator() as i128
+            / time_base.denominator() as i128)
+            .max(0) as u64,
+        TimestampBase::Wallclock => chrono::Utc::now().timestamp_micros() as u64,
+        TimestampBase::Monotonic => {
+            static START: OnceLock<Instant> = OnceLock::new();
+            START.get_or_init(Instant::now).elapsed().as_micros() as u64
+        }
+        TimestampBase::FrameIndex => index * 1000,
+    }
+}
+
+/// Returns the timestamp (ms) written in the watermark of the `index`-th
+/// watermarked frame. The pts are truncated to milliseconds.
+pub fn watermark_timestamp_ms(
+    base: TimestampBase,
+    pts: i64,
+    time_base: Rational,
+    index: u64,
+) -> u64 {
+    watermark_timestamp_us(base, pts, time_base, index) / 1000
+}
+
+/// Formats a timestamp (us) as the milliseconds drawn by the text overlay,
+/// with `decimals` fractional digits (truncated).
+pub fn timestamp_text(timestamp_us: u64, decimals: u32) -> String {
+    let decimals = decimals.min(MAX_TIMESTAMP_DECIMALS);
+    if decimals == 0 {
+        return (timestamp_us / 1000).to_string();
+    }
+    let fraction = timestamp_us % 1000 / 10u64.pow(MAX_TIMESTAMP_DECIMALS - decimals);
+    format!(
+        "{}.{:0width$}",
+        timestamp_us / 1000,
+        fraction,
+        width = decimals as usize
+    )
+}
+
+/// Sets a frame metadata entry, readable by the filters (e.g. `drawtext`
+/// with `%{metadata:key}`).
+pub fn set_frame_metadata(frame: &mut frame::Video, key: &str, value: &str) {
+    let key = CString::new(key).unwrap();
+    let value = CString::new(value).unwrap();
+    unsafe {
+        ffmpeg::ffi::av_dict_set(
+            &mut (*frame.as_mut_ptr()).metadata,
+            key.as_ptr(),
+            value.as_ptr(),
+            0,
+        );
+    }
+}
+
+/// Converts a recognized time (seconds) to a pts in `time_base` units.
+pub fn time_to_pts(time: f64, time_base: Rational, rounding: PtsRounding) -> i64 {
+    rounding.round(time / f64::from(time_base))
+}
+
+/// Returns the height of the overlay band drawn at the top of the frames.
+pub fn band_height(height: u32) -> u32 {
+    (height as f32 / 15.0).round() as u32
+}
+
+/// Returns the watermark id, defaulting to `1`.
+fn watermark_id_or_default(watermark_id: Option<&str>) -> &str {
+    match watermark_id {
+        Some(id) if !id.is_empty() => id,
+        _ => "1",
+    }
+}
+
+/// Returns the watermark id encoded by the bars overlay.
+pub fn bars_id(watermark_id: Option<&str>) -> Result<u32, Box<dyn std::error::Error>> {
+    let id = watermark_id_or_default(watermark_id);
+    match id.parse::<u32>() {
+        Ok(id) if id < 1000 => Ok(id),
+        _ => Err(format!("invalid watermark id {}, the bars overlay needs 0-999", id).into()),
+    }
+}
+
+/// Ensures that the frame data can be modified in place.
+pub fn make_writable(frame: &mut frame::Video) {
+    unsafe {
+        ffmpeg::ffi::av_frame_make_writable(frame.as_mut_ptr());
+    }
+}
+
+/// The default watermark text format.
+pub const DEFAULT_WATERMARK_FORMAT: &str = "{id}-{timestamp}";
+
+/// The `drawtext` text expansion functions accepted in the watermark format.
+const DRAWTEXT_FUNCTIONS: &[&str] = &[
+    "e",
+    "expr",
+    "eif",
+    "expr_int_format",
+    "gmtime",
+    "localtime",
+    "metadata",
+    "n",
+    "frame_num",
+    "pict_type",
+    "pts",
+];
+
+/// Checks a watermark text format: static text with the `{id}` and
+/// `{timestamp}` placeholders and `drawtext` `%{...}` expansions.
+pub fn check_watermark_format(format: &str) -> Result<(), String> {
+    if format.is_empty() {
+        return Err("empty watermark format".to_string());
+    }
+    if format.contains('\'') {
+        return Err(format!(
+            "quotes are not allowed in the watermark format: {}",
+            format
+        ));
+    }
+    let mut rest = format;
+    while let Some(start) = rest.find("%{") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unterminated %{{ in the watermark format: {}", format))?;
+        let expansion = &rest[start + 2..start + end];
+        let name = expansion.split([':', '\\']).next().unwrap_or_default();
+        if !DRAWTEXT_FUNCTIONS.contains(&name) {
+            return Err(format!(
+                "unknown drawtext function \"{}\" in the watermark format: {}",
+                name, format
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+/// Checks the watermark opacity, which must be in the `0-1` range. The bars
+/// overlay is decoded from the exact luma levels, so it can't be blended.
+pub fn check_watermark_opacity(opacity: Option<f32>, overlay: Overlay) -> Result<(), String> {
+    match opacity {
+        Some(opacity) if !(0.0..=1.0).contains(&opacity) => Err(format!(
+            "invalid watermark opacity {}, expected a value in 0-1",
+            opacity
+        )),
+        Some(opacity) if opacity < 1.0 && overlay == Overlay::Bars => {
+            Err("the bars overlay can't be drawn with a reduced opacity".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Expands the `{id}`, `{timestamp}` and `{timecode}` placeholders of a
+/// watermark format.
+fn watermark_text(format: &str, id: &str) -> String {
+    format
+        .replace("{id}", id)
+        .replace(
+            "{timestamp}",
+            &format!("%{{metadata\\:{}}}", TIMESTAMP_METADATA_KEY),
+        )
+        .replace(
+            "{timecode}",
+            &format!("%{{metadata\\:{}}}", TIMECODE_METADATA_KEY),
+        )
+}
+
+/// Returns the filter graph description drawing the overlay band and, when
+/// `font_path` is set, the `<id>-<timestamp>` text. With an `opacity` below 1
+/// the overlay is drawn on a copy of the frame and composited with `blend`.
+pub fn watermark_filter_desc(
+    height: u32,
+    watermark_id: Option<&str>,
+    font_path: Option<&str>,
+    watermark_format: Option<&str>,
+    opacity: Option<f32>,
+) -> String {
+    let text_height = band_height(height);
+    let band = format!("drawbox=x=0:y=0:w=iw:h={}:color=black:t=fill", text_height);
+    let overlay = match font_path {
+        Some(font_path) => {
+            let font_size = (height as f32 / 18.0).round() as i32;
+            let id = watermark_id_or_default(watermark_id);
+            format!(
+                "\
+{band},\
+drawtext=fontfile={font_path}:text='{text}'\
+:fontcolor=white:fontsize={font_size}:x=(w-text_w)/2:y=({text_height}-text_h)/2",
+                band = band,
+                text_height = text_height,
+                text = watermark_text(watermark_format.unwrap_or(DEFAULT_WATERMARK_FORMAT), id),
+                font_path = font_path,
+                font_size = font_size
+            )
+        }
+        None => band,
+    };
+    match opacity {
+        // Outside of the band the blended frames are identical.
+        Some(opacity) if opacity < 1.0 => format!(
+            "split[main][band];[band]{}[overlay];[main][overlay]blend=all_mode=normal:all_opacity={}",
+            overlay, opacity
+        ),
+        _ => overlay,
+    }
+}
+
+/// Disables the side output (e.g. the `--frames-csv` dump) in `output` when
+/// `result` is a write error, warning instead of failing the transcoding.
+fn disable_on_error<T>(output: &mut Option<T>, name: &str, result: std::io::Result<()>) {
+    if let Err(e) = result {
+        warn!("Failed to write the {}, disabling it: {}", name, e);
+        *output = None;
+    }
+}
+
+/// Returns a black copy of a planar YUV frame.
+fn black_frame(frame: &frame::Video) -> frame::Video {
+    let mut black = frame.clone();
+    for plane in 0..black.planes() {
+        black
+            .data_mut(plane)
+            .fill(if plane == 0 { 16 } else { 128 });
+    }
+    black
+}
+
+/// Opens the video decoder of a stream with the `key=val,key=val` decoder
+/// options, warning about the options unknown to the decoder.
+pub(crate) fn open_video_decoder(
+    parameters: codec::Parameters,
+    options: Option<&str>,
+) -> Result<decoder::Video, ffmpeg::Error> {
+    let context = codec::context::Context::from_parameters(parameters)?;
+    let options = match options {
+        Some(options) => parse_opts(options).map_err(|e| {
+            warn!("Invalid decoder options: {}", e);
+            ffmpeg::Error::InvalidData
+        })?,
+        None => return context.decoder().video(),
+    };
+    let codec = decoder::find(context.id()).ok_or(ffmpeg::Error::DecoderNotFound)?;
+    for (key, value) in options.iter() {
+        let name = CString::new(key).map_err(|_| ffmpeg::Error::InvalidData)?;
+        let classes = unsafe {
+            [
+                ffmpeg::ffi::avcodec_get_class(),
+                (*codec.as_ptr()).priv_class,
+            ]
+        };
+        let found = classes.iter().any(|class| {
+            !class.is_null()
+                && unsafe {
+                    !ffmpeg::ffi::av_opt_find(
+                        class as *const _ as *mut _,
+                        name.as_ptr(),
+                        std::ptr::null(),
+                        0,
+                        ffmpeg::ffi::AV_OPT_SEARCH_FAKE_OBJ as i32,
+                    )
+                    .is_null()
+                }
+        });
+        if !found {
+            warn!(
+                "Decoder option {}={} not found for {}",
+                key,
+                value,
+                codec.name()
+            );
+        }
+    }
+    context.decoder().open_as_with(codec, options)?.video()
+}
+
+/// Splits the `,` separated options, where `\,` is a comma in a value.
+fn split_options(s: &str) -> Vec<String> {
+    let mut tokens = vec![String::new()];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&',') => {
+                chars.next();
+                tokens.last_mut().unwrap().push(',');
+            }
+            ',' => tokens.push(String::new()),
+            c => tokens.last_mut().unwrap().push(c),
+        }
+    }
+    tokens
+}
+
+/// Parses the `KEY=VAL,KEY=VAL` options. The values may contain `=` (the
+/// key ends at the first one) and escaped commas (`\,`). The empty options,
+/// e.g. a trailing comma, are ignored; with duplicated keys the last value
+/// wins.
+pub fn parse_opts<'a>(s: &str) -> Result<Dictionary<'a>, OptParseError> {
+    let mut dict = Dictionary::new();
+    for token in split_options(s) {
+        if token.is_empty() {
+            continue;
+        }
+        let (key, value) = match token.split_once('=') {
+            Some((key, value)) if !key.is_empty() => (key, value),
+            _ => return Err(OptParseError { token }),
+        };
+        if let Some(previous) = dict.get(key) {
+            warn!("Duplicated option {}: {} replaces {}", key, value, previous);
+        }
+        dict.set(key, value);
+    }
+    Ok(dict)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ocr_region_is_clipped_to_the_band() {
+        assert_eq!(clip_region((0, 0, 200, 20), 640, 40), Some((0, 0, 200, 20)));
+        assert_eq!(
+            clip_region((600, 10, 200, 60), 640, 40),
+            Some((600, 10, 40, 30))
+        );
+        assert_eq!(clip_region((640, 0, 10, 10), 640, 40), None);
+        assert_eq!(clip_region((0, 0, 0, 10), 640, 40), None);
+    }
+
+    #[test]
+    fn input_frame_rate_ignores_invalid_metadata() {
+        assert_eq!(input_frame_rate(Some(Rational(0, 0)), None), None);
+        assert_eq!(input_frame_rate(Some(Rational(0, 1)), None), None);
+        assert_eq!(
+            input_frame_rate(Some(Rational(0, 0)), Some(30.0)),
+            Some(Rational(30, 1))
+        );
+        assert_eq!(
+            input_frame_rate(Some(Rational(25, 1)), Some(30.0)),
+            Some(Rational(30, 1))
+        );
+        assert_eq!(
+            input_frame_rate(Some(Rational(25, 1)), None),
+            Some(Rational(25, 1))
+        );
+    }
+
+    #[test]
+    fn average_bitrate_counts_the_last_frame() {
+        // 30 frames at 30 fps: 29 intervals span 29/30 s, the duration is 1 s.
+        let bitrate = average_bitrate(125_000, 30, 29.0 / 30.0).unwrap();
+        assert!((bitrate - 1_000_000.0).abs() < 1e-6);
+        assert_eq!(average_bitrate(125_000, 1, 0.0), None);
+        assert_eq!(average_bitrate(125_000, 2, 0.0), None);
+    }
+
+    #[test]
+    fn parse_opts_splits_on_the_first_equal_sign() {
+        assert_eq!(parse_opts("").unwrap().iter().count(), 0);
+        assert_eq!(parse_opts(",").unwrap().iter().count(), 0);
+
+        let dict = parse_opts("quality=best,cpu-used=0,").unwrap();
+        assert_eq!(dict.get("quality"), Some("best"));
+        assert_eq!(dict.get("cpu-used"), Some("0"));
+        assert_eq!(dict.iter().count(), 2);
+
+        let dict = parse_opts("ts-parameters=ts_number_layers=2,x=a\\,b").unwrap();
+        assert_eq!(dict.get("ts-parameters"), Some("ts_number_layers=2"));
+        assert_eq!(dict.get("x"), Some("a,b"));
+
+        // The last value wins.
+        let dict = parse_opts("crf=10,crf=20").unwrap();
+        assert_eq!(dict.get("crf"), Some("20"));
+        assert_eq!(dict.iter().count(), 1);
+
+        assert_eq!(
+            parse_opts("crf=10,best").unwrap_err(),
+            OptParseError {
+                token: "best".to_string()
+            }
+        );
+        assert_eq!(parse_opts("=1").unwrap_err().token, "=1");
+    }
+
+    #[test]
+    fn majority_id_is_robust_to_misread_frames() {
+        let counts = HashMap::from([("1".to_string(), 98), ("7".to_string(), 2)]);
+        let (id, agreement) = majority_id(&counts).unwrap();
+        assert_eq!(id, "1");
+        assert!((agreement - 0.98).abs() < 1e-9);
+
+        let tie = HashMap::from([("2".to_string(), 5), ("1".to_string(), 5)]);
+        assert_eq!(majority_id(&tie).unwrap().0, "1");
+        assert!(majority_id(&HashMap::new()).is_none());
+    }
+
+    /// The overlay timestamps are integer milliseconds: with nearest rounding
+    /// the recovered pts must be exactly the expected ticks.
+    #[test]
+    fn time_to_pts_nearest_matches_watermarked_times() {
+        for (time_base, ticks_per_ms) in [
+            (Rational(1, 1000), 1),
+            (Rational(1, 30000), 30),
+            (Rational(1, 90000), 90),
+        ] {
+            // Frame times of a 29.97 fps video, as written by the watermark.
+            for frame in 0..10000i64 {
+                let time_ms = frame * 1001 / 30;
+                let time = time_ms as f64 / 1000f64;
+                assert_eq!(
+                    time_to_pts(time, time_base, PtsRounding::Nearest),
+                    time_ms * ticks_per_ms,
+                    "time: {} time base: {}",
+                    time,
+                    time_base
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn time_to_pts_rounding_modes() {
+        let time_base = Rational(1, 1000);
+        assert_eq!(time_to_pts(0.0104, time_base, PtsRounding::Floor), 10);
+        assert_eq!(time_to_pts(0.0104, time_base, PtsRounding::Nearest), 10);
+        assert_eq!(time_to_pts(0.0104, time_base, PtsRounding::Ceil), 11);
+        assert_eq!(time_to_pts(0.0106, time_base, PtsRounding::Floor), 10);
+        assert_eq!(time_to_pts(0.0106, time_base, PtsRounding::Nearest), 11);
+    }
+
+    fn test_params() -> VideoParams {
+        VideoParams {
+            width: 64,
+            height: 48,
+            format: format::Pixel::YUV420P,
+            aspect_ratio: Rational(1, 1),
+            color: ColorProperties::default(),
+        }
+    }
+
+    fn test_filter(desc: &str) -> Result<VideoFilter, ffmpeg::Error> {
+        ffmpeg::init().unwrap();
+        VideoFilter::new(Rational(1, 1000), test_params(), desc.to_string())
+    }
+
+    #[test]
+    fn video_filter_output_format() {
+        ffmpeg::init().unwrap();
+        let params = test_params();
+        let mut filter = VideoFilter::with_output_format(
+            Rational(1, 1000),
+            params,
+            "format=gray".to_string(),
+            Some(format::Pixel::YUV420P),
+        )
+        .unwrap();
+        let mut frame = frame::Video::new(params.format, params.width, params.height);
+        frame.set_pts(Some(0));
+        let filtered = filter.apply(&frame).unwrap();
+        assert_eq!(filtered.format(), format::Pixel::YUV420P);
+        assert_eq!(
+            encoder_input_format(codec::Id::PNG, format::Pixel::YUV420P),
+            Some(format::Pixel::RGB24)
+        );
+    }
+
+    #[test]
+    fn video_filter_valid_descriptions() {
+        for desc in [
+            watermark_filter_desc(48, Some("1"), None, None, None),
+            watermark_filter_desc(48, Some("1"), None, None, Some(0.5)),
+            "yadif=mode=send_frame:parity=auto:deint=all".to_string(),
+            "fps=fps=30".to_string(),
+            "scale=32:-2".to_string(),
+        ] {
+            assert!(test_filter(&desc).is_ok(), "filter: {}", desc);
+        }
+    }
+
+    #[test]
+    fn video_filter_invalid_descriptions() {
+        for desc in [
+            "nosuchfilter",
+            "drawbox=x=0:nosuchoption=1",
+            "drawbox=color=nosuchcolor",
+        ] {
+            assert!(test_filter(desc).is_err(), "filter: {}", desc);
+        }
+    }
+
+    /// The full text watermark. The font is resolved by fontconfig instead of
+    /// downloaded, so that the test runs offline.
+    #[test]
+    fn video_filter_drawtext_description() {
+        let desc = watermark_filter_desc(48, Some("1"), Some("font.ttf"), None, None)
+            .replace("fontfile=font.ttf:", "");
+        assert!(desc.contains("drawtext=text="), "filter: {}", desc);
+        assert!(test_filter(&desc).is_ok(), "filter: {}", desc);
+        let invalid = desc.replace("fontcolor=white", "fontcolor=nosuchcolor");
+        assert!(test_filter(&invalid).is_err(), "filter: {}", invalid);
+    }
+
+    #[test]
+    fn video_filter_applies_to_synthetic_frame() {
+        let mut filter =
+            test_filter(&watermark_filter_desc(48, Some("1"), None, None, None)).unwrap();
+        let params = test_params();
+        let mut frame = frame::Video::new(params.format, params.width, params.height);
+        frame.set_pts(Some(40));
+        let filtered = filter.apply(&frame).unwrap();
+        assert_eq!(filtered.width(), params.width);
+        assert_eq!(filtered.height(), params.height);
+        assert_eq!(filtered.format(), params.format);
+        assert_eq!(filtered.pts(), Some(40));
+        // The band is filled with black.
+        assert_eq!(filtered.data(0)[0], 16);
+    }
+
+    /// The watermark timestamps are derived from the frames pts, so an input
+    /// starting at 5 s is watermarked with its original timestamps.
+    #[test]
+    fn watermark_timestamps_round_trip_with_start_offset() {
+        ffmpeg::init().unwrap();
+        let time_base = Rational(1, 1000);
+        let params = VideoParams {
+            width: 288,
+            height: 180,
+            ..test_params()
+        };
+        let band_height = band_height(params.height);
+        let mut filter = VideoFilter::new(
+            time_base,
+            params,
+            watermark_filter_desc(params.height, Some("1"), None, None, None),
+        )
+        .unwrap();
+        for index in 0..30u64 {
+            let pts = 5000 + index as i64 * 1001 / 30;
+            let timestamp_ms = watermark_timestamp_ms(TimestampBase::Pts, pts, time_base, index);
+            assert_eq!(timestamp_ms, pts as u64);
+
+            let mut frame = frame::Video::new(params.format, params.width, params.height);
+            frame.set_pts(Some(pts));
+            set_frame_metadata(
+                &mut frame,
+                TIMESTAMP_METADATA_KEY,
+                &timestamp_ms.to_string(),
+            );
+            let mut frame = filter.apply(&frame).unwrap();
+            make_writable(&mut frame);
+            bars::draw(&mut frame, band_height, 1, timestamp_ms);
+
+            let stride = frame.stride(0);
+            let band = image::GrayImage::from_fn(params.width, band_height, |x, y| {
+                image::Luma([frame.data(0)[y as usize * stride + x as usize]])
+            });
+            assert_eq!(bars::decode(&band), Some((1, pts as u64)));
+        }
+        assert_eq!(
+            watermark_timestamp_ms(TimestampBase::FrameIndex, 5000, time_base, 7),
+            7
+        );
+    }
+
+    #[test]
+    fn concurrent_cache_writes_leave_a_complete_file() {
+        let dir =
+            std::env::temp_dir().join(format!("webrtcperf-cache-write-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.bin").to_string_lossy().to_string();
+        let content = vec![7u8; 1 << 20];
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    write_cache_file(&path, |file| {
+                        for chunk in content.chunks(4096) {
+                            file.write_all(chunk)?;
+                        }
+                        Ok(())
+                    })
+                    .unwrap();
+                });
+            }
+        });
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+        // No temporary file is left behind.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // A failed write leaves the cache untouched.
+        let missing = dir.join("missing.bin").to_string_lossy().to_string();
+        assert!(write_cache_file(&missing, |_| Err("download failed".into())).is_err());
+        assert!(!std::path::Path::new(&missing).exists());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn timestamp_patterns_are_tried_in_order() {
+        let regexes = vec![
+            timestamp_pattern_regex(r"id=(?<id>[0-9]+):ts=(?<time>[0-9]+)").unwrap(),
+            timestamp_regex().clone(),
+        ];
+        let (index, text) = parse_timestamp_text_any(&regexes, "id=3:ts=1500", None).unwrap();
+        assert_eq!((index, text.id.as_str(), text.time), (0, "3", 1.5));
+        let (index, text) = parse_timestamp_text_any(&regexes, "7-2000", None).unwrap();
+        assert_eq!((index, text.id.as_str(), text.time), (1, "7", 2.0));
+        assert!(parse_timestamp_text_any(&regexes, "none", None).is_none());
+
+        assert!(timestamp_pattern_regex(r"(?<time>[0-9]+)").is_err());
+        assert!(timestamp_pattern_regex(r"(?<id>[0-9]+)-([0-9]+)").is_err());
+        assert!(timestamp_pattern_regex(r"(?<id>[0-9]+").is_err());
+    }
+
+    #[test]
+    fn timestamp_text_decimals_round_trip() {
+        let frame_re = timestamp_regex();
+        // 60 fps frame times in a 1/90000 time base.
+        let time_base = Rational(1, 90000);
+        let mut last_time = -1.0;
+        for index in 0..120i64 {
+            let pts = index * 1500;
+            let timestamp_us = watermark_timestamp_us(TimestampBase::Pts, pts, time_base, 0);
+            let text = format!("1-{}", timestamp_text(timestamp_us, 3));
+            let parsed = parse_timestamp_text(frame_re, &text, None).unwrap();
+            assert!(
+                (parsed.time - pts as f64 / 90000.0).abs() < 1e-6,
+                "{}",
+                text
+            );
+            assert!(parsed.time > last_time);
+            last_time = parsed.time;
+        }
+        assert_eq!(timestamp_text(16_666, 0), "16");
+        assert_eq!(timestamp_text(16_666, 1), "16.6");
+        assert_eq!(timestamp_text(1_000_050, 3), "1000.050");
+        assert_eq!(
+            parse_timestamp_text(frame_re, "12-345", None).unwrap().time,
+            0.345
+        );
+    }
+
+    #[test]
+    fn timecode_round_trip() {
+        let frame_re = timestamp_regex();
+        for fps in [25.0, 30000.0 / 1001.0, 60.0] {
+            for frame_index in [0, 1, 29, 30, 1799, 1800, 107_892, 2_000_000] {
+                let text = format!("7-{}", timecode_text(frame_index, fps));
+                let parsed = parse_timestamp_text(frame_re, &text, Some(fps)).unwrap();
+                assert_eq!(parsed.id, "7");
+                assert_eq!(parsed.time_digits, 11);
+                assert!(
+                    (parsed.time - frame_index as f64 / fps).abs() < 1e-9,
+                    "{} at {} fps",
+                    text,
+                    fps
+                );
+            }
+        }
+        assert_eq!(timecode_text(90_061 * 30 + 12, 30.0), "01:01:01:12");
+        // A timecode isn't converted without the frame rate.
+        assert!(parse_timestamp_text(frame_re, "7-01:01:01:12", None).is_none());
+        assert_eq!(parse_timecode("00:00:01:30", 30.0), None);
+        assert_eq!(parse_timecode("00:60:00:00", 30.0), None);
+        assert_eq!(parse_timecode("00:00:01:29", 29.97), Some(59));
+    }
+
+    #[test]
+    fn watermark_format_check() {
+        assert!(check_watermark_format(DEFAULT_WATERMARK_FORMAT).is_ok());
+        assert!(check_watermark_format("frame %{n} {id}-{timestamp}").is_ok());
+        assert!(check_watermark_format("%{eif\\:n\\:d} %{localtime\\:%T}").is_ok());
+        assert!(check_watermark_format("static text").is_ok());
+        assert!(check_watermark_format("").is_err());
+        assert!(check_watermark_format("{id}'").is_err());
+        assert!(check_watermark_format("%{n").is_err());
+        assert!(check_watermark_format("%{unknown}").is_err());
+        assert_eq!(
+            watermark_text(DEFAULT_WATERMARK_FORMAT, "7"),
+            "7-%{metadata\\:webrtcperf_timestamp}"
+        );
+    }
+
+    #[test]
+    fn normalize_sar_rescales_to_square_pixels() {
+        ffmpeg::init().unwrap();
+        let params = VideoParams {
+            width: 480,
+            height: 360,
+            aspect_ratio: Rational(4, 3),
+            ..test_params()
+        };
+        let width = square_pixels_width(params.width, params.aspect_ratio);
+        assert_eq!(width, 640);
+        let desc = output_filter_desc(0, Some(width)).unwrap();
+        let mut filter = VideoFilter::new(Rational(1, 1000), params, desc).unwrap();
+        let mut frame = frame::Video::new(params.format, params.width, params.height);
+        frame.set_pts(Some(0));
+        frame.set_aspect_ratio(params.aspect_ratio);
+        let frame = filter.apply(&frame).unwrap();
+        assert_eq!((frame.width(), frame.height()), (640, 360));
+        assert_eq!(frame.aspect_ratio(), Rational(1, 1));
+
+        assert_eq!(square_pixels_width(321, Rational(1, 1)), 322);
+        assert_eq!(valid_aspect_ratio(Rational(0, 0)), Rational(1, 1));
+        assert_eq!(valid_aspect_ratio(Rational(0, 1)), Rational(1, 1));
+        assert_eq!(valid_aspect_ratio(Rational(4, 3)), Rational(4, 3));
+        assert_eq!(output_filter_desc(0, None), None);
+    }
+
+    #[test]
+    fn noise_filter_alters_the_frames() {
+        ffmpeg::init().unwrap();
+        let params = test_params();
+        let mut filter =
+            VideoFilter::new(Rational(1, 1000), params, noise_filter_desc(20)).unwrap();
+        let mut frame = frame::Video::new(params.format, params.width, params.height);
+        frame.data_mut(0).fill(128);
+        frame.set_pts(Some(0));
+        let frame = filter.apply(&frame).unwrap();
+        assert!(frame.data(0).iter().any(|&value| value != 128));
     }
-    Some(dict)
 }
@@ -0,0 +1,65 @@
+use std::collections::VecDeque;
+
+/// The number of recent reads the digit count is compared with.
+const HISTORY_FRAMES: usize = 5;
+
+/// Detects the timestamp reads truncated by an occlusion of the watermark band
+/// (e.g. receiver controls drawn over the tail of the text): they still match
+/// the timestamp pattern, but with fewer digits than the recent reads, giving
+/// a wrong (smaller) time. A timestamp grows its digits but never loses them,
+/// so a read with fewer digits than all the recent reads of the same id is
+/// considered truncated.
+#[derive(Debug, Default)]
+pub(crate) struct TruncationDetector {
+    id: Option<String>,
+    recent_digits: VecDeque<usize>,
+}
+
+impl TruncationDetector {
+    /// Checks the time field digits of a read of `id`, returning true if the
+    /// read is truncated. The truncated reads aren't added to the history.
+    pub(crate) fn is_truncated(&mut self, id: &str, digits: usize) -> bool {
+        if self.id.as_deref() != Some(id) {
+            // A different id restarts the timestamps.
+            self.id = Some(id.to_string());
+            self.recent_digits.clear();
+        }
+        if self.recent_digits.len() == HISTORY_FRAMES
+            && self.recent_digits.iter().all(|recent| digits < *recent)
+        {
+            return true;
+        }
+        if self.recent_digits.len() == HISTORY_FRAMES {
+            self.recent_digits.pop_front();
+        }
+        self.recent_digits.push_back(digits);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digit_drops_are_truncated_reads() {
+        let mut detector = TruncationDetector::default();
+        for _ in 0..HISTORY_FRAMES {
+            assert!(!detector.is_truncated("1", 6));
+        }
+        assert!(detector.is_truncated("1", 4));
+        assert!(detector.is_truncated("1", 5));
+        assert!(!detector.is_truncated("1", 6));
+        // The digits grow with the timestamps.
+        assert!(!detector.is_truncated("1", 7));
+        // A new id restarts the history.
+        assert!(!detector.is_truncated("2", 3));
+    }
+
+    #[test]
+    fn short_history_accepts_the_reads() {
+        let mut detector = TruncationDetector::default();
+        assert!(!detector.is_truncated("1", 6));
+        assert!(!detector.is_truncated("1", 4));
+    }
+}
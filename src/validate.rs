@@ -0,0 +1,262 @@
+use crate::bars;
+use crate::config::{Config, Deinterlace, Keyframes, Overlay};
+use crate::ocr::PooledOcr;
+use crate::transcoder::{
+    bars_id, check_color_space, check_video_codec, check_watermark_format, check_watermark_opacity,
+    font_path, input_frame_rate, noise_filter_desc, open_encoder, open_video_decoder, output_codec,
+    watermark_filter_desc, Mode, VideoFilter,
+};
+use crate::{open_input, open_output, processed_video_streams};
+use ffmpeg::{codec, encoder, format, Rational};
+use log::debug;
+use regex::Regex;
+
+/// The result of a single validation step.
+#[derive(Debug, Clone)]
+pub struct ValidationCheck {
+    /// The validation step description.
+    pub name: String,
+    /// The error message, `None` if the step passed.
+    pub error: Option<String>,
+}
+
+impl ValidationCheck {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl std::fmt::Display for ValidationCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error {
+            None => write!(f, "PASS {}", self.name),
+            Some(error) => write!(f, "FAIL {}: {}", self.name, error),
+        }
+    }
+}
+
+struct Checks(Vec<ValidationCheck>);
+
+impl Checks {
+    /// Records the result of a validation step, returning the value on success.
+    fn check<T, E: std::fmt::Display>(&mut self, name: String, result: Result<T, E>) -> Option<T> {
+        debug!("validate: {}", name);
+        match result {
+            Ok(value) => {
+                self.0.push(ValidationCheck { name, error: None });
+                Some(value)
+            }
+            Err(e) => {
+                self.0.push(ValidationCheck {
+                    name,
+                    error: Some(e.to_string()),
+                });
+                None
+            }
+        }
+    }
+}
+
+/// Runs every setup step of the transcoding (input, decoders, filter graphs,
+/// Tesseract, encoders and output context) without processing any frame.
+/// The output context is created on a temporary file which is removed.
+pub fn validate(
+    input_files: &[&str],
+    output_file: &str,
+    mode: &Mode,
+    watermark_id: Option<&str>,
+    config: &Config,
+) -> Vec<ValidationCheck> {
+    let mut checks = Checks(Vec::new());
+    if checks
+        .check("ffmpeg init".to_string(), ffmpeg::init())
+        .is_none()
+    {
+        return checks.0;
+    }
+
+    let temp_output_file = Regex::new(r"(\.\w+)$")
+        .unwrap()
+        .replace(output_file, format!(".dry-run.{}$1", std::process::id()))
+        .to_string();
+    let mut octx = checks.check(
+        format!("create output {}", output_file),
+        open_output(&temp_output_file, config),
+    );
+    let global_header = octx.as_ref().map_or(false, |octx| {
+        octx.format().flags().contains(format::Flags::GLOBAL_HEADER)
+    });
+    checks.check("video codec".to_string(), check_video_codec(config.codec));
+    let codec_id = octx
+        .as_ref()
+        .map_or(codec::Id::VP8, |octx| output_codec(octx, config.codec));
+
+    if let Some(color_space) = config.color_space {
+        checks.check(
+            "color space".to_string(),
+            check_color_space(codec_id, color_space),
+        );
+    }
+
+    let with_watermark = matches!(mode, Mode::Watermark);
+    let font_file = if with_watermark && config.overlay == Overlay::Text {
+        checks.check(
+            "watermark font".to_string(),
+            font_path(config.cache_dir.as_deref()),
+        )
+    } else {
+        None
+    };
+    if with_watermark && config.overlay == Overlay::Bars {
+        checks.check("watermark id".to_string(), bars_id(watermark_id));
+    }
+
+    for (segment_index, input_file) in input_files.iter().enumerate() {
+        let ictx = match checks.check(
+            format!("open input {}", input_file),
+            format::input(input_file),
+        ) {
+            Some(ictx) => ictx,
+            None => continue,
+        };
+        let processed_streams = processed_video_streams(&ictx, config);
+        let mut video_streams = 0;
+        for ist in ictx.streams() {
+            if !processed_streams.contains(&ist.index()) {
+                continue;
+            }
+            video_streams += 1;
+            let name = format!("{}#{}", input_file, ist.index());
+            let decoder = match checks.check(
+                format!("decoder {}", name),
+                open_video_decoder(ist.parameters(), config.decoder_options.as_deref()),
+            ) {
+                Some(decoder) => decoder,
+                None => continue,
+            };
+            let time_base = ist.time_base();
+            if with_watermark {
+                checks.check(
+                    format!("watermark filter {}", name),
+                    VideoFilter::new(
+                        time_base,
+                        &decoder,
+                        watermark_filter_desc(
+                            decoder.height(),
+                            watermark_id,
+                            font_file.as_deref(),
+                            config.watermark_format.as_deref(),
+                            config.watermark_opacity,
+                        ),
+                    ),
+                );
+            }
+            if config.overlay == Overlay::Bars {
+                let result = if bars::supports_format(decoder.format()) {
+                    Ok(())
+                } else {
+                    Err(format!("unsupported pixel format {:?}", decoder.format()))
+                };
+                checks.check(format!("bars overlay {}", name), result);
+            }
+            if let (true, Some(sigma)) = (with_watermark, config.noise) {
+                checks.check(
+                    format!("noise filter {}", name),
+                    VideoFilter::new(time_base, &decoder, noise_filter_desc(sigma)),
+                );
+            }
+            if config.deinterlace == Deinterlace::On {
+                checks.check(
+                    format!("deinterlace filter {}", name),
+                    VideoFilter::new(time_base, &decoder, "yadif".to_string()),
+                );
+            }
+            let target_fps = config.target_fps.filter(|_| matches!(mode, Mode::Process));
+            if let Some(target_fps) = target_fps {
+                checks.check(
+                    format!("fps filter {}", name),
+                    VideoFilter::new(time_base, &decoder, format!("fps=fps={}", target_fps)),
+                );
+            }
+            if config.keyframes == Keyframes::SceneCut {
+                checks.check(
+                    format!("scene cut filter {}", name),
+                    VideoFilter::new(
+                        time_base,
+                        &decoder,
+                        format!("scdet=threshold={}", config.scene_threshold),
+                    ),
+                );
+            }
+            let frame_rate = target_fps
+                .map(Rational::from)
+                .or(input_frame_rate(decoder.frame_rate(), config.fps));
+            let encoder = checks.check(
+                format!("encoder {}", name),
+                open_encoder(
+                    &decoder,
+                    codec_id,
+                    time_base,
+                    frame_rate,
+                    global_header,
+                    config.keyframes,
+                    config.gop,
+                    config.crf,
+                    config.encoder_options.as_ref(),
+                ),
+            );
+            // The output streams are created from the first input only.
+            if let (0, Some(octx), Some(encoder)) = (segment_index, octx.as_mut(), encoder) {
+                let result = octx.add_stream(encoder.codec()).map(|mut ost| {
+                    ost.set_parameters(&encoder);
+                    ost.set_time_base(time_base);
+                });
+                checks.check(format!("output stream {}", name), result);
+            }
+        }
+        if video_streams == 0 {
+            checks.check::<(), _>(
+                format!("video streams {}", input_file),
+                Err("no video stream found"),
+            );
+        }
+    }
+
+    if config.audio_sync && matches!(mode, Mode::Watermark) {
+        checks.check(
+            "audio markers encoder".to_string(),
+            encoder::find_by_name("libopus").ok_or("libopus encoder not available"),
+        );
+    }
+
+    if let (true, Some(format)) = (with_watermark, config.watermark_format.as_deref()) {
+        checks.check(
+            "watermark format".to_string(),
+            check_watermark_format(format),
+        );
+    }
+
+    if with_watermark {
+        checks.check(
+            "watermark opacity".to_string(),
+            check_watermark_opacity(config.watermark_opacity, config.overlay),
+        );
+    }
+
+    if matches!(mode, Mode::Process) && config.overlay == Overlay::Text {
+        checks.check("tesseract".to_string(), PooledOcr::acquire(config));
+    }
+
+    if let Some(mut octx) = octx {
+        checks.check(
+            format!("write output header {}", output_file),
+            octx.write_header(),
+        );
+        drop(octx);
+        if let Err(e) = std::fs::remove_file(&temp_output_file) {
+            debug!("Failed to remove {}: {}", temp_output_file, e);
+        }
+    }
+
+    checks.0
+}
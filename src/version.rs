@@ -0,0 +1,43 @@
+use std::ffi::CStr;
+
+/// Formats an `AV_VERSION_INT` encoded library version.
+fn format_version(version: u32) -> String {
+    format!(
+        "{}.{}.{}",
+        version >> 16,
+        (version >> 8) & 0xff,
+        version & 0xff
+    )
+}
+
+/// Returns a report of the ffmpeg libraries and Tesseract versions linked at
+/// runtime, used for reproducing the encoding and recognition behavior.
+pub fn version_info() -> String {
+    let ffmpeg_version = unsafe { CStr::from_ptr(ffmpeg::ffi::av_version_info()) }
+        .to_string_lossy()
+        .into_owned();
+    let libvpx = match ffmpeg::encoder::find_by_name("libvpx") {
+        Some(codec) => codec.description().to_string(),
+        None => "not available".to_string(),
+    };
+    let info = [
+        format!("webrtcperf-vmaf-utils: {}", env!("CARGO_PKG_VERSION")),
+        format!("ffmpeg: {}", ffmpeg_version),
+        format!("libavutil: {}", format_version(ffmpeg::util::version())),
+        format!("libavcodec: {}", format_version(ffmpeg::codec::version())),
+        format!("libavformat: {}", format_version(ffmpeg::format::version())),
+        format!("libavfilter: {}", format_version(ffmpeg::filter::version())),
+        format!(
+            "libswscale: {}",
+            format_version(ffmpeg::software::scaling::version())
+        ),
+        format!(
+            "libswresample: {}",
+            format_version(ffmpeg::software::resampling::version())
+        ),
+        format!("libvpx encoder: {}", libvpx),
+        format!("tesseract: {}", crate::ocr::version()),
+        format!("ffmpeg configuration: {}", ffmpeg::util::configuration()),
+    ];
+    info.join("\n")
+}
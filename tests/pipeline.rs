@@ -0,0 +1,608 @@
+//! End-to-end tests running the watermark and process modes on a synthetic
+//! video. They require the `ffmpeg` command line tool to generate the input.
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use webrtcperf_vmaf_utils::{
+    process_video, watermark_video, Config, FrameAction, FrameCallback, Overlay, Padding,
+    ProcessError, PtsRemapper, RecordingSession,
+};
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+const FPS: u32 = 30;
+const DURATION: u32 = 3;
+
+/// Generates a `testsrc2` video into a new temporary directory and returns its
+/// path.
+fn generate_input(name: &str) -> PathBuf {
+    generate_input_with_args(name, &[])
+}
+
+/// Like `generate_input`, with additional ffmpeg output options.
+fn generate_input_with_args(name: &str, output_args: &[&str]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "webrtcperf-vmaf-utils-{}-{}",
+        name,
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("input.ivf");
+    let status = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-f", "lavfi", "-i"])
+        .arg(format!(
+            "testsrc2=size={}x{}:rate={}:duration={}",
+            WIDTH, HEIGHT, FPS, DURATION
+        ))
+        .args(["-c:v", "libvpx", "-b:v", "2M"])
+        .args(output_args)
+        .arg(&input)
+        .status()
+        .expect("ffmpeg is required to run the integration tests");
+    assert!(status.success(), "ffmpeg failed: {}", status);
+    input
+}
+
+/// Watermarks and processes a synthetic video, checking the recognized
+/// timestamps against the input frame times.
+fn round_trip(name: &str, config: Config) {
+    let input = generate_input(name);
+    let input_file = input.to_str().unwrap();
+
+    let (_sender, receiver) = crossbeam_channel::unbounded();
+    let watermarked = watermark_video(input_file, "1", &config, receiver).unwrap();
+    let frames = (FPS * DURATION) as usize;
+    assert_eq!(watermarked.frames, frames);
+
+    let frames_csv = input.with_file_name("frames.csv");
+    let config = Config {
+        frames_csv: Some(frames_csv.to_str().unwrap().to_string()),
+        ..config
+    };
+    let (_sender, receiver) = crossbeam_channel::unbounded();
+    let processed = process_video(&[&watermarked.output_file], &config, receiver).unwrap();
+    assert_eq!(processed.frames, frames);
+    assert!(
+        processed.failed_frames * 10 <= processed.frames,
+        "failed frames: {}/{}",
+        processed.failed_frames,
+        processed.frames
+    );
+
+    let csv = std::fs::read_to_string(&frames_csv).unwrap();
+    let mut recognized = 0;
+    for row in csv.lines().skip(1) {
+        let columns: Vec<&str> = row.split(',').collect();
+        let frame: usize = columns[0].parse().unwrap();
+        if columns[5].is_empty() {
+            continue;
+        }
+        let recognized_time: f64 = columns[5].parse().unwrap();
+        // The frame column counts from 1.
+        let expected_time = (frame - 1) as f64 / FPS as f64;
+        assert!(
+            (recognized_time - expected_time).abs() <= 0.010,
+            "frame {}: recognized {} expected {}",
+            frame,
+            recognized_time,
+            expected_time
+        );
+        recognized += 1;
+    }
+    assert!(
+        recognized * 10 >= frames * 9,
+        "recognized frames: {}/{}",
+        recognized,
+        frames
+    );
+
+    std::fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}
+
+#[cfg(feature = "ocr")]
+#[test]
+fn text_overlay_round_trip() {
+    round_trip("text", Config::default());
+}
+
+/// The drawtext overlay shows the frame pts, including the input start time.
+#[cfg(feature = "ocr")]
+#[test]
+fn text_overlay_round_trip_with_start_offset() {
+    let input = generate_input_with_args("text-offset", &["-output_ts_offset", "5"]);
+    let config = Config {
+        // The timestamps are recognized from the overlay only.
+        ignore_timestamp_metadata: true,
+        ..Default::default()
+    };
+    let (_sender, receiver) = crossbeam_channel::unbounded();
+    let watermarked = watermark_video(input.to_str().unwrap(), "1", &config, receiver).unwrap();
+
+    let frames_csv = input.with_file_name("frames.csv");
+    let config = Config {
+        frames_csv: Some(frames_csv.to_str().unwrap().to_string()),
+        ..config
+    };
+    let (_sender, receiver) = crossbeam_channel::unbounded();
+    process_video(&[&watermarked.output_file], &config, receiver).unwrap();
+
+    let csv = std::fs::read_to_string(&frames_csv).unwrap();
+    let mut recognized = 0;
+    for row in csv.lines().skip(1) {
+        let columns: Vec<&str> = row.split(',').collect();
+        let frame: usize = columns[0].parse().unwrap();
+        if columns[5].is_empty() {
+            continue;
+        }
+        let recognized_time: f64 = columns[5].parse().unwrap();
+        let expected_time = 5.0 + (frame - 1) as f64 / FPS as f64;
+        assert!(
+            (recognized_time - expected_time).abs() <= 0.010,
+            "frame {}: recognized {} expected {}",
+            frame,
+            recognized_time,
+            expected_time
+        );
+        recognized += 1;
+    }
+    let frames = (FPS * DURATION) as usize;
+    assert!(
+        recognized * 10 >= frames * 9,
+        "recognized frames: {}/{}",
+        recognized,
+        frames
+    );
+
+    std::fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn bars_overlay_round_trip() {
+    round_trip(
+        "bars",
+        Config {
+            overlay: Overlay::Bars,
+            ..Default::default()
+        },
+    );
+}
+
+#[test]
+fn y4m_output_is_accepted_by_ffprobe() {
+    let input = generate_input("y4m");
+    let config = Config {
+        overlay: Overlay::Bars,
+        ..Default::default()
+    };
+    let (_sender, receiver) = crossbeam_channel::unbounded();
+    let watermarked = watermark_video(input.to_str().unwrap(), "1", &config, receiver).unwrap();
+
+    let output = input.with_file_name("output.y4m");
+    let config = Config {
+        output: Some(output.to_str().unwrap().to_string()),
+        force_output_format: Some("yuv4mpegpipe".to_string()),
+        target_fps: Some(FPS as f64),
+        ..config
+    };
+    let (_sender, receiver) = crossbeam_channel::unbounded();
+    process_video(&[&watermarked.output_file], &config, receiver).unwrap();
+
+    let probe = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0", "-count_frames"])
+        .args([
+            "-show_entries",
+            "stream=codec_name,width,height,r_frame_rate,nb_read_frames",
+        ])
+        .args(["-of", "csv=p=0"])
+        .arg(&output)
+        .output()
+        .expect("ffprobe is required to run the integration tests");
+    assert!(probe.status.success(), "ffprobe failed: {:?}", probe);
+    assert_eq!(
+        String::from_utf8_lossy(&probe.stdout).trim(),
+        format!("rawvideo,{},{},{}/1,{}", WIDTH, HEIGHT, FPS, FPS * DURATION)
+    );
+
+    std::fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn target_duration_pads_and_truncates_the_output() {
+    let input = generate_input("target-duration");
+    let config = Config {
+        overlay: Overlay::Bars,
+        ..Default::default()
+    };
+    let (_sender, receiver) = crossbeam_channel::unbounded();
+    let watermarked = watermark_video(input.to_str().unwrap(), "1", &config, receiver).unwrap();
+
+    for (i, duration) in [DURATION + 1, DURATION - 1].into_iter().enumerate() {
+        let config = Config {
+            output: Some(
+                input
+                    .with_file_name(format!("output{}.ivf", i))
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            ),
+            target_fps: Some(FPS as f64),
+            target_duration: Some(duration as f64),
+            padding: Padding::Black,
+            ..config.clone()
+        };
+        let (_sender, receiver) = crossbeam_channel::unbounded();
+        let processed = process_video(&[&watermarked.output_file], &config, receiver).unwrap();
+        assert_eq!(processed.output_frames, (FPS * duration) as usize);
+        if duration > DURATION {
+            assert!(processed.padded_frames >= FPS as usize);
+            assert_eq!(processed.truncated_frames, 0);
+        } else {
+            assert_eq!(processed.padded_frames, 0);
+            assert!(processed.truncated_frames > 0);
+        }
+    }
+
+    std::fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}
+
+/// Returns the color properties of the first video stream of `file`.
+fn probe_color_properties(file: &std::path::Path) -> String {
+    let probe = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0"])
+        .args([
+            "-show_entries",
+            "stream=color_range,color_space,color_primaries,color_transfer",
+        ])
+        .args(["-of", "csv=p=0"])
+        .arg(file)
+        .output()
+        .expect("ffprobe is required to run the integration tests");
+    assert!(probe.status.success(), "ffprobe failed: {:?}", probe);
+    String::from_utf8_lossy(&probe.stdout).trim().to_string()
+}
+
+#[test]
+fn color_properties_are_preserved() {
+    let input = generate_input("color");
+    let tagged = input.with_file_name("tagged.webm");
+    let status = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-i"])
+        .arg(&input)
+        .args(["-c:v", "libvpx", "-b:v", "2M", "-color_range", "pc"])
+        .args(["-colorspace", "bt709", "-color_primaries", "bt709"])
+        .args(["-color_trc", "bt709"])
+        .arg(&tagged)
+        .status()
+        .unwrap();
+    assert!(status.success(), "ffmpeg failed: {}", status);
+    let expected = probe_color_properties(&tagged);
+    assert_eq!(expected, "pc,bt709,bt709,bt709");
+
+    let output = input.with_file_name("output.webm");
+    let config = Config {
+        overlay: Overlay::Bars,
+        output: Some(output.to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+    let (_sender, receiver) = crossbeam_channel::unbounded();
+    watermark_video(tagged.to_str().unwrap(), "1", &config, receiver).unwrap();
+    assert_eq!(probe_color_properties(&output), expected);
+
+    std::fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn simulated_packet_loss_is_reproducible() {
+    let input = generate_input("packet-loss");
+    let frames = (FPS * DURATION) as usize;
+    let mut lost = Vec::new();
+    for i in 0..2 {
+        let config = Config {
+            overlay: Overlay::Bars,
+            output: Some(
+                input
+                    .with_file_name(format!("watermarked{}.ivf", i))
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            ),
+            simulate_packet_loss: Some(0.2),
+            packet_loss_seed: Some(42),
+            ..Default::default()
+        };
+        let (_sender, receiver) = crossbeam_channel::unbounded();
+        let watermarked = watermark_video(input.to_str().unwrap(), "1", &config, receiver).unwrap();
+        assert_eq!(watermarked.frames, frames);
+        lost.push(watermarked.simulated_lost_frames);
+
+        let config = Config {
+            overlay: Overlay::Bars,
+            output: Some(
+                input
+                    .with_file_name(format!("processed{}.ivf", i))
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+        let (_sender, receiver) = crossbeam_channel::unbounded();
+        let processed = process_video(&[&watermarked.output_file], &config, receiver).unwrap();
+        assert_eq!(processed.frames, frames - watermarked.simulated_lost_frames);
+        // Only the runs of two or more lost frames are reported as gaps.
+        let interval_ms = 1000.0 / FPS as f64;
+        let mut missing = 0;
+        for gap in processed.pts_gaps.iter() {
+            assert!(gap.gap_ms > 2.0 * interval_ms, "gap: {:?}", gap);
+            missing += (gap.gap_ms / interval_ms).round() as usize - 1;
+        }
+        assert!(missing <= watermarked.simulated_lost_frames);
+    }
+    assert!(lost[0] > 0 && lost[0] < frames / 2, "lost: {}", lost[0]);
+    assert_eq!(lost[0], lost[1]);
+
+    std::fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn second_stop_request_aborts_the_flush() {
+    let input = generate_input("stop-flush");
+    let config = Config {
+        overlay: Overlay::Bars,
+        ..Default::default()
+    };
+    let (_sender, receiver) = crossbeam_channel::unbounded();
+    let watermarked = watermark_video(input.to_str().unwrap(), "1", &config, receiver).unwrap();
+
+    let output = input.with_file_name("output.ivf");
+    let config = Config {
+        output: Some(output.to_str().unwrap().to_string()),
+        ..config
+    };
+    // The first request stops the packet loop, the second one is received
+    // while flushing.
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    sender.send("stop").unwrap();
+    sender.send("stop").unwrap();
+    let result = process_video(&[&watermarked.output_file], &config, receiver);
+    match result {
+        Err(e) => match e.downcast_ref::<ProcessError>() {
+            Some(ProcessError::Truncated { output_file, .. }) => {
+                assert_eq!(output_file, output.to_str().unwrap())
+            }
+            _ => panic!("unexpected error: {}", e),
+        },
+        Ok(_) => panic!("the flush was not aborted"),
+    }
+    // The trailer was written.
+    let probe = Command::new("ffprobe")
+        .args(["-v", "error"])
+        .arg(&output)
+        .status()
+        .expect("ffprobe is required to run the integration tests");
+    assert!(probe.success());
+
+    std::fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn spliced_id_change_is_reported() {
+    let input = generate_input("id-change");
+    let config = Config {
+        overlay: Overlay::Bars,
+        ..Default::default()
+    };
+    let mut parts = Vec::new();
+    for id in ["1", "2"] {
+        let part = input.with_file_name(format!("part-{}.ivf", id));
+        let config = Config {
+            output: Some(part.to_str().unwrap().to_string()),
+            ..config.clone()
+        };
+        let (_sender, receiver) = crossbeam_channel::unbounded();
+        watermark_video(input.to_str().unwrap(), id, &config, receiver).unwrap();
+        parts.push(part);
+    }
+    let list = input.with_file_name("parts.txt");
+    let list_text: String = parts
+        .iter()
+        .map(|part| format!("file '{}'\n", part.to_str().unwrap()))
+        .collect();
+    std::fs::write(&list, list_text).unwrap();
+    let spliced = input.with_file_name("spliced.ivf");
+    let status = Command::new("ffmpeg")
+        .args([
+            "-y",
+            "-loglevel",
+            "error",
+            "-f",
+            "concat",
+            "-safe",
+            "0",
+            "-i",
+        ])
+        .arg(&list)
+        .args(["-c", "copy"])
+        .arg(&spliced)
+        .status()
+        .unwrap();
+    assert!(status.success(), "ffmpeg failed: {}", status);
+
+    let config = Config {
+        output: Some(
+            input
+                .with_file_name("output.ivf")
+                .to_str()
+                .unwrap()
+                .to_string(),
+        ),
+        id_change_frames: 10,
+        ..config
+    };
+    let (_sender, receiver) = crossbeam_channel::unbounded();
+    let processed = process_video(&[spliced.to_str().unwrap()], &config, receiver).unwrap();
+    assert_eq!(processed.id_transitions.len(), 1);
+    let transition = &processed.id_transitions[0];
+    assert_eq!(
+        (transition.from_id.as_str(), transition.to_id.as_str()),
+        ("1", "2")
+    );
+    assert!(
+        (transition.time - DURATION as f64).abs() <= 0.1,
+        "transition time: {}",
+        transition.time
+    );
+
+    std::fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn recording_session_stops_and_returns_the_result() {
+    let input = generate_input("session");
+    let output = input.with_file_name("output.ivf");
+    let config = Config {
+        overlay: Overlay::Bars,
+        output: Some(output.to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+    let mut session = RecordingSession::start(input.to_str().unwrap(), "1", &config);
+    session.stop();
+    let result = session.result().unwrap();
+    assert!(result.frames <= (FPS * DURATION) as usize);
+    assert!(output.exists());
+
+    std::fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn on_frame_hook_skips_the_vetoed_frames() {
+    let input = generate_input("on-frame");
+    let config = Config {
+        overlay: Overlay::Bars,
+        ..Default::default()
+    };
+    let (_sender, receiver) = crossbeam_channel::unbounded();
+    let watermarked = watermark_video(input.to_str().unwrap(), "1", &config, receiver).unwrap();
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let encoded = Arc::new(AtomicUsize::new(0));
+    let on_frame = {
+        let calls = calls.clone();
+        let encoded = encoded.clone();
+        FrameCallback::new(move |view| {
+            assert_eq!((view.width, view.height), (WIDTH, HEIGHT));
+            assert!(!view.planes.is_empty());
+            if calls.fetch_add(1, Ordering::SeqCst) % 2 == 1 {
+                return FrameAction::Skip;
+            }
+            if view.recognized_time.is_some() {
+                assert_eq!(view.recognized_id, Some("1"));
+                encoded.fetch_add(1, Ordering::SeqCst);
+            }
+            FrameAction::Encode
+        })
+    };
+    let config = Config {
+        output: Some(
+            input
+                .with_file_name("output.ivf")
+                .to_str()
+                .unwrap()
+                .to_string(),
+        ),
+        on_frame: Some(on_frame),
+        ..config
+    };
+    let (_sender, receiver) = crossbeam_channel::unbounded();
+    let processed = process_video(&[&watermarked.output_file], &config, receiver).unwrap();
+
+    let frames = (FPS * DURATION) as usize;
+    assert_eq!(processed.frames, frames);
+    assert_eq!(calls.load(Ordering::SeqCst), frames);
+    assert_eq!(processed.output_frames, encoded.load(Ordering::SeqCst));
+    assert!(processed.output_frames <= frames / 2);
+
+    std::fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn pts_remapper_replaces_the_recognition() {
+    let input = generate_input("pts-remapper");
+    let frames_csv = input.with_file_name("frames.csv");
+    let config = Config {
+        output: Some(
+            input
+                .with_file_name("output.ivf")
+                .to_str()
+                .unwrap()
+                .to_string(),
+        ),
+        frames_csv: Some(frames_csv.to_str().unwrap().to_string()),
+        // Not watermarked: the timestamps come only from the remapper.
+        pts_remapper: Some(PtsRemapper::new(|pts, time_base| {
+            pts + (1.0 / f64::from(time_base)).round() as i64
+        })),
+        ..Default::default()
+    };
+    let (_sender, receiver) = crossbeam_channel::unbounded();
+    let processed = process_video(&[input.to_str().unwrap()], &config, receiver).unwrap();
+
+    let frames = (FPS * DURATION) as usize;
+    assert_eq!(processed.frames, frames);
+    assert_eq!(processed.failed_frames, 0);
+    assert_eq!(processed.output_frames, frames);
+    let csv = std::fs::read_to_string(&frames_csv).unwrap();
+    for row in csv.lines().skip(1) {
+        let columns: Vec<&str> = row.split(',').collect();
+        let time: f64 = columns[2].parse().unwrap();
+        let recognized_time: f64 = columns[5].parse().unwrap();
+        assert!((recognized_time - time - 1.0).abs() < 1e-3, "{}", row);
+        assert_eq!(columns[6], "remapped");
+    }
+
+    std::fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}
+
+#[cfg(feature = "ocr")]
+#[test]
+#[ignore = "downloads the traineddata"]
+fn concurrent_recognition_init_with_an_empty_cache() {
+    let input = generate_input("cache-race");
+    let cache_dir = input.with_file_name("cache");
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let config = Config {
+                    cache_dir: Some(cache_dir.to_str().unwrap().to_string()),
+                    output: Some(
+                        input
+                            .with_file_name(format!("processed{}.ivf", i))
+                            .to_str()
+                            .unwrap()
+                            .to_string(),
+                    ),
+                    ..Default::default()
+                };
+                let input = input.to_str().unwrap().to_string();
+                scope.spawn(move || {
+                    webrtcperf_vmaf_utils::validate_process_video(&[&input], &config)
+                })
+            })
+            .collect();
+        for handle in handles {
+            for check in handle.join().unwrap() {
+                assert!(check.passed(), "{}", check);
+            }
+        }
+    });
+    let files: Vec<String> = std::fs::read_dir(&cache_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(files, vec!["eng.traineddata".to_string()]);
+
+    std::fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}